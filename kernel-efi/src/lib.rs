@@ -3,6 +3,13 @@ pub use ttf_parser;
 pub use uefi::proto::console::gop;
 pub const KERNEL_ADDR:*mut u8=0x01FF_FFFF_0000_0000 as *mut u8;
 pub const ARGS_ADDR:*mut Args=0x8000_0000 as *mut Args;
+///Where the kernel heap starts, if KASLR is off.
+pub const HEAP_ADDR:u64=0xFFFF_C000_0000_0000;
+
+///KASLR picks the kernel base from this window by default. boot.cfg can change it with `kaslr_window`.
+pub const KERNEL_WINDOW:Window=Window{start:0xFFFF_FFFF_8000_0000, end:0xFFFF_FFFF_F000_0000, align:2<<20};
+pub const HEAP_WINDOW:Window=Window{start:0xFFFF_C000_0000_0000, end:0xFFFF_E000_0000_0000, align:1<<30};
+pub const DIRECT_MAP_WINDOW:Window=Window{start:0xFFFF_8000_0000_0000, end:0xFFFF_C000_0000_0000, align:1<<30};
 
 
 #[repr(C)]
@@ -14,6 +21,93 @@ pub struct Args<'b> {
 	pub page_tracker_base: *mut (),
 	pub page_tracker_page_size: usize,
 	pub page_table_entry: [*mut u64;3],
+	///Physical address of the ACPI RSDP. 0, if the firmware didn't provide one.
+	pub rsdp: u64,
+	///The partition, that the boot loader was loaded from.
+	pub boot_partition: BootPartition,
+	///Physical address of the initial ramdisk (a cpio or tar archive). 0, if there is none.
+	pub initrd_base: u64,
+	pub initrd_size: u64,
+	///The kernel command line, UTF-8. Only the first cmdline_len bytes are used.
+	pub cmdline: [u8;CMDLINE_MAX],
+	pub cmdline_len: usize,
+	///Where the kernel, its heap and the direct map are. With KASLR they change on every boot.
+	pub bases: Bases,
+	///The symbol table of the kernel, for symbolized backtraces.
+	pub symbols: Symbols,
+}
+
+///The longest kernel command line in bytes.
+pub const CMDLINE_MAX:usize=1024;
+
+impl Args<'_>{
+	///The kernel command line. Invalid UTF-8 is cut off.
+	pub fn cmdline(&self)->&str{
+		let bytes=&self.cmdline[..self.cmdline_len.min(CMDLINE_MAX)];
+		match core::str::from_utf8(bytes){
+			Ok(s)=>s,
+			Err(e)=>core::str::from_utf8(&bytes[..e.valid_up_to()]).unwrap_or_default(),
+		}
+	}
+}
+
+///A partition, as described by the hard drive node of an UEFI device path.
+#[derive(Debug, Copy, Clone)]
+#[repr(C)]
+pub struct BootPartition{
+	///One of the SIGNATURE_* constants.
+	pub signature_type: u8,
+	///The partition GUID for GPT, or the disk signature (in the first 4 bytes) for MBR.
+	pub signature: [u8;16],
+	///The number of the partition, starting at 1.
+	pub partition_number: u32,
+}
+
+impl BootPartition{
+	pub const SIGNATURE_NONE:u8=0;
+	pub const SIGNATURE_MBR:u8=1;
+	pub const SIGNATURE_GPT:u8=2;
+	pub const UNKNOWN:Self=Self{signature_type:Self::SIGNATURE_NONE,signature:[0;16],partition_number:0};
+}
+
+///The virtual base addresses, that the boot loader chose.
+#[derive(Debug, Copy, Clone)]
+#[repr(C)]
+pub struct Bases{
+	///The address, that the link time addresses of the kernel are relative to. KERNEL_ADDR, if the kernel isn't position independent.
+	pub kernel: u64,
+	///Start of the virtual address range for the kernel heap.
+	pub heap: u64,
+	///The virtual address, at which physical address 0 is mapped. 0 means the identity map of UEFI.
+	pub direct_map: u64,
+}
+
+///Copies of the .symtab section of the kernel and of its string table, in memory that the boot loader marked as used.
+///The symbol values are link time addresses.
+#[derive(Debug, Copy, Clone)]
+#[repr(C)]
+pub struct Symbols{
+	///Physical address of the ELF64 symbol entries. 0, if the kernel has no symbol table.
+	pub symtab_base: u64,
+	pub symtab_size: u64,
+	///Physical address of the string table, that the symbol names point into.
+	pub strtab_base: u64,
+	pub strtab_size: u64,
+	///Link time address of .eh_frame_hdr, for unwinding without frame pointers. 0, if the kernel has none.
+	pub eh_frame_hdr: u64,
+	pub eh_frame_hdr_size: u64,
+}
+
+impl Symbols{
+	pub const NONE:Self=Self{symtab_base:0,symtab_size:0,strtab_base:0,strtab_size:0,eh_frame_hdr:0,eh_frame_hdr_size:0};
+}
+
+///A range of virtual addresses, that a base is picked from. A base is aligned to `align`, and whatever starts there has to end before `end`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Window{
+	pub start: u64,
+	pub end: u64,
+	pub align: u64,
 }
 
 #[derive(Debug)]
@@ -22,6 +116,8 @@ pub struct MapElfRet{
 	pub base: *mut u8,
 	pub pages: usize,
 	pub entry_point: usize,
+	///The virtual address of the first page of the image. The image ends `pages` pages after it.
+	pub start: u64,
 }
 
 #[repr(C)]
@@ -0,0 +1,313 @@
+#![allow(non_snake_case)]
+//Access to the ACPI tables, that the bootloader found for us.
+//Only the static tables are parsed here. There is no AML interpreter.
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use crate::x86_64::mem::phys_to_ptr;
+
+const RSDP_SIGNATURE:&[u8;8] = b"RSD PTR ";
+
+#[repr(C, packed)]
+#[derive(Debug, Copy, Clone)]
+struct RSDP2{
+	Signature:[u8;8],
+	Checksum:u8,
+	OEMID:[u8;6],
+	Revision:u8,
+	RsdtAddress:u32,
+	//Only valid for Revision >= 2
+	Length:u32,
+	XsdtAddress:u64,
+	ExtendedChecksum:u8,
+	Reserved:[u8;3],
+}
+
+///The header every system description table starts with.
+#[repr(C, packed)]
+#[derive(Debug, Copy, Clone)]
+pub struct SdtHeader{
+	pub Signature:[u8;4],
+	pub Length:u32,
+	pub Revision:u8,
+	pub Checksum:u8,
+	pub OEMID:[u8;6],
+	pub OEMTableID:[u8;8],
+	pub OEMRevision:u32,
+	pub CreatorID:u32,
+	pub CreatorRevision:u32,
+}
+
+///The Fixed ACPI Description Table, up to the fields we care about.
+#[repr(C, packed)]
+#[derive(Debug, Copy, Clone)]
+pub struct Fadt{
+	pub Header:SdtHeader,
+	pub FirmwareCtrl:u32,
+	pub Dsdt:u32,
+	pub Reserved:u8,
+	pub PreferredPMProfile:u8,
+	pub SCIInterrupt:u16,
+	pub SMICommandPort:u32,
+	pub AcpiEnable:u8,
+	pub AcpiDisable:u8,
+	pub S4BIOSReq:u8,
+	pub PStateControl:u8,
+	pub PM1aEventBlock:u32,
+	pub PM1bEventBlock:u32,
+	pub PM1aControlBlock:u32,
+	pub PM1bControlBlock:u32,
+	pub PM2ControlBlock:u32,
+	pub PMTimerBlock:u32,
+	pub GPE0Block:u32,
+	pub GPE1Block:u32,
+	pub PM1EventLength:u8,
+	pub PM1ControlLength:u8,
+	pub PM2ControlLength:u8,
+	pub PMTimerLength:u8,
+	pub GPE0Length:u8,
+	pub GPE1Length:u8,
+	pub GPE1Base:u8,
+	pub CStateControl:u8,
+	pub WorstC2Latency:u16,
+	pub WorstC3Latency:u16,
+	pub FlushSize:u16,
+	pub FlushStride:u16,
+	pub DutyOffset:u8,
+	pub DutyWidth:u8,
+	pub DayAlarm:u8,
+	pub MonthAlarm:u8,
+	///The CMOS RTC index of the century register. 0, if there is none.
+	pub Century:u8,
+	///Only valid for ACPI 2.0+
+	pub BootArchitectureFlags:u16,
+}
+
+///An address in the ACPI Generic Address Structure format.
+#[repr(C, packed)]
+#[derive(Debug, Copy, Clone)]
+pub struct GenericAddress{
+	///0 for system memory, 1 for system I/O.
+	pub AddressSpaceId:u8,
+	pub RegisterBitWidth:u8,
+	pub RegisterBitOffset:u8,
+	pub AccessSize:u8,
+	pub Address:u64,
+}
+
+///The HPET Description Table.
+#[repr(C, packed)]
+#[derive(Debug, Copy, Clone)]
+pub struct HpetTable{
+	pub Header:SdtHeader,
+	pub EventTimerBlockId:u32,
+	pub BaseAddress:GenericAddress,
+	pub HpetNumber:u8,
+	pub MinimumTick:u16,
+	pub PageProtection:u8,
+}
+
+///The fixed part of the MADT. The interrupt controller structures follow it.
+#[repr(C, packed)]
+#[derive(Debug, Copy, Clone)]
+struct MadtHeader{
+	Header:SdtHeader,
+	LocalApicAddress:u32,
+	Flags:u32,
+}
+
+///An interrupt controller structure from the MADT.
+#[derive(Debug, Copy, Clone)]
+pub enum MadtEntry{
+	LocalApic{processor_id:u8, apic_id:u8, flags:u32},
+	IoApic{id:u8, address:u32, gsi_base:u32},
+	///An ISA interrupt, that isn't identity mapped to a GSI, or has a non-standard polarity or trigger mode.
+	InterruptOverride{bus:u8, source:u8, gsi:u32, flags:u16},
+	///Any other structure, with its type.
+	Other(u8),
+}
+
+///An ECAM region from the MCFG table.
+#[derive(Debug, Copy, Clone)]
+pub struct McfgEntry{
+	///Physical address of the configuration space of bus 0 in this segment, even if start_bus is higher.
+	pub base:u64,
+	pub segment:u16,
+	pub start_bus:u8,
+	pub end_bus:u8,
+}
+
+///Physical address of the RSDT or XSDT.
+static ROOT:AtomicU64 = AtomicU64::new(0);
+///True, if ROOT is a XSDT (64 bit entries).
+static ROOT_IS_XSDT:AtomicBool = AtomicBool::new(false);
+
+fn checksum_ok(p:*const u8, len:usize)->bool{
+	//Safety:
+	//Callers only pass in memory, that the firmware described as a table of that length.
+	let bytes = unsafe{core::slice::from_raw_parts(p, len)};
+	bytes.iter().fold(0u8, |a, b|a.wrapping_add(*b)) == 0
+}
+
+///Sets up table access from the RSDP the bootloader passed in `Args`.
+///Returns false, if there are no usable ACPI tables.
+pub fn init(rsdp:u64)->bool{
+	if rsdp == 0{
+		log::warn!("The bootloader didn't find any ACPI tables.");
+		return false;
+	}
+	let p = phys_to_ptr::<RSDP2>(rsdp);
+	//Safety:
+	//The first 20 bytes are the ACPI 1.0 RSDP, which always exists.
+	//The rest is only read, if the revision says it exists.
+	let rsdp = unsafe{core::ptr::read_unaligned(p)};
+	if &rsdp.Signature != RSDP_SIGNATURE || !checksum_ok(p as *const u8, 20){
+		log::error!("The RSDP at {:#x} is invalid.", p as usize);
+		return false;
+	}
+	if rsdp.Revision >= 2 && rsdp.XsdtAddress != 0 && checksum_ok(p as *const u8, core::mem::size_of::<RSDP2>()){
+		ROOT.store(rsdp.XsdtAddress, Ordering::Relaxed);
+		ROOT_IS_XSDT.store(true, Ordering::Relaxed);
+	}else{
+		ROOT.store(rsdp.RsdtAddress as u64, Ordering::Relaxed);
+		ROOT_IS_XSDT.store(false, Ordering::Relaxed);
+	}
+	let root = ROOT.load(Ordering::Relaxed);
+	if table_at(root).is_none(){
+		log::error!("The ACPI root table at {:#x} is invalid.", root);
+		ROOT.store(0, Ordering::Relaxed);
+		return false;
+	}
+	for table in tables(){
+		let header = unsafe{core::ptr::read_unaligned(table)};
+		log::debug!("ACPI table {} at {:#x}", core::str::from_utf8(&header.Signature).unwrap_or("????"), table as usize);
+	}
+	true
+}
+
+///Returns a pointer to the table at the given physical address, if its checksum is valid.
+fn table_at(addr:u64)->Option<*const SdtHeader>{
+	if addr == 0{
+		return None;
+	}
+	let p = phys_to_ptr::<SdtHeader>(addr) as *const SdtHeader;
+	//Safety:
+	//The address came from the RSDT/XSDT, which tells us, that there is a table there.
+	let header = unsafe{core::ptr::read_unaligned(p)};
+	if (header.Length as usize) < core::mem::size_of::<SdtHeader>() || !checksum_ok(p as *const u8, header.Length as usize){
+		return None;
+	}
+	Some(p)
+}
+
+///Iterates over all valid tables listed in the RSDT/XSDT.
+pub fn tables()->impl Iterator<Item=*const SdtHeader>{
+	let root = ROOT.load(Ordering::Relaxed);
+	let xsdt = ROOT_IS_XSDT.load(Ordering::Relaxed);
+	let (entries, count) = match table_at(root){
+		None=>(core::ptr::null::<u8>(), 0),
+		Some(p)=>{
+			let len = unsafe{core::ptr::read_unaligned(p)}.Length as usize;
+			let entry_size = if xsdt {8} else {4};
+			((p as *const u8).wrapping_add(core::mem::size_of::<SdtHeader>()), (len-core::mem::size_of::<SdtHeader>())/entry_size)
+		}
+	};
+	(0..count).filter_map(move |i|{
+		//Safety:
+		//The entries directly follow the header, and count was calculated from the length of the table.
+		let addr = unsafe{
+			if xsdt{
+				core::ptr::read_unaligned((entries as *const u64).add(i))
+			}else{
+				core::ptr::read_unaligned((entries as *const u32).add(i)) as u64
+			}
+		};
+		table_at(addr)
+	})
+}
+
+///Finds the first table with the given signature.
+pub fn find_table(signature:&[u8;4])->Option<*const SdtHeader>{
+	tables().find(|t|&unsafe{core::ptr::read_unaligned(*t)}.Signature == signature)
+}
+
+///Returns a copy of the FADT.
+///Fields, that are past the end of the table the firmware provided, are 0.
+pub fn fadt()->Option<Fadt>{
+	let p = find_table(b"FACP")?;
+	let len = (unsafe{core::ptr::read_unaligned(p)}.Length as usize).min(core::mem::size_of::<Fadt>());
+	//Safety:
+	//Fadt only contains integers, so all zeroes is a valid value.
+	//We only copy as many bytes, as the table has.
+	let mut fadt:Fadt = unsafe{core::mem::zeroed()};
+	unsafe{core::ptr::copy_nonoverlapping(p as *const u8, &mut fadt as *mut Fadt as *mut u8, len)};
+	Some(fadt)
+}
+
+///Returns a copy of the HPET table.
+pub fn hpet()->Option<HpetTable>{
+	let p = find_table(b"HPET")?;
+	if (unsafe{core::ptr::read_unaligned(p)}.Length as usize) < core::mem::size_of::<HpetTable>(){
+		return None;
+	}
+	//Safety:
+	//The table is at least as long as HpetTable.
+	Some(unsafe{core::ptr::read_unaligned(p as *const HpetTable)})
+}
+
+///Iterates over the interrupt controller structures in the MADT.
+pub fn madt_entries()->impl Iterator<Item=MadtEntry>{
+	let (mut p, end) = match find_table(b"APIC"){
+		Some(t)=>{
+			let len = unsafe{core::ptr::read_unaligned(t)}.Length as usize;
+			((t as *const u8).wrapping_add(core::mem::size_of::<MadtHeader>()), (t as *const u8).wrapping_add(len))
+		},
+		None=>(core::ptr::null(), core::ptr::null()),
+	};
+	core::iter::from_fn(move ||{
+		if (p as usize)+2 > end as usize{
+			return None;
+		}
+		//Safety:
+		//Every structure starts with its type and length, and we stay within the length of the table.
+		let (kind, len) = unsafe{(*p, *p.add(1) as usize)};
+		if len < 2 || (p as usize)+len > end as usize{
+			return None;
+		}
+		let read_u16 = |o:usize|unsafe{core::ptr::read_unaligned(p.add(o) as *const u16)};
+		let read_u32 = |o:usize|unsafe{core::ptr::read_unaligned(p.add(o) as *const u32)};
+		let entry = match (kind, len){
+			(0, 8..)=>MadtEntry::LocalApic{processor_id:unsafe{*p.add(2)}, apic_id:unsafe{*p.add(3)}, flags:read_u32(4)},
+			(1, 12..)=>MadtEntry::IoApic{id:unsafe{*p.add(2)}, address:read_u32(4), gsi_base:read_u32(8)},
+			(2, 10..)=>MadtEntry::InterruptOverride{bus:unsafe{*p.add(2)}, source:unsafe{*p.add(3)}, gsi:read_u32(4), flags:read_u16(8)},
+			_=>MadtEntry::Other(kind),
+		};
+		p = p.wrapping_add(len);
+		Some(entry)
+	})
+}
+
+///Iterates over the ECAM regions in the MCFG table.
+pub fn mcfg_entries()->impl Iterator<Item=McfgEntry>{
+	//The header is followed by 8 reserved bytes, and then by 16 byte entries.
+	const ENTRIES:usize = core::mem::size_of::<SdtHeader>()+8;
+	const ENTRY_SIZE:usize = 16;
+	let (p, count) = match find_table(b"MCFG"){
+		Some(t)=>{
+			let len = unsafe{core::ptr::read_unaligned(t)}.Length as usize;
+			((t as *const u8).wrapping_add(ENTRIES), len.saturating_sub(ENTRIES)/ENTRY_SIZE)
+		},
+		None=>(core::ptr::null(), 0),
+	};
+	(0..count).map(move |i|{
+		//Safety:
+		//count was calculated from the length of the table.
+		unsafe{
+			let e = p.add(i*ENTRY_SIZE);
+			McfgEntry{
+				base:core::ptr::read_unaligned(e as *const u64),
+				segment:core::ptr::read_unaligned(e.add(8) as *const u16),
+				start_bus:*e.add(10),
+				end_bus:*e.add(11),
+			}
+		}
+	})
+}
@@ -0,0 +1,90 @@
+//Backtraces for the panic handler.
+//By default the kernel is built with frame pointers, and the chain of saved rbp values is followed.
+//With the eh_frame feature the kernel can be built without them, and the unwind tables in .eh_frame are used instead.
+//Nothing here allocates or takes locks, since it runs when the kernel is already broken.
+use core::fmt::Write;
+use kernel_efi::Symbols;
+use ::x86_64::VirtAddr;
+
+#[cfg(feature="eh_frame")]
+mod eh_frame;
+
+///Deeper stacks are cut off, in case the stack is corrupted into a loop.
+const MAX_FRAMES:usize = 64;
+
+///Remembers where the unwind tables are.
+pub fn init(symbols:&Symbols){
+	#[cfg(feature="eh_frame")]
+	eh_frame::init(symbols);
+	#[cfg(not(feature="eh_frame"))]
+	let _ = symbols;
+}
+
+///Whether `len` bytes at `addr` can be read without a page fault.
+fn readable(addr:u64, len:u64)->bool{
+	let Some(last) = addr.checked_add(len-1) else{
+		return false;
+	};
+	[addr, last].into_iter().all(|a|VirtAddr::try_new(a).is_ok_and(|a|crate::x86_64::mem::virt_to_phys(a).is_some()))
+}
+
+///Reads a stack slot, if it is mapped.
+fn read_u64(addr:u64)->Option<u64>{
+	//Safety:
+	//We checked, that the memory is mapped. Stacks are always readable.
+	readable(addr, 8).then(||unsafe{core::ptr::read_unaligned(addr as *const u64)})
+}
+
+///Calls `f` with the return address of every frame, starting with the caller of walk, until it returns false.
+#[cfg(not(feature="eh_frame"))]
+#[inline(never)]
+fn walk(mut f:impl FnMut(u64)->bool){
+	let mut rbp:u64;
+	//Safety:
+	//Only reads rbp.
+	unsafe{core::arch::asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags))};
+	//Every frame starts with the rbp of its caller, followed by the return address.
+	while rbp != 0 && rbp.is_multiple_of(8){
+		let (Some(next), Some(ret)) = (read_u64(rbp), read_u64(rbp+8)) else{
+			break;
+		};
+		if ret == 0 || !f(ret){
+			break;
+		}
+		//Callers have their frames at higher addresses. Anything else is a broken chain.
+		if next <= rbp{
+			break;
+		}
+		rbp = next;
+	}
+}
+
+#[cfg(feature="eh_frame")]
+use eh_frame::walk;
+
+fn print_frame(w:&mut dyn Write, index:usize, ret:u64)->core::fmt::Result{
+	write!(w, "{:>3}: {:#018x}", index, ret)?;
+	if let Some(unslid) = crate::kaslr::unslide(ret){
+		write!(w, " ({:#x})", unslid)?;
+	}
+	//The return address is right after the call, which can already be the next function.
+	if let Some(symbol) = crate::symbols::lookup(ret-1){
+		write!(w, " {}", crate::symbols::Symbol{offset:symbol.offset+1, ..symbol})?;
+	}
+	writeln!(w)
+}
+
+///Prints the stack of the caller, one line per frame, with unslid addresses and function names.
+pub fn print(w:&mut dyn Write)->core::fmt::Result{
+	let mut index = 0;
+	let mut result = Ok(());
+	walk(|ret|{
+		result = print_frame(w, index, ret);
+		index += 1;
+		result.is_ok() && index < MAX_FRAMES
+	});
+	if index == MAX_FRAMES{
+		writeln!(w, "     ...")?;
+	}
+	result
+}
@@ -0,0 +1,483 @@
+//Unwinds the stack with the call frame information in .eh_frame, for kernels built without frame pointers.
+//.eh_frame_hdr has a table sorted by address, that leads to the FDE of a function. The FDE and its CIE hold a small program,
+//which says for every instruction, how to compute the canonical frame address (CFA) and where the caller's registers were saved.
+//Only what compilers emit for ordinary functions is supported. DWARF expressions end the backtrace.
+use core::sync::atomic::{AtomicU64, Ordering};
+use kernel_efi::Symbols;
+use super::read_u64;
+
+//DWARF register numbers.
+const RBX:usize = 3;
+const RBP:usize = 6;
+const RSP:usize = 7;
+const R12:usize = 12;
+const RA:usize = 16;
+const REGS:usize = 17;
+
+//Pointer encodings.
+const DW_EH_PE_OMIT:u8 = 0xFF;
+const DW_EH_PE_ABSPTR:u8 = 0x00;
+const DW_EH_PE_ULEB128:u8 = 0x01;
+const DW_EH_PE_UDATA2:u8 = 0x02;
+const DW_EH_PE_UDATA4:u8 = 0x03;
+const DW_EH_PE_UDATA8:u8 = 0x04;
+const DW_EH_PE_SLEB128:u8 = 0x09;
+const DW_EH_PE_SDATA2:u8 = 0x0A;
+const DW_EH_PE_SDATA4:u8 = 0x0B;
+const DW_EH_PE_SDATA8:u8 = 0x0C;
+const DW_EH_PE_PCREL:u8 = 0x10;
+const DW_EH_PE_DATAREL:u8 = 0x30;
+const DW_EH_PE_INDIRECT:u8 = 0x80;
+
+///How many states DW_CFA_remember_state can save.
+const STATE_STACK:usize = 8;
+
+///Run time address and size of .eh_frame_hdr. The size is 0, if there is none.
+static HDR:AtomicU64 = AtomicU64::new(0);
+static HDR_SIZE:AtomicU64 = AtomicU64::new(0);
+
+pub fn init(symbols:&Symbols){
+	if symbols.eh_frame_hdr_size == 0{
+		log::warn!("The kernel has no .eh_frame_hdr, so there will be no backtraces");
+		return;
+	}
+	HDR.store(crate::kaslr::kernel_base()+symbols.eh_frame_hdr, Ordering::Relaxed);
+	HDR_SIZE.store(symbols.eh_frame_hdr_size, Ordering::Release);
+}
+
+///Reads the unwind tables. Every read checks, that it stays inside of the kernel image.
+struct Reader{
+	pos:u64,
+}
+
+impl Reader{
+	fn bytes<const N:usize>(&mut self)->Option<[u8;N]>{
+		let end = self.pos.checked_add(N as u64)?;
+		crate::kaslr::unslide(self.pos)?;
+		crate::kaslr::unslide(end-1)?;
+		//Safety:
+		//The bytes are in the kernel image, which is mapped.
+		let bytes = unsafe{core::ptr::read_unaligned(self.pos as *const [u8;N])};
+		self.pos = end;
+		Some(bytes)
+	}
+
+	fn u8(&mut self)->Option<u8>{
+		Some(self.bytes::<1>()?[0])
+	}
+
+	fn u16(&mut self)->Option<u16>{
+		Some(u16::from_le_bytes(self.bytes()?))
+	}
+
+	fn u32(&mut self)->Option<u32>{
+		Some(u32::from_le_bytes(self.bytes()?))
+	}
+
+	fn u64(&mut self)->Option<u64>{
+		Some(u64::from_le_bytes(self.bytes()?))
+	}
+
+	fn uleb(&mut self)->Option<u64>{
+		let mut value = 0u64;
+		let mut shift = 0;
+		loop{
+			let b = self.u8()?;
+			if shift < 64{
+				value |= ((b&0x7F) as u64)<<shift;
+			}
+			shift += 7;
+			if b&0x80 == 0{
+				return Some(value);
+			}
+		}
+	}
+
+	fn sleb(&mut self)->Option<i64>{
+		let mut value = 0i64;
+		let mut shift = 0;
+		loop{
+			let b = self.u8()?;
+			if shift < 64{
+				value |= ((b&0x7F) as i64)<<shift;
+			}
+			shift += 7;
+			if b&0x80 == 0{
+				if shift < 64 && b&0x40 != 0{
+					value |= -1i64<<shift;
+				}
+				return Some(value);
+			}
+		}
+	}
+
+	///Reads a pointer in the given encoding. `data` is the base for DW_EH_PE_datarel.
+	fn pointer(&mut self, encoding:u8, data:u64)->Option<u64>{
+		if encoding == DW_EH_PE_OMIT || encoding&DW_EH_PE_INDIRECT != 0{
+			return None;
+		}
+		let base = match encoding&0x70{
+			DW_EH_PE_ABSPTR=>0,
+			DW_EH_PE_PCREL=>self.pos,
+			DW_EH_PE_DATAREL=>data,
+			_=>return None,
+		};
+		let value = match encoding&0x0F{
+			DW_EH_PE_ABSPTR|DW_EH_PE_UDATA8=>self.u64()?,
+			DW_EH_PE_ULEB128=>self.uleb()?,
+			DW_EH_PE_UDATA2=>self.u16()? as u64,
+			DW_EH_PE_UDATA4=>self.u32()? as u64,
+			DW_EH_PE_SLEB128=>self.sleb()? as u64,
+			DW_EH_PE_SDATA2=>self.u16()? as i16 as u64,
+			DW_EH_PE_SDATA4=>self.u32()? as i32 as u64,
+			DW_EH_PE_SDATA8=>self.u64()?,
+			_=>return None,
+		};
+		Some(base.wrapping_add(value))
+	}
+}
+
+///Finds the FDE for `pc` with the binary search table in .eh_frame_hdr.
+fn find_fde(pc:u64)->Option<u64>{
+	if HDR_SIZE.load(Ordering::Acquire) == 0{
+		return None;
+	}
+	let hdr = HDR.load(Ordering::Relaxed);
+	let mut r = Reader{pos:hdr};
+	let [version, eh_frame_enc, count_enc, table_enc] = r.bytes()?;
+	//The table has fixed size entries only with this encoding, which is what linkers write.
+	if version != 1 || table_enc != DW_EH_PE_DATAREL|DW_EH_PE_SDATA4{
+		return None;
+	}
+	r.pointer(eh_frame_enc, hdr)?;
+	let count = r.pointer(count_enc, hdr)?;
+	let table = r.pos;
+	let entry = |i:u64|->Option<(u64, u64)>{
+		let mut r = Reader{pos:table+i*8};
+		Some((r.pointer(table_enc, hdr)?, r.pointer(table_enc, hdr)?))
+	};
+	//The last entry, that starts at or below pc.
+	let (mut low, mut high) = (0, count);
+	while low < high{
+		let mid = low+(high-low)/2;
+		if entry(mid)?.0 <= pc{
+			low = mid+1;
+		}else{
+			high = mid;
+		}
+	}
+	Some(entry(low.checked_sub(1)?)?.1)
+}
+
+#[derive(Copy, Clone)]
+enum Rule{
+	///The register keeps its value, or it doesn't matter.
+	Same,
+	Undefined,
+	///Saved at CFA+offset.
+	Offset(i64),
+	///The value is CFA+offset.
+	ValOffset(i64),
+	///The value is in another register.
+	Register(usize),
+}
+
+#[derive(Copy, Clone)]
+struct State{
+	cfa_reg:usize,
+	cfa_offset:i64,
+	rules:[Rule;REGS],
+}
+
+///What the CIE says about all FDEs, that use it.
+struct Cie{
+	code_align:u64,
+	data_align:i64,
+	ra:usize,
+	fde_enc:u8,
+	///Whether the FDE has augmentation data, whose length comes first.
+	z:bool,
+	instructions:(u64, u64),
+}
+
+fn parse_cie(at:u64)->Option<Cie>{
+	let mut r = Reader{pos:at};
+	let len = r.u32()?;
+	//64 bit DWARF isn't used for .eh_frame in practice.
+	if len == 0xFFFF_FFFF || r.u32()? != 0{
+		return None;
+	}
+	let end = at+4+len as u64;
+	let version = r.u8()?;
+	let mut augmentation = [0u8;8];
+	let mut n = 0;
+	loop{
+		let c = r.u8()?;
+		if c == 0{
+			break;
+		}
+		*augmentation.get_mut(n)? = c;
+		n += 1;
+	}
+	let augmentation = &augmentation[..n];
+	let code_align = r.uleb()?;
+	let data_align = r.sleb()?;
+	let ra = (if version == 1 {r.u8()? as u64} else {r.uleb()?}) as usize;
+	let mut cie = Cie{code_align, data_align, ra, fde_enc:DW_EH_PE_ABSPTR, z:false, instructions:(0, end)};
+	if let Some((b'z', rest)) = augmentation.split_first(){
+		cie.z = true;
+		let data_len = r.uleb()?;
+		let data_end = r.pos+data_len;
+		for c in rest{
+			match c{
+				b'R'=>cie.fde_enc = r.u8()?,
+				b'P'=>{
+					let enc = r.u8()?;
+					//Only skipped over, so an indirect personality pointer doesn't need to be followed.
+					r.pointer(enc&!DW_EH_PE_INDIRECT, 0)?;
+				},
+				b'L'=>{
+					r.u8()?;
+				},
+				b'S'|b'B'=>(),
+				_=>return None,
+			}
+		}
+		r.pos = data_end;
+	}else if !augmentation.is_empty(){
+		return None;
+	}
+	if ra >= REGS{
+		return None;
+	}
+	cie.instructions.0 = r.pos;
+	Some(cie)
+}
+
+///Runs the CFA program in start..end, until the location passes `pc`.
+fn execute(cie:&Cie, (start, end):(u64, u64), mut loc:u64, pc:u64, state:&mut State, initial:&State)->Option<()>{
+	let mut r = Reader{pos:start};
+	let mut stack = [*initial;STATE_STACK];
+	let mut depth = 0;
+	let rule = |state:&mut State, reg:u64, rule:Rule|{
+		if let Some(r) = state.rules.get_mut(reg as usize){
+			*r = rule;
+		}
+	};
+	while r.pos < end{
+		let op = r.u8()?;
+		let (high, low) = (op>>6, (op&0x3F) as u64);
+		let advance = match (high, low){
+			(1, delta)=>delta,
+			(2, reg)=>{
+				let offset = r.uleb()? as i64*cie.data_align;
+				rule(state, reg, Rule::Offset(offset));
+				0
+			},
+			(3, reg)=>{
+				rule(state, reg, initial.rules.get(reg as usize).copied().unwrap_or(Rule::Same));
+				0
+			},
+			(_, 0x00)=>0,
+			(_, 0x01)=>{
+				loc = r.pointer(cie.fde_enc, 0)?;
+				0
+			},
+			(_, 0x02)=>r.u8()? as u64,
+			(_, 0x03)=>r.u16()? as u64,
+			(_, 0x04)=>r.u32()? as u64,
+			(_, 0x05)=>{
+				let reg = r.uleb()?;
+				let offset = r.uleb()? as i64*cie.data_align;
+				rule(state, reg, Rule::Offset(offset));
+				0
+			},
+			(_, 0x06)=>{
+				let reg = r.uleb()?;
+				rule(state, reg, initial.rules.get(reg as usize).copied().unwrap_or(Rule::Same));
+				0
+			},
+			(_, 0x07)=>{
+				let reg = r.uleb()?;
+				rule(state, reg, Rule::Undefined);
+				0
+			},
+			(_, 0x08)=>{
+				let reg = r.uleb()?;
+				rule(state, reg, Rule::Same);
+				0
+			},
+			(_, 0x09)=>{
+				let reg = r.uleb()?;
+				let other = r.uleb()? as usize;
+				rule(state, reg, if other < REGS {Rule::Register(other)} else {Rule::Undefined});
+				0
+			},
+			(_, 0x0A)=>{
+				*stack.get_mut(depth)? = *state;
+				depth += 1;
+				0
+			},
+			(_, 0x0B)=>{
+				depth = depth.checked_sub(1)?;
+				*state = stack[depth];
+				0
+			},
+			(_, 0x0C)=>{
+				state.cfa_reg = r.uleb()? as usize;
+				state.cfa_offset = r.uleb()? as i64;
+				0
+			},
+			(_, 0x0D)=>{
+				state.cfa_reg = r.uleb()? as usize;
+				0
+			},
+			(_, 0x0E)=>{
+				state.cfa_offset = r.uleb()? as i64;
+				0
+			},
+			//DW_CFA_expression and DW_CFA_val_expression: the register can't be recovered.
+			(_, 0x10|0x16)=>{
+				let reg = r.uleb()?;
+				let len = r.uleb()?;
+				r.pos = r.pos.checked_add(len)?;
+				rule(state, reg, Rule::Undefined);
+				0
+			},
+			(_, 0x11)=>{
+				let reg = r.uleb()?;
+				let offset = r.sleb()?*cie.data_align;
+				rule(state, reg, Rule::Offset(offset));
+				0
+			},
+			(_, 0x12)=>{
+				state.cfa_reg = r.uleb()? as usize;
+				state.cfa_offset = r.sleb()?*cie.data_align;
+				0
+			},
+			(_, 0x13)=>{
+				state.cfa_offset = r.sleb()?*cie.data_align;
+				0
+			},
+			(_, 0x14)=>{
+				let reg = r.uleb()?;
+				let offset = r.uleb()? as i64*cie.data_align;
+				rule(state, reg, Rule::ValOffset(offset));
+				0
+			},
+			(_, 0x15)=>{
+				let reg = r.uleb()?;
+				let offset = r.sleb()?*cie.data_align;
+				rule(state, reg, Rule::ValOffset(offset));
+				0
+			},
+			//DW_CFA_GNU_args_size
+			(_, 0x2E)=>{
+				r.uleb()?;
+				0
+			},
+			//DW_CFA_def_cfa_expression and anything unknown.
+			_=>return None,
+		};
+		loc = loc.checked_add(advance*cie.code_align)?;
+		if loc > pc{
+			break;
+		}
+	}
+	Some(())
+}
+
+///The registers, that unwinding needs. Only the callee saved ones, rsp and the return address are known.
+#[derive(Copy, Clone)]
+struct Regs([Option<u64>;REGS]);
+
+///Computes the registers of the caller. `pc` is the address to look up, one before the return address for all but the first frame.
+fn step(regs:&Regs, pc:u64)->Option<Regs>{
+	let fde = find_fde(pc)?;
+	let mut r = Reader{pos:fde};
+	let len = r.u32()?;
+	if len == 0xFFFF_FFFF{
+		return None;
+	}
+	let end = fde+4+len as u64;
+	let cie_pointer = r.pos;
+	let cie = parse_cie(cie_pointer.checked_sub(r.u32()? as u64)?)?;
+	let pc_begin = r.pointer(cie.fde_enc, 0)?;
+	let pc_range = r.pointer(cie.fde_enc&0x0F, 0)?;
+	if !(pc_begin..pc_begin.checked_add(pc_range)?).contains(&pc){
+		return None;
+	}
+	if cie.z{
+		let data_len = r.uleb()?;
+		r.pos = r.pos.checked_add(data_len)?;
+	}
+	let mut state = State{cfa_reg:RSP, cfa_offset:0, rules:[Rule::Same;REGS]};
+	execute(&cie, cie.instructions, pc_begin, u64::MAX, &mut state, &State{cfa_reg:RSP, cfa_offset:0, rules:[Rule::Same;REGS]})?;
+	let initial = state;
+	execute(&cie, (r.pos, end), pc_begin, pc, &mut state, &initial)?;
+	let cfa = regs.0.get(state.cfa_reg).copied().flatten()?.checked_add_signed(state.cfa_offset)?;
+	let mut caller = *regs;
+	for (reg, rule) in state.rules.iter().enumerate(){
+		caller.0[reg] = match *rule{
+			Rule::Same=>regs.0[reg],
+			Rule::Undefined=>None,
+			Rule::Offset(offset)=>Some(read_u64(cfa.checked_add_signed(offset)?)?),
+			Rule::ValOffset(offset)=>cfa.checked_add_signed(offset),
+			Rule::Register(other)=>regs.0[other],
+		};
+	}
+	//The caller's rsp is the CFA by definition, and its pc is the return address.
+	caller.0[RSP] = Some(cfa);
+	caller.0[RA] = caller.0[cie.ra];
+	Some(caller)
+}
+
+///Calls `f` with the return address of every frame, starting with the caller of walk, until it returns false.
+#[inline(never)]
+pub fn walk(mut f:impl FnMut(u64)->bool){
+	//rip, rsp, rbp, rbx, r12, r13, r14, r15 at the same instruction, so they match the unwind rules there.
+	let mut saved = [0u64;8];
+	//Safety:
+	//Only writes to saved.
+	unsafe{core::arch::asm!(
+		"lea {tmp}, [rip]",
+		"mov [{buf}], {tmp}",
+		"mov [{buf}+8], rsp",
+		"mov [{buf}+16], rbp",
+		"mov [{buf}+24], rbx",
+		"mov [{buf}+32], r12",
+		"mov [{buf}+40], r13",
+		"mov [{buf}+48], r14",
+		"mov [{buf}+56], r15",
+		buf = in(reg) saved.as_mut_ptr(), tmp = out(reg) _,
+		options(nostack, preserves_flags),
+	)};
+	let [rip, rsp, rbp, rbx, r12, r13, r14, r15] = saved;
+	let mut regs = Regs([None;REGS]);
+	regs.0[RSP] = Some(rsp);
+	regs.0[RBP] = Some(rbp);
+	regs.0[RBX] = Some(rbx);
+	for (i, value) in [r12, r13, r14, r15].into_iter().enumerate(){
+		regs.0[R12+i] = Some(value);
+	}
+	regs.0[RA] = Some(rip);
+	//The first pc is inside of walk, all later ones are return addresses.
+	let mut pc = rip;
+	loop{
+		let Some(caller) = step(&regs, pc) else{
+			break;
+		};
+		let Some(ret) = caller.0[RA].filter(|&ret|ret != 0) else{
+			break;
+		};
+		if !f(ret){
+			break;
+		}
+		//Callers have their frames at higher addresses. Anything else would loop.
+		if caller.0[RSP] <= regs.0[RSP]{
+			break;
+		}
+		regs = caller;
+		pc = ret-1;
+	}
+}
@@ -0,0 +1,190 @@
+//Block devices, like disks.
+//Drivers register every device they find here, and everything else finds them by name.
+//Whole disks get their partition table read when they are registered, and every partition becomes a device of its own.
+mod guid;
+pub mod partition;
+pub mod mbr;
+pub mod gpt;
+pub mod queue;
+pub mod cache;
+
+pub use guid::Guid;
+pub use partition::{Partition, PartitionInfo, PartitionKind};
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt;
+use x86_64::instructions::interrupts;
+use crate::lock::{LockClass, RWLock, RWLockMode};
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum BlockError{
+	///The request goes past the end of the device.
+	OutOfRange,
+	///The buffer isn't a multiple of the block size.
+	BadBufferSize,
+	///The device is read only.
+	ReadOnly,
+	///The device doesn't support the request.
+	Unsupported,
+	///The device reported an error, or didn't answer.
+	Io,
+	///There was no memory for the request.
+	NoMemory,
+}
+
+impl fmt::Display for BlockError{
+	fn fmt(&self, f:&mut fmt::Formatter<'_>)->fmt::Result{
+		f.write_str(match self{
+			Self::OutOfRange=>"out of range",
+			Self::BadBufferSize=>"buffer size isn't a multiple of the block size",
+			Self::ReadOnly=>"read only device",
+			Self::Unsupported=>"unsupported request",
+			Self::Io=>"I/O error",
+			Self::NoMemory=>"out of memory",
+		})
+	}
+}
+
+///A device, that is read and written in fixed size blocks.
+///The methods block the calling task, so they can only be used from tasks.
+pub trait BlockDevice:Send+Sync{
+	///A short, unique name, like vda.
+	fn name(&self)->&str;
+	///The size of a block in bytes.
+	fn block_size(&self)->usize;
+	fn block_count(&self)->u64;
+	fn is_read_only(&self)->bool{
+		false
+	}
+	///Where the device is on its disk, if it is a partition.
+	fn partition(&self)->Option<&PartitionInfo>{
+		None
+	}
+	///Reads `buf.len()/block_size()` blocks starting at block `lba`.
+	fn read_blocks(&self, lba:u64, buf:&mut [u8])->Result<(), BlockError>;
+	///Writes `buf.len()/block_size()` blocks starting at block `lba`.
+	fn write_blocks(&self, lba:u64, buf:&[u8])->Result<(), BlockError>;
+	///Makes sure, that all completed writes are on stable storage.
+	fn flush(&self)->Result<(), BlockError>;
+}
+
+///Checks a request against the size of the device.
+///Returns the amount of blocks.
+pub fn check_request(dev:&dyn BlockDevice, lba:u64, len:usize)->Result<u64, BlockError>{
+	let block_size = dev.block_size();
+	if !len.is_multiple_of(block_size){
+		return Err(BlockError::BadBufferSize);
+	}
+	let count = (len/block_size) as u64;
+	match lba.checked_add(count){
+		Some(end) if end <= dev.block_count()=>Ok(count),
+		_=>Err(BlockError::OutOfRange),
+	}
+}
+
+static DEVICES_CLASS:LockClass = LockClass::new("block::devices");
+static DEVICES:RWLock<Vec<Arc<dyn BlockDevice>>> = RWLock::with_class(Vec::new(), RWLockMode::ReaderPreferring, &DEVICES_CLASS);
+
+///Adds a device, and for whole disks, all of its partitions.
+pub fn register(dev:Arc<dyn BlockDevice>){
+	log::info!("Block device {}: {} blocks of {} bytes ({} MiB){}",
+		dev.name(), dev.block_count(), dev.block_size(),
+		dev.block_count()*dev.block_size() as u64/(1024*1024),
+		if dev.is_read_only() {", read only"} else {""});
+	interrupts::without_interrupts(||DEVICES.write_lock().push(dev.clone()));
+	if dev.partition().is_none(){
+		for info in read_partitions(&*dev){
+			let part = Arc::new(Partition::new(dev.clone(), info));
+			match &part.info().kind{
+				PartitionKind::Gpt{guid, type_guid, label, ..}=>log::info!("Partition {}: blocks {}..{}, type {}, guid {}, \"{}\"",
+					part.name(), part.info().first_block, part.info().first_block+part.info().block_count, type_guid, guid, label),
+				PartitionKind::Mbr{partition_type, ..}=>log::info!("Partition {}: blocks {}..{}, type {:#04x}",
+					part.name(), part.info().first_block, part.info().first_block+part.info().block_count, partition_type),
+			}
+			interrupts::without_interrupts(||DEVICES.write_lock().push(part));
+		}
+	}
+}
+
+///Reads the partition table of a disk. A valid GPT wins over the MBR.
+///Read errors are logged, and leave the disk without partitions.
+fn read_partitions(disk:&dyn BlockDevice)->Vec<PartitionInfo>{
+	let result = gpt::read(disk).and_then(|gpt|match gpt{
+		Some(gpt)=>Ok(Some(gpt.partitions)),
+		None=>Ok(mbr::read(disk)?.and_then(|mbr|{
+			if mbr.protective{
+				log::warn!("{}: protective MBR, but no valid GPT", disk.name());
+				return None;
+			}
+			Some(mbr.partitions)
+		})),
+	});
+	match result{
+		Ok(partitions)=>partitions.unwrap_or_default(),
+		Err(e)=>{
+			log::warn!("{}: can't read the partition table: {}", disk.name(), e);
+			Vec::new()
+		},
+	}
+}
+
+pub fn devices()->Vec<Arc<dyn BlockDevice>>{
+	interrupts::without_interrupts(||DEVICES.read_lock().clone())
+}
+
+pub fn find(name:&str)->Option<Arc<dyn BlockDevice>>{
+	interrupts::without_interrupts(||DEVICES.read_lock().iter().find(|d|d.name() == name).cloned())
+}
+
+pub fn find_by_partition_guid(guid:Guid)->Option<Arc<dyn BlockDevice>>{
+	interrupts::without_interrupts(||DEVICES.read_lock().iter().find(|d|d.partition().and_then(|p|p.guid()) == Some(guid)).cloned())
+}
+
+///The partition, that the boot loader was loaded from, as the firmware described it.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum BootPartition{
+	Gpt(Guid),
+	Mbr{
+		disk_signature:u32,
+		number:u32,
+	},
+}
+
+impl BootPartition{
+	///Converts what the boot loader passed. None, if it didn't know the partition.
+	pub fn from_args(boot:&kernel_efi::BootPartition)->Option<Self>{
+		match boot.signature_type{
+			kernel_efi::BootPartition::SIGNATURE_GPT=>Some(Self::Gpt(Guid(boot.signature))),
+			kernel_efi::BootPartition::SIGNATURE_MBR=>Some(Self::Mbr{
+				disk_signature:u32::from_le_bytes([boot.signature[0], boot.signature[1], boot.signature[2], boot.signature[3]]),
+				number:boot.partition_number,
+			}),
+			_=>None,
+		}
+	}
+}
+
+static BOOT_CLASS:LockClass = LockClass::new("block::boot_partition");
+static BOOT_PARTITION:RWLock<Option<BootPartition>> = RWLock::with_class(None, RWLockMode::ReaderPreferring, &BOOT_CLASS);
+
+pub fn set_boot_partition(boot:BootPartition){
+	interrupts::without_interrupts(||*BOOT_PARTITION.write_lock() = Some(boot));
+}
+
+///Finds the device of the partition, that we booted from. That is the ESP, that has the kernel on it.
+///None, if the boot loader didn't know, or the disk has no driver.
+pub fn boot_partition()->Option<Arc<dyn BlockDevice>>{
+	let boot = interrupts::without_interrupts(||*BOOT_PARTITION.read_lock())?;
+	match boot{
+		BootPartition::Gpt(guid)=>find_by_partition_guid(guid),
+		BootPartition::Mbr{disk_signature, number}=>devices().into_iter().find(|d|{
+			d.partition().is_some_and(|p|p.number == number && matches!(p.kind, PartitionKind::Mbr{disk_signature:s, ..} if s == disk_signature))
+		}),
+	}
+}
+
+///The EFI system partition, that we booted from, or else the first one, that we can find.
+pub fn esp()->Option<Arc<dyn BlockDevice>>{
+	boot_partition().or_else(||devices().into_iter().find(|d|d.partition().is_some_and(|p|p.is_esp())))
+}
@@ -0,0 +1,192 @@
+//A write-back cache of single blocks.
+//Misses and write-backs go through a request queue. Dirty blocks are written back, when they are evicted, or on flush.
+//The cache doesn't know about other users of the device, so everything should go through the same cache.
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use crate::sync::Mutex;
+use super::queue::{Completion, Operation, RequestQueue};
+use super::{check_request, BlockDevice, BlockError, PartitionInfo};
+
+struct Entry{
+	data:Vec<u8>,
+	dirty:bool,
+	///When the block was used last, for LRU eviction.
+	used:u64,
+}
+
+struct State{
+	blocks:BTreeMap<u64, Entry>,
+	clock:u64,
+	///Write-backs of evicted blocks, that flush has to wait for.
+	writing:Vec<Arc<Completion>>,
+	///An evicted block couldn't be written back. Reported by the next flush.
+	write_error:Option<BlockError>,
+}
+
+pub struct BlockCache{
+	name:String,
+	queue:Arc<RequestQueue>,
+	///The maximum amount of cached blocks.
+	capacity:usize,
+	state:Mutex<State>,
+}
+
+impl BlockCache{
+	pub fn new(dev:Arc<dyn BlockDevice>, capacity:usize)->Self{
+		Self{
+			name:String::from(dev.name()),
+			queue:RequestQueue::new(dev),
+			capacity:capacity.max(1),
+			state:Mutex::new(State{blocks:BTreeMap::new(), clock:0, writing:Vec::new(), write_error:None}),
+		}
+	}
+
+	pub fn device(&self)->&Arc<dyn BlockDevice>{
+		self.queue.device()
+	}
+
+	///Makes room for one more block.
+	fn evict(&self, state:&mut State){
+		state.writing.retain(|c|!c.is_done() || {
+			//Finished write-backs only matter, if they failed.
+			if let (Err(e), _) = c.wait(){
+				state.write_error = Some(e);
+			}
+			false
+		});
+		while state.blocks.len() >= self.capacity{
+			let Some((&lba, _)) = state.blocks.iter().min_by_key(|(_, e)|e.used) else{break};
+			let entry = state.blocks.remove(&lba).unwrap();
+			if entry.dirty{
+				state.writing.push(self.queue.submit(Operation::Write, lba, entry.data));
+			}
+		}
+	}
+
+	fn insert(&self, state:&mut State, lba:u64, data:Vec<u8>, dirty:bool){
+		if !state.blocks.contains_key(&lba){
+			self.evict(state);
+		}
+		state.clock += 1;
+		let used = state.clock;
+		state.blocks.insert(lba, Entry{data, dirty, used});
+	}
+}
+
+impl BlockDevice for BlockCache{
+	fn name(&self)->&str{
+		&self.name
+	}
+
+	fn block_size(&self)->usize{
+		self.device().block_size()
+	}
+
+	fn block_count(&self)->u64{
+		self.device().block_count()
+	}
+
+	fn is_read_only(&self)->bool{
+		self.device().is_read_only()
+	}
+
+	fn partition(&self)->Option<&PartitionInfo>{
+		self.queue.device().partition()
+	}
+
+	fn read_blocks(&self, lba:u64, buf:&mut [u8])->Result<(), BlockError>{
+		let count = check_request(self, lba, buf.len())?;
+		let block_size = self.block_size();
+		let mut state = self.state.lock();
+		//Read every run of missing blocks at once, with all runs in flight together.
+		let mut reads = Vec::new();
+		let mut i = 0;
+		while i < count{
+			if state.blocks.contains_key(&(lba+i)){
+				i += 1;
+				continue;
+			}
+			let start = i;
+			while i < count && !state.blocks.contains_key(&(lba+i)){
+				i += 1;
+			}
+			let len = (i-start) as usize*block_size;
+			reads.push((lba+start, self.queue.submit(Operation::Read, lba+start, alloc::vec![0;len])));
+		}
+		let mut result = Ok(());
+		for (start, completion) in reads{
+			let (r, data) = completion.wait();
+			if r.is_err(){
+				result = r;
+				continue;
+			}
+			for (n, block) in data.chunks_exact(block_size).enumerate(){
+				self.insert(&mut state, start+n as u64, block.to_vec(), false);
+			}
+		}
+		result?;
+		for (n, out) in buf.chunks_exact_mut(block_size).enumerate(){
+			state.clock += 1;
+			let clock = state.clock;
+			match state.blocks.get_mut(&(lba+n as u64)){
+				Some(entry)=>{
+					entry.used = clock;
+					out.copy_from_slice(&entry.data);
+				},
+				//Only possible, if the request is bigger than the whole cache.
+				None=>{
+					let (r, data) = self.queue.run(Operation::Read, lba+n as u64, alloc::vec![0;block_size]);
+					r?;
+					out.copy_from_slice(&data);
+				},
+			}
+		}
+		Ok(())
+	}
+
+	fn write_blocks(&self, lba:u64, buf:&[u8])->Result<(), BlockError>{
+		check_request(self, lba, buf.len())?;
+		if self.is_read_only(){
+			return Err(BlockError::ReadOnly);
+		}
+		let block_size = self.block_size();
+		let mut state = self.state.lock();
+		for (n, data) in buf.chunks_exact(block_size).enumerate(){
+			self.insert(&mut state, lba+n as u64, data.to_vec(), true);
+		}
+		Ok(())
+	}
+
+	fn flush(&self)->Result<(), BlockError>{
+		let mut state = self.state.lock();
+		let mut writes = core::mem::take(&mut state.writing);
+		//Write back every run of dirty blocks with one request.
+		let dirty:Vec<u64> = state.blocks.iter().filter(|(_, e)|e.dirty).map(|(lba, _)|*lba).collect();
+		let mut i = 0;
+		while i < dirty.len(){
+			let start = dirty[i];
+			let mut data = Vec::new();
+			while i < dirty.len() && dirty[i] == start+(data.len()/self.block_size()) as u64{
+				let entry = state.blocks.get_mut(&dirty[i]).unwrap();
+				entry.dirty = false;
+				data.extend_from_slice(&entry.data);
+				i += 1;
+			}
+			writes.push(self.queue.submit(Operation::Write, start, data));
+		}
+		let mut result = match state.write_error.take(){
+			Some(e)=>Err(e),
+			None=>Ok(()),
+		};
+		for w in writes{
+			if let (Err(e), _) = w.wait(){
+				log::error!("{}: write-back failed: {}", self.name, e);
+				result = Err(e);
+			}
+		}
+		result?;
+		self.queue.run(Operation::Flush, 0, Vec::new()).0
+	}
+}
@@ -0,0 +1,177 @@
+//The GUID partition table.
+//The primary header is in block 1, the backup header in the last block. Both have CRC32 checksums over themselves and the entries.
+//If the primary table is broken, the backup is used.
+use alloc::string::String;
+use alloc::vec::Vec;
+use super::partition::{PartitionInfo, PartitionKind};
+use super::{BlockDevice, BlockError, Guid};
+
+const SIGNATURE:&[u8;8] = b"EFI PART";
+const HEADER_SIZE_MIN:usize = 92;
+const ENTRY_SIZE_MIN:usize = 128;
+///Tables beyond this are rejected, so a broken header can't make us allocate huge buffers.
+const MAX_ENTRIES_SIZE:usize = 1024*1024;
+
+const CRC32_POLY:u32 = 0xEDB8_8320;
+
+///The CRC32 used by GPT, zlib and Ethernet.
+pub fn crc32(data:&[u8])->u32{
+	!crc32_update(!0, data)
+}
+
+fn crc32_update(mut crc:u32, data:&[u8])->u32{
+	for b in data{
+		crc ^= *b as u32;
+		for _ in 0..8{
+			crc = if crc & 1 != 0 {(crc>>1) ^ CRC32_POLY} else {crc>>1};
+		}
+	}
+	crc
+}
+
+#[derive(Debug, Clone)]
+pub struct Header{
+	pub current_lba:u64,
+	pub backup_lba:u64,
+	pub first_usable:u64,
+	pub last_usable:u64,
+	pub disk_guid:Guid,
+	pub entries_lba:u64,
+	pub entry_count:u32,
+	pub entry_size:u32,
+	pub entries_crc:u32,
+}
+
+pub struct Gpt{
+	pub header:Header,
+	pub partitions:Vec<PartitionInfo>,
+}
+
+fn u32_at(b:&[u8], i:usize)->u32{
+	u32::from_le_bytes(b[i..i+4].try_into().unwrap())
+}
+
+fn u64_at(b:&[u8], i:usize)->u64{
+	u64::from_le_bytes(b[i..i+8].try_into().unwrap())
+}
+
+///Reads and checks the header at `lba`.
+fn read_header(disk:&dyn BlockDevice, lba:u64)->Result<Option<Header>, BlockError>{
+	let block_size = disk.block_size();
+	let mut block = alloc::vec![0;block_size];
+	disk.read_blocks(lba, &mut block)?;
+	if &block[0..8] != SIGNATURE{
+		return Ok(None);
+	}
+	let header_size = u32_at(&block, 12) as usize;
+	if !(HEADER_SIZE_MIN..=block_size).contains(&header_size){
+		return Ok(None);
+	}
+	let crc = u32_at(&block, 16);
+	block[16..20].fill(0);
+	if crc32(&block[..header_size]) != crc{
+		log::warn!("{}: GPT header in block {} has a wrong checksum", disk.name(), lba);
+		return Ok(None);
+	}
+	let header = Header{
+		current_lba:u64_at(&block, 24),
+		backup_lba:u64_at(&block, 32),
+		first_usable:u64_at(&block, 40),
+		last_usable:u64_at(&block, 48),
+		disk_guid:Guid(block[56..72].try_into().unwrap()),
+		entries_lba:u64_at(&block, 72),
+		entry_count:u32_at(&block, 80),
+		entry_size:u32_at(&block, 84),
+		entries_crc:u32_at(&block, 88),
+	};
+	let blocks = disk.block_count();
+	if header.current_lba != lba || header.first_usable > header.last_usable || header.last_usable >= blocks
+		|| (header.entry_size as usize) < ENTRY_SIZE_MIN || !header.entry_size.is_power_of_two()
+		|| header.entry_count as usize*header.entry_size as usize > MAX_ENTRIES_SIZE{
+		log::warn!("{}: GPT header in block {} is inconsistent", disk.name(), lba);
+		return Ok(None);
+	}
+	Ok(Some(header))
+}
+
+///Reads and checks the partition entries, that the header points to.
+fn read_entries(disk:&dyn BlockDevice, header:&Header)->Result<Option<Vec<PartitionInfo>>, BlockError>{
+	let block_size = disk.block_size();
+	let size = header.entry_count as usize*header.entry_size as usize;
+	let blocks = size.div_ceil(block_size);
+	if header.entries_lba.checked_add(blocks as u64).is_none_or(|end|end > disk.block_count()){
+		return Ok(None);
+	}
+	let mut data = alloc::vec![0;blocks*block_size];
+	disk.read_blocks(header.entries_lba, &mut data)?;
+	if crc32(&data[..size]) != header.entries_crc{
+		log::warn!("{}: GPT entries in block {} have a wrong checksum", disk.name(), header.entries_lba);
+		return Ok(None);
+	}
+	let mut partitions = Vec::new();
+	for (i, entry) in data[..size].chunks_exact(header.entry_size as usize).enumerate(){
+		let type_guid = Guid(entry[0..16].try_into().unwrap());
+		if type_guid.is_nil(){
+			continue;
+		}
+		let first = u64_at(entry, 32);
+		let last = u64_at(entry, 40);
+		if first < header.first_usable || last > header.last_usable || first > last{
+			log::warn!("{}: GPT partition {} is outside the usable area", disk.name(), i+1);
+			continue;
+		}
+		let label = char::decode_utf16(entry[56..128].chunks_exact(2).map(|c|u16::from_le_bytes([c[0], c[1]])).take_while(|c|*c != 0))
+			.map(|c|c.unwrap_or(char::REPLACEMENT_CHARACTER))
+			.collect::<String>();
+		partitions.push(PartitionInfo{
+			number:i as u32+1,
+			first_block:first,
+			block_count:last-first+1,
+			kind:PartitionKind::Gpt{
+				guid:Guid(entry[16..32].try_into().unwrap()),
+				type_guid,
+				label,
+				attributes:u64_at(entry, 48),
+			},
+		});
+	}
+	Ok(Some(partitions))
+}
+
+fn read_table(disk:&dyn BlockDevice, lba:u64)->Result<Option<Gpt>, BlockError>{
+	let header = match read_header(disk, lba)?{
+		Some(h)=>h,
+		None=>return Ok(None),
+	};
+	Ok(read_entries(disk, &header)?.map(|partitions|Gpt{header, partitions}))
+}
+
+///Reads the GPT of a disk.
+///Returns None, if neither the primary nor the backup table is valid.
+pub fn read(disk:&dyn BlockDevice)->Result<Option<Gpt>, BlockError>{
+	let last = disk.block_count().saturating_sub(1);
+	if last < 2{
+		return Ok(None);
+	}
+	let primary = read_table(disk, 1)?;
+	//The backup should be, where the primary header says, and that should be the last block.
+	let backup_lba = primary.as_ref().map_or(last, |p|p.header.backup_lba);
+	let backup = if backup_lba > 1 && backup_lba <= last {read_table(disk, backup_lba)?} else {None};
+	match (primary, backup){
+		(Some(p), Some(b))=>{
+			if b.header.disk_guid != p.header.disk_guid || b.header.entries_crc != p.header.entries_crc || b.header.backup_lba != 1{
+				log::warn!("{}: the backup GPT doesn't match the primary one", disk.name());
+			}
+			Ok(Some(p))
+		},
+		(Some(p), None)=>{
+			log::warn!("{}: the backup GPT is broken", disk.name());
+			Ok(Some(p))
+		},
+		(None, Some(b))=>{
+			log::warn!("{}: the primary GPT is broken. Using the backup.", disk.name());
+			Ok(Some(b))
+		},
+		(None, None)=>Ok(None),
+	}
+}
@@ -0,0 +1,47 @@
+//GUIDs, as used by GPT and UEFI.
+use core::fmt;
+
+///A GUID in its on-disk byte order.
+///The first three fields are little endian, the rest is stored as is.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Default)]
+pub struct Guid(pub [u8;16]);
+
+impl Guid{
+	pub const NIL:Guid = Guid([0;16]);
+	///The partition type of an EFI system partition.
+	pub const EFI_SYSTEM_PARTITION:Guid = Guid::from_fields(0xC12A7328, 0xF81F, 0x11D2, [0xBA, 0x4B, 0x00, 0xA0, 0xC9, 0x3E, 0xC9, 0x3B]);
+
+	pub const fn from_fields(a:u32, b:u16, c:u16, d:[u8;8])->Self{
+		let a = a.to_le_bytes();
+		let b = b.to_le_bytes();
+		let c = c.to_le_bytes();
+		Guid([a[0], a[1], a[2], a[3], b[0], b[1], c[0], c[1], d[0], d[1], d[2], d[3], d[4], d[5], d[6], d[7]])
+	}
+
+	pub fn is_nil(&self)->bool{
+		*self == Self::NIL
+	}
+
+	///Parses the usual text form, like C12A7328-F81F-11D2-BA4B-00A0C93EC93B.
+	pub fn parse(s:&str)->Option<Self>{
+		let s = s.as_bytes();
+		if s.len() != 36 || s[8] != b'-' || s[13] != b'-' || s[18] != b'-' || s[23] != b'-'{
+			return None;
+		}
+		let mut digits = s.iter().filter(|c|**c != b'-').map(|c|(*c as char).to_digit(16));
+		let mut bytes = [0u8;16];
+		for b in bytes.iter_mut(){
+			*b = (digits.next()?? as u8)<<4 | digits.next()?? as u8;
+		}
+		let be = |i:usize, n:usize|bytes[i..i+n].iter().fold(0u32, |a, b|a<<8 | *b as u32);
+		Some(Self::from_fields(be(0, 4), be(4, 2) as u16, be(6, 2) as u16, bytes[8..16].try_into().ok()?))
+	}
+}
+
+impl fmt::Display for Guid{
+	fn fmt(&self, f:&mut fmt::Formatter<'_>)->fmt::Result{
+		let g = &self.0;
+		write!(f, "{:02X}{:02X}{:02X}{:02X}-{:02X}{:02X}-{:02X}{:02X}-{:02X}{:02X}-{:02X}{:02X}{:02X}{:02X}{:02X}{:02X}",
+			g[3], g[2], g[1], g[0], g[5], g[4], g[7], g[6], g[8], g[9], g[10], g[11], g[12], g[13], g[14], g[15])
+	}
+}
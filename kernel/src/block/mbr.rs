@@ -0,0 +1,101 @@
+//The classic MBR partition table, with logical partitions in extended partitions.
+use alloc::vec::Vec;
+use super::partition::{PartitionInfo, PartitionKind};
+use super::{BlockDevice, BlockError};
+
+pub const TYPE_EMPTY:u8 = 0x00;
+pub const TYPE_EXTENDED_CHS:u8 = 0x05;
+pub const TYPE_EXTENDED_LBA:u8 = 0x0F;
+pub const TYPE_LINUX_EXTENDED:u8 = 0x85;
+pub const TYPE_EFI_SYSTEM:u8 = 0xEF;
+///Covers the whole disk, so GPT unaware tools leave it alone.
+pub const TYPE_GPT_PROTECTIVE:u8 = 0xEE;
+
+const DISK_SIGNATURE:usize = 440;
+const ENTRIES:usize = 446;
+const ENTRY_SIZE:usize = 16;
+const BOOT_SIGNATURE:usize = 510;
+///Extended partition chains, that are longer than this, have to contain a loop.
+const MAX_LOGICAL:u32 = 128;
+
+pub struct Mbr{
+	pub disk_signature:u32,
+	///True, if the MBR only protects a GPT.
+	pub protective:bool,
+	pub partitions:Vec<PartitionInfo>,
+}
+
+#[derive(Debug, Copy, Clone)]
+struct Entry{
+	partition_type:u8,
+	first:u64,
+	count:u64,
+}
+
+fn is_extended(t:u8)->bool{
+	matches!(t, TYPE_EXTENDED_CHS | TYPE_EXTENDED_LBA | TYPE_LINUX_EXTENDED)
+}
+
+///Reads the boot record at `lba`.
+///Returns None, if it doesn't have the boot signature.
+fn read_entries(disk:&dyn BlockDevice, lba:u64)->Result<Option<(u32, [Entry;4])>, BlockError>{
+	let mut sector = alloc::vec![0;disk.block_size()];
+	disk.read_blocks(lba, &mut sector)?;
+	if sector.len() < 512 || sector[BOOT_SIGNATURE] != 0x55 || sector[BOOT_SIGNATURE+1] != 0xAA{
+		return Ok(None);
+	}
+	let u32_at = |i:usize|u32::from_le_bytes([sector[i], sector[i+1], sector[i+2], sector[i+3]]);
+	let entry = |n:usize|{
+		let e = ENTRIES+n*ENTRY_SIZE;
+		Entry{partition_type:sector[e+4], first:u32_at(e+8) as u64, count:u32_at(e+12) as u64}
+	};
+	Ok(Some((u32_at(DISK_SIGNATURE), [entry(0), entry(1), entry(2), entry(3)])))
+}
+
+///Reads the MBR of a disk.
+///Returns None, if the disk doesn't have one.
+pub fn read(disk:&dyn BlockDevice)->Result<Option<Mbr>, BlockError>{
+	let (disk_signature, entries) = match read_entries(disk, 0)?{
+		Some(e)=>e,
+		None=>return Ok(None),
+	};
+	let blocks = disk.block_count();
+	let valid = |first:u64, count:u64|count != 0 && first != 0 && first.checked_add(count).is_some_and(|end|end <= blocks);
+	let protective = entries.iter().any(|e|e.partition_type == TYPE_GPT_PROTECTIVE);
+	let mut partitions = Vec::new();
+	let kind = |partition_type|PartitionKind::Mbr{disk_signature, partition_type};
+	for (i, e) in entries.iter().enumerate(){
+		if e.partition_type == TYPE_EMPTY || e.partition_type == TYPE_GPT_PROTECTIVE{
+			continue;
+		}
+		if !valid(e.first, e.count){
+			log::warn!("{}: MBR partition {} is outside the disk", disk.name(), i+1);
+			continue;
+		}
+		if !is_extended(e.partition_type){
+			partitions.push(PartitionInfo{number:i as u32+1, first_block:e.first, block_count:e.count, kind:kind(e.partition_type)});
+			continue;
+		}
+		//Every EBR has the logical partition relative to itself, and the next EBR relative to the extended partition.
+		let mut ebr = e.first;
+		for n in 0..MAX_LOGICAL{
+			let links = match read_entries(disk, ebr)?{
+				Some((_, l))=>l,
+				None=>break,
+			};
+			let logical = links[0];
+			if logical.partition_type != TYPE_EMPTY && valid(ebr+logical.first, logical.count){
+				partitions.push(PartitionInfo{number:5+n, first_block:ebr+logical.first, block_count:logical.count, kind:kind(logical.partition_type)});
+			}
+			let next = links[1];
+			if !is_extended(next.partition_type) || next.first == 0{
+				break;
+			}
+			ebr = e.first+next.first;
+			if ebr >= blocks{
+				break;
+			}
+		}
+	}
+	Ok(Some(Mbr{disk_signature, protective, partitions}))
+}
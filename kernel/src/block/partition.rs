@@ -0,0 +1,113 @@
+//Partitions, as block devices on top of their disk.
+use alloc::string::String;
+use alloc::sync::Arc;
+use super::{check_request, BlockDevice, BlockError, Guid};
+
+#[derive(Debug, Clone)]
+pub enum PartitionKind{
+	Gpt{
+		guid:Guid,
+		type_guid:Guid,
+		///The name from the partition entry.
+		label:String,
+		attributes:u64,
+	},
+	Mbr{
+		///The disk signature of the MBR.
+		disk_signature:u32,
+		partition_type:u8,
+	},
+}
+
+#[derive(Debug, Clone)]
+pub struct PartitionInfo{
+	///The number of the partition on its disk, starting at 1.
+	///For MBRs, logical partitions start at 5, like everywhere else.
+	pub number:u32,
+	pub first_block:u64,
+	pub block_count:u64,
+	pub kind:PartitionKind,
+}
+
+impl PartitionInfo{
+	pub fn guid(&self)->Option<Guid>{
+		match &self.kind{
+			PartitionKind::Gpt{guid, ..}=>Some(*guid),
+			PartitionKind::Mbr{..}=>None,
+		}
+	}
+
+	pub fn type_guid(&self)->Option<Guid>{
+		match &self.kind{
+			PartitionKind::Gpt{type_guid, ..}=>Some(*type_guid),
+			PartitionKind::Mbr{..}=>None,
+		}
+	}
+
+	///Returns true for EFI system partitions.
+	pub fn is_esp(&self)->bool{
+		match &self.kind{
+			PartitionKind::Gpt{type_guid, ..}=>*type_guid == Guid::EFI_SYSTEM_PARTITION,
+			PartitionKind::Mbr{partition_type, ..}=>*partition_type == super::mbr::TYPE_EFI_SYSTEM,
+		}
+	}
+}
+
+pub struct Partition{
+	disk:Arc<dyn BlockDevice>,
+	name:String,
+	info:PartitionInfo,
+}
+
+impl Partition{
+	pub fn new(disk:Arc<dyn BlockDevice>, info:PartitionInfo)->Self{
+		//Disks, whose name ends with a digit, get a p in between, like nvme0n1p1.
+		let separator = if disk.name().ends_with(|c:char|c.is_ascii_digit()) {"p"} else {""};
+		let name = alloc::format!("{}{}{}", disk.name(), separator, info.number);
+		Self{disk, name, info}
+	}
+
+	pub fn disk(&self)->&Arc<dyn BlockDevice>{
+		&self.disk
+	}
+
+	pub fn info(&self)->&PartitionInfo{
+		&self.info
+	}
+}
+
+impl BlockDevice for Partition{
+	fn name(&self)->&str{
+		&self.name
+	}
+
+	fn block_size(&self)->usize{
+		self.disk.block_size()
+	}
+
+	fn block_count(&self)->u64{
+		self.info.block_count
+	}
+
+	fn is_read_only(&self)->bool{
+		self.disk.is_read_only()
+	}
+
+	fn partition(&self)->Option<&PartitionInfo>{
+		Some(&self.info)
+	}
+
+	fn read_blocks(&self, lba:u64, buf:&mut [u8])->Result<(), BlockError>{
+		check_request(self, lba, buf.len())?;
+		self.disk.read_blocks(self.info.first_block+lba, buf)
+	}
+
+	fn write_blocks(&self, lba:u64, buf:&[u8])->Result<(), BlockError>{
+		check_request(self, lba, buf.len())?;
+		self.disk.write_blocks(self.info.first_block+lba, buf)
+	}
+
+	fn flush(&self)->Result<(), BlockError>{
+		self.disk.flush()
+	}
+}
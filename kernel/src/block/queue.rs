@@ -0,0 +1,177 @@
+//Request queues.
+//A worker task runs the requests of one device, in elevator order: it keeps moving up through the blocks,
+//and starts over at the lowest block, once nothing is left above.
+//Requests, that overlap an earlier one, and requests after a flush, wait for the earlier ones, so reordering never changes the result.
+//Once the queue is dropped, the worker finishes the requests, that are left, and exits.
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use x86_64::instructions::interrupts;
+use crate::lock::{Lock, LockClass};
+use crate::sync::WaitQueue;
+use super::{BlockDevice, BlockError};
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Operation{
+	Read,
+	Write,
+	Flush,
+}
+
+///The result of a request. The buffer comes back with it.
+pub type Outcome = (Result<(), BlockError>, Vec<u8>);
+
+///Where the result of a request shows up.
+pub struct Completion{
+	result:Lock<Option<Outcome>>,
+	waiters:WaitQueue,
+}
+
+static COMPLETION_CLASS:LockClass = LockClass::new("block::completion");
+static QUEUE_CLASS:LockClass = LockClass::new("block::queue");
+
+impl Completion{
+	fn new()->Self{
+		Self{result:Lock::with_class(None, &COMPLETION_CLASS), waiters:WaitQueue::new()}
+	}
+
+	pub fn is_done(&self)->bool{
+		interrupts::without_interrupts(||self.result.lock().is_some())
+	}
+
+	///Waits for the request to finish.
+	pub fn wait(&self)->Outcome{
+		let mut outcome = None;
+		self.waiters.wait_until(||{
+			outcome = self.result.lock().take();
+			outcome.is_some()
+		});
+		outcome.unwrap_or((Err(BlockError::Io), Vec::new()))
+	}
+
+	fn complete(&self, outcome:Outcome){
+		interrupts::without_interrupts(||*self.result.lock() = Some(outcome));
+		self.waiters.wake_all();
+	}
+}
+
+struct Request{
+	op:Operation,
+	lba:u64,
+	buf:Vec<u8>,
+	completion:Arc<Completion>,
+}
+
+impl Request{
+	fn blocks(&self, block_size:usize)->core::ops::Range<u64>{
+		self.lba..self.lba+(self.buf.len()/block_size) as u64
+	}
+}
+
+struct State{
+	pending:Vec<Request>,
+	///The block after the last request. The elevator continues from here.
+	position:u64,
+	///Set, when the queue is dropped.
+	closed:bool,
+}
+
+///The part of the queue, that the worker shares. It doesn't keep the queue alive, so dropping the queue stops it.
+struct Shared{
+	dev:Arc<dyn BlockDevice>,
+	state:Lock<State>,
+	work:WaitQueue,
+}
+
+pub struct RequestQueue{
+	shared:Arc<Shared>,
+}
+
+impl RequestQueue{
+	///Starts the worker task of the queue.
+	pub fn new(dev:Arc<dyn BlockDevice>)->Arc<Self>{
+		let shared = Arc::new(Shared{
+			dev,
+			state:Lock::with_class(State{pending:Vec::new(), position:0, closed:false}, &QUEUE_CLASS),
+			work:WaitQueue::new(),
+		});
+		let worker = shared.clone();
+		crate::sched::spawn("block::queue", move||worker.worker());
+		Arc::new(Self{shared})
+	}
+
+	pub fn device(&self)->&Arc<dyn BlockDevice>{
+		&self.shared.dev
+	}
+
+	///Queues a request. For reads, `buf` gets the data. Flushes ignore lba and buf.
+	pub fn submit(&self, op:Operation, lba:u64, buf:Vec<u8>)->Arc<Completion>{
+		let completion = Arc::new(Completion::new());
+		let request = Request{op, lba, buf, completion:completion.clone()};
+		interrupts::without_interrupts(||self.shared.state.lock().pending.push(request));
+		self.shared.work.wake_all();
+		completion
+	}
+
+	///Runs a request and waits for it.
+	pub fn run(&self, op:Operation, lba:u64, buf:Vec<u8>)->Outcome{
+		self.submit(op, lba, buf).wait()
+	}
+}
+
+impl Drop for RequestQueue{
+	fn drop(&mut self){
+		interrupts::without_interrupts(||self.shared.state.lock().closed = true);
+		self.shared.work.wake_all();
+	}
+}
+
+impl Shared{
+	fn worker(self:Arc<Self>){
+		loop{
+			let mut request = None;
+			let mut closed = false;
+			self.work.wait_until(||{
+				let mut state = self.state.lock();
+				request = self.next(&mut state);
+				closed = state.closed;
+				request.is_some() || closed
+			});
+			let Some(mut request) = request else{
+				if closed{
+					return;
+				}
+				continue;
+			};
+			let result = match request.op{
+				Operation::Read=>self.dev.read_blocks(request.lba, &mut request.buf),
+				Operation::Write=>self.dev.write_blocks(request.lba, &request.buf),
+				Operation::Flush=>self.dev.flush(),
+			};
+			request.completion.complete((result, request.buf));
+		}
+	}
+
+	///Takes the next request in elevator order.
+	fn next(&self, state:&mut State)->Option<Request>{
+		let block_size = self.dev.block_size();
+		let pending = &state.pending;
+		let barrier = pending.iter().position(|r|r.op == Operation::Flush).unwrap_or(pending.len());
+		if barrier == 0{
+			return (!pending.is_empty()).then(||state.pending.remove(0));
+		}
+		let overlaps_earlier = |i:usize|{
+			let r = &pending[i];
+			let blocks = r.blocks(block_size);
+			pending[..i].iter().any(|e|{
+				let other = e.blocks(block_size);
+				(e.op == Operation::Write || r.op == Operation::Write) && other.start < blocks.end && blocks.start < other.end
+			})
+		};
+		let eligible = (0..barrier).filter(|i|!overlaps_earlier(*i));
+		let ahead = eligible.clone().filter(|i|pending[*i].lba >= state.position).min_by_key(|i|pending[*i].lba);
+		let index = ahead.or_else(||eligible.min_by_key(|i|pending[*i].lba))?;
+		let request = state.pending.remove(index);
+		state.position = request.blocks(block_size).end;
+		Some(request)
+	}
+}
@@ -0,0 +1,363 @@
+//The kernel command line, that the boot loader passes in Args.
+//It is a list of words separated by spaces. A word is either `key=value`, or just `key`. Values can be put in double quotes, to contain spaces.
+//Subsystems declare their parameters as statics, and register them. A parameter gets its value from the command line, when it is registered,
+//or when the command line is set, whichever happens later. This works without a heap, so parameters can be used in early boot.
+use core::cell::UnsafeCell;
+use core::fmt::{Display, Formatter};
+use core::sync::atomic::{AtomicBool, AtomicI64, AtomicUsize, Ordering};
+use x86_64::instructions::interrupts;
+use kernel_efi::CMDLINE_MAX;
+use crate::lock::{Lock, LockClass};
+
+///How many parameters can be registered.
+const MAX_PARAMS:usize = 64;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ParamError{
+	///The parameter needs `=value`.
+	MissingValue,
+	InvalidBool,
+	InvalidInt,
+	OutOfRange{min:i64, max:i64},
+	InvalidChoice(&'static [&'static str]),
+}
+
+impl Display for ParamError{
+	fn fmt(&self, f:&mut Formatter<'_>)->core::fmt::Result{
+		match self{
+			ParamError::MissingValue=>write!(f, "a value is needed"),
+			ParamError::InvalidBool=>write!(f, "expected one of 1, 0, true, false, on, off, yes, no"),
+			ParamError::InvalidInt=>write!(f, "expected a number"),
+			ParamError::OutOfRange{min, max}=>write!(f, "expected a number from {} to {}", min, max),
+			ParamError::InvalidChoice(choices)=>{
+				write!(f, "expected one of")?;
+				for (i, c) in choices.iter().enumerate(){
+					write!(f, "{}{}", if i == 0 {" "} else {", "}, c)?;
+				}
+				Ok(())
+			},
+		}
+	}
+}
+
+///A parameter, that can be set on the command line.
+pub trait Param:Sync{
+	fn name(&self)->&'static str;
+	///Parses and stores a value. `value` is None for a word without `=`.
+	fn set(&self, value:Option<&'static str>)->Result<(), ParamError>;
+}
+
+///A flag. Just `key` sets it to true.
+pub struct BoolParam{
+	name:&'static str,
+	value:AtomicBool,
+}
+
+impl BoolParam{
+	pub const fn new(name:&'static str, default:bool)->Self{
+		Self{name, value:AtomicBool::new(default)}
+	}
+
+	pub fn get(&self)->bool{
+		self.value.load(Ordering::Relaxed)
+	}
+}
+
+impl Param for BoolParam{
+	fn name(&self)->&'static str{
+		self.name
+	}
+
+	fn set(&self, value:Option<&'static str>)->Result<(), ParamError>{
+		let value = match value{
+			None|Some("1"|"true"|"on"|"yes")=>true,
+			Some("0"|"false"|"off"|"no")=>false,
+			Some(_)=>return Err(ParamError::InvalidBool),
+		};
+		self.value.store(value, Ordering::Relaxed);
+		Ok(())
+	}
+}
+
+///A number in `min..=max`. Hexadecimal numbers start with 0x.
+pub struct IntParam{
+	name:&'static str,
+	value:AtomicI64,
+	min:i64,
+	max:i64,
+}
+
+impl IntParam{
+	pub const fn new(name:&'static str, default:i64, min:i64, max:i64)->Self{
+		Self{name, value:AtomicI64::new(default), min, max}
+	}
+
+	pub fn get(&self)->i64{
+		self.value.load(Ordering::Relaxed)
+	}
+}
+
+fn parse_int(s:&str)->Option<i64>{
+	let (negative, s) = match s.strip_prefix('-'){
+		Some(s)=>(true, s),
+		None=>(false, s),
+	};
+	let value = match s.strip_prefix("0x").or_else(||s.strip_prefix("0X")){
+		Some(hex)=>i64::from_str_radix(hex, 16).ok()?,
+		None=>s.parse().ok()?,
+	};
+	Some(if negative {-value} else {value})
+}
+
+impl Param for IntParam{
+	fn name(&self)->&'static str{
+		self.name
+	}
+
+	fn set(&self, value:Option<&'static str>)->Result<(), ParamError>{
+		let value = parse_int(value.ok_or(ParamError::MissingValue)?).ok_or(ParamError::InvalidInt)?;
+		if !(self.min..=self.max).contains(&value){
+			return Err(ParamError::OutOfRange{min:self.min, max:self.max});
+		}
+		self.value.store(value, Ordering::Relaxed);
+		Ok(())
+	}
+}
+
+static STR_PARAM_CLASS:LockClass = LockClass::new("cmdline::str_param");
+
+///A string. It points right into the command line.
+pub struct StrParam{
+	name:&'static str,
+	value:Lock<&'static str>,
+}
+
+impl StrParam{
+	pub const fn new(name:&'static str, default:&'static str)->Self{
+		Self{name, value:Lock::with_class(default, &STR_PARAM_CLASS)}
+	}
+
+	pub fn get(&self)->&'static str{
+		interrupts::without_interrupts(||*self.value.lock())
+	}
+}
+
+impl Param for StrParam{
+	fn name(&self)->&'static str{
+		self.name
+	}
+
+	fn set(&self, value:Option<&'static str>)->Result<(), ParamError>{
+		let value = value.ok_or(ParamError::MissingValue)?;
+		interrupts::without_interrupts(||*self.value.lock() = value);
+		Ok(())
+	}
+}
+
+///One out of a fixed list of names.
+pub struct EnumParam{
+	name:&'static str,
+	choices:&'static [&'static str],
+	value:AtomicUsize,
+}
+
+impl EnumParam{
+	///`default` is an index into `choices`.
+	pub const fn new(name:&'static str, choices:&'static [&'static str], default:usize)->Self{
+		assert!(default < choices.len());
+		Self{name, choices, value:AtomicUsize::new(default)}
+	}
+
+	///The index of the choice.
+	pub fn get(&self)->usize{
+		self.value.load(Ordering::Relaxed)
+	}
+
+	pub fn get_name(&self)->&'static str{
+		self.choices[self.get()]
+	}
+}
+
+impl Param for EnumParam{
+	fn name(&self)->&'static str{
+		self.name
+	}
+
+	fn set(&self, value:Option<&'static str>)->Result<(), ParamError>{
+		let value = value.ok_or(ParamError::MissingValue)?;
+		let i = self.choices.iter().position(|c|*c == value).ok_or(ParamError::InvalidChoice(self.choices))?;
+		self.value.store(i, Ordering::Relaxed);
+		Ok(())
+	}
+}
+
+///The command line is copied here once, and never changed after that, so the values can be handed out as &'static str.
+struct Buffer{
+	data:UnsafeCell<[u8;CMDLINE_MAX]>,
+	len:AtomicUsize,
+	set:AtomicBool,
+}
+
+//Safety:
+//data is only written once by init, before len is published with Release. Afterwards it is only read.
+unsafe impl Sync for Buffer{}
+
+static CMDLINE:Buffer = Buffer{data:UnsafeCell::new([0;CMDLINE_MAX]), len:AtomicUsize::new(0), set:AtomicBool::new(false)};
+
+static PARAMS_CLASS:LockClass = LockClass::new("cmdline::params");
+static PARAMS:Lock<[Option<&'static dyn Param>;MAX_PARAMS]> = Lock::with_class([None;MAX_PARAMS], &PARAMS_CLASS);
+
+///A value, that a parameter didn't accept.
+#[derive(Copy, Clone)]
+struct InvalidValue{
+	name:&'static str,
+	value:&'static str,
+	error:ParamError,
+}
+
+impl Display for InvalidValue{
+	fn fmt(&self, f:&mut Formatter<'_>)->core::fmt::Result{
+		write!(f, "Invalid value {:?} for the kernel parameter {}: {}", self.value, self.name, self.error)
+	}
+}
+
+static ERRORS_CLASS:LockClass = LockClass::new("cmdline::errors");
+///Invalid values, that were found before check ran. Parameters of the serial port and the logger are set, before there is a logger,
+///so they are only reported by check.
+static ERRORS:Lock<[Option<InvalidValue>;MAX_PARAMS]> = Lock::with_class([None;MAX_PARAMS], &ERRORS_CLASS);
+///Set by check. Invalid values, that are found afterwards, are reported right away. Only changed with ERRORS locked.
+static CHECKED:AtomicBool = AtomicBool::new(false);
+
+///The whole command line. Empty, until init was called.
+pub fn get()->&'static str{
+	let len = CMDLINE.len.load(Ordering::Acquire);
+	//Safety:
+	//The first len bytes were written by init, before it published len, and are never written again.
+	let bytes = unsafe{core::slice::from_raw_parts(CMDLINE.data.get() as *const u8, len)};
+	//init only copies whole strs.
+	core::str::from_utf8(bytes).unwrap_or_default()
+}
+
+///Splits the command line into (key, value) pairs.
+fn words(cmdline:&'static str)->impl Iterator<Item=(&'static str, Option<&'static str>)>{
+	let mut rest = cmdline;
+	core::iter::from_fn(move ||{
+		rest = rest.trim_start_matches(' ');
+		if rest.is_empty(){
+			return None;
+		}
+		//A word ends at the first space, that isn't inside of quotes.
+		let mut quoted = false;
+		let end = rest.char_indices().find(|&(_, c)|{
+			if c == '"'{
+				quoted = !quoted;
+			}
+			c == ' ' && !quoted
+		}).map(|(i, _)|i).unwrap_or(rest.len());
+		let word = &rest[..end];
+		rest = &rest[end..];
+		Some(match word.split_once('='){
+			Some((key, value))=>(key, Some(value.strip_prefix('"').and_then(|v|v.strip_suffix('"')).unwrap_or(value))),
+			None=>(word, None),
+		})
+	})
+}
+
+///Sets a parameter from the last occurrence of its key.
+fn apply(param:&'static dyn Param){
+	let Some((_, value)) = words(get()).filter(|(key, _)|*key == param.name()).last() else{
+		return;
+	};
+	let Err(error) = param.set(value) else{
+		return;
+	};
+	let invalid = InvalidValue{name:param.name(), value:value.unwrap_or_default(), error};
+	let deferred = interrupts::without_interrupts(||{
+		let mut errors = ERRORS.lock();
+		if CHECKED.load(Ordering::Relaxed){
+			return false;
+		}
+		//Every parameter is only registered once, so there is a slot for each.
+		if let Some(slot) = errors.iter_mut().find(|e|e.is_none()){
+			*slot = Some(invalid);
+		}
+		true
+	});
+	if !deferred{
+		log::warn!("{}", invalid);
+	}
+}
+
+///Sets the command line, and applies it to the parameters, that are already registered.
+///Can only be called once.
+pub fn init(cmdline:&str){
+	if CMDLINE.set.swap(true, Ordering::AcqRel){
+		log::error!("The kernel command line was already set.");
+		return;
+	}
+	let len = cmdline.len().min(CMDLINE_MAX);
+	let len = (0..=len).rev().find(|&i|cmdline.is_char_boundary(i)).unwrap_or(0);
+	//Safety:
+	//We are the only caller, that gets past `set`, and len isn't published yet, so nobody reads the buffer.
+	unsafe{core::ptr::copy_nonoverlapping(cmdline.as_ptr(), CMDLINE.data.get() as *mut u8, len)};
+	CMDLINE.len.store(len, Ordering::Release);
+	let params = interrupts::without_interrupts(||*PARAMS.lock());
+	for param in params.into_iter().flatten(){
+		apply(param);
+	}
+}
+
+///Registers a parameter, and sets it from the command line.
+///Registering the same parameter again does nothing.
+pub fn register(param:&'static dyn Param){
+	let registered = interrupts::without_interrupts(||{
+		let mut params = PARAMS.lock();
+		if let Some(other) = params.iter().flatten().find(|p|p.name() == param.name()){
+			if !core::ptr::addr_eq(*other, param){
+				log::error!("The kernel parameter {} is registered twice.", param.name());
+			}
+			return false;
+		}
+		match params.iter_mut().find(|p|p.is_none()){
+			Some(slot)=>{
+				*slot = Some(param);
+				true
+			},
+			None=>{
+				log::error!("Too many kernel parameters. {} is ignored.", param.name());
+				false
+			},
+		}
+	});
+	if registered{
+		apply(param);
+	}
+}
+
+///Warns about invalid values, keys, that appear more than once, and keys, that no parameter was registered for.
+///Should be called, once all subsystems are set up.
+pub fn check(){
+	let errors = interrupts::without_interrupts(||{
+		let mut errors = ERRORS.lock();
+		CHECKED.store(true, Ordering::Relaxed);
+		core::mem::replace(&mut *errors, [None;MAX_PARAMS])
+	});
+	for invalid in errors.iter().flatten(){
+		log::warn!("{}", invalid);
+	}
+	let params = interrupts::without_interrupts(||*PARAMS.lock());
+	for (i, (key, _)) in words(get()).enumerate(){
+		//Only the second occurrence of a key is reported.
+		match words(get()).take(i).filter(|(k, _)|*k == key).count(){
+			0=>(),
+			1=>{
+				log::warn!("The kernel parameter {} is given more than once. The last one is used.", key);
+				continue;
+			},
+			_=>continue,
+		}
+		if !params.iter().flatten().any(|p|p.name() == key){
+			log::warn!("Unknown kernel parameter {}", key);
+		}
+	}
+}
@@ -0,0 +1,7 @@
+//Device drivers.
+pub mod ahci;
+pub mod nvme;
+pub mod pci;
+pub mod ps2;
+pub mod serial;
+pub mod virtio;
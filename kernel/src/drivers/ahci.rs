@@ -0,0 +1,177 @@
+//AHCI SATA host bus adapters.
+//Every implemented port with a SATA disk becomes a block device. ATAPI devices and port multipliers are ignored.
+//All ports of an HBA share one MSI interrupt. Without MSI, the ports are polled.
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::time::Duration;
+use x86_64::instructions::interrupts;
+use x86_64::PhysAddr;
+use crate::drivers::pci::{self, msi, Bar, PciDevice, PciDriver, PciMatch};
+use crate::lock::{LockClass, RWLock, RWLockMode};
+use crate::x86_64::interrupts::InterruptFrame;
+
+mod ata;
+mod port;
+
+pub use port::AhciPort;
+
+const ABAR:usize = 5;
+
+const REG_CAP:usize = 0x00;
+const REG_GHC:usize = 0x04;
+const REG_IS:usize = 0x08;
+const REG_PI:usize = 0x0C;
+const REG_VS:usize = 0x10;
+const REG_CAP2:usize = 0x24;
+const REG_BOHC:usize = 0x28;
+
+const CAP_NCQ:u32 = 1<<30;
+const CAP_64BIT:u32 = 1<<31;
+const CAP_STAGGERED_SPIN_UP:u32 = 1<<27;
+const CAP2_BIOS_HANDOFF:u32 = 1<<0;
+const GHC_RESET:u32 = 1<<0;
+const GHC_INTERRUPT_ENABLE:u32 = 1<<1;
+const GHC_AHCI_ENABLE:u32 = 1<<31;
+const BOHC_BIOS_OWNED:u32 = 1<<0;
+const BOHC_OS_OWNED:u32 = 1<<1;
+const BOHC_BIOS_BUSY:u32 = 1<<4;
+
+pub static DRIVER:AhciDriver = AhciDriver;
+
+pub struct AhciDriver;
+
+static MATCHES:[PciMatch;1] = [PciMatch::class_prog_if(pci::CLASS_MASS_STORAGE, 0x06, 0x01)];
+
+///The registers of an HBA. Ports use them too, so this is shared.
+#[derive(Debug, Copy, Clone)]
+pub(crate) struct Registers{
+	///Virtual address of ABAR.
+	base:usize,
+}
+
+impl Registers{
+	fn read(&self, reg:usize)->u32{
+		//Safety:
+		//All AHCI registers are 32 bit wide and aligned, and inside ABAR.
+		unsafe{core::ptr::read_volatile((self.base+reg) as *const u32)}
+	}
+
+	fn write(&self, reg:usize, value:u32){
+		//Safety:
+		//See read.
+		unsafe{core::ptr::write_volatile((self.base+reg) as *mut u32, value)}
+	}
+}
+
+///Polls `cond` every 10µs, until it returns true or `timeout` expired.
+fn poll(timeout:Duration, mut cond:impl FnMut()->bool)->bool{
+	let step = Duration::from_micros(10);
+	let mut waited = Duration::ZERO;
+	while !cond(){
+		if waited >= timeout{
+			return false;
+		}
+		crate::time::busy_wait(step);
+		waited += step;
+	}
+	true
+}
+
+struct Hba{
+	regs:Registers,
+	irqs:Option<msi::MsiInterrupts>,
+	ports:Vec<Arc<AhciPort>>,
+}
+
+static HBAS_CLASS:LockClass = LockClass::new("ahci::hbas");
+static HBAS:RWLock<Vec<Hba>> = RWLock::with_class(Vec::new(), RWLockMode::ReaderPreferring, &HBAS_CLASS);
+
+impl PciDriver for AhciDriver{
+	fn name(&self)->&'static str{
+		"ahci"
+	}
+
+	fn matches(&self)->&'static [PciMatch]{
+		&MATCHES
+	}
+
+	fn probe(&self, dev:&Arc<PciDevice>)->bool{
+		let base = match dev.bar(ABAR){
+			Some(Bar::Memory{address, ..})=>crate::x86_64::mem::phys_to_virt(PhysAddr::new(address)).as_u64() as usize,
+			_=>{
+				log::warn!("AHCI controller {} has no memory BAR", dev.address);
+				return false;
+			},
+		};
+		dev.enable_memory_and_bus_master();
+		let regs = Registers{base};
+		if !init_hba(regs){
+			log::error!("Couldn't reset AHCI controller {}", dev.address);
+			return false;
+		}
+		let cap = regs.read(REG_CAP);
+		let version = regs.read(REG_VS);
+		let implemented = regs.read(REG_PI);
+		log::info!("AHCI {}.{} controller {}: {} ports, {} command slots{}{}",
+			version>>16, (version>>8)&0xFF, dev.address, implemented.count_ones(), (cap>>8 & 0x1F)+1,
+			if cap & CAP_NCQ != 0 {", NCQ"} else {""},
+			if cap & CAP_64BIT != 0 {", 64 bit"} else {""});
+		let irqs = msi::allocate(dev, &[irq], crate::x86_64::cpu::id());
+		let polling = irqs.is_none();
+		let mut ports = Vec::new();
+		for i in 0..32u8{
+			if implemented & (1<<i) != 0{
+				if let Some(port) = AhciPort::new(regs, i, polling){
+					ports.push(Arc::new(port));
+				}
+			}
+		}
+		if ports.is_empty(){
+			return false;
+		}
+		interrupts::without_interrupts(||HBAS.write_lock().push(Hba{regs, irqs, ports:ports.clone()}));
+		regs.write(REG_GHC, regs.read(REG_GHC) | GHC_INTERRUPT_ENABLE);
+		for port in ports{
+			port.enable_interrupts();
+			crate::block::register(port);
+		}
+		true
+	}
+}
+
+///Takes the HBA from the firmware and resets it.
+fn init_hba(regs:Registers)->bool{
+	regs.write(REG_GHC, regs.read(REG_GHC) | GHC_AHCI_ENABLE);
+	if regs.read(REG_CAP2) & CAP2_BIOS_HANDOFF != 0{
+		regs.write(REG_BOHC, regs.read(REG_BOHC) | BOHC_OS_OWNED);
+		//The firmware gets 25ms to notice, and 2s more, if it says it is busy.
+		poll(Duration::from_millis(25), ||regs.read(REG_BOHC) & BOHC_BIOS_OWNED == 0);
+		if regs.read(REG_BOHC) & BOHC_BIOS_BUSY != 0{
+			poll(Duration::from_secs(2), ||regs.read(REG_BOHC) & BOHC_BIOS_OWNED == 0);
+		}
+	}
+	regs.write(REG_GHC, regs.read(REG_GHC) | GHC_RESET);
+	if !poll(Duration::from_secs(1), ||regs.read(REG_GHC) & GHC_RESET == 0){
+		return false;
+	}
+	//The reset clears AHCI_ENABLE again.
+	regs.write(REG_GHC, GHC_AHCI_ENABLE);
+	regs.write(REG_IS, u32::MAX);
+	true
+}
+
+///The handler for all HBAs. Every port, that has a pending interrupt, gets to handle it.
+fn irq(frame:&mut InterruptFrame){
+	let vector = frame.vector as u8;
+	let hbas = HBAS.read_lock();
+	for hba in hbas.iter().filter(|h|h.irqs.as_ref().is_some_and(|i|i.index_of(vector).is_some())){
+		let pending = hba.regs.read(REG_IS);
+		for port in &hba.ports{
+			if pending & (1<<port.index()) != 0{
+				port.handle_interrupt();
+			}
+		}
+		//The port interrupt status has to be cleared first, or the bit is set again.
+		hba.regs.write(REG_IS, pending);
+	}
+}
@@ -0,0 +1,115 @@
+//ATA commands, and the FIS, that carries them to the device.
+use alloc::string::String;
+
+pub const CMD_READ_DMA_EXT:u8 = 0x25;
+pub const CMD_WRITE_DMA_EXT:u8 = 0x35;
+pub const CMD_READ_FPDMA_QUEUED:u8 = 0x60;
+pub const CMD_WRITE_FPDMA_QUEUED:u8 = 0x61;
+pub const CMD_FLUSH_CACHE:u8 = 0xE7;
+pub const CMD_FLUSH_CACHE_EXT:u8 = 0xEA;
+pub const CMD_IDENTIFY_DEVICE:u8 = 0xEC;
+
+const FIS_TYPE_REG_H2D:u8 = 0x27;
+///Set in byte 1 of a register FIS, if it carries a command.
+const FIS_COMMAND:u8 = 1<<7;
+pub const FIS_H2D_SIZE:usize = 20;
+const DEVICE_LBA:u8 = 1<<6;
+
+///The largest sector count, that fits into a 48 bit command.
+pub const MAX_SECTORS_EXT:u32 = 65536;
+
+///Builds a host to device register FIS.
+///A count of MAX_SECTORS_EXT is encoded as 0.
+pub fn h2d_fis(command:u8, lba:u64, count:u32, features:u16)->[u8;FIS_H2D_SIZE]{
+	let mut fis = [0;FIS_H2D_SIZE];
+	fis[0] = FIS_TYPE_REG_H2D;
+	fis[1] = FIS_COMMAND;
+	fis[2] = command;
+	fis[3] = features as u8;
+	fis[4] = lba as u8;
+	fis[5] = (lba>>8) as u8;
+	fis[6] = (lba>>16) as u8;
+	fis[7] = DEVICE_LBA;
+	fis[8] = (lba>>24) as u8;
+	fis[9] = (lba>>32) as u8;
+	fis[10] = (lba>>40) as u8;
+	fis[11] = (features>>8) as u8;
+	fis[12] = count as u8;
+	fis[13] = (count>>8) as u8;
+	fis
+}
+
+///Builds a READ or WRITE FPDMA QUEUED command.
+///NCQ commands move the sector count into the features field, and the tag into the count field.
+pub fn ncq_fis(write:bool, lba:u64, count:u32, tag:u8)->[u8;FIS_H2D_SIZE]{
+	let command = if write {CMD_WRITE_FPDMA_QUEUED} else {CMD_READ_FPDMA_QUEUED};
+	h2d_fis(command, lba, (tag as u32)<<3, count as u16)
+}
+
+///The interesting parts of the IDENTIFY DEVICE data.
+#[derive(Debug, Clone, Default)]
+pub struct Identify{
+	pub model:String,
+	pub serial:String,
+	pub firmware:String,
+	pub sectors:u64,
+	///The logical sector size in bytes.
+	pub sector_size:u32,
+	pub lba48:bool,
+	///The NCQ queue depth, if the device supports NCQ.
+	pub ncq_depth:Option<u8>,
+	pub flush_ext:bool,
+}
+
+impl Identify{
+	///Returns None for devices, that aren't ATA disks.
+	pub fn parse(data:&[u8])->Option<Self>{
+		if data.len() < 512{
+			return None;
+		}
+		let word = |i:usize|u16::from_le_bytes([data[2*i], data[2*i+1]]);
+		//Bit 15 of word 0 is set for ATAPI devices.
+		if word(0) & (1<<15) != 0{
+			return None;
+		}
+		let lba48 = word(83) & (1<<10) != 0;
+		let sectors = if lba48{
+			(0..4).fold(0, |n, i|n | (word(100+i) as u64)<<(16*i))
+		}else{
+			word(60) as u64 | (word(61) as u64)<<16
+		};
+		//Word 106 is only valid, if bit 14 is set and bit 15 is clear.
+		let sector_info = word(106);
+		let sector_size = if sector_info & 0xC000 == 0x4000 && sector_info & (1<<12) != 0{
+			2*(word(117) as u32 | (word(118) as u32)<<16)
+		}else{
+			512
+		};
+		let ncq_depth = if word(76) != 0xFFFF && word(76) & (1<<8) != 0{
+			Some((word(75) & 0x1F) as u8+1)
+		}else{
+			None
+		};
+		Some(Self{
+			model:ata_string(data, 27, 47),
+			serial:ata_string(data, 10, 20),
+			firmware:ata_string(data, 23, 27),
+			sectors,
+			sector_size,
+			lba48,
+			ncq_depth,
+			flush_ext:word(83) & (1<<13) != 0,
+		})
+	}
+}
+
+///ATA strings have the two characters of every word swapped, and are padded with spaces.
+fn ata_string(data:&[u8], start:usize, end:usize)->String{
+	let mut s = String::new();
+	for i in start..end{
+		for b in [data[2*i+1], data[2*i]]{
+			s.push(if b.is_ascii_graphic() || b == b' ' {b as char} else {'?'});
+		}
+	}
+	String::from(s.trim())
+}
@@ -0,0 +1,635 @@
+//A port of an AHCI HBA with a SATA disk attached.
+//Every command slot has its own command table. A request owns its slot from allocation, until it picked up the result.
+//NCQ and non-NCQ commands can't be mixed, so a request of the other kind waits, until all slots are released.
+//Fatal errors stop the port. All commands in flight fail, and the next request restarts the port.
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use core::time::Duration;
+use x86_64::instructions::interrupts;
+use crate::block::{self, BlockDevice, BlockError};
+use crate::lock::{Lock, LockClass};
+use crate::sync::WaitQueue;
+use crate::x86_64::dma::DmaBuffer;
+use crate::x86_64::mem::{phys_segments, PAGE_SIZE};
+use super::ata::{self, Identify};
+use super::{poll, Registers, CAP_64BIT, CAP_NCQ, CAP_STAGGERED_SPIN_UP, REG_CAP};
+
+const PORT_BASE:usize = 0x100;
+const PORT_SIZE:usize = 0x80;
+const PX_CLB:usize = 0x00;
+const PX_FB:usize = 0x08;
+const PX_IS:usize = 0x10;
+const PX_IE:usize = 0x14;
+const PX_CMD:usize = 0x18;
+const PX_TFD:usize = 0x20;
+const PX_SIG:usize = 0x24;
+const PX_SSTS:usize = 0x28;
+const PX_SCTL:usize = 0x2C;
+const PX_SERR:usize = 0x30;
+const PX_SACT:usize = 0x34;
+const PX_CI:usize = 0x38;
+
+const CMD_START:u32 = 1<<0;
+const CMD_SPIN_UP:u32 = 1<<1;
+const CMD_POWER_ON:u32 = 1<<2;
+const CMD_FIS_RECEIVE:u32 = 1<<4;
+const CMD_FIS_RUNNING:u32 = 1<<14;
+const CMD_LIST_RUNNING:u32 = 1<<15;
+
+const IS_D2H_REGISTER:u32 = 1<<0;
+const IS_PIO_SETUP:u32 = 1<<1;
+const IS_DMA_SETUP:u32 = 1<<2;
+const IS_SET_DEVICE_BITS:u32 = 1<<3;
+const IS_DESCRIPTOR_PROCESSED:u32 = 1<<5;
+const IS_INTERFACE_NON_FATAL:u32 = 1<<26;
+const IS_INTERFACE_FATAL:u32 = 1<<27;
+const IS_HOST_BUS_DATA:u32 = 1<<28;
+const IS_HOST_BUS_FATAL:u32 = 1<<29;
+const IS_TASK_FILE_ERROR:u32 = 1<<30;
+const IS_FATAL:u32 = IS_INTERFACE_FATAL | IS_HOST_BUS_DATA | IS_HOST_BUS_FATAL | IS_TASK_FILE_ERROR;
+
+const TFD_ERR:u32 = 1<<0;
+const TFD_DRQ:u32 = 1<<3;
+const TFD_BSY:u32 = 1<<7;
+
+const SSTS_DET_MASK:u32 = 0xF;
+const SSTS_DET_PRESENT:u32 = 3;
+const SSTS_IPM_ACTIVE:u32 = 1;
+const SCTL_DET_MASK:u32 = 0xF;
+const SCTL_DET_COMRESET:u32 = 1;
+
+const SIG_ATA:u32 = 0x0000_0101;
+
+const COMMAND_HEADER_SIZE:usize = 32;
+const COMMAND_LIST_SIZE:usize = 32*COMMAND_HEADER_SIZE;
+const FIS_AREA_SIZE:usize = 256;
+const HEADER_WRITE:u32 = 1<<6;
+const HEADER_PRDTL_SHIFT:u32 = 16;
+
+const TABLE_PRDT:usize = 0x80;
+const PRD_SIZE:usize = 16;
+///Keeps a command table at 1KiB, so 4 of them fit into a page.
+const PRD_ENTRIES:usize = 56;
+const TABLE_SIZE:usize = TABLE_PRDT+PRD_ENTRIES*PRD_SIZE;
+///The byte count field has 22 bits.
+const PRD_MAX_BYTES:usize = 4*1024*1024;
+const PRD_INTERRUPT:u32 = 1<<31;
+
+const MAX_CHUNK:usize = 128*1024;
+const COMMAND_TIMEOUT:Duration = Duration::from_secs(10);
+
+static NEXT_INDEX:AtomicU32 = AtomicU32::new(0);
+static PORT_CLASS:LockClass = LockClass::new("ahci::port");
+
+struct State{
+	///Slots, that belong to a request.
+	allocated:u32,
+	///Slots, that the device didn't complete yet.
+	issued:u32,
+	///Completed slots, whose command failed.
+	failed:u32,
+	///Whether the allocated slots hold NCQ commands.
+	queued:bool,
+	///A request of the other kind waits for the allocated slots to be released.
+	switch_pending:bool,
+	///The port stopped after an error, and needs a restart before the next command.
+	needs_recovery:bool,
+	recovering:bool,
+}
+
+pub struct AhciPort{
+	port:PortRegisters,
+	name:String,
+	identify:Identify,
+	ncq:bool,
+	dma64:bool,
+	///One bit for every command slot, that we use.
+	slot_mask:u32,
+	///Interrupts are only used, once the HBA delivers them. Until then, the port is polled.
+	use_interrupts:AtomicBool,
+	polling:bool,
+	command_list:DmaBuffer,
+	_fis_area:DmaBuffer,
+	tables:Vec<DmaBuffer>,
+	state:Lock<State>,
+	waiters:WaitQueue,
+}
+
+///The registers of one port.
+#[derive(Debug, Copy, Clone)]
+struct PortRegisters{
+	regs:Registers,
+	index:u8,
+}
+
+impl PortRegisters{
+	fn read(&self, reg:usize)->u32{
+		self.regs.read(PORT_BASE+self.index as usize*PORT_SIZE+reg)
+	}
+
+	fn write(&self, reg:usize, value:u32){
+		self.regs.write(PORT_BASE+self.index as usize*PORT_SIZE+reg, value)
+	}
+
+	///Stops command processing and FIS reception.
+	fn stop(&self)->bool{
+		self.write(PX_CMD, self.read(PX_CMD) & !CMD_START);
+		if !poll(Duration::from_millis(500), ||self.read(PX_CMD) & CMD_LIST_RUNNING == 0){
+			return false;
+		}
+		self.write(PX_CMD, self.read(PX_CMD) & !CMD_FIS_RECEIVE);
+		poll(Duration::from_millis(500), ||self.read(PX_CMD) & CMD_FIS_RUNNING == 0)
+	}
+
+	///Restarts command processing, once the device isn't busy anymore.
+	fn start(&self)->bool{
+		self.write(PX_CMD, self.read(PX_CMD) | CMD_FIS_RECEIVE);
+		if !poll(Duration::from_secs(1), ||self.read(PX_TFD) & (TFD_BSY|TFD_DRQ) == 0){
+			return false;
+		}
+		self.write(PX_CMD, self.read(PX_CMD) | CMD_START);
+		true
+	}
+
+	///Resets the link to the device with a COMRESET.
+	fn comreset(&self)->bool{
+		let sctl = self.read(PX_SCTL) & !SCTL_DET_MASK;
+		self.write(PX_SCTL, sctl | SCTL_DET_COMRESET);
+		crate::time::busy_wait(Duration::from_millis(1));
+		self.write(PX_SCTL, sctl);
+		let linked = poll(Duration::from_secs(1), ||self.read(PX_SSTS) & SSTS_DET_MASK == SSTS_DET_PRESENT);
+		self.write(PX_SERR, u32::MAX);
+		linked
+	}
+
+	///Gets the port back into a working state after an error.
+	///Stopping the port clears all outstanding commands.
+	fn recover(&self)->bool{
+		self.stop();
+		self.write(PX_SERR, u32::MAX);
+		self.write(PX_IS, u32::MAX);
+		if self.read(PX_TFD) & (TFD_BSY|TFD_DRQ|TFD_ERR) != 0 && !self.comreset(){
+			return false;
+		}
+		self.start()
+	}
+}
+
+impl AhciPort{
+	///Sets up the port, and identifies the disk on it.
+	///Returns None, if there is no ATA disk.
+	pub(super) fn new(regs:Registers, index:u8, polling:bool)->Option<Self>{
+		let cap = regs.read(REG_CAP);
+		let slots = (cap>>8 & 0x1F)+1;
+		let port = PortRegisters{regs, index};
+		let command_list = DmaBuffer::new(COMMAND_LIST_SIZE)?;
+		let fis_area = DmaBuffer::new(FIS_AREA_SIZE)?;
+		let tables = (0..slots).map(|_|DmaBuffer::new(TABLE_SIZE)).collect::<Option<Vec<_>>>()?;
+		let dma64 = cap & CAP_64BIT != 0;
+		if !port.stop(){
+			log::warn!("AHCI port {} doesn't stop.", index);
+			return None;
+		}
+		let (list, fis) = (command_list.phys(), fis_area.phys());
+		if !dma64 && (list|fis|tables.iter().fold(0, |a, t|a|t.phys()))>>32 != 0{
+			log::error!("AHCI port {}: command memory above 4GiB, but the HBA only has 32 bit addresses", index);
+			return None;
+		}
+		port.write(PX_CLB, list as u32);
+		port.write(PX_CLB+4, (list>>32) as u32);
+		port.write(PX_FB, fis as u32);
+		port.write(PX_FB+4, (fis>>32) as u32);
+		let mut cmd = port.read(PX_CMD) | CMD_POWER_ON;
+		if cap & CAP_STAGGERED_SPIN_UP != 0{
+			cmd |= CMD_SPIN_UP;
+		}
+		port.write(PX_CMD, cmd | CMD_FIS_RECEIVE);
+		//The device has 10ms to establish the link after spin up.
+		poll(Duration::from_millis(10), ||port.read(PX_SSTS) & SSTS_DET_MASK == SSTS_DET_PRESENT);
+		let ssts = port.read(PX_SSTS);
+		if ssts & SSTS_DET_MASK != SSTS_DET_PRESENT || (ssts>>8 & 0xF) != SSTS_IPM_ACTIVE{
+			port.stop();
+			return None;
+		}
+		port.write(PX_SERR, u32::MAX);
+		port.write(PX_IS, u32::MAX);
+		//The signature arrives with the first D2H register FIS after the reset.
+		poll(Duration::from_secs(1), ||port.read(PX_TFD) & TFD_BSY == 0);
+		let signature = port.read(PX_SIG);
+		if signature != SIG_ATA{
+			log::info!("AHCI port {}: ignoring device with signature {:#010x}", index, signature);
+			port.stop();
+			return None;
+		}
+		if !port.start(){
+			log::warn!("AHCI port {}: device stays busy", index);
+			port.stop();
+			return None;
+		}
+		port.write(PX_IE, IS_D2H_REGISTER | IS_PIO_SETUP | IS_DMA_SETUP | IS_SET_DEVICE_BITS | IS_DESCRIPTOR_PROCESSED
+			| IS_INTERFACE_NON_FATAL | IS_FATAL);
+		let mut disk = Self{
+			port,
+			name:String::new(),
+			identify:Identify::default(),
+			ncq:false,
+			dma64,
+			slot_mask:if slots == 32 {u32::MAX} else {(1<<slots)-1},
+			use_interrupts:AtomicBool::new(false),
+			polling,
+			command_list,
+			_fis_area:fis_area,
+			tables,
+			state:Lock::with_class(State{
+				allocated:0,
+				issued:0,
+				failed:0,
+				queued:false,
+				switch_pending:false,
+				needs_recovery:false,
+				recovering:false,
+			}, &PORT_CLASS),
+			waiters:WaitQueue::new(),
+		};
+		if !disk.identify(cap){
+			port.stop();
+			return None;
+		}
+		Some(disk)
+	}
+
+	///Reads the IDENTIFY DEVICE data, and decides about NCQ.
+	fn identify(&mut self, cap:u32)->bool{
+		let index = self.port.index;
+		let data = match DmaBuffer::new(512){
+			Some(d)=>d,
+			None=>return false,
+		};
+		let fis = ata::h2d_fis(ata::CMD_IDENTIFY_DEVICE, 0, 0, 0);
+		if self.command(&fis, false, Some((data.as_ptr(), data.len())), false).is_err(){
+			log::warn!("AHCI port {}: IDENTIFY DEVICE failed", index);
+			return false;
+		}
+		let identify = match Identify::parse(data.as_slice()){
+			Some(i)=>i,
+			None=>return false,
+		};
+		if !identify.lba48{
+			log::warn!("AHCI port {}: {} doesn't support 48 bit addresses", index, identify.model);
+			return false;
+		}
+		if identify.sector_size as usize > PAGE_SIZE || !identify.sector_size.is_power_of_two(){
+			log::warn!("AHCI port {}: unsupported sector size {}", index, identify.sector_size);
+			return false;
+		}
+		if let (true, Some(depth)) = (cap & CAP_NCQ != 0, identify.ncq_depth){
+			self.ncq = true;
+			//NCQ tags are slot numbers, so the slots can't go beyond the queue depth.
+			if depth < 32{
+				self.slot_mask &= (1<<depth)-1;
+			}
+		}
+		self.name = format!("sd{}", (b'a'+NEXT_INDEX.fetch_add(1, Ordering::Relaxed) as u8) as char);
+		log::info!("AHCI port {} is {}: {} (serial {}, firmware {}){}", index, self.name, identify.model, identify.serial, identify.firmware,
+			if self.ncq {", NCQ"} else {""});
+		self.identify = identify;
+		true
+	}
+
+	pub fn index(&self)->u8{
+		self.port.index
+	}
+
+	///Switches from polling to interrupts, once the HBA delivers them.
+	pub(super) fn enable_interrupts(&self){
+		if !self.polling{
+			self.use_interrupts.store(true, Ordering::Release);
+		}
+	}
+
+	///Blocks, until `f` returns true or the timeout expires. `f` runs with the port locked.
+	///Returns false on timeout.
+	fn wait<F:FnMut(&mut State)->bool>(&self, mut f:F, timeout:Duration)->bool{
+		if self.use_interrupts.load(Ordering::Acquire){
+			return self.waiters.wait_until_timeout(||f(&mut self.state.lock()), timeout);
+		}
+		let mut deadline = crate::time::Deadline::after(timeout);
+		loop{
+			let done = interrupts::without_interrupts(||{
+				let mut state = self.state.lock();
+				self.check(&mut state);
+				f(&mut state)
+			});
+			if done{
+				return true;
+			}
+			if deadline.expired(){
+				return false;
+			}
+			crate::sched::yield_now();
+		}
+	}
+
+	///Finds completed and failed commands.
+	///Returns true, if anything changed.
+	fn check(&self, state:&mut State)->bool{
+		let status = self.port.read(PX_IS);
+		self.port.write(PX_IS, status);
+		if status & IS_FATAL != 0{
+			if state.issued != 0{
+				log::error!("{}: port error {:#x}, task file {:#x}, SATA error {:#x}",
+					self.name, status, self.port.read(PX_TFD), self.port.read(PX_SERR));
+			}
+			//The HBA stopped processing commands. None of the outstanding ones will complete.
+			state.failed |= state.issued;
+			state.issued = 0;
+			state.needs_recovery = true;
+			return true;
+		}
+		let outstanding = self.port.read(PX_CI) | self.port.read(PX_SACT);
+		let done = state.issued & !outstanding;
+		state.issued &= !done;
+		done != 0
+	}
+
+	///Called from the interrupt handler of the HBA.
+	pub(super) fn handle_interrupt(&self){
+		if self.check(&mut self.state.lock()){
+			self.waiters.wake_all();
+		}
+	}
+
+	///Takes a free slot for an NCQ, or non-NCQ, command.
+	fn try_allocate(&self, state:&mut State, queued:bool)->Option<usize>{
+		if state.needs_recovery || state.recovering{
+			return None;
+		}
+		if state.allocated != 0 && state.queued != queued{
+			state.switch_pending = true;
+			return None;
+		}
+		if state.allocated != 0 && state.switch_pending{
+			return None;
+		}
+		let free = !state.allocated & self.slot_mask;
+		if free == 0{
+			return None;
+		}
+		if state.allocated == 0{
+			state.queued = queued;
+			state.switch_pending = false;
+		}
+		let slot = free.trailing_zeros() as usize;
+		state.allocated |= 1<<slot;
+		Some(slot)
+	}
+
+	fn allocate(&self, queued:bool)->usize{
+		loop{
+			//An error, that hit no command, still stops the port, and nobody else would restart it.
+			self.recover();
+			let mut slot = None;
+			self.wait(|state|{
+				slot = self.try_allocate(state, queued);
+				slot.is_some()
+			}, COMMAND_TIMEOUT);
+			if let Some(s) = slot{
+				return s;
+			}
+		}
+	}
+
+	fn release(&self, slot:usize){
+		interrupts::without_interrupts(||{
+			let mut state = self.state.lock();
+			state.allocated &= !(1<<slot);
+			state.failed &= !(1<<slot);
+		});
+		self.waiters.wake_all();
+	}
+
+	///Fills in the command header and table of the slot.
+	fn prepare(&self, slot:usize, fis:&[u8;ata::FIS_H2D_SIZE], write:bool, data:Option<(*const u8, usize)>)->Result<(), BlockError>{
+		let table = &self.tables[slot];
+		table.write::<[u8;ata::FIS_H2D_SIZE]>(0, *fis);
+		let mut entries = 0;
+		if let Some((ptr, len)) = data{
+			if !(ptr as usize).is_multiple_of(2) || !len.is_multiple_of(2){
+				log::error!("{}: DMA buffers have to be 2 byte aligned", self.name);
+				return Err(BlockError::Io);
+			}
+			for (mut phys, mut len) in phys_segments(ptr, len).ok_or(BlockError::Io)?{
+				if !self.dma64 && (phys+len as u64)>>32 != 0{
+					log::error!("{}: buffer above 4GiB, but the HBA only has 32 bit addresses", self.name);
+					return Err(BlockError::Io);
+				}
+				while len > 0{
+					if entries == PRD_ENTRIES{
+						log::error!("{}: request has too many segments", self.name);
+						return Err(BlockError::Io);
+					}
+					let piece = len.min(PRD_MAX_BYTES);
+					let entry = TABLE_PRDT+entries*PRD_SIZE;
+					table.write::<u64>(entry, phys);
+					table.write::<u32>(entry+8, 0);
+					table.write::<u32>(entry+12, (piece-1) as u32);
+					phys += piece as u64;
+					len -= piece;
+					entries += 1;
+				}
+			}
+			//Only the last entry interrupts.
+			let last = TABLE_PRDT+(entries-1)*PRD_SIZE+12;
+			table.write::<u32>(last, table.read::<u32>(last) | PRD_INTERRUPT);
+		}
+		let header = slot*COMMAND_HEADER_SIZE;
+		let fis_dwords = (ata::FIS_H2D_SIZE/4) as u32;
+		let flags = if write {HEADER_WRITE} else {0};
+		self.command_list.write::<u32>(header, fis_dwords | flags | (entries as u32)<<HEADER_PRDTL_SHIFT);
+		//The HBA counts the transferred bytes here.
+		self.command_list.write::<u32>(header+4, 0);
+		self.command_list.write::<u64>(header+8, table.phys());
+		Ok(())
+	}
+
+	///Hands a prepared slot to the device.
+	fn issue(&self, slot:usize, queued:bool){
+		loop{
+			self.recover();
+			let issued = self.wait(|state|{
+				if state.needs_recovery || state.recovering{
+					return false;
+				}
+				//The command table has to be visible, before the device looks at it.
+				core::sync::atomic::fence(Ordering::SeqCst);
+				if queued{
+					self.port.write(PX_SACT, 1<<slot);
+				}
+				self.port.write(PX_CI, 1<<slot);
+				state.issued |= 1<<slot;
+				true
+			}, COMMAND_TIMEOUT);
+			if issued{
+				return;
+			}
+		}
+	}
+
+	///Waits for the command in the slot to finish, and releases the slot.
+	fn complete(&self, slot:usize)->Result<(), BlockError>{
+		let bit = 1<<slot;
+		let mut failed = false;
+		let done = self.wait(|state|{
+			if state.issued & bit != 0{
+				return false;
+			}
+			failed = state.failed & bit != 0;
+			true
+		}, COMMAND_TIMEOUT);
+		if !done{
+			log::error!("{}: command timed out", self.name);
+			interrupts::without_interrupts(||{
+				let mut state = self.state.lock();
+				state.failed |= state.issued;
+				state.issued = 0;
+				state.needs_recovery = true;
+			});
+			self.waiters.wake_all();
+			failed = true;
+		}
+		self.release(slot);
+		if failed{
+			self.recover();
+			return Err(BlockError::Io);
+		}
+		Ok(())
+	}
+
+	///Restarts the port after an error, unless somebody else already does.
+	fn recover(&self){
+		let recover = interrupts::without_interrupts(||{
+			let mut state = self.state.lock();
+			if !state.needs_recovery || state.recovering || state.issued != 0{
+				return false;
+			}
+			state.needs_recovery = false;
+			state.recovering = true;
+			true
+		});
+		if !recover{
+			return;
+		}
+		if self.port.recover(){
+			log::info!("{}: port recovered", self.name);
+		}else{
+			log::error!("{}: port recovery failed", self.name);
+		}
+		//A failed recovery is tried again after the next error.
+		interrupts::without_interrupts(||self.state.lock().recovering = false);
+		self.waiters.wake_all();
+	}
+
+	///Runs a single command and waits for it.
+	fn command(&self, fis:&[u8;ata::FIS_H2D_SIZE], write:bool, data:Option<(*const u8, usize)>, queued:bool)->Result<(), BlockError>{
+		let slot = self.allocate(queued);
+		if let Err(e) = self.prepare(slot, fis, write, data){
+			self.release(slot);
+			return Err(e);
+		}
+		self.issue(slot, queued);
+		self.complete(slot)
+	}
+
+	///The largest chunk, whose buffer is guaranteed to fit into the PRDT.
+	fn max_chunk(&self)->usize{
+		let sector_size = self.identify.sector_size as usize;
+		let max = ((PRD_ENTRIES-1)*PAGE_SIZE)
+			.min(MAX_CHUNK)
+			.min(ata::MAX_SECTORS_EXT as usize*sector_size);
+		max-max%sector_size
+	}
+
+	fn transfer(&self, write:bool, lba:u64, ptr:*const u8, len:usize)->Result<(), BlockError>{
+		block::check_request(self, lba, len)?;
+		let sector_size = self.identify.sector_size as usize;
+		let chunk = self.max_chunk();
+		let mut pending:alloc::collections::VecDeque<usize> = alloc::collections::VecDeque::new();
+		let mut result = Ok(());
+		let mut offset = 0;
+		'chunks: while offset < len{
+			let size = chunk.min(len-offset);
+			let sector = lba+(offset/sector_size) as u64;
+			let count = (size/sector_size) as u32;
+			//Our own chunks only give their slots back, when we complete them.
+			let slot = loop{
+				if let Some(s) = interrupts::without_interrupts(||self.try_allocate(&mut self.state.lock(), self.ncq)){
+					break s;
+				}
+				match pending.pop_front(){
+					Some(p)=>if let Err(e) = self.complete(p){
+						result = Err(e);
+						break 'chunks;
+					},
+					None=>break self.allocate(self.ncq),
+				}
+			};
+			let fis = match (self.ncq, write){
+				(true, _)=>ata::ncq_fis(write, sector, count, slot as u8),
+				(false, false)=>ata::h2d_fis(ata::CMD_READ_DMA_EXT, sector, count, 0),
+				(false, true)=>ata::h2d_fis(ata::CMD_WRITE_DMA_EXT, sector, count, 0),
+			};
+			//Safety:
+			//offset+size is inside the buffer.
+			let data = unsafe{ptr.add(offset)};
+			match self.prepare(slot, &fis, write, Some((data, size))){
+				Ok(())=>{
+					self.issue(slot, self.ncq);
+					pending.push_back(slot);
+				},
+				Err(e)=>{
+					self.release(slot);
+					result = Err(e);
+					break;
+				},
+			}
+			offset += size;
+		}
+		//Even after an error, the issued commands have to finish, before the buffer can be given back.
+		for slot in pending{
+			let r = self.complete(slot);
+			if result.is_ok(){
+				result = r;
+			}
+		}
+		result
+	}
+}
+
+impl BlockDevice for AhciPort{
+	fn name(&self)->&str{
+		&self.name
+	}
+
+	fn block_size(&self)->usize{
+		self.identify.sector_size as usize
+	}
+
+	fn block_count(&self)->u64{
+		self.identify.sectors
+	}
+
+	fn read_blocks(&self, lba:u64, buf:&mut [u8])->Result<(), BlockError>{
+		self.transfer(false, lba, buf.as_mut_ptr(), buf.len())
+	}
+
+	fn write_blocks(&self, lba:u64, buf:&[u8])->Result<(), BlockError>{
+		self.transfer(true, lba, buf.as_ptr(), buf.len())
+	}
+
+	fn flush(&self)->Result<(), BlockError>{
+		let command = if self.identify.flush_ext {ata::CMD_FLUSH_CACHE_EXT} else {ata::CMD_FLUSH_CACHE};
+		self.command(&ata::h2d_fis(command, 0, 0, 0), false, None, false)
+	}
+}
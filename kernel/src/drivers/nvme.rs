@@ -0,0 +1,417 @@
+//NVMe controllers.
+//Every cpu from the MADT gets its own I/O queue pair, whose interrupt goes to that cpu, as far as the controller has queues.
+//Every active namespace becomes a block device.
+//QEMU emulates a controller with
+//  -drive file=disk.img,format=raw,if=none,id=nvm0 -device nvme,serial=deadbeef,drive=nvm0
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU32, Ordering};
+use core::time::Duration;
+use x86_64::instructions::interrupts;
+use x86_64::PhysAddr;
+use crate::drivers::pci::{self, msi, Bar, PciDevice, PciDriver, PciMatch};
+use crate::lock::{LockClass, RWLock, RWLockMode};
+use crate::x86_64::cpu::{self, MAX_CPUS};
+use crate::x86_64::dma::DmaBuffer;
+use crate::x86_64::interrupts::{Handler, InterruptFrame};
+use crate::x86_64::mem::PAGE_SIZE;
+
+mod namespace;
+mod queue;
+
+pub use namespace::Namespace;
+pub use queue::{Command, QueuePair};
+
+const REG_CAP:usize = 0x00;
+const REG_VS:usize = 0x08;
+const REG_CC:usize = 0x14;
+const REG_CSTS:usize = 0x1C;
+const REG_AQA:usize = 0x24;
+const REG_ASQ:usize = 0x28;
+const REG_ACQ:usize = 0x30;
+const DOORBELL_BASE:usize = 0x1000;
+
+const CAP_CSS_NVM:u64 = 1<<37;
+const CC_ENABLE:u32 = 1<<0;
+const CC_IOSQES_SHIFT:u32 = 16;
+const CC_IOCQES_SHIFT:u32 = 20;
+const CSTS_READY:u32 = 1<<0;
+const CSTS_FATAL:u32 = 1<<1;
+
+const ADMIN_CREATE_SQ:u8 = 0x01;
+const ADMIN_DELETE_CQ:u8 = 0x04;
+const ADMIN_CREATE_CQ:u8 = 0x05;
+const ADMIN_IDENTIFY:u8 = 0x06;
+const ADMIN_SET_FEATURES:u8 = 0x09;
+
+const IDENTIFY_NAMESPACE:u32 = 0;
+const IDENTIFY_CONTROLLER:u32 = 1;
+const IDENTIFY_ACTIVE_NAMESPACES:u32 = 2;
+const FEATURE_NUMBER_OF_QUEUES:u32 = 0x07;
+
+const QUEUE_PHYSICALLY_CONTIGUOUS:u32 = 1<<0;
+const CQ_INTERRUPTS_ENABLED:u32 = 1<<1;
+
+///Transfers are split into chunks of at most this size, so their PRP list stays small.
+const MAX_CHUNK:usize = 128*1024;
+
+pub static DRIVER:NvmeDriver = NvmeDriver;
+static NEXT_INDEX:AtomicU32 = AtomicU32::new(0);
+
+pub struct NvmeDriver;
+
+static MATCHES:[PciMatch;1] = [PciMatch::class_prog_if(pci::CLASS_MASS_STORAGE, 0x08, 0x02)];
+
+impl PciDriver for NvmeDriver{
+	fn name(&self)->&'static str{
+		"nvme"
+	}
+
+	fn matches(&self)->&'static [PciMatch]{
+		&MATCHES
+	}
+
+	fn probe(&self, dev:&Arc<PciDevice>)->bool{
+		let controller = match Controller::new(dev){
+			Some(c)=>Arc::new(c),
+			None=>{
+				log::error!("Couldn't set up NVMe controller {}", dev.address);
+				return false;
+			},
+		};
+		for nsid in controller.active_namespaces(){
+			if let Some(ns) = Namespace::new(&controller, nsid){
+				crate::block::register(Arc::new(ns));
+			}
+		}
+		true
+	}
+}
+
+static QUEUES_CLASS:LockClass = LockClass::new("nvme::queues");
+///The queue pair, that each interrupt vector belongs to.
+static IRQ_QUEUES:RWLock<Vec<(u8, Arc<QueuePair>)>> = RWLock::with_class(Vec::new(), RWLockMode::ReaderPreferring, &QUEUES_CLASS);
+
+fn irq(frame:&mut InterruptFrame){
+	let vector = frame.vector as u8;
+	let queues = IRQ_QUEUES.read_lock();
+	for (_, q) in queues.iter().filter(|(v, _)|*v == vector){
+		q.handle_interrupt();
+	}
+}
+
+///Waits up to `timeout` for `cond`.
+fn poll(timeout:Duration, mut cond:impl FnMut()->bool)->bool{
+	let mut deadline = crate::time::Deadline::after(timeout);
+	while !cond(){
+		if deadline.expired(){
+			return false;
+		}
+		crate::time::busy_wait(Duration::from_micros(100));
+	}
+	true
+}
+
+pub struct Controller{
+	index:u32,
+	///Virtual address of the registers.
+	base:usize,
+	admin:Arc<QueuePair>,
+	io:Vec<Arc<QueuePair>>,
+	///The I/O queue pair of every cpu.
+	cpu_queue:[u8;MAX_CPUS],
+	_irqs:Option<msi::MsiInterrupts>,
+	///The largest transfer, that the controller accepts, in bytes.
+	max_transfer:usize,
+	volatile_write_cache:bool,
+}
+
+impl Controller{
+	fn new(dev:&Arc<PciDevice>)->Option<Self>{
+		let base = match dev.bar(0)?{
+			Bar::Memory{address, ..}=>crate::x86_64::mem::phys_to_virt(PhysAddr::new(address)).as_u64() as usize,
+			Bar::Io{..}=>return None,
+		};
+		dev.enable_memory_and_bus_master();
+		let read32 = |reg:usize|{
+			//Safety:
+			//The NVMe registers are in BAR0, and naturally aligned.
+			unsafe{core::ptr::read_volatile((base+reg) as *const u32)}
+		};
+		let write32 = |reg:usize, value:u32|{
+			//Safety:
+			//See read32.
+			unsafe{core::ptr::write_volatile((base+reg) as *mut u32, value)}
+		};
+		let write64 = |reg:usize, value:u64|{
+			write32(reg, value as u32);
+			write32(reg+4, (value>>32) as u32);
+		};
+		let cap = read32(REG_CAP) as u64 | (read32(REG_CAP+4) as u64)<<32;
+		let version = read32(REG_VS);
+		let max_entries = ((cap & 0xFFFF)+1).min(queue::MAX_ENTRIES as u64) as u16;
+		let doorbell_stride = 4<<(cap>>32 & 0xF);
+		let timeout = Duration::from_millis(500*(cap>>24 & 0xFF).max(1));
+		if cap & CAP_CSS_NVM == 0{
+			log::warn!("NVMe controller {} doesn't support the NVM command set", dev.address);
+			return None;
+		}
+		//The minimum page size is 2^(12+MPSMIN).
+		if cap>>48 & 0xF != 0{
+			log::warn!("NVMe controller {} doesn't support 4KiB pages", dev.address);
+			return None;
+		}
+		let doorbell = |queue:u16, completion:bool|base+DOORBELL_BASE+(2*queue as usize+completion as usize)*doorbell_stride;
+
+		if read32(REG_CC) & CC_ENABLE != 0{
+			write32(REG_CC, read32(REG_CC) & !CC_ENABLE);
+		}
+		if !poll(timeout, ||read32(REG_CSTS) & CSTS_READY == 0){
+			log::error!("NVMe controller {} doesn't reset", dev.address);
+			return None;
+		}
+
+		//Interrupt 0 is for the admin queue, the others for the I/O queues.
+		let cpus = cpu_ids();
+		let handlers:Vec<Handler> = alloc::vec![irq as Handler;cpus.len()+1];
+		let irqs = msi::allocate(dev, &handlers, cpu::id()).or_else(||msi::allocate(dev, &handlers[..2], cpu::id()));
+		let polling = irqs.is_none();
+		let admin = Arc::new(QueuePair::new(0, max_entries, doorbell(0, false), doorbell(0, true), polling)?);
+		let (asq, acq) = admin.addresses();
+		write32(REG_AQA, (max_entries as u32-1)<<16 | (max_entries as u32-1));
+		write64(REG_ASQ, asq);
+		write64(REG_ACQ, acq);
+		if let Some(irqs) = &irqs{
+			attach_irq(irqs.vector(0), admin.clone());
+		}
+		//Once enabled, the controller owns the queues, until it is disabled again. So every failure from here on
+		//disables it, before the queues are freed.
+		let disable = |queues:Vec<Arc<QueuePair>>|->Option<Self>{
+			write32(REG_CC, read32(REG_CC) & !CC_ENABLE);
+			let stopped = poll(timeout, ||read32(REG_CSTS) & CSTS_READY == 0);
+			detach_irqs(&queues);
+			if !stopped{
+				log::error!("NVMe controller {} doesn't stop, leaking its queues", dev.address);
+				core::mem::forget(queues);
+			}
+			None
+		};
+		write32(REG_CC, 6<<CC_IOSQES_SHIFT | 4<<CC_IOCQES_SHIFT | CC_ENABLE);
+		if !poll(timeout, ||read32(REG_CSTS) & (CSTS_READY|CSTS_FATAL) != 0) || read32(REG_CSTS) & CSTS_FATAL != 0{
+			log::error!("NVMe controller {} doesn't get ready", dev.address);
+			return disable(alloc::vec![admin]);
+		}
+
+		let identify = match DmaBuffer::new(PAGE_SIZE){
+			Some(i)=>i,
+			None=>return disable(alloc::vec![admin]),
+		};
+		let c = admin.run(&Command{
+			opcode:ADMIN_IDENTIFY,
+			prp1:identify.phys(),
+			cdw:[IDENTIFY_CONTROLLER, 0, 0, 0, 0, 0],
+			..Command::default()
+		});
+		if !c.is_ok(){
+			log::error!("NVMe controller {}: identify failed with {:x?}", dev.address, c.code());
+			return disable(alloc::vec![admin]);
+		}
+		let data = identify.as_slice();
+		let serial = ascii(&data[4..24]);
+		let model = ascii(&data[24..64]);
+		let firmware = ascii(&data[64..72]);
+		let mdts = data[77];
+		let volatile_write_cache = data[525] & 1 != 0;
+		let max_transfer = match mdts{
+			0=>MAX_CHUNK,
+			m=>(PAGE_SIZE<<m.min(20)).min(MAX_CHUNK),
+		};
+
+		//The controller answers with the amount of queues, that it allocated, which might be less.
+		let wanted = match &irqs{
+			Some(i) if i.len() > cpus.len()=>cpus.len(),
+			_=>1,
+		} as u32;
+		let c = admin.run(&Command{
+			opcode:ADMIN_SET_FEATURES,
+			cdw:[FEATURE_NUMBER_OF_QUEUES, (wanted-1)<<16 | (wanted-1), 0, 0, 0, 0],
+			..Command::default()
+		});
+		if !c.is_ok(){
+			log::error!("NVMe controller {}: setting the number of queues failed with {:x?}", dev.address, c.code());
+			return disable(alloc::vec![admin]);
+		}
+		let granted = ((c.result & 0xFFFF).min(c.result>>16)+1).min(wanted) as u16;
+
+		let mut io = Vec::new();
+		let mut cpu_queue = [0;MAX_CPUS];
+		for (i, cpu) in cpus.iter().enumerate(){
+			cpu_queue[*cpu] = (i%granted as usize) as u8;
+		}
+		for qid in 1..=granted{
+			//Queues share the last vector, if there aren't enough.
+			let vector_index = match irqs.as_ref().map_or(0, |i|i.len()){
+				0=>0,
+				n=>(qid as usize).min(n-1),
+			};
+			let queue = match QueuePair::new(qid, max_entries, doorbell(qid, false), doorbell(qid, true), polling){
+				Some(q)=>Arc::new(q),
+				None=>break,
+			};
+			let (sq, cq) = queue.addresses();
+			let c = admin.run(&Command{
+				opcode:ADMIN_CREATE_CQ,
+				prp1:cq,
+				cdw:[
+					(max_entries as u32-1)<<16 | qid as u32,
+					(vector_index as u32)<<16 | if polling {0} else {CQ_INTERRUPTS_ENABLED} | QUEUE_PHYSICALLY_CONTIGUOUS,
+					0, 0, 0, 0,
+				],
+				..Command::default()
+			});
+			if !c.is_ok(){
+				log::error!("NVMe controller {}: creating completion queue {} failed with {:x?}", dev.address, qid, c.code());
+				break;
+			}
+			let c = admin.run(&Command{
+				opcode:ADMIN_CREATE_SQ,
+				prp1:sq,
+				cdw:[(max_entries as u32-1)<<16 | qid as u32, (qid as u32)<<16 | QUEUE_PHYSICALLY_CONTIGUOUS, 0, 0, 0, 0],
+				..Command::default()
+			});
+			if !c.is_ok(){
+				log::error!("NVMe controller {}: creating submission queue {} failed with {:x?}", dev.address, qid, c.code());
+				let c = admin.run(&Command{
+					opcode:ADMIN_DELETE_CQ,
+					cdw:[qid as u32, 0, 0, 0, 0, 0],
+					..Command::default()
+				});
+				if !c.is_ok(){
+					//The controller still owns the completion queue, so its memory can't be reused.
+					log::error!("NVMe controller {}: deleting completion queue {} failed with {:x?}", dev.address, qid, c.code());
+					core::mem::forget(queue);
+				}
+				break;
+			}
+			if let Some(irqs) = &irqs{
+				attach_irq(irqs.vector(vector_index), queue.clone());
+				//If every cpu has its own queue, the interrupt goes to that cpu. This needs MSI-X.
+				if let (msi::Mode::MsiX{..}, true) = (irqs.mode(), granted as usize == cpus.len()){
+					irqs.target(vector_index, cpus[qid as usize-1]);
+				}
+			}
+			io.push(queue);
+		}
+		if io.is_empty(){
+			log::error!("NVMe controller {} has no I/O queues", dev.address);
+			return disable(alloc::vec![admin]);
+		}
+		let index = NEXT_INDEX.fetch_add(1, Ordering::Relaxed);
+		log::info!("NVMe {}.{} controller {} is nvme{}: {} (serial {}, firmware {}), {} I/O queues{}",
+			version>>16, (version>>8)&0xFF, dev.address, index, model, serial, firmware, io.len(),
+			if polling {", polling"} else {""});
+		Some(Self{
+			index,
+			base,
+			admin,
+			io,
+			cpu_queue,
+			_irqs:irqs,
+			max_transfer,
+			volatile_write_cache,
+		})
+	}
+
+	pub fn index(&self)->u32{
+		self.index
+	}
+
+	///The I/O queue pair for the current cpu.
+	pub fn io_queue(&self)->&QueuePair{
+		let i = self.cpu_queue[cpu::id()] as usize;
+		&self.io[i.min(self.io.len()-1)]
+	}
+
+	pub fn admin_queue(&self)->&QueuePair{
+		&self.admin
+	}
+
+	pub fn max_transfer(&self)->usize{
+		self.max_transfer
+	}
+
+	pub fn has_volatile_write_cache(&self)->bool{
+		self.volatile_write_cache
+	}
+
+	///Returns true, if the controller hit a fatal error.
+	pub fn is_failed(&self)->bool{
+		//Safety:
+		//CSTS is a register in BAR0.
+		let status = unsafe{core::ptr::read_volatile((self.base+REG_CSTS) as *const u32)};
+		status & CSTS_FATAL != 0
+	}
+
+	///Reads the ids of all active namespaces.
+	fn active_namespaces(&self)->Vec<u32>{
+		let mut ids = Vec::new();
+		let list = match DmaBuffer::new(PAGE_SIZE){
+			Some(l)=>l,
+			None=>return ids,
+		};
+		//The list holds the first 1024 active ids after the given one.
+		let c = self.admin.run(&Command{
+			opcode:ADMIN_IDENTIFY,
+			prp1:list.phys(),
+			cdw:[IDENTIFY_ACTIVE_NAMESPACES, 0, 0, 0, 0, 0],
+			..Command::default()
+		});
+		if !c.is_ok(){
+			log::error!("nvme{}: reading the namespace list failed with {:x?}", self.index, c.code());
+			return ids;
+		}
+		for i in 0..PAGE_SIZE/4{
+			match list.read::<u32>(4*i){
+				0=>break,
+				id=>ids.push(id),
+			}
+		}
+		ids
+	}
+
+	///Reads the identify data of a namespace.
+	fn identify_namespace(&self, nsid:u32)->Option<DmaBuffer>{
+		let data = DmaBuffer::new(PAGE_SIZE)?;
+		let c = self.admin.run(&Command{
+			opcode:ADMIN_IDENTIFY,
+			nsid,
+			prp1:data.phys(),
+			cdw:[IDENTIFY_NAMESPACE, 0, 0, 0, 0, 0],
+			..Command::default()
+		});
+		if !c.is_ok(){
+			log::error!("nvme{}: identifying namespace {} failed with {:x?}", self.index, nsid, c.code());
+			return None;
+		}
+		Some(data)
+	}
+}
+
+fn attach_irq(vector:u8, queue:Arc<QueuePair>){
+	interrupts::without_interrupts(||IRQ_QUEUES.write_lock().push((vector, queue)));
+}
+
+fn detach_irqs(queues:&[Arc<QueuePair>]){
+	interrupts::without_interrupts(||IRQ_QUEUES.write_lock().retain(|(_, q)|!queues.iter().any(|d|Arc::ptr_eq(q, d))));
+}
+
+///The indices of all usable cpus. cpu::register_madt gave every one from the MADT an index.
+fn cpu_ids()->Vec<usize>{
+	(0..cpu::count()).collect()
+}
+
+fn ascii(bytes:&[u8])->String{
+	let s:String = bytes.iter().map(|b|if b.is_ascii_graphic() || *b == b' ' {*b as char} else {'?'}).collect();
+	String::from(s.trim())
+}
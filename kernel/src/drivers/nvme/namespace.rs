@@ -0,0 +1,165 @@
+//NVMe namespaces as block devices.
+//Data moves straight between the caller's buffer and the controller. PRP lists describe the pages of the buffer.
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use x86_64::VirtAddr;
+use crate::block::{self, BlockDevice, BlockError};
+use crate::x86_64::dma::DmaBuffer;
+use crate::x86_64::mem::{virt_to_phys, PAGE_SIZE};
+use super::{Command, Controller};
+
+const IO_FLUSH:u8 = 0x00;
+const IO_WRITE:u8 = 0x01;
+const IO_READ:u8 = 0x02;
+
+///Set in FLBAS, if the metadata is transferred at the end of every block.
+const FLBAS_EXTENDED_METADATA:u8 = 1<<4;
+
+pub struct Namespace{
+	controller:Arc<Controller>,
+	nsid:u32,
+	name:String,
+	block_size:usize,
+	blocks:u64,
+}
+
+///The data pointer of a command. The PRP list has to live, until the command completes.
+struct Prps{
+	prp1:u64,
+	prp2:u64,
+	_list:Option<DmaBuffer>,
+}
+
+impl Namespace{
+	pub(super) fn new(controller:&Arc<Controller>, nsid:u32)->Option<Self>{
+		let data = controller.identify_namespace(nsid)?;
+		let blocks = data.read::<u64>(0);
+		let lba_formats = data.read::<u8>(25) as usize+1;
+		let flbas = data.read::<u8>(26);
+		//Bits 5 and 6 extend the format index beyond 16 formats.
+		let format = (flbas & 0xF) as usize | if lba_formats > 16 {((flbas>>5 & 0x3) as usize)<<4} else {0};
+		let lbaf = data.read::<u32>(128+4*format);
+		let metadata = lbaf & 0xFFFF;
+		let block_size = 1usize<<(lbaf>>16 & 0xFF);
+		let name = format!("nvme{}n{}", controller.index(), nsid);
+		if blocks == 0{
+			return None;
+		}
+		if !(512..=PAGE_SIZE).contains(&block_size) || (metadata != 0 && flbas & FLBAS_EXTENDED_METADATA != 0){
+			log::warn!("{}: unsupported format with {} byte blocks and {} bytes metadata", name, block_size, metadata);
+			return None;
+		}
+		Some(Self{controller:controller.clone(), nsid, name, block_size, blocks})
+	}
+
+	pub fn nsid(&self)->u32{
+		self.nsid
+	}
+
+	///Builds the PRP entries for a buffer of at most MAX_CHUNK bytes.
+	fn prps(&self, ptr:*const u8, len:usize)->Result<Prps, BlockError>{
+		let translate = |addr:usize|virt_to_phys(VirtAddr::new(addr as u64)).map(|p|p.as_u64()).ok_or(BlockError::Io);
+		let addr = ptr as usize;
+		if !addr.is_multiple_of(4){
+			log::error!("{}: DMA buffers have to be 4 byte aligned", self.name);
+			return Err(BlockError::Io);
+		}
+		let prp1 = translate(addr)?;
+		//Every entry after the first one points to the start of a page.
+		let first_page_end = (addr/PAGE_SIZE+1)*PAGE_SIZE;
+		let pages = (first_page_end..addr+len).step_by(PAGE_SIZE).map(translate).collect::<Result<Vec<_>, _>>()?;
+		match pages.len(){
+			0=>Ok(Prps{prp1, prp2:0, _list:None}),
+			1=>Ok(Prps{prp1, prp2:pages[0], _list:None}),
+			n=>{
+				let list = DmaBuffer::new(n*8).ok_or(BlockError::NoMemory)?;
+				for (i, page) in pages.iter().enumerate(){
+					list.write::<u64>(8*i, *page);
+				}
+				Ok(Prps{prp1, prp2:list.phys(), _list:Some(list)})
+			},
+		}
+	}
+
+	fn transfer(&self, opcode:u8, lba:u64, ptr:*const u8, len:usize)->Result<(), BlockError>{
+		block::check_request(self, lba, len)?;
+		let queue = self.controller.io_queue();
+		let max = self.controller.max_transfer();
+		let chunk = (max-max%self.block_size).max(self.block_size);
+		let mut pending = Vec::new();
+		let mut result = Ok(());
+		let mut offset = 0;
+		while offset < len{
+			let size = chunk.min(len-offset);
+			let start = lba+(offset/self.block_size) as u64;
+			let count = (size/self.block_size) as u32;
+			//Safety:
+			//offset+size is inside the buffer.
+			let data = unsafe{ptr.add(offset)};
+			let prps = match self.prps(data, size){
+				Ok(p)=>p,
+				Err(e)=>{
+					result = Err(e);
+					break;
+				},
+			};
+			let id = queue.submit(&Command{
+				opcode,
+				nsid:self.nsid,
+				prp1:prps.prp1,
+				prp2:prps.prp2,
+				cdw:[start as u32, (start>>32) as u32, count-1, 0, 0, 0],
+			});
+			pending.push((id, prps));
+			offset += size;
+		}
+		//Even after an error, the submitted commands have to finish, before the buffer can be given back.
+		for (id, _prps) in pending{
+			let c = queue.wait_for(id);
+			if !c.is_ok() && result.is_ok(){
+				log::error!("{}: I/O failed with {:x?}", self.name, c.code());
+				result = Err(BlockError::Io);
+			}
+		}
+		if result.is_err() && self.controller.is_failed(){
+			log::error!("{}: the controller failed", self.name);
+		}
+		result
+	}
+}
+
+impl BlockDevice for Namespace{
+	fn name(&self)->&str{
+		&self.name
+	}
+
+	fn block_size(&self)->usize{
+		self.block_size
+	}
+
+	fn block_count(&self)->u64{
+		self.blocks
+	}
+
+	fn read_blocks(&self, lba:u64, buf:&mut [u8])->Result<(), BlockError>{
+		self.transfer(IO_READ, lba, buf.as_mut_ptr(), buf.len())
+	}
+
+	fn write_blocks(&self, lba:u64, buf:&[u8])->Result<(), BlockError>{
+		self.transfer(IO_WRITE, lba, buf.as_ptr(), buf.len())
+	}
+
+	fn flush(&self)->Result<(), BlockError>{
+		if !self.controller.has_volatile_write_cache(){
+			return Ok(());
+		}
+		let c = self.controller.io_queue().run(&Command{opcode:IO_FLUSH, nsid:self.nsid, ..Command::default()});
+		if !c.is_ok(){
+			log::error!("{}: flush failed with {:x?}", self.name, c.code());
+			return Err(BlockError::Io);
+		}
+		Ok(())
+	}
+}
@@ -0,0 +1,244 @@
+//Submission and completion queue pairs.
+//Each queue pair gets its own completion queue, so the pairs never share state.
+//A command id goes back to the free set, as soon as its completion arrives. The completion waits in `results`,
+//until the submitter picks it up, under an id, that is never reused.
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::sync::atomic::{fence, Ordering};
+use core::time::Duration;
+use x86_64::instructions::interrupts;
+use crate::lock::{Lock, LockClass};
+use crate::sync::WaitQueue;
+use crate::x86_64::dma::DmaBuffer;
+
+pub const SQ_ENTRY_SIZE:usize = 64;
+pub const CQ_ENTRY_SIZE:usize = 16;
+///A submission queue of this size fills exactly one page.
+pub const MAX_ENTRIES:u16 = 64;
+
+///Commands, that take longer than this, get a warning.
+const SLOW_COMMAND:Duration = Duration::from_secs(30);
+
+///A submission queue entry.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct Command{
+	pub opcode:u8,
+	pub nsid:u32,
+	pub prp1:u64,
+	pub prp2:u64,
+	///Command dwords 10 to 15.
+	pub cdw:[u32;6],
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct Completion{
+	///Dword 0 of the completion entry. Its meaning depends on the command.
+	pub result:u32,
+	///The status field without the phase bit.
+	pub status:u16,
+}
+
+impl Completion{
+	pub fn is_ok(&self)->bool{
+		self.status & 0x7FF == 0
+	}
+
+	///The status code type and status code.
+	pub fn code(&self)->(u8, u8){
+		((self.status>>8 & 0x7) as u8, self.status as u8)
+	}
+}
+
+struct State{
+	sq_tail:u16,
+	cq_head:u16,
+	///The phase bit, that new completion entries have.
+	phase:bool,
+	///The request id of every command id in flight.
+	cids:Vec<Option<u64>>,
+	next_id:u64,
+	results:BTreeMap<u64, Completion>,
+}
+
+pub struct QueuePair{
+	id:u16,
+	size:u16,
+	sq:DmaBuffer,
+	cq:DmaBuffer,
+	///Virtual addresses of the doorbell registers.
+	sq_doorbell:usize,
+	cq_doorbell:usize,
+	polling:bool,
+	state:Lock<State>,
+	waiters:WaitQueue,
+}
+
+static QUEUE_CLASS:LockClass = LockClass::new("nvme::queue");
+
+impl QueuePair{
+	pub fn new(id:u16, size:u16, sq_doorbell:usize, cq_doorbell:usize, polling:bool)->Option<Self>{
+		if !(2..=MAX_ENTRIES).contains(&size){
+			return None;
+		}
+		Some(Self{
+			id,
+			size,
+			sq:DmaBuffer::new(SQ_ENTRY_SIZE*size as usize)?,
+			cq:DmaBuffer::new(CQ_ENTRY_SIZE*size as usize)?,
+			sq_doorbell,
+			cq_doorbell,
+			polling,
+			state:Lock::with_class(State{
+				sq_tail:0,
+				cq_head:0,
+				phase:true,
+				cids:alloc::vec![None;size as usize],
+				next_id:0,
+				results:BTreeMap::new(),
+			}, &QUEUE_CLASS),
+			waiters:WaitQueue::new(),
+		})
+	}
+
+	pub fn id(&self)->u16{
+		self.id
+	}
+
+	pub fn size(&self)->u16{
+		self.size
+	}
+
+	///Physical addresses of the submission and completion queue.
+	pub fn addresses(&self)->(u64, u64){
+		(self.sq.phys(), self.cq.phys())
+	}
+
+	///Hands the command to the controller. Waits, if the queue is full.
+	///Returns an id for wait_for.
+	pub fn submit(&self, command:&Command)->u64{
+		let mut id = 0;
+		self.wait(|state|{
+			//One entry stays empty, so a full queue can be told from an empty one.
+			let in_flight = state.cids.iter().filter(|c|c.is_some()).count();
+			if in_flight+1 >= self.size as usize{
+				return false;
+			}
+			let cid = match state.cids.iter().position(|c|c.is_none()){
+				Some(c)=>c,
+				None=>return false,
+			};
+			id = state.next_id;
+			state.next_id += 1;
+			state.cids[cid] = Some(id);
+			let entry = state.sq_tail as usize*SQ_ENTRY_SIZE;
+			self.sq.write::<u32>(entry, command.opcode as u32 | (cid as u32)<<16);
+			self.sq.write::<u32>(entry+4, command.nsid);
+			self.sq.write::<u64>(entry+8, 0);
+			self.sq.write::<u64>(entry+16, 0);
+			self.sq.write::<u64>(entry+24, command.prp1);
+			self.sq.write::<u64>(entry+32, command.prp2);
+			for (i, dw) in command.cdw.iter().enumerate(){
+				self.sq.write::<u32>(entry+40+4*i, *dw);
+			}
+			state.sq_tail = (state.sq_tail+1)%self.size;
+			//The entry has to be visible, before the controller learns about it.
+			fence(Ordering::SeqCst);
+			//Safety:
+			//sq_doorbell is the doorbell register of this queue.
+			unsafe{core::ptr::write_volatile(self.sq_doorbell as *mut u32, state.sq_tail as u32)};
+			true
+		});
+		id
+	}
+
+	///Waits for the command to complete.
+	pub fn wait_for(&self, id:u64)->Completion{
+		let mut completion = None;
+		loop{
+			let done = self.wait_timeout(|state|{
+				completion = state.results.remove(&id);
+				completion.is_some()
+			}, SLOW_COMMAND);
+			if let (true, Some(c)) = (done, completion){
+				return c;
+			}
+			log::warn!("NVMe command {} on queue {} takes longer than {:?}", id, self.id, SLOW_COMMAND);
+		}
+	}
+
+	///Runs a command and waits for it.
+	pub fn run(&self, command:&Command)->Completion{
+		let id = self.submit(command);
+		self.wait_for(id)
+	}
+
+	fn wait<F:FnMut(&mut State)->bool>(&self, mut f:F){
+		while !self.wait_timeout(&mut f, SLOW_COMMAND){}
+	}
+
+	///Blocks, until `f` returns true. `f` runs with the queue locked.
+	///Returns false on timeout.
+	fn wait_timeout<F:FnMut(&mut State)->bool>(&self, mut f:F, timeout:Duration)->bool{
+		if !self.polling{
+			return self.waiters.wait_until_timeout(||f(&mut self.state.lock()), timeout);
+		}
+		let mut deadline = crate::time::Deadline::after(timeout);
+		loop{
+			let done = interrupts::without_interrupts(||{
+				let mut state = self.state.lock();
+				self.collect(&mut state);
+				f(&mut state)
+			});
+			if done{
+				return true;
+			}
+			if deadline.expired(){
+				return false;
+			}
+			crate::sched::yield_now();
+		}
+	}
+
+	///Takes new entries from the completion queue.
+	///Returns true, if there were any.
+	fn collect(&self, state:&mut State)->bool{
+		let mut any = false;
+		loop{
+			let entry = state.cq_head as usize*CQ_ENTRY_SIZE;
+			let status = self.cq.read::<u16>(entry+14);
+			if (status & 1 != 0) != state.phase{
+				break;
+			}
+			//The rest of the entry is only valid after the phase bit.
+			fence(Ordering::Acquire);
+			let result = self.cq.read::<u32>(entry);
+			let cid = self.cq.read::<u16>(entry+12) as usize;
+			state.cq_head += 1;
+			if state.cq_head == self.size{
+				state.cq_head = 0;
+				state.phase = !state.phase;
+			}
+			match state.cids.get_mut(cid).and_then(|c|c.take()){
+				Some(id)=>{
+					state.results.insert(id, Completion{result, status:status>>1});
+				},
+				None=>log::warn!("NVMe queue {} completed command {}, which wasn't in flight", self.id, cid),
+			}
+			any = true;
+		}
+		if any{
+			//Safety:
+			//cq_doorbell is the doorbell register of this queue.
+			unsafe{core::ptr::write_volatile(self.cq_doorbell as *mut u32, state.cq_head as u32)};
+		}
+		any
+	}
+
+	///Called from the interrupt handler of the queue.
+	pub fn handle_interrupt(&self){
+		let any = self.collect(&mut self.state.lock());
+		if any{
+			self.waiters.wake_all();
+		}
+	}
+}
@@ -0,0 +1,401 @@
+//PCI bus enumeration.
+//All buses behind the host bridges are scanned once at boot, following PCI-to-PCI bridges.
+//The bus numbers, BARs and bridge windows are used as the firmware set them up.
+//Drivers register a PciDriver and get probed for every matching device.
+pub mod capability;
+pub mod config;
+pub mod msi;
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt;
+use x86_64::instructions::interrupts;
+use crate::lock::{Lock, LockClass, RWLock, RWLockMode};
+use self::capability::Capability;
+
+pub const REG_VENDOR_ID:u16 = 0x00;
+pub const REG_DEVICE_ID:u16 = 0x02;
+pub const REG_COMMAND:u16 = 0x04;
+pub const REG_STATUS:u16 = 0x06;
+pub const REG_REVISION:u16 = 0x08;
+pub const REG_PROG_IF:u16 = 0x09;
+pub const REG_SUBCLASS:u16 = 0x0A;
+pub const REG_CLASS:u16 = 0x0B;
+pub const REG_HEADER_TYPE:u16 = 0x0E;
+pub const REG_BAR0:u16 = 0x10;
+pub const REG_SUBSYSTEM_VENDOR_ID:u16 = 0x2C;
+pub const REG_SUBSYSTEM_ID:u16 = 0x2E;
+pub const REG_INTERRUPT_LINE:u16 = 0x3C;
+pub const REG_INTERRUPT_PIN:u16 = 0x3D;
+///Bridge headers only.
+const REG_SECONDARY_BUS:u16 = 0x19;
+
+pub const COMMAND_IO:u16 = 1<<0;
+pub const COMMAND_MEMORY:u16 = 1<<1;
+pub const COMMAND_BUS_MASTER:u16 = 1<<2;
+pub const COMMAND_INTX_DISABLE:u16 = 1<<10;
+
+const HEADER_TYPE_MASK:u8 = 0x7F;
+const HEADER_MULTI_FUNCTION:u8 = 1<<7;
+const HEADER_GENERAL:u8 = 0x00;
+const HEADER_PCI_BRIDGE:u8 = 0x01;
+
+const BAR_IO:u32 = 1<<0;
+const BAR_TYPE_MASK:u32 = 0b110;
+const BAR_TYPE_64:u32 = 0b100;
+const BAR_PREFETCHABLE:u32 = 1<<3;
+
+pub const CLASS_MASS_STORAGE:u8 = 0x01;
+
+///The location of a function.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct PciAddress{
+	pub segment:u16,
+	pub bus:u8,
+	pub device:u8,
+	pub function:u8,
+}
+
+impl fmt::Display for PciAddress{
+	fn fmt(&self, f:&mut fmt::Formatter<'_>)->fmt::Result{
+		write!(f, "{:04x}:{:02x}:{:02x}.{}", self.segment, self.bus, self.device, self.function)
+	}
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Bar{
+	Memory{
+		///Physical address.
+		address:u64,
+		size:u64,
+		prefetchable:bool,
+		///The BAR uses the next BAR slot for the upper half of the address.
+		is_64bit:bool,
+	},
+	Io{
+		port:u32,
+		size:u32,
+	},
+}
+
+impl Bar{
+	pub fn size(&self)->u64{
+		match self{
+			Bar::Memory{size, ..}=>*size,
+			Bar::Io{size, ..}=>*size as u64,
+		}
+	}
+}
+
+///A PCI function, as found while scanning.
+pub struct PciDevice{
+	pub address:PciAddress,
+	pub vendor_id:u16,
+	pub device_id:u16,
+	pub subsystem_vendor_id:u16,
+	pub subsystem_id:u16,
+	pub class:u8,
+	pub subclass:u8,
+	pub prog_if:u8,
+	pub revision:u8,
+	pub header_type:u8,
+	///Empty slots, and the upper halves of 64 bit BARs, are None.
+	pub bars:[Option<Bar>;6],
+	pub capabilities:Vec<Capability>,
+	///The legacy interrupt pin: 0 for none, 1 to 4 for INTA# to INTD#.
+	pub interrupt_pin:u8,
+	///The name of the driver, that claimed this device.
+	driver:Lock<Option<&'static str>>,
+}
+
+static DRIVER_CLASS:LockClass = LockClass::new("pci::device::driver");
+
+impl PciDevice{
+	pub fn read32(&self, offset:u16)->u32{
+		config::read32(self.address, offset)
+	}
+
+	pub fn write32(&self, offset:u16, value:u32){
+		config::write32(self.address, offset, value)
+	}
+
+	pub fn read16(&self, offset:u16)->u16{
+		config::read16(self.address, offset)
+	}
+
+	pub fn write16(&self, offset:u16, value:u16){
+		config::write16(self.address, offset, value)
+	}
+
+	pub fn read8(&self, offset:u16)->u8{
+		config::read8(self.address, offset)
+	}
+
+	pub fn write8(&self, offset:u16, value:u8){
+		config::write8(self.address, offset, value)
+	}
+
+	pub fn command(&self)->u16{
+		self.read16(REG_COMMAND)
+	}
+
+	pub fn set_command(&self, command:u16){
+		//The status register shares the dword, and writing its bits back would clear them.
+		self.write32(REG_COMMAND, command as u32);
+	}
+
+	///Enables memory space decoding and bus mastering, which every DMA capable device needs.
+	pub fn enable_memory_and_bus_master(&self){
+		self.set_command(self.command() | COMMAND_MEMORY | COMMAND_BUS_MASTER);
+	}
+
+	pub fn bar(&self, index:usize)->Option<Bar>{
+		*self.bars.get(index)?
+	}
+
+	///Returns the first capability with the given id.
+	pub fn capability(&self, id:u8)->Option<&Capability>{
+		self.capabilities.iter().find(|c|c.id == id)
+	}
+
+	pub fn driver(&self)->Option<&'static str>{
+		interrupts::without_interrupts(||*self.driver.lock())
+	}
+
+	fn is_bridge(&self)->bool{
+		self.header_type == HEADER_PCI_BRIDGE
+	}
+}
+
+impl fmt::Display for PciDevice{
+	fn fmt(&self, f:&mut fmt::Formatter<'_>)->fmt::Result{
+		write!(f, "{} [{:04x}:{:04x}] class {:02x}.{:02x}.{:02x}",
+			self.address, self.vendor_id, self.device_id, self.class, self.subclass, self.prog_if)
+	}
+}
+
+///Selects devices for a driver.
+///Fields, that are None, match anything.
+#[derive(Debug, Copy, Clone)]
+pub struct PciMatch{
+	pub vendor_id:Option<u16>,
+	pub device_id:Option<u16>,
+	pub class:Option<u8>,
+	pub subclass:Option<u8>,
+	pub prog_if:Option<u8>,
+}
+
+impl PciMatch{
+	pub const fn device(vendor_id:u16, device_id:u16)->Self{
+		Self{vendor_id:Some(vendor_id), device_id:Some(device_id), class:None, subclass:None, prog_if:None}
+	}
+
+	pub const fn class(class:u8, subclass:u8)->Self{
+		Self{vendor_id:None, device_id:None, class:Some(class), subclass:Some(subclass), prog_if:None}
+	}
+
+	pub const fn class_prog_if(class:u8, subclass:u8, prog_if:u8)->Self{
+		Self{vendor_id:None, device_id:None, class:Some(class), subclass:Some(subclass), prog_if:Some(prog_if)}
+	}
+
+	pub fn matches(&self, dev:&PciDevice)->bool{
+		self.vendor_id.map(|v|v == dev.vendor_id).unwrap_or(true)
+			&& self.device_id.map(|d|d == dev.device_id).unwrap_or(true)
+			&& self.class.map(|c|c == dev.class).unwrap_or(true)
+			&& self.subclass.map(|s|s == dev.subclass).unwrap_or(true)
+			&& self.prog_if.map(|p|p == dev.prog_if).unwrap_or(true)
+	}
+}
+
+pub trait PciDriver:Sync{
+	fn name(&self)->&'static str;
+	///The devices, this driver might be able to handle.
+	fn matches(&self)->&'static [PciMatch];
+	///Sets up a matching device, that no other driver claimed yet.
+	///Returns true, if the driver claims the device.
+	fn probe(&self, dev:&Arc<PciDevice>)->bool;
+}
+
+static DEVICES_CLASS:LockClass = LockClass::new("pci::devices");
+static DEVICES:RWLock<Vec<Arc<PciDevice>>> = RWLock::with_class(Vec::new(), RWLockMode::ReaderPreferring, &DEVICES_CLASS);
+
+///Scans all PCI buses.
+///Needs acpi::init to have run.
+pub fn init(){
+	let roots = config::init();
+	let mut found = Vec::new();
+	for (segment, bus) in roots{
+		let mut scanned = [false;256];
+		scan_host(segment, bus, &mut scanned, &mut found);
+	}
+	for dev in &found{
+		log::info!("PCI {} {}", dev, class_name(dev.class, dev.subclass));
+		for (i, bar) in dev.bars.iter().enumerate(){
+			if let Some(bar) = bar{
+				log::debug!("  BAR{}: {:x?}", i, bar);
+			}
+		}
+	}
+	interrupts::without_interrupts(||*DEVICES.write_lock() = found);
+}
+
+///Scans the buses behind a host bridge.
+fn scan_host(segment:u16, bus:u8, scanned:&mut [bool;256], found:&mut Vec<Arc<PciDevice>>){
+	let host = PciAddress{segment, bus, device:0, function:0};
+	//A multi-function host bridge means, that there are several host controllers, each with its own root bus.
+	if bus != 0 || config::read16(host, REG_VENDOR_ID) == 0xFFFF || config::read8(host, REG_HEADER_TYPE) & HEADER_MULTI_FUNCTION == 0{
+		scan_bus(segment, bus, scanned, found);
+		return;
+	}
+	for function in 0..8{
+		let addr = PciAddress{function, ..host};
+		if config::read16(addr, REG_VENDOR_ID) != 0xFFFF{
+			scan_bus(segment, function, scanned, found);
+		}
+	}
+}
+
+fn scan_bus(segment:u16, bus:u8, scanned:&mut [bool;256], found:&mut Vec<Arc<PciDevice>>){
+	//Badly configured bridges could make us go in circles.
+	if core::mem::replace(&mut scanned[bus as usize], true){
+		return;
+	}
+	for device in 0..32{
+		let addr = PciAddress{segment, bus, device, function:0};
+		if config::read16(addr, REG_VENDOR_ID) == 0xFFFF{
+			continue;
+		}
+		let functions = if config::read8(addr, REG_HEADER_TYPE) & HEADER_MULTI_FUNCTION != 0 {8} else {1};
+		for function in 0..functions{
+			let addr = PciAddress{function, ..addr};
+			if config::read16(addr, REG_VENDOR_ID) == 0xFFFF{
+				continue;
+			}
+			let dev = Arc::new(read_device(addr));
+			let secondary = if dev.is_bridge() {Some(dev.read8(REG_SECONDARY_BUS))} else {None};
+			found.push(dev);
+			if let Some(secondary) = secondary{
+				if secondary > bus{
+					scan_bus(segment, secondary, scanned, found);
+				}
+			}
+		}
+	}
+}
+
+fn read_device(addr:PciAddress)->PciDevice{
+	let header_type = config::read8(addr, REG_HEADER_TYPE) & HEADER_TYPE_MASK;
+	let bar_count = match header_type{
+		HEADER_GENERAL=>6,
+		HEADER_PCI_BRIDGE=>2,
+		_=>0,
+	};
+	let mut dev = PciDevice{
+		address:addr,
+		vendor_id:config::read16(addr, REG_VENDOR_ID),
+		device_id:config::read16(addr, REG_DEVICE_ID),
+		subsystem_vendor_id:if header_type == HEADER_GENERAL {config::read16(addr, REG_SUBSYSTEM_VENDOR_ID)} else {0},
+		subsystem_id:if header_type == HEADER_GENERAL {config::read16(addr, REG_SUBSYSTEM_ID)} else {0},
+		class:config::read8(addr, REG_CLASS),
+		subclass:config::read8(addr, REG_SUBCLASS),
+		prog_if:config::read8(addr, REG_PROG_IF),
+		revision:config::read8(addr, REG_REVISION),
+		header_type,
+		bars:[None;6],
+		capabilities:capability::parse(addr),
+		interrupt_pin:config::read8(addr, REG_INTERRUPT_PIN),
+		driver:Lock::with_class(None, &DRIVER_CLASS),
+	};
+	read_bars(&mut dev, bar_count);
+	dev
+}
+
+///Decodes the BARs and finds their sizes, by writing all ones and seeing which address bits stick.
+fn read_bars(dev:&mut PciDevice, count:usize){
+	//The device mustn't decode the half written addresses, while we are probing.
+	let command = dev.command();
+	dev.set_command(command & !(COMMAND_IO|COMMAND_MEMORY));
+	let mut i = 0;
+	while i < count{
+		let reg = REG_BAR0+4*i as u16;
+		let value = dev.read32(reg);
+		dev.write32(reg, u32::MAX);
+		let mask = dev.read32(reg);
+		dev.write32(reg, value);
+		if value & BAR_IO != 0{
+			let size = (!(mask & !0x3)).wrapping_add(1) & 0xFFFF;
+			if mask != 0 && size != 0{
+				dev.bars[i] = Some(Bar::Io{port:value & !0x3, size});
+			}
+			i += 1;
+			continue;
+		}
+		let is_64bit = value & BAR_TYPE_MASK == BAR_TYPE_64 && i+1 < count;
+		let (address, mask) = if is_64bit{
+			let high_reg = reg+4;
+			let high = dev.read32(high_reg);
+			dev.write32(high_reg, u32::MAX);
+			let high_mask = dev.read32(high_reg);
+			dev.write32(high_reg, high);
+			((high as u64)<<32 | (value & !0xF) as u64, (high_mask as u64)<<32 | (mask & !0xF) as u64)
+		}else{
+			((value & !0xF) as u64, (mask & !0xF) as u64 | 0xFFFF_FFFF_0000_0000)
+		};
+		let size = (!mask).wrapping_add(1);
+		if mask & 0xFFFF_FFF0 != 0 && size != 0{
+			dev.bars[i] = Some(Bar::Memory{address, size, prefetchable:value & BAR_PREFETCHABLE != 0, is_64bit});
+		}
+		i += if is_64bit {2} else {1};
+	}
+	dev.set_command(command);
+}
+
+///Returns all devices found while scanning.
+pub fn devices()->Vec<Arc<PciDevice>>{
+	interrupts::without_interrupts(||DEVICES.read_lock().clone())
+}
+
+///Returns the device at the given address.
+pub fn device(address:PciAddress)->Option<Arc<PciDevice>>{
+	interrupts::without_interrupts(||DEVICES.read_lock().iter().find(|d|d.address == address).cloned())
+}
+
+///Probes the driver for all matching devices, that don't have a driver yet.
+///Needs pci::init to have run.
+pub fn register_driver(driver:&'static dyn PciDriver){
+	for dev in devices(){
+		if dev.driver().is_some() || !driver.matches().iter().any(|m|m.matches(&dev)){
+			continue;
+		}
+		if driver.probe(&dev){
+			log::info!("{} claimed PCI {}", driver.name(), dev.address);
+			interrupts::without_interrupts(||*dev.driver.lock() = Some(driver.name()));
+		}
+	}
+}
+
+///A human readable name for the common device classes.
+pub fn class_name(class:u8, subclass:u8)->&'static str{
+	match (class, subclass){
+		(0x01, 0x01)=>"IDE controller",
+		(0x01, 0x06)=>"SATA controller",
+		(0x01, 0x08)=>"NVM controller",
+		(0x01, 0x00)=>"SCSI controller",
+		(0x01, _)=>"Mass storage controller",
+		(0x02, 0x00)=>"Ethernet controller",
+		(0x02, _)=>"Network controller",
+		(0x03, _)=>"Display controller",
+		(0x04, _)=>"Multimedia controller",
+		(0x05, _)=>"Memory controller",
+		(0x06, 0x00)=>"Host bridge",
+		(0x06, 0x01)=>"ISA bridge",
+		(0x06, 0x04)=>"PCI bridge",
+		(0x06, _)=>"Bridge",
+		(0x07, _)=>"Communication controller",
+		(0x08, _)=>"System peripheral",
+		(0x0C, 0x03)=>"USB controller",
+		(0x0C, 0x05)=>"SMBus controller",
+		(0x0C, _)=>"Serial bus controller",
+		_=>"Unknown device",
+	}
+}
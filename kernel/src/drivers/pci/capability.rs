@@ -0,0 +1,123 @@
+//The capability list in the configuration space of a PCI function.
+use alloc::vec::Vec;
+use super::{config, PciAddress};
+
+pub const ID_POWER_MANAGEMENT:u8 = 0x01;
+pub const ID_MSI:u8 = 0x05;
+pub const ID_VENDOR:u8 = 0x09;
+pub const ID_PCI_EXPRESS:u8 = 0x10;
+pub const ID_MSIX:u8 = 0x11;
+
+const STATUS_CAPABILITIES:u16 = 1<<4;
+const REG_CAPABILITIES_POINTER:u16 = 0x34;
+///A list, that is longer than this, has to contain a loop.
+const MAX_CAPABILITIES:usize = 48;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum PcieDeviceType{
+	Endpoint,
+	LegacyEndpoint,
+	RootPort,
+	UpstreamPort,
+	DownstreamPort,
+	PcieToPciBridge,
+	PciToPcieBridge,
+	RootComplexEndpoint,
+	RootComplexEventCollector,
+	Unknown(u8),
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum CapabilityKind{
+	Msi{
+		///The function can use a 64 bit message address.
+		is_64bit:bool,
+		///The function has mask and pending bits for every vector.
+		per_vector_mask:bool,
+		///The amount of vectors, that the function can use.
+		max_vectors:u8,
+	},
+	MsiX{
+		///The amount of entries in the MSI-X table.
+		table_size:u16,
+		table_bar:u8,
+		table_offset:u32,
+		pba_bar:u8,
+		pba_offset:u32,
+	},
+	PciExpress{
+		device_type:PcieDeviceType,
+	},
+	PowerManagement,
+	Vendor,
+	Other(u8),
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Capability{
+	pub id:u8,
+	///Where the capability starts in the configuration space.
+	pub offset:u16,
+	pub kind:CapabilityKind,
+}
+
+///Reads the capability list of the function.
+pub(super) fn parse(addr:PciAddress)->Vec<Capability>{
+	let mut caps = Vec::new();
+	if config::read16(addr, super::REG_STATUS) & STATUS_CAPABILITIES == 0{
+		return caps;
+	}
+	let mut offset = (config::read8(addr, REG_CAPABILITIES_POINTER) & 0xFC) as u16;
+	while offset >= 0x40 && caps.len() < MAX_CAPABILITIES{
+		let header = config::read16(addr, offset);
+		let id = header as u8;
+		caps.push(Capability{id, offset, kind:decode(addr, id, offset)});
+		offset = (header>>8) & 0xFC;
+	}
+	caps
+}
+
+fn decode(addr:PciAddress, id:u8, offset:u16)->CapabilityKind{
+	match id{
+		ID_MSI=>{
+			let control = config::read16(addr, offset+2);
+			CapabilityKind::Msi{
+				is_64bit:control & (1<<7) != 0,
+				per_vector_mask:control & (1<<8) != 0,
+				max_vectors:1<<((control>>1)&0x7).min(5),
+			}
+		},
+		ID_MSIX=>{
+			let control = config::read16(addr, offset+2);
+			let table = config::read32(addr, offset+4);
+			let pba = config::read32(addr, offset+8);
+			CapabilityKind::MsiX{
+				table_size:(control & 0x7FF)+1,
+				table_bar:(table & 0x7) as u8,
+				table_offset:table & !0x7,
+				pba_bar:(pba & 0x7) as u8,
+				pba_offset:pba & !0x7,
+			}
+		},
+		ID_PCI_EXPRESS=>{
+			let flags = config::read16(addr, offset+2);
+			CapabilityKind::PciExpress{
+				device_type:match ((flags>>4)&0xF) as u8{
+					0x0=>PcieDeviceType::Endpoint,
+					0x1=>PcieDeviceType::LegacyEndpoint,
+					0x4=>PcieDeviceType::RootPort,
+					0x5=>PcieDeviceType::UpstreamPort,
+					0x6=>PcieDeviceType::DownstreamPort,
+					0x7=>PcieDeviceType::PcieToPciBridge,
+					0x8=>PcieDeviceType::PciToPcieBridge,
+					0x9=>PcieDeviceType::RootComplexEndpoint,
+					0xA=>PcieDeviceType::RootComplexEventCollector,
+					t=>PcieDeviceType::Unknown(t),
+				},
+			}
+		},
+		ID_POWER_MANAGEMENT=>CapabilityKind::PowerManagement,
+		ID_VENDOR=>CapabilityKind::Vendor,
+		other=>CapabilityKind::Other(other),
+	}
+}
@@ -0,0 +1,151 @@
+//Access to the configuration space of PCI functions.
+//ECAM (memory mapped, 4KiB per function) is used for everything the MCFG table covers.
+//Everything else in segment 0 goes through the legacy 0xCF8/0xCFC ports, which only reach the first 256 bytes.
+use alloc::vec::Vec;
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+use x86_64::PhysAddr;
+use crate::lock::{Lock, LockClass, RWLock, RWLockMode};
+use super::PciAddress;
+
+const CONFIG_ADDRESS:u16 = 0xCF8;
+const CONFIG_DATA:u16 = 0xCFC;
+const CONFIG_ENABLE:u32 = 1<<31;
+
+///The size of the configuration space with ECAM.
+pub const ECAM_CONFIG_SIZE:u16 = 4096;
+///The size of the configuration space with the legacy ports.
+pub const LEGACY_CONFIG_SIZE:u16 = 256;
+
+#[derive(Debug, Copy, Clone)]
+struct EcamRegion{
+	segment:u16,
+	start_bus:u8,
+	end_bus:u8,
+	///Virtual address of the configuration space of bus 0.
+	base:usize,
+}
+
+static ECAM_CLASS:LockClass = LockClass::new("pci::ecam");
+static LEGACY_CLASS:LockClass = LockClass::new("pci::legacy");
+static ECAM:RWLock<Vec<EcamRegion>> = RWLock::with_class(Vec::new(), RWLockMode::ReaderPreferring, &ECAM_CLASS);
+///Address and data port have to be used together.
+static LEGACY:Lock<()> = Lock::with_class((), &LEGACY_CLASS);
+
+///Reads the ECAM regions from the MCFG table.
+///Returns the (segment, first bus) of every region, which is where scanning starts.
+pub(super) fn init()->Vec<(u16, u8)>{
+	let mut regions = Vec::new();
+	for entry in crate::acpi::mcfg_entries(){
+		if entry.base == 0 || entry.start_bus > entry.end_bus{
+			continue;
+		}
+		let base = crate::x86_64::mem::phys_to_virt(PhysAddr::new(entry.base)).as_u64() as usize;
+		log::debug!("ECAM for segment {} buses {}..={} at {:#x}", entry.segment, entry.start_bus, entry.end_bus, entry.base);
+		regions.push(EcamRegion{segment:entry.segment, start_bus:entry.start_bus, end_bus:entry.end_bus, base});
+	}
+	let roots = if regions.is_empty(){
+		log::info!("No MCFG table. Using legacy PCI configuration access.");
+		alloc::vec![(0, 0)]
+	}else{
+		regions.iter().map(|r|(r.segment, r.start_bus)).collect()
+	};
+	interrupts::without_interrupts(||*ECAM.write_lock() = regions);
+	roots
+}
+
+fn ecam_address(addr:PciAddress, offset:u16)->Option<usize>{
+	interrupts::without_interrupts(||{
+		let ecam = ECAM.read_lock();
+		let region = ecam.iter().find(|r|r.segment == addr.segment && (r.start_bus..=r.end_bus).contains(&addr.bus))?;
+		Some(region.base
+			+ ((addr.bus as usize)<<20)
+			+ ((addr.device as usize)<<15)
+			+ ((addr.function as usize)<<12)
+			+ offset as usize)
+	})
+}
+
+///The size of the configuration space, that can be reached for this function.
+pub fn config_size(addr:PciAddress)->u16{
+	if ecam_address(addr, 0).is_some() {ECAM_CONFIG_SIZE} else {LEGACY_CONFIG_SIZE}
+}
+
+fn legacy_address(addr:PciAddress, offset:u16)->Option<u32>{
+	if addr.segment != 0 || offset >= LEGACY_CONFIG_SIZE{
+		return None;
+	}
+	Some(CONFIG_ENABLE
+		| (addr.bus as u32)<<16
+		| (addr.device as u32)<<11
+		| (addr.function as u32)<<8
+		| (offset as u32 & 0xFC))
+}
+
+///Reads the dword at `offset`, which has to be 4 byte aligned.
+///Returns all ones, if the function can't be reached, just like a missing device would.
+pub fn read32(addr:PciAddress, offset:u16)->u32{
+	debug_assert!(offset.is_multiple_of(4));
+	if let Some(p) = ecam_address(addr, offset){
+		//Safety:
+		//p is inside the ECAM region of this function, and aligned.
+		return unsafe{core::ptr::read_volatile(p as *const u32)};
+	}
+	match legacy_address(addr, offset){
+		Some(a)=>interrupts::without_interrupts(||{
+			let _guard = LEGACY.lock();
+			//Safety:
+			//These are the standard PCI configuration ports, and the lock keeps address and data together.
+			unsafe{
+				Port::<u32>::new(CONFIG_ADDRESS).write(a);
+				Port::<u32>::new(CONFIG_DATA).read()
+			}
+		}),
+		None=>u32::MAX,
+	}
+}
+
+///Writes the dword at `offset`, which has to be 4 byte aligned.
+pub fn write32(addr:PciAddress, offset:u16, value:u32){
+	debug_assert!(offset.is_multiple_of(4));
+	if let Some(p) = ecam_address(addr, offset){
+		//Safety:
+		//p is inside the ECAM region of this function, and aligned.
+		unsafe{core::ptr::write_volatile(p as *mut u32, value)};
+		return;
+	}
+	if let Some(a) = legacy_address(addr, offset){
+		interrupts::without_interrupts(||{
+			let _guard = LEGACY.lock();
+			//Safety:
+			//See read32.
+			unsafe{
+				Port::<u32>::new(CONFIG_ADDRESS).write(a);
+				Port::<u32>::new(CONFIG_DATA).write(value);
+			}
+		});
+	}
+}
+
+pub fn read16(addr:PciAddress, offset:u16)->u16{
+	(read32(addr, offset & !3)>>((offset & 2)*8)) as u16
+}
+
+pub fn read8(addr:PciAddress, offset:u16)->u8{
+	(read32(addr, offset & !3)>>((offset & 3)*8)) as u8
+}
+
+///Writes a word with a read-modify-write of the surrounding dword.
+///Careful with registers, that have write-1-to-clear bits next to it.
+pub fn write16(addr:PciAddress, offset:u16, value:u16){
+	let shift = (offset & 2)*8;
+	let old = read32(addr, offset & !3) & !(0xFFFF<<shift);
+	write32(addr, offset & !3, old | (value as u32)<<shift);
+}
+
+///Writes a byte with a read-modify-write of the surrounding dword.
+pub fn write8(addr:PciAddress, offset:u16, value:u8){
+	let shift = (offset & 3)*8;
+	let old = read32(addr, offset & !3) & !(0xFF<<shift);
+	write32(addr, offset & !3, old | (value as u32)<<shift);
+}
@@ -0,0 +1,262 @@
+//Message signaled interrupts for PCI devices.
+//MSI-X is preferred, since every vector gets its own address, data and mask.
+//Plain MSI needs a block of consecutive vectors, and all of them go to the same cpu.
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use x86_64::PhysAddr;
+use crate::x86_64::apic;
+use crate::x86_64::interrupts::{allocate_vector, allocate_vector_block, free_vector, Handler};
+use super::capability::{self, CapabilityKind};
+use super::{Bar, PciDevice, COMMAND_INTX_DISABLE};
+
+const MSI_CONTROL_ENABLE:u16 = 1<<0;
+const MSI_CONTROL_MULTIPLE_ENABLE_SHIFT:u16 = 4;
+const MSI_CONTROL_MULTIPLE_ENABLE_MASK:u16 = 0b111<<MSI_CONTROL_MULTIPLE_ENABLE_SHIFT;
+
+const MSIX_CONTROL_FUNCTION_MASK:u16 = 1<<14;
+const MSIX_CONTROL_ENABLE:u16 = 1<<15;
+const MSIX_ENTRY_SIZE:usize = 16;
+const MSIX_ENTRY_ADDRESS_LOW:usize = 0;
+const MSIX_ENTRY_ADDRESS_HIGH:usize = 4;
+const MSIX_ENTRY_DATA:usize = 8;
+const MSIX_ENTRY_CONTROL:usize = 12;
+const MSIX_ENTRY_MASKED:u32 = 1<<0;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Mode{
+	MsiX{
+		///Virtual address of the MSI-X table.
+		table:usize,
+	},
+	Msi{
+		is_64bit:bool,
+		per_vector_mask:bool,
+	},
+}
+
+///Interrupt vectors, that a device signals through MSI or MSI-X.
+///Dropping it disables the interrupts and frees the vectors.
+pub struct MsiInterrupts{
+	dev:Arc<PciDevice>,
+	///Where the MSI or MSI-X capability starts in the configuration space.
+	offset:u16,
+	mode:Mode,
+	vectors:Vec<u8>,
+}
+
+///Sets up an interrupt for every handler, all delivered to the given cpu.
+///Uses MSI-X, if the device has it, and MSI otherwise.
+///Also disables the legacy INTx interrupt of the device.
+///Returns None, if the device supports neither, or not enough vectors.
+pub fn allocate(dev:&Arc<PciDevice>, handlers:&[Handler], cpu:usize)->Option<MsiInterrupts>{
+	if handlers.is_empty(){
+		return None;
+	}
+	let irqs = allocate_msix(dev, handlers, cpu).or_else(||allocate_msi(dev, handlers, cpu))?;
+	dev.set_command(dev.command() | COMMAND_INTX_DISABLE);
+	Some(irqs)
+}
+
+fn allocate_msix(dev:&Arc<PciDevice>, handlers:&[Handler], cpu:usize)->Option<MsiInterrupts>{
+	let cap = dev.capability(capability::ID_MSIX)?;
+	let (table_size, table_bar, table_offset) = match cap.kind{
+		CapabilityKind::MsiX{table_size, table_bar, table_offset, ..}=>(table_size, table_bar, table_offset),
+		_=>return None,
+	};
+	if handlers.len() > table_size as usize{
+		return None;
+	}
+	let table = match dev.bar(table_bar as usize)?{
+		Bar::Memory{address, ..}=>crate::x86_64::mem::phys_to_virt(PhysAddr::new(address+table_offset as u64)).as_u64() as usize,
+		Bar::Io{..}=>return None,
+	};
+	let mut vectors = Vec::with_capacity(handlers.len());
+	for handler in handlers{
+		match allocate_vector(*handler){
+			Some(v)=>vectors.push(v),
+			None=>{
+				vectors.into_iter().for_each(free_vector);
+				return None;
+			}
+		}
+	}
+	let irqs = MsiInterrupts{dev:dev.clone(), offset:cap.offset, mode:Mode::MsiX{table}, vectors};
+	//The table is only accessible with MSI-X enabled. The function mask keeps everything quiet, until the entries are written.
+	let control_reg = cap.offset+2;
+	dev.enable_memory_and_bus_master();
+	dev.write16(control_reg, dev.read16(control_reg) | MSIX_CONTROL_ENABLE | MSIX_CONTROL_FUNCTION_MASK);
+	for entry in 0..table_size as usize{
+		irqs.write_msix(entry, MSIX_ENTRY_CONTROL, MSIX_ENTRY_MASKED);
+	}
+	for i in 0..irqs.vectors.len(){
+		irqs.target(i, cpu);
+		irqs.unmask(i);
+	}
+	dev.write16(control_reg, dev.read16(control_reg) & !MSIX_CONTROL_FUNCTION_MASK);
+	Some(irqs)
+}
+
+fn allocate_msi(dev:&Arc<PciDevice>, handlers:&[Handler], cpu:usize)->Option<MsiInterrupts>{
+	let cap = dev.capability(capability::ID_MSI)?;
+	let (is_64bit, per_vector_mask, max_vectors) = match cap.kind{
+		CapabilityKind::Msi{is_64bit, per_vector_mask, max_vectors}=>(is_64bit, per_vector_mask, max_vectors),
+		_=>return None,
+	};
+	if handlers.len() > max_vectors as usize{
+		return None;
+	}
+	//The device sends the base vector with the message number in the low bits, so the block has to be a power of two.
+	let count = handlers.len().next_power_of_two();
+	let mut padded = handlers.to_vec();
+	padded.resize(count, handlers[handlers.len()-1]);
+	let base = allocate_vector_block(&padded)?;
+	let irqs = MsiInterrupts{
+		dev:dev.clone(),
+		offset:cap.offset,
+		mode:Mode::Msi{is_64bit, per_vector_mask},
+		vectors:(base..base+count as u8).collect(),
+	};
+	irqs.target(0, cpu);
+	let control_reg = cap.offset+2;
+	let control = dev.read16(control_reg) & !MSI_CONTROL_MULTIPLE_ENABLE_MASK;
+	let multiple = (count.trailing_zeros() as u16)<<MSI_CONTROL_MULTIPLE_ENABLE_SHIFT;
+	dev.write16(control_reg, control | multiple | MSI_CONTROL_ENABLE);
+	for i in 0..count{
+		irqs.unmask(i);
+	}
+	Some(irqs)
+}
+
+impl MsiInterrupts{
+	pub fn mode(&self)->Mode{
+		self.mode
+	}
+
+	///The amount of vectors. With MSI, this can be more than requested, since it is rounded up to a power of two.
+	pub fn len(&self)->usize{
+		self.vectors.len()
+	}
+
+	pub fn is_empty(&self)->bool{
+		self.vectors.is_empty()
+	}
+
+	///The cpu vector of the interrupt with the given index.
+	pub fn vector(&self, index:usize)->u8{
+		self.vectors[index]
+	}
+
+	///The MSI-X table entry, or MSI message number, that raises `vector`.
+	pub fn index_of(&self, vector:u8)->Option<usize>{
+		self.vectors.iter().position(|v|*v == vector)
+	}
+
+	fn write_msix(&self, entry:usize, field:usize, value:u32){
+		if let Mode::MsiX{table} = self.mode{
+			//Safety:
+			//The table has an entry for every index, that we use, and the entries are 4 byte aligned.
+			unsafe{core::ptr::write_volatile((table+entry*MSIX_ENTRY_SIZE+field) as *mut u32, value)};
+		}
+	}
+
+	fn read_msix(&self, entry:usize, field:usize)->u32{
+		match self.mode{
+			//Safety:
+			//See write_msix.
+			Mode::MsiX{table}=>unsafe{core::ptr::read_volatile((table+entry*MSIX_ENTRY_SIZE+field) as *const u32)},
+			Mode::Msi{..}=>0,
+		}
+	}
+
+	///Delivers the interrupt to the given cpu.
+	///With plain MSI, all interrupts share one address, so this moves all of them.
+	pub fn target(&self, index:usize, cpu:usize){
+		let apic_id = crate::x86_64::cpu::apic_id(cpu);
+		match self.mode{
+			Mode::MsiX{..}=>{
+				let (address, data) = apic::msi_message(apic_id, self.vectors[index]);
+				//The entry must be masked, while it is changed.
+				let control = self.read_msix(index, MSIX_ENTRY_CONTROL);
+				self.write_msix(index, MSIX_ENTRY_CONTROL, control | MSIX_ENTRY_MASKED);
+				self.write_msix(index, MSIX_ENTRY_ADDRESS_LOW, address as u32);
+				self.write_msix(index, MSIX_ENTRY_ADDRESS_HIGH, (address>>32) as u32);
+				self.write_msix(index, MSIX_ENTRY_DATA, data);
+				self.write_msix(index, MSIX_ENTRY_CONTROL, control);
+			},
+			Mode::Msi{is_64bit, ..}=>{
+				let (address, data) = apic::msi_message(apic_id, self.vectors[0]);
+				self.dev.write32(self.offset+4, address as u32);
+				if is_64bit{
+					self.dev.write32(self.offset+8, (address>>32) as u32);
+					self.dev.write16(self.offset+12, data as u16);
+				}else{
+					self.dev.write16(self.offset+8, data as u16);
+				}
+			},
+		}
+	}
+
+	fn msi_mask_reg(&self)->Option<u16>{
+		match self.mode{
+			Mode::Msi{per_vector_mask:true, is_64bit}=>Some(self.offset+if is_64bit {16} else {12}),
+			_=>None,
+		}
+	}
+
+	///Stops the device from signaling this interrupt. It remembers it as pending instead.
+	///Returns false, if the device can't mask single MSI vectors.
+	pub fn mask(&self, index:usize)->bool{
+		match self.mode{
+			Mode::MsiX{..}=>{
+				let control = self.read_msix(index, MSIX_ENTRY_CONTROL);
+				self.write_msix(index, MSIX_ENTRY_CONTROL, control | MSIX_ENTRY_MASKED);
+				true
+			},
+			Mode::Msi{..}=>match self.msi_mask_reg(){
+				Some(reg)=>{
+					self.dev.write32(reg, self.dev.read32(reg) | 1<<index);
+					true
+				},
+				None=>false,
+			},
+		}
+	}
+
+	///Lets the device signal this interrupt again.
+	pub fn unmask(&self, index:usize)->bool{
+		match self.mode{
+			Mode::MsiX{..}=>{
+				let control = self.read_msix(index, MSIX_ENTRY_CONTROL);
+				self.write_msix(index, MSIX_ENTRY_CONTROL, control & !MSIX_ENTRY_MASKED);
+				true
+			},
+			Mode::Msi{..}=>match self.msi_mask_reg(){
+				Some(reg)=>{
+					self.dev.write32(reg, self.dev.read32(reg) & !(1<<index));
+					true
+				},
+				None=>false,
+			},
+		}
+	}
+}
+
+impl Drop for MsiInterrupts{
+	fn drop(&mut self){
+		let control_reg = self.offset+2;
+		match self.mode{
+			Mode::MsiX{..}=>{
+				for i in 0..self.vectors.len(){
+					self.mask(i);
+				}
+				self.dev.write16(control_reg, self.dev.read16(control_reg) & !MSIX_CONTROL_ENABLE);
+			},
+			Mode::Msi{..}=>{
+				self.dev.write16(control_reg, self.dev.read16(control_reg) & !MSI_CONTROL_ENABLE);
+			},
+		}
+		for v in &self.vectors{
+			free_vector(*v);
+		}
+	}
+}
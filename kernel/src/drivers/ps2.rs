@@ -0,0 +1,153 @@
+//Driver for the i8042 PS/2 controller.
+//Only the first port is used, for a keyboard.
+pub mod keyboard;
+pub mod keymap;
+pub mod scancode;
+
+use x86_64::instructions::port::Port;
+use self::scancode::ScancodeSet;
+
+const DATA:u16 = 0x60;
+///Status on read, command on write.
+const STATUS_COMMAND:u16 = 0x64;
+
+const STATUS_OUTPUT_FULL:u8 = 1<<0;
+const STATUS_INPUT_FULL:u8 = 1<<1;
+
+const CMD_READ_CONFIG:u8 = 0x20;
+const CMD_WRITE_CONFIG:u8 = 0x60;
+const CMD_DISABLE_PORT2:u8 = 0xA7;
+const CMD_SELF_TEST:u8 = 0xAA;
+const CMD_TEST_PORT1:u8 = 0xAB;
+const CMD_DISABLE_PORT1:u8 = 0xAD;
+const CMD_ENABLE_PORT1:u8 = 0xAE;
+
+const CONFIG_PORT1_IRQ:u8 = 1<<0;
+const CONFIG_PORT2_IRQ:u8 = 1<<1;
+///The controller translates set 2 scancodes from the keyboard to set 1.
+const CONFIG_TRANSLATION:u8 = 1<<6;
+
+const SELF_TEST_OK:u8 = 0x55;
+const PORT_TEST_OK:u8 = 0x00;
+
+///The FADT flag, that says, that there is an i8042.
+const BOOT_ARCH_8042:u16 = 1<<1;
+
+///How often the status register is polled, before a command is considered failed.
+///Every read takes around a microsecond, and a keyboard reset can take half a second.
+const TIMEOUT:usize = 1_000_000;
+
+fn status()->u8{
+	//Safety:
+	//Reading the status register has no side effects.
+	unsafe{Port::<u8>::new(STATUS_COMMAND).read()}
+}
+
+fn wait_input_empty()->bool{
+	(0..TIMEOUT).any(|_|status() & STATUS_INPUT_FULL == 0)
+}
+
+fn write_command(cmd:u8)->bool{
+	if !wait_input_empty(){
+		return false;
+	}
+	//Safety:
+	//The controller accepts commands, once its input buffer is empty.
+	unsafe{Port::<u8>::new(STATUS_COMMAND).write(cmd)};
+	true
+}
+
+///Writes a byte to the device on the first port.
+fn write_data(data:u8)->bool{
+	if !wait_input_empty(){
+		return false;
+	}
+	//Safety:
+	//The controller accepts data, once its input buffer is empty.
+	unsafe{Port::<u8>::new(DATA).write(data)};
+	true
+}
+
+///Waits for a byte from the controller or the device.
+fn read_data()->Option<u8>{
+	if !(0..TIMEOUT).any(|_|status() & STATUS_OUTPUT_FULL != 0){
+		return None;
+	}
+	Some(read_data_now())
+}
+
+///Reads the data register without waiting.
+fn read_data_now()->u8{
+	//Safety:
+	//Reading the data register only removes the byte from the output buffer.
+	unsafe{Port::<u8>::new(DATA).read()}
+}
+
+fn flush(){
+	for _ in 0..16{
+		if status() & STATUS_OUTPUT_FULL == 0{
+			break;
+		}
+		read_data_now();
+	}
+}
+
+///Sets up the controller and the keyboard on its first port.
+///Needs the IOAPIC to be set up. Returns false, if there is no working keyboard.
+pub fn init()->bool{
+	if let Some(fadt) = crate::acpi::fadt(){
+		let flags = fadt.BootArchitectureFlags;
+		//Plenty of firmware gets this flag wrong, so it is only a hint.
+		if fadt.Header.Revision >= 3 && flags & BOOT_ARCH_8042 == 0{
+			log::debug!("The FADT says, that there is no i8042. Probing anyway.");
+		}
+	}
+	if !write_command(CMD_DISABLE_PORT1) || !write_command(CMD_DISABLE_PORT2){
+		log::info!("No PS/2 controller found.");
+		return false;
+	}
+	flush();
+	let config = match write_command(CMD_READ_CONFIG).then(read_data).flatten(){
+		Some(c)=>c,
+		None=>return false,
+	};
+	let config = config & !(CONFIG_PORT1_IRQ|CONFIG_PORT2_IRQ);
+	write_command(CMD_WRITE_CONFIG);
+	write_data(config);
+	if write_command(CMD_SELF_TEST).then(read_data).flatten() != Some(SELF_TEST_OK){
+		log::warn!("The PS/2 controller failed its self test.");
+		return false;
+	}
+	//The self test can reset the controller, so write the config again.
+	write_command(CMD_WRITE_CONFIG);
+	write_data(config);
+	if write_command(CMD_TEST_PORT1).then(read_data).flatten() != Some(PORT_TEST_OK){
+		log::warn!("The first PS/2 port failed its test.");
+		return false;
+	}
+	write_command(CMD_ENABLE_PORT1);
+	let set = if config & CONFIG_TRANSLATION != 0 {ScancodeSet::Set1} else {ScancodeSet::Set2};
+	if !keyboard::init(set){
+		return false;
+	}
+	write_command(CMD_WRITE_CONFIG);
+	write_data(config | CONFIG_PORT1_IRQ);
+	true
+}
+
+///Sends a command to the keyboard and waits for its acknowledgement.
+fn keyboard_command(cmd:u8)->Option<u8>{
+	const ACK:u8 = 0xFA;
+	const RESEND:u8 = 0xFE;
+	for _ in 0..3{
+		if !write_data(cmd){
+			return None;
+		}
+		match read_data()?{
+			ACK=>return Some(ACK),
+			RESEND=>continue,
+			other=>return Some(other),
+		}
+	}
+	None
+}
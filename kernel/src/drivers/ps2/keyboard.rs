@@ -0,0 +1,213 @@
+//The PS/2 keyboard.
+//The IRQ handler decodes scancodes, tracks the modifiers and queues the resulting KeyEvents.
+use alloc::collections::VecDeque;
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::instructions::interrupts;
+use crate::lock::{Lock, LockClass};
+use crate::sync::WaitQueue;
+use crate::x86_64::interrupts::{allocate_vector, InterruptFrame};
+use crate::x86_64::{apic, ioapic};
+use super::keymap::{self, Keymap, Modifiers};
+use super::scancode::{Decoder, KeyCode, ScancodeSet};
+
+const KEYBOARD_IRQ:u8 = 1;
+///Events beyond this are dropped, until somebody reads them.
+const QUEUE_CAPACITY:usize = 256;
+
+const CMD_SET_LEDS:u8 = 0xED;
+const CMD_ENABLE_SCANNING:u8 = 0xF4;
+const CMD_RESET:u8 = 0xFF;
+const ACK:u8 = 0xFA;
+const SELF_TEST_PASSED:u8 = 0xAA;
+
+const LED_SCROLL_LOCK:u8 = 1<<0;
+const LED_NUM_LOCK:u8 = 1<<1;
+const LED_CAPS_LOCK:u8 = 1<<2;
+
+#[derive(Debug, Copy, Clone)]
+pub struct KeyEvent{
+	pub key:KeyCode,
+	///False, if the key was released.
+	pub pressed:bool,
+	///The modifiers after this event.
+	pub modifiers:Modifiers,
+	///The character, that the current keymap gives for this key. Only set for presses.
+	pub char:Option<char>,
+}
+
+struct State{
+	decoder:Decoder,
+	modifiers:Modifiers,
+	left_shift:bool,
+	right_shift:bool,
+	left_ctrl:bool,
+	right_ctrl:bool,
+	left_gui:bool,
+	right_gui:bool,
+	///The LED state waits for the keyboard to acknowledge CMD_SET_LEDS.
+	pending_leds:Option<u8>,
+	keymap:&'static dyn Keymap,
+}
+
+static KEYBOARD_CLASS:LockClass = LockClass::new("ps2::keyboard");
+static EVENTS_CLASS:LockClass = LockClass::new("ps2::keyboard::events");
+static STATE:Lock<State> = Lock::with_class(State{
+	decoder:Decoder::new(ScancodeSet::Set1),
+	modifiers:Modifiers{shift:false, ctrl:false, alt:false, altgr:false, gui:false, caps_lock:false, num_lock:false, scroll_lock:false},
+	left_shift:false,
+	right_shift:false,
+	left_ctrl:false,
+	right_ctrl:false,
+	left_gui:false,
+	right_gui:false,
+	pending_leds:None,
+	keymap:&keymap::US,
+}, &KEYBOARD_CLASS);
+static EVENTS:Lock<VecDeque<KeyEvent>> = Lock::with_class(VecDeque::new(), &EVENTS_CLASS);
+static WAITERS:WaitQueue = WaitQueue::new();
+static PRESENT:AtomicBool = AtomicBool::new(false);
+
+///Resets the keyboard and routes its IRQ to the current cpu.
+///`set` is the scancode set, that arrives at the controller's data port.
+pub(super) fn init(set:ScancodeSet)->bool{
+	if super::keyboard_command(CMD_RESET) != Some(ACK) || super::read_data() != Some(SELF_TEST_PASSED){
+		log::info!("No PS/2 keyboard found.");
+		return false;
+	}
+	super::keyboard_command(CMD_ENABLE_SCANNING);
+	interrupts::without_interrupts(||STATE.lock().decoder = Decoder::new(set));
+	let vector = match allocate_vector(irq){
+		Some(v)=>v,
+		None=>{
+			log::error!("No free vector for the keyboard.");
+			return false;
+		}
+	};
+	if !ioapic::route_isa_irq(KEYBOARD_IRQ, vector, apic::id()){
+		log::error!("Couldn't route IRQ {} of the keyboard.", KEYBOARD_IRQ);
+		return false;
+	}
+	PRESENT.store(true, Ordering::Relaxed);
+	log::info!("PS/2 keyboard using scancode {:?}", set);
+	true
+}
+
+pub fn is_present()->bool{
+	PRESENT.load(Ordering::Relaxed)
+}
+
+///Changes the layout used for KeyEvent::char.
+pub fn set_keymap(keymap:&'static dyn Keymap){
+	interrupts::without_interrupts(||STATE.lock().keymap = keymap);
+}
+
+pub fn keymap()->&'static dyn Keymap{
+	interrupts::without_interrupts(||STATE.lock().keymap)
+}
+
+fn irq(_frame:&mut InterruptFrame){
+	let byte = super::read_data_now();
+	let event = {
+		let mut state = STATE.lock();
+		if byte == ACK{
+			if let Some(leds) = state.pending_leds.take(){
+				super::write_data(leds);
+			}
+			return;
+		}
+		match state.decoder.feed(byte){
+			Some((key, pressed))=>state.handle(key, pressed),
+			None=>return,
+		}
+	};
+	{
+		let mut events = EVENTS.lock();
+		if events.len() < QUEUE_CAPACITY{
+			events.push_back(event);
+		}
+	}
+	WAITERS.wake_all();
+}
+
+impl State{
+	fn handle(&mut self, key:KeyCode, pressed:bool)->KeyEvent{
+		let mut leds_changed = false;
+		match key{
+			KeyCode::LeftShift=>self.left_shift = pressed,
+			KeyCode::RightShift=>self.right_shift = pressed,
+			KeyCode::LeftCtrl=>self.left_ctrl = pressed,
+			KeyCode::RightCtrl=>self.right_ctrl = pressed,
+			KeyCode::LeftGui=>self.left_gui = pressed,
+			KeyCode::RightGui=>self.right_gui = pressed,
+			KeyCode::LeftAlt=>self.modifiers.alt = pressed,
+			KeyCode::RightAlt=>self.modifiers.altgr = pressed,
+			//Lock keys toggle on press. Holding them down sends repeated presses, but no releases in between.
+			KeyCode::CapsLock if pressed=>{
+				self.modifiers.caps_lock = !self.modifiers.caps_lock;
+				leds_changed = true;
+			},
+			KeyCode::NumLock if pressed=>{
+				self.modifiers.num_lock = !self.modifiers.num_lock;
+				leds_changed = true;
+			},
+			KeyCode::ScrollLock if pressed=>{
+				self.modifiers.scroll_lock = !self.modifiers.scroll_lock;
+				leds_changed = true;
+			},
+			_=>{},
+		}
+		self.modifiers.shift = self.left_shift || self.right_shift;
+		self.modifiers.ctrl = self.left_ctrl || self.right_ctrl;
+		self.modifiers.gui = self.left_gui || self.right_gui;
+		if leds_changed{
+			self.update_leds();
+		}
+		KeyEvent{
+			key,
+			pressed,
+			modifiers:self.modifiers,
+			char:if pressed {keymap::translate(self.keymap, key, self.modifiers)} else {None},
+		}
+	}
+
+	///Starts setting the LEDs. The LED byte is sent, once the keyboard acknowledges the command.
+	fn update_leds(&mut self){
+		let mut leds = 0;
+		if self.modifiers.scroll_lock{
+			leds |= LED_SCROLL_LOCK;
+		}
+		if self.modifiers.num_lock{
+			leds |= LED_NUM_LOCK;
+		}
+		if self.modifiers.caps_lock{
+			leds |= LED_CAPS_LOCK;
+		}
+		self.pending_leds = Some(leds);
+		super::write_data(CMD_SET_LEDS);
+	}
+}
+
+///Returns the next key event, if there is one.
+pub fn try_read_event()->Option<KeyEvent>{
+	interrupts::without_interrupts(||EVENTS.lock().pop_front())
+}
+
+///Waits for the next key event.
+///Can only be used from tasks.
+pub fn read_event()->KeyEvent{
+	loop{
+		if let Some(e) = try_read_event(){
+			return e;
+		}
+		WAITERS.wait_until(||interrupts::without_interrupts(||!EVENTS.lock().is_empty()));
+	}
+}
+
+///Waits for the next key press, that produces a character.
+pub fn read_char()->char{
+	loop{
+		if let Some(c) = read_event().char{
+			return c;
+		}
+	}
+}
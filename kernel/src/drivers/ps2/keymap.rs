@@ -0,0 +1,135 @@
+//Keymaps turn physical keys into characters.
+use super::scancode::KeyCode;
+
+///The state of the modifier and lock keys.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct Modifiers{
+	pub shift:bool,
+	pub ctrl:bool,
+	pub alt:bool,
+	///The right Alt key. Most non-US layouts use it for a third level of characters.
+	pub altgr:bool,
+	pub gui:bool,
+	pub caps_lock:bool,
+	pub num_lock:bool,
+	pub scroll_lock:bool,
+}
+
+///A keyboard layout.
+pub trait Keymap:Sync{
+	fn name(&self)->&'static str;
+	///The characters on a key: without modifiers, with Shift and with AltGr.
+	///Only keys, that differ between layouts, are handled here.
+	fn key(&self, key:KeyCode)->Option<(char, char, Option<char>)>;
+}
+
+///The character, that a key press produces with the given keymap.
+///Ctrl with a letter gives the matching control character.
+pub fn translate(keymap:&dyn Keymap, key:KeyCode, mods:Modifiers)->Option<char>{
+	if let Some(c) = common(key, mods){
+		return Some(c);
+	}
+	let (normal, shifted, altgr) = keymap.key(key)?;
+	if mods.altgr{
+		return altgr;
+	}
+	//Caps Lock only affects letters, and Shift undoes it.
+	let shift = if shifted.is_uppercase() {mods.shift != mods.caps_lock} else {mods.shift};
+	let c = if shift {shifted} else {normal};
+	if mods.ctrl && c.is_ascii_alphabetic(){
+		return Some((c.to_ascii_uppercase() as u8 & 0x1F) as char);
+	}
+	Some(c)
+}
+
+///Keys, that are the same on every layout.
+fn common(key:KeyCode, mods:Modifiers)->Option<char>{
+	use KeyCode::*;
+	Some(match key{
+		Escape=>'\x1b',
+		Backspace=>'\x08',
+		Tab=>'\t',
+		Enter|KeypadEnter=>'\n',
+		Space=>' ',
+		KeypadSlash=>'/',
+		KeypadStar=>'*',
+		KeypadMinus=>'-',
+		KeypadPlus=>'+',
+		//Without Num Lock, the keypad keys are cursor keys.
+		_ if !mods.num_lock=>return None,
+		Keypad0=>'0', Keypad1=>'1', Keypad2=>'2', Keypad3=>'3', Keypad4=>'4',
+		Keypad5=>'5', Keypad6=>'6', Keypad7=>'7', Keypad8=>'8', Keypad9=>'9',
+		KeypadDot=>'.',
+		_=>return None,
+	})
+}
+
+pub struct Us;
+
+impl Keymap for Us{
+	fn name(&self)->&'static str{
+		"us"
+	}
+
+	fn key(&self, key:KeyCode)->Option<(char, char, Option<char>)>{
+		use KeyCode::*;
+		Some(match key{
+			Backtick=>('`', '~', None),
+			Key1=>('1', '!', None), Key2=>('2', '@', None), Key3=>('3', '#', None), Key4=>('4', '$', None), Key5=>('5', '%', None),
+			Key6=>('6', '^', None), Key7=>('7', '&', None), Key8=>('8', '*', None), Key9=>('9', '(', None), Key0=>('0', ')', None),
+			Minus=>('-', '_', None), Equals=>('=', '+', None),
+			LeftBracket=>('[', '{', None), RightBracket=>(']', '}', None), Backslash=>('\\', '|', None),
+			Semicolon=>(';', ':', None), Quote=>('\'', '"', None),
+			Iso102=>('\\', '|', None),
+			Comma=>(',', '<', None), Period=>('.', '>', None), Slash=>('/', '?', None),
+			_=>return letter(key, false).map(|c|(c, c.to_ascii_uppercase(), None)),
+		})
+	}
+}
+
+///German QWERTZ layout.
+///The dead keys (^, ´ and `) produce their character directly.
+pub struct De;
+
+impl Keymap for De{
+	fn name(&self)->&'static str{
+		"de"
+	}
+
+	fn key(&self, key:KeyCode)->Option<(char, char, Option<char>)>{
+		use KeyCode::*;
+		Some(match key{
+			Backtick=>('^', '°', None),
+			Key1=>('1', '!', None), Key2=>('2', '"', Some('²')), Key3=>('3', '§', Some('³')), Key4=>('4', '$', None), Key5=>('5', '%', None),
+			Key6=>('6', '&', None), Key7=>('7', '/', Some('{')), Key8=>('8', '(', Some('[')), Key9=>('9', ')', Some(']')), Key0=>('0', '=', Some('}')),
+			Minus=>('ß', '?', Some('\\')), Equals=>('´', '`', None),
+			Q=>('q', 'Q', Some('@')), E=>('e', 'E', Some('€')), M=>('m', 'M', Some('µ')),
+			LeftBracket=>('ü', 'Ü', None), RightBracket=>('+', '*', Some('~')), Backslash=>('#', '\'', None),
+			Semicolon=>('ö', 'Ö', None), Quote=>('ä', 'Ä', None),
+			Iso102=>('<', '>', Some('|')),
+			Comma=>(',', ';', None), Period=>('.', ':', None), Slash=>('-', '_', None),
+			_=>return letter(key, true).map(|c|(c, c.to_ascii_uppercase(), None)),
+		})
+	}
+}
+
+///The lowercase letter on a key. `qwertz` swaps Y and Z.
+fn letter(key:KeyCode, qwertz:bool)->Option<char>{
+	use KeyCode::*;
+	Some(match key{
+		A=>'a', B=>'b', C=>'c', D=>'d', E=>'e', F=>'f', G=>'g', H=>'h', I=>'i', J=>'j', K=>'k', L=>'l', M=>'m',
+		N=>'n', O=>'o', P=>'p', Q=>'q', R=>'r', S=>'s', T=>'t', U=>'u', V=>'v', W=>'w', X=>'x',
+		Y=>if qwertz {'z'} else {'y'},
+		Z=>if qwertz {'y'} else {'z'},
+		_=>return None,
+	})
+}
+
+pub static US:Us = Us;
+pub static DE:De = De;
+pub static KEYMAPS:[&dyn Keymap;2] = [&US, &DE];
+
+///Finds a keymap by its name.
+pub fn by_name(name:&str)->Option<&'static dyn Keymap>{
+	KEYMAPS.iter().copied().find(|k|k.name() == name)
+}
@@ -0,0 +1,205 @@
+//Translation of scancode set 1 and set 2 byte sequences into key presses and releases.
+
+///A physical key, named after what it shows on a US keyboard.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum KeyCode{
+	Escape, F1, F2, F3, F4, F5, F6, F7, F8, F9, F10, F11, F12,
+	PrintScreen, ScrollLock, Pause,
+	Backtick, Key1, Key2, Key3, Key4, Key5, Key6, Key7, Key8, Key9, Key0, Minus, Equals, Backspace,
+	Tab, Q, W, E, R, T, Y, U, I, O, P, LeftBracket, RightBracket, Backslash,
+	CapsLock, A, S, D, F, G, H, J, K, L, Semicolon, Quote, Enter,
+	LeftShift, Iso102, Z, X, C, V, B, N, M, Comma, Period, Slash, RightShift,
+	LeftCtrl, LeftGui, LeftAlt, Space, RightAlt, RightGui, Menu, RightCtrl,
+	Insert, Home, PageUp, Delete, End, PageDown,
+	Up, Left, Down, Right,
+	NumLock, KeypadSlash, KeypadStar, KeypadMinus, KeypadPlus, KeypadEnter, KeypadDot,
+	Keypad0, Keypad1, Keypad2, Keypad3, Keypad4, Keypad5, Keypad6, Keypad7, Keypad8, Keypad9,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ScancodeSet{
+	Set1,
+	Set2,
+}
+
+///Bytes, that are not part of a scancode.
+const ACK:u8 = 0xFA;
+const RESEND:u8 = 0xFE;
+const ERROR_0:u8 = 0x00;
+const ERROR_FF:u8 = 0xFF;
+const SELF_TEST_PASSED:u8 = 0xAA;
+const ECHO:u8 = 0xEE;
+
+const EXTENDED:u8 = 0xE0;
+///Starts the Pause sequence, which has no break code.
+const PAUSE_PREFIX:u8 = 0xE1;
+const SET2_BREAK:u8 = 0xF0;
+const SET1_BREAK:u8 = 0x80;
+
+///The amount of bytes, that follow PAUSE_PREFIX.
+const fn pause_len(set:ScancodeSet)->u8{
+	match set{
+		ScancodeSet::Set1=>5,
+		ScancodeSet::Set2=>7,
+	}
+}
+
+///Turns a stream of scancode bytes into key events.
+pub struct Decoder{
+	set:ScancodeSet,
+	extended:bool,
+	release:bool,
+	///Bytes of the Pause sequence, that are still to come.
+	pause_remaining:u8,
+}
+
+impl Decoder{
+	pub const fn new(set:ScancodeSet)->Self{
+		Self{set, extended:false, release:false, pause_remaining:0}
+	}
+
+	pub fn set(&self)->ScancodeSet{
+		self.set
+	}
+
+	///Feeds the next byte from the keyboard.
+	///Returns the key and true for a press or false for a release, once a scancode is complete.
+	pub fn feed(&mut self, byte:u8)->Option<(KeyCode, bool)>{
+		if self.pause_remaining > 0{
+			self.pause_remaining -= 1;
+			return if self.pause_remaining == 0 {Some((KeyCode::Pause, true))} else {None};
+		}
+		match byte{
+			ACK|RESEND|ERROR_FF|ECHO|ERROR_0=>return None,
+			//In set 1, this is the release of LeftShift.
+			SELF_TEST_PASSED if self.set == ScancodeSet::Set2=>return None,
+			EXTENDED=>{
+				self.extended = true;
+				return None;
+			},
+			PAUSE_PREFIX=>{
+				self.pause_remaining = pause_len(self.set);
+				return None;
+			},
+			SET2_BREAK if self.set == ScancodeSet::Set2=>{
+				self.release = true;
+				return None;
+			},
+			_=>{},
+		}
+		let extended = core::mem::take(&mut self.extended);
+		let (code, pressed) = match self.set{
+			ScancodeSet::Set1=>(byte & !SET1_BREAK, byte & SET1_BREAK == 0),
+			ScancodeSet::Set2=>(byte, !core::mem::take(&mut self.release)),
+		};
+		let key = match (self.set, extended){
+			(ScancodeSet::Set1, false)=>set1(code),
+			(ScancodeSet::Set1, true)=>set1_extended(code),
+			(ScancodeSet::Set2, false)=>set2(code),
+			(ScancodeSet::Set2, true)=>set2_extended(code),
+		}?;
+		Some((key, pressed))
+	}
+}
+
+fn set1(code:u8)->Option<KeyCode>{
+	use KeyCode::*;
+	const TABLE:[Option<KeyCode>;0x59] = {
+		let mut t = [None;0x59];
+		let keys = [
+			Escape, Key1, Key2, Key3, Key4, Key5, Key6, Key7, Key8, Key9, Key0, Minus, Equals, Backspace,
+			Tab, Q, W, E, R, T, Y, U, I, O, P, LeftBracket, RightBracket, Enter,
+			LeftCtrl, A, S, D, F, G, H, J, K, L, Semicolon, Quote, Backtick,
+			LeftShift, Backslash, Z, X, C, V, B, N, M, Comma, Period, Slash, RightShift,
+			KeypadStar, LeftAlt, Space, CapsLock, F1, F2, F3, F4, F5, F6, F7, F8, F9, F10,
+			NumLock, ScrollLock, Keypad7, Keypad8, Keypad9, KeypadMinus, Keypad4, Keypad5, Keypad6, KeypadPlus,
+			Keypad1, Keypad2, Keypad3, Keypad0, KeypadDot,
+		];
+		//These are the codes 0x01 to 0x53 in order.
+		let mut i = 0;
+		while i < keys.len(){
+			t[i+1] = Some(keys[i]);
+			i += 1;
+		}
+		t[0x56] = Some(Iso102);
+		t[0x57] = Some(F11);
+		t[0x58] = Some(F12);
+		t
+	};
+	TABLE.get(code as usize).copied().flatten()
+}
+
+fn set1_extended(code:u8)->Option<KeyCode>{
+	use KeyCode::*;
+	Some(match code{
+		0x1C=>KeypadEnter,
+		0x1D=>RightCtrl,
+		0x35=>KeypadSlash,
+		0x37=>PrintScreen,
+		0x38=>RightAlt,
+		0x47=>Home,
+		0x48=>Up,
+		0x49=>PageUp,
+		0x4B=>Left,
+		0x4D=>Right,
+		0x4F=>End,
+		0x50=>Down,
+		0x51=>PageDown,
+		0x52=>Insert,
+		0x53=>Delete,
+		0x5B=>LeftGui,
+		0x5C=>RightGui,
+		0x5D=>Menu,
+		//Includes the fake shifts, that some keys send with E0 2A/E0 AA.
+		_=>return None,
+	})
+}
+
+fn set2(code:u8)->Option<KeyCode>{
+	use KeyCode::*;
+	Some(match code{
+		0x76=>Escape,
+		0x05=>F1, 0x06=>F2, 0x04=>F3, 0x0C=>F4, 0x03=>F5, 0x0B=>F6,
+		0x83=>F7, 0x0A=>F8, 0x01=>F9, 0x09=>F10, 0x78=>F11, 0x07=>F12,
+		0x7E=>ScrollLock,
+		0x0E=>Backtick, 0x16=>Key1, 0x1E=>Key2, 0x26=>Key3, 0x25=>Key4, 0x2E=>Key5,
+		0x36=>Key6, 0x3D=>Key7, 0x3E=>Key8, 0x46=>Key9, 0x45=>Key0, 0x4E=>Minus, 0x55=>Equals, 0x66=>Backspace,
+		0x0D=>Tab, 0x15=>Q, 0x1D=>W, 0x24=>E, 0x2D=>R, 0x2C=>T, 0x35=>Y, 0x3C=>U, 0x43=>I, 0x44=>O, 0x4D=>P,
+		0x54=>LeftBracket, 0x5B=>RightBracket, 0x5D=>Backslash,
+		0x58=>CapsLock, 0x1C=>A, 0x1B=>S, 0x23=>D, 0x2B=>F, 0x34=>G, 0x33=>H, 0x3B=>J, 0x42=>K, 0x4B=>L,
+		0x4C=>Semicolon, 0x52=>Quote, 0x5A=>Enter,
+		0x12=>LeftShift, 0x61=>Iso102, 0x1A=>Z, 0x22=>X, 0x21=>C, 0x2A=>V, 0x32=>B, 0x31=>N, 0x3A=>M,
+		0x41=>Comma, 0x49=>Period, 0x4A=>Slash, 0x59=>RightShift,
+		0x14=>LeftCtrl, 0x11=>LeftAlt, 0x29=>Space,
+		0x77=>NumLock, 0x7C=>KeypadStar, 0x7B=>KeypadMinus, 0x79=>KeypadPlus,
+		0x6C=>Keypad7, 0x75=>Keypad8, 0x7D=>Keypad9, 0x6B=>Keypad4, 0x73=>Keypad5, 0x74=>Keypad6,
+		0x69=>Keypad1, 0x72=>Keypad2, 0x7A=>Keypad3, 0x70=>Keypad0, 0x71=>KeypadDot,
+		_=>return None,
+	})
+}
+
+fn set2_extended(code:u8)->Option<KeyCode>{
+	use KeyCode::*;
+	Some(match code{
+		0x11=>RightAlt,
+		0x14=>RightCtrl,
+		0x1F=>LeftGui,
+		0x27=>RightGui,
+		0x2F=>Menu,
+		0x4A=>KeypadSlash,
+		0x5A=>KeypadEnter,
+		0x69=>End,
+		0x6B=>Left,
+		0x6C=>Home,
+		0x70=>Insert,
+		0x71=>Delete,
+		0x72=>Down,
+		0x74=>Right,
+		0x75=>Up,
+		0x7A=>PageDown,
+		0x7C=>PrintScreen,
+		0x7D=>PageUp,
+		//Includes the fake shifts, that some keys send with E0 12.
+		_=>return None,
+	})
+}
@@ -0,0 +1,257 @@
+//Driver for 16550 compatible UARTs.
+//Transmitting is always polled, so it works in early boot, in interrupt handlers and in panics.
+//Received bytes are collected by the IRQ handler into a buffer, from which tasks can read.
+use alloc::collections::VecDeque;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicU16, AtomicU8, Ordering};
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+use crate::cmdline::{self, EnumParam, IntParam};
+use crate::lock::{Lock, LockClass};
+use crate::sync::WaitQueue;
+use crate::x86_64::interrupts::{allocate_vector, InterruptFrame};
+use crate::x86_64::{apic, ioapic};
+
+///The I/O port bases and ISA IRQs of COM1 to COM4.
+const PORTS:[(u16, u8);4] = [(0x3F8, 4), (0x2F8, 3), (0x3E8, 4), (0x2E8, 3)];
+pub const DEFAULT_BAUD:u32 = 115_200;
+///The UART clock divided by 16, which is the highest possible baud rate.
+const MAX_BAUD:u32 = 115_200;
+///Received bytes beyond this are dropped, until somebody reads them.
+const RX_CAPACITY:usize = 4096;
+
+const REG_DATA:u16 = 0;
+const REG_INTERRUPT_ENABLE:u16 = 1;
+///FIFO control on write, interrupt identification on read.
+const REG_FIFO_CONTROL:u16 = 2;
+const REG_LINE_CONTROL:u16 = 3;
+const REG_MODEM_CONTROL:u16 = 4;
+const REG_LINE_STATUS:u16 = 5;
+///With LINE_DLAB set, registers 0 and 1 are the baud rate divisor.
+const REG_DIVISOR_LOW:u16 = 0;
+const REG_DIVISOR_HIGH:u16 = 1;
+
+const IER_RX_AVAILABLE:u8 = 1<<0;
+const FCR_ENABLE_CLEAR_14:u8 = 0b1100_0111;
+const LINE_8N1:u8 = 0b0000_0011;
+const LINE_DLAB:u8 = 1<<7;
+const MCR_DTR_RTS:u8 = 0b0000_0011;
+///Routes the interrupt line of the UART to the interrupt controller.
+const MCR_OUT2:u8 = 1<<3;
+const MCR_LOOPBACK:u8 = 1<<4;
+const LSR_DATA_READY:u8 = 1<<0;
+const LSR_TX_EMPTY:u8 = 1<<5;
+
+///A 16550 UART at an I/O port base.
+pub struct SerialPort{
+	base:u16,
+}
+
+impl SerialPort{
+	///Safety:
+	///There has to be a UART at `base`, or nothing at all.
+	///Nobody else may be programming it at the same time.
+	pub const unsafe fn new(base:u16)->Self{
+		Self{base}
+	}
+
+	fn read(&self, reg:u16)->u8{
+		//Safety:
+		//See new.
+		unsafe{Port::<u8>::new(self.base+reg).read()}
+	}
+
+	fn write(&mut self, reg:u16, value:u8){
+		//Safety:
+		//See new.
+		unsafe{Port::<u8>::new(self.base+reg).write(value)}
+	}
+
+	///Sets the UART up for 8N1 at `baud` with interrupts disabled.
+	///Returns false, if the baud rate isn't possible, or there is no working UART.
+	pub fn init(&mut self, baud:u32)->bool{
+		if baud == 0 || !MAX_BAUD.is_multiple_of(baud){
+			return false;
+		}
+		let divisor = (MAX_BAUD/baud) as u16;
+		self.write(REG_INTERRUPT_ENABLE, 0);
+		self.write(REG_LINE_CONTROL, LINE_DLAB);
+		self.write(REG_DIVISOR_LOW, divisor as u8);
+		self.write(REG_DIVISOR_HIGH, (divisor>>8) as u8);
+		self.write(REG_LINE_CONTROL, LINE_8N1);
+		self.write(REG_FIFO_CONTROL, FCR_ENABLE_CLEAR_14);
+		//Send a byte to ourselves, to check, that there really is a UART.
+		self.write(REG_MODEM_CONTROL, MCR_LOOPBACK | MCR_DTR_RTS);
+		self.write(REG_DATA, 0xAE);
+		let mut ok = false;
+		for _ in 0..1000{
+			if self.read(REG_LINE_STATUS) & LSR_DATA_READY != 0{
+				ok = self.read(REG_DATA) == 0xAE;
+				break;
+			}
+		}
+		self.write(REG_MODEM_CONTROL, MCR_DTR_RTS | MCR_OUT2);
+		ok
+	}
+
+	pub fn write_byte(&mut self, b:u8){
+		//Without anything listening, a real UART still drains its FIFO, so this only spins for the time a byte takes.
+		//Give up eventually anyway, so a broken UART can't hang a panic.
+		for _ in 0..100_000{
+			if self.read(REG_LINE_STATUS) & LSR_TX_EMPTY != 0{
+				break;
+			}
+			core::hint::spin_loop();
+		}
+		self.write(REG_DATA, b);
+	}
+
+	pub fn try_read_byte(&mut self)->Option<u8>{
+		if self.read(REG_LINE_STATUS) & LSR_DATA_READY == 0{
+			return None;
+		}
+		Some(self.read(REG_DATA))
+	}
+
+	fn enable_rx_interrupt(&mut self){
+		self.write(REG_INTERRUPT_ENABLE, IER_RX_AVAILABLE);
+	}
+}
+
+impl fmt::Write for SerialPort{
+	fn write_str(&mut self, s:&str)->fmt::Result{
+		for b in s.bytes(){
+			if b == b'\n'{
+				self.write_byte(b'\r');
+			}
+			self.write_byte(b);
+		}
+		Ok(())
+	}
+}
+
+static SERIAL_CLASS:LockClass = LockClass::new("serial");
+static SERIAL_RX_CLASS:LockClass = LockClass::new("serial::rx");
+static PORT:Lock<Option<SerialPort>> = Lock::with_class(None, &SERIAL_CLASS);
+///The base of PORT, so that panics can write to it without the lock. 0, if there is none.
+static PORT_BASE:AtomicU16 = AtomicU16::new(0);
+static RX:Lock<VecDeque<u8>> = Lock::with_class(VecDeque::new(), &SERIAL_RX_CLASS);
+static RX_WAITERS:WaitQueue = WaitQueue::new();
+static RX_OVERFLOW:AtomicBool = AtomicBool::new(false);
+static PORT_IRQ:AtomicU8 = AtomicU8::new(0);
+
+///`console=` on the command line picks the serial port for the kernel log, or none.
+static CONSOLE:EnumParam = EnumParam::new("console", &["ttyS0", "ttyS1", "ttyS2", "ttyS3", "none"], 0);
+///`console_baud=` on the command line. It has to divide 115200.
+static BAUD:IntParam = IntParam::new("console_baud", DEFAULT_BAUD as i64, 1, MAX_BAUD as i64);
+
+///Sets up the serial port and baud rate from the command line.
+///Only transmitting works, until init_irq is called.
+pub fn init()->bool{
+	cmdline::register(&CONSOLE);
+	cmdline::register(&BAUD);
+	let Some(&(base, irq)) = PORTS.get(CONSOLE.get()) else{
+		return false;
+	};
+	//Safety:
+	//The serial ports are only ever programmed through PORT.
+	let mut port = unsafe{SerialPort::new(base)};
+	if !port.init(BAUD.get() as u32){
+		return false;
+	}
+	interrupts::without_interrupts(||*PORT.lock() = Some(port));
+	PORT_IRQ.store(irq, Ordering::Relaxed);
+	PORT_BASE.store(base, Ordering::Relaxed);
+	true
+}
+
+///Routes the IRQ of the serial port to the current cpu, so bytes can be received.
+///Needs the IOAPIC to be set up.
+pub fn init_irq(){
+	if !is_present(){
+		return;
+	}
+	let vector = match allocate_vector(irq){
+		Some(v)=>v,
+		None=>{
+			log::error!("No free vector for the serial port.");
+			return;
+		}
+	};
+	let irq = PORT_IRQ.load(Ordering::Relaxed);
+	if !ioapic::route_isa_irq(irq, vector, apic::id()){
+		log::error!("Couldn't route IRQ {} of the serial port.", irq);
+		return;
+	}
+	with_port(|p|p.enable_rx_interrupt());
+}
+
+pub fn is_present()->bool{
+	PORT_BASE.load(Ordering::Relaxed) != 0
+}
+
+///Runs `f` with the serial port locked and interrupts disabled.
+///Returns None, if there is no serial port.
+pub fn with_port<R>(f:impl FnOnce(&mut SerialPort)->R)->Option<R>{
+	interrupts::without_interrupts(||PORT.lock().as_mut().map(f))
+}
+
+///Returns the serial port, without locking it.
+///Output will be interleaved with anyone else writing at the same time.
+///Safety:
+///Only meant for panics, where waiting for the lock could hang forever.
+pub unsafe fn steal()->Option<SerialPort>{
+	match PORT_BASE.load(Ordering::Relaxed){
+		0=>None,
+		base=>Some(SerialPort::new(base)),
+	}
+}
+
+fn irq(_frame:&mut InterruptFrame){
+	let mut buf = [0u8;16];
+	let mut len = 0;
+	//The FIFO triggers at 14 bytes, so at most 16 are waiting.
+	with_port(|p|{
+		while len < buf.len(){
+			match p.try_read_byte(){
+				Some(b)=>{
+					buf[len] = b;
+					len += 1;
+				},
+				None=>break,
+			}
+		}
+	});
+	{
+		let mut rx = RX.lock();
+		for b in &buf[..len]{
+			if rx.len() >= RX_CAPACITY{
+				RX_OVERFLOW.store(true, Ordering::Relaxed);
+				break;
+			}
+			rx.push_back(*b);
+		}
+	}
+	RX_WAITERS.wake_all();
+}
+
+///Returns the next received byte, if there is one.
+pub fn try_read_byte()->Option<u8>{
+	interrupts::without_interrupts(||RX.lock().pop_front())
+}
+
+///Waits for the next received byte.
+///Can only be used from tasks.
+pub fn read_byte()->u8{
+	loop{
+		if let Some(b) = try_read_byte(){
+			return b;
+		}
+		RX_WAITERS.wait_until(||interrupts::without_interrupts(||!RX.lock().is_empty()));
+	}
+}
+
+///Returns true once, after received bytes were dropped, because nobody read them.
+pub fn take_rx_overflow()->bool{
+	RX_OVERFLOW.swap(false, Ordering::Relaxed)
+}
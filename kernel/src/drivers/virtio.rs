@@ -0,0 +1,55 @@
+//Virtio devices on PCI.
+//Only modern (virtio 1.x) devices are supported. Transitional devices work through their modern interface.
+//Queue interrupts arrive through MSI-X. Without it, the driver polls the used rings.
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use x86_64::instructions::interrupts;
+use crate::lock::{LockClass, RWLock, RWLockMode};
+use crate::x86_64::interrupts::InterruptFrame;
+
+pub mod pci;
+pub mod queue;
+pub mod blk;
+
+pub use queue::VirtQueue;
+
+pub const VENDOR_ID:u16 = 0x1AF4;
+///Modern devices have the device id 0x1040 plus the virtio device type.
+pub const MODERN_DEVICE_ID_BASE:u16 = 0x1040;
+
+pub const STATUS_ACKNOWLEDGE:u8 = 1;
+pub const STATUS_DRIVER:u8 = 2;
+pub const STATUS_DRIVER_OK:u8 = 4;
+pub const STATUS_FEATURES_OK:u8 = 8;
+pub const STATUS_DEVICE_NEEDS_RESET:u8 = 64;
+pub const STATUS_FAILED:u8 = 128;
+
+pub const F_VERSION_1:u64 = 1<<32;
+
+static QUEUES_CLASS:LockClass = LockClass::new("virtio::queues");
+///The queue, that each interrupt vector belongs to.
+static IRQ_QUEUES:RWLock<Vec<(u8, Arc<VirtQueue>)>> = RWLock::with_class(Vec::new(), RWLockMode::ReaderPreferring, &QUEUES_CLASS);
+
+///Registers the virtio drivers with the PCI bus.
+pub fn init(){
+	crate::drivers::pci::register_driver(&blk::DRIVER);
+}
+
+///Makes interrupts on `vector` complete requests of `queue`.
+pub(crate) fn attach_irq(vector:u8, queue:Arc<VirtQueue>){
+	interrupts::without_interrupts(||IRQ_QUEUES.write_lock().push((vector, queue)));
+}
+
+pub(crate) fn detach_irq(vector:u8){
+	interrupts::without_interrupts(||IRQ_QUEUES.write_lock().retain(|(v, _)|*v != vector));
+}
+
+///The handler for all queue interrupts.
+pub(crate) fn irq(frame:&mut InterruptFrame){
+	let vector = frame.vector as u8;
+	let queue = IRQ_QUEUES.read_lock().iter().find(|(v, _)|*v == vector).map(|(_, q)|q.clone());
+	match queue{
+		Some(q)=>q.handle_interrupt(),
+		None=>log::warn!("Virtio interrupt on vector {:#x} without a queue", vector),
+	}
+}
@@ -0,0 +1,310 @@
+//Virtio block devices.
+//Requests are split into chunks, that fit into the queue, and all chunks of a request are in flight at once.
+//The data goes straight from and to the caller's buffer. Only the request header and status byte get their own DMA buffer.
+//To try it with QEMU, attach a raw disk image with
+//  -drive file=disk.img,format=raw,if=none,id=disk0 -device virtio-blk-pci,drive=disk0
+//The disk shows up as block device vda.
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU32, Ordering};
+use crate::block::{self, BlockDevice, BlockError};
+use crate::drivers::pci::{msi, PciDevice, PciDriver, PciMatch};
+use crate::x86_64::dma::DmaBuffer;
+use crate::x86_64::mem::{phys_segments, PAGE_SIZE};
+use super::pci::Transport;
+use super::queue::Buffer;
+use super::{VirtQueue, STATUS_ACKNOWLEDGE, STATUS_DRIVER, STATUS_DRIVER_OK};
+
+const DEVICE_TYPE:u16 = 2;
+const TRANSITIONAL_DEVICE_ID:u16 = 0x1001;
+
+const F_SIZE_MAX:u64 = 1<<1;
+const F_SEG_MAX:u64 = 1<<2;
+const F_RO:u64 = 1<<5;
+const F_BLK_SIZE:u64 = 1<<6;
+const F_FLUSH:u64 = 1<<9;
+
+const CONFIG_CAPACITY:usize = 0;
+const CONFIG_SIZE_MAX:usize = 8;
+const CONFIG_SEG_MAX:usize = 12;
+const CONFIG_BLK_SIZE:usize = 20;
+
+const T_IN:u32 = 0;
+const T_OUT:u32 = 1;
+const T_FLUSH:u32 = 4;
+
+const S_OK:u8 = 0;
+const S_UNSUPP:u8 = 2;
+
+///The capacity and requests always count in 512 byte sectors, whatever the block size is.
+const SECTOR_SIZE:u64 = 512;
+const HEADER_SIZE:usize = 16;
+///The header and status byte share one DMA buffer.
+const STATUS_OFFSET:usize = HEADER_SIZE;
+const MAX_CHUNK:usize = 128*1024;
+
+pub static DRIVER:VirtioBlkDriver = VirtioBlkDriver;
+static NEXT_INDEX:AtomicU32 = AtomicU32::new(0);
+
+pub struct VirtioBlkDriver;
+
+static MATCHES:[PciMatch;2] = [
+	PciMatch::device(super::VENDOR_ID, super::MODERN_DEVICE_ID_BASE+DEVICE_TYPE),
+	PciMatch::device(super::VENDOR_ID, TRANSITIONAL_DEVICE_ID),
+];
+
+impl PciDriver for VirtioBlkDriver{
+	fn name(&self)->&'static str{
+		"virtio-blk"
+	}
+
+	fn matches(&self)->&'static [PciMatch]{
+		&MATCHES
+	}
+
+	fn probe(&self, dev:&Arc<PciDevice>)->bool{
+		match VirtioBlk::new(dev){
+			Some(disk)=>{
+				block::register(Arc::new(disk));
+				true
+			},
+			None=>false,
+		}
+	}
+}
+
+pub struct VirtioBlk{
+	name:String,
+	transport:Transport,
+	queue:Arc<VirtQueue>,
+	irqs:Option<msi::MsiInterrupts>,
+	sectors:u64,
+	block_size:usize,
+	read_only:bool,
+	has_flush:bool,
+	///The maximum size of a single data buffer.
+	size_max:usize,
+	///The maximum amount of data buffers in a request.
+	seg_max:usize,
+}
+
+impl VirtioBlk{
+	fn new(dev:&Arc<PciDevice>)->Option<Self>{
+		let transport = match Transport::new(dev){
+			Some(t)=>t,
+			None=>{
+				log::warn!("Virtio block device {} has no modern interface.", dev.address);
+				return None;
+			},
+		};
+		if !transport.reset(){
+			return None;
+		}
+		transport.add_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+		let features = match transport.negotiate(F_SIZE_MAX | F_SEG_MAX | F_RO | F_BLK_SIZE | F_FLUSH, 0){
+			Some(f)=>f,
+			None=>{
+				transport.fail();
+				return None;
+			},
+		};
+		let disk = Self::setup(transport, features);
+		if disk.is_none(){
+			log::error!("Couldn't set up virtio block device {}.", dev.address);
+		}
+		disk
+	}
+
+	fn setup(transport:Transport, features:u64)->Option<Self>{
+		let dev = transport.device().clone();
+		let config = |offset|transport.read_config32(offset);
+		let (sectors, size_max, seg_max, block_size) = loop{
+			let generation = transport.config_generation();
+			let sectors = config(CONFIG_CAPACITY)? as u64 | (config(CONFIG_CAPACITY+4)? as u64)<<32;
+			let size_max = if features & F_SIZE_MAX != 0 {config(CONFIG_SIZE_MAX)?} else {0};
+			let seg_max = if features & F_SEG_MAX != 0 {config(CONFIG_SEG_MAX)?} else {0};
+			let block_size = if features & F_BLK_SIZE != 0 {config(CONFIG_BLK_SIZE)?} else {SECTOR_SIZE as u32};
+			if transport.config_generation() == generation{
+				break (sectors, size_max, seg_max, block_size);
+			}
+		};
+		if !block_size.is_power_of_two() || (block_size as u64) < SECTOR_SIZE || block_size as usize > PAGE_SIZE{
+			log::error!("Virtio block device {} has an unsupported block size of {}", dev.address, block_size);
+			transport.fail();
+			return None;
+		}
+		let irqs = msi::allocate(&dev, &[super::irq], crate::x86_64::cpu::id());
+		let msix_entry = match irqs.as_ref().map(|i|i.mode()){
+			Some(msi::Mode::MsiX{..})=>Some(0),
+			_=>None,
+		};
+		//Virtio only supports MSI-X. Plain MSI would be enabled, but never used.
+		let irqs = irqs.filter(|_|msix_entry.is_some());
+		transport.disable_config_interrupt();
+		let queue = match transport.setup_queue(0, msix_entry){
+			Some(q)=>Arc::new(q),
+			None=>{
+				transport.fail();
+				return None;
+			},
+		};
+		if let Some(irqs) = &irqs{
+			super::attach_irq(irqs.vector(0), queue.clone());
+		}
+		transport.add_status(STATUS_DRIVER_OK);
+		//Every request needs a descriptor for the header and the status byte.
+		let max_data = queue.size() as usize-2;
+		let seg_max = if seg_max == 0 {max_data} else {(seg_max as usize).min(max_data)};
+		let size_max = if size_max == 0 {u32::MAX as usize} else {size_max as usize};
+		let disk = Self{
+			name:format!("vd{}", (b'a'+NEXT_INDEX.fetch_add(1, Ordering::Relaxed) as u8) as char),
+			transport,
+			queue,
+			irqs,
+			sectors,
+			block_size:block_size as usize,
+			read_only:features & F_RO != 0,
+			has_flush:features & F_FLUSH != 0,
+			size_max,
+			seg_max,
+		};
+		log::info!("Virtio block device {} is {}{}", dev.address, disk.name,
+			if disk.irqs.is_none() {", polling"} else {""});
+		Some(disk)
+	}
+
+	///The largest chunk, whose buffer is guaranteed to fit into seg_max data buffers.
+	fn max_chunk(&self)->usize{
+		let per_segment = self.size_max.min(PAGE_SIZE);
+		//An unaligned buffer touches one page more, than its size needs.
+		let max = if per_segment == PAGE_SIZE{
+			self.seg_max.saturating_sub(1)*PAGE_SIZE
+		}else{
+			self.seg_max.saturating_sub(2)/2*per_segment
+		};
+		let max = max.min(MAX_CHUNK);
+		(max-max%self.block_size).max(self.block_size)
+	}
+
+	///Submits a single request.
+	///`data` is (pointer, length), that stays valid, until the request completes.
+	fn submit(&self, request_type:u32, sector:u64, data:Option<(*const u8, usize)>)->Result<(u64, DmaBuffer), BlockError>{
+		let header = DmaBuffer::new(HEADER_SIZE+1).ok_or(BlockError::NoMemory)?;
+		header.write::<u32>(0, request_type);
+		header.write::<u64>(8, sector);
+		header.write::<u8>(STATUS_OFFSET, 0xFF);
+		let mut buffers = Vec::with_capacity(self.seg_max+2);
+		buffers.push(Buffer{phys:header.phys(), len:HEADER_SIZE as u32, device_writable:false});
+		if let Some((ptr, len)) = data{
+			let segments = phys_segments(ptr, len).ok_or(BlockError::Io)?;
+			for (mut phys, mut len) in segments{
+				while len > 0{
+					let piece = len.min(self.size_max);
+					buffers.push(Buffer{phys, len:piece as u32, device_writable:request_type == T_IN});
+					phys += piece as u64;
+					len -= piece;
+				}
+			}
+			if buffers.len()-1 > self.seg_max{
+				log::error!("{}: request has too many segments", self.name);
+				return Err(BlockError::Io);
+			}
+		}
+		buffers.push(Buffer{phys:header.phys()+STATUS_OFFSET as u64, len:1, device_writable:true});
+		let id = self.queue.submit(&buffers);
+		Ok((id, header))
+	}
+
+	///Waits for a request and checks its status.
+	fn complete(&self, id:u64, header:DmaBuffer)->Result<(), BlockError>{
+		self.queue.wait_for(id);
+		match header.read::<u8>(STATUS_OFFSET){
+			S_OK=>Ok(()),
+			S_UNSUPP=>Err(BlockError::Unsupported),
+			status=>{
+				log::error!("{}: request failed with status {}", self.name, status);
+				Err(BlockError::Io)
+			},
+		}
+	}
+
+	fn transfer(&self, request_type:u32, lba:u64, ptr:*const u8, len:usize)->Result<(), BlockError>{
+		block::check_request(self, lba, len)?;
+		let chunk = self.max_chunk();
+		let mut pending = Vec::new();
+		let mut result = Ok(());
+		let mut offset = 0;
+		while offset < len{
+			let size = chunk.min(len-offset);
+			let sector = (lba*self.block_size as u64+offset as u64)/SECTOR_SIZE;
+			//Safety:
+			//offset+size is inside the buffer.
+			let data = unsafe{ptr.add(offset)};
+			match self.submit(request_type, sector, Some((data, size))){
+				Ok(p)=>pending.push(p),
+				Err(e)=>{
+					result = Err(e);
+					break;
+				},
+			}
+			offset += size;
+		}
+		//Even after an error, the submitted requests have to finish, before the buffer can be given back.
+		for (id, header) in pending{
+			let r = self.complete(id, header);
+			if result.is_ok(){
+				result = r;
+			}
+		}
+		result
+	}
+}
+
+impl BlockDevice for VirtioBlk{
+	fn name(&self)->&str{
+		&self.name
+	}
+
+	fn block_size(&self)->usize{
+		self.block_size
+	}
+
+	fn block_count(&self)->u64{
+		self.sectors*SECTOR_SIZE/self.block_size as u64
+	}
+
+	fn is_read_only(&self)->bool{
+		self.read_only
+	}
+
+	fn read_blocks(&self, lba:u64, buf:&mut [u8])->Result<(), BlockError>{
+		self.transfer(T_IN, lba, buf.as_mut_ptr(), buf.len())
+	}
+
+	fn write_blocks(&self, lba:u64, buf:&[u8])->Result<(), BlockError>{
+		if self.read_only{
+			return Err(BlockError::ReadOnly);
+		}
+		self.transfer(T_OUT, lba, buf.as_ptr(), buf.len())
+	}
+
+	fn flush(&self)->Result<(), BlockError>{
+		//Without the flush feature, the device writes through.
+		if !self.has_flush{
+			return Ok(());
+		}
+		let (id, header) = self.submit(T_FLUSH, 0, None)?;
+		self.complete(id, header)
+	}
+}
+
+impl Drop for VirtioBlk{
+	fn drop(&mut self){
+		self.transport.reset();
+		if let Some(irqs) = &self.irqs{
+			super::detach_irq(irqs.vector(0));
+		}
+	}
+}
@@ -0,0 +1,247 @@
+//The virtio PCI transport.
+//The device describes where its register blocks are with vendor specific capabilities.
+use alloc::sync::Arc;
+use x86_64::PhysAddr;
+use crate::drivers::pci::{capability, Bar, PciDevice};
+use super::queue::{self, VirtQueue};
+use super::{STATUS_FAILED, STATUS_FEATURES_OK};
+
+const CFG_TYPE_COMMON:u8 = 1;
+const CFG_TYPE_NOTIFY:u8 = 2;
+const CFG_TYPE_ISR:u8 = 3;
+const CFG_TYPE_DEVICE:u8 = 4;
+
+const COMMON_DEVICE_FEATURE_SELECT:usize = 0x00;
+const COMMON_DEVICE_FEATURE:usize = 0x04;
+const COMMON_DRIVER_FEATURE_SELECT:usize = 0x08;
+const COMMON_DRIVER_FEATURE:usize = 0x0C;
+const COMMON_MSIX_CONFIG:usize = 0x10;
+const COMMON_NUM_QUEUES:usize = 0x12;
+const COMMON_DEVICE_STATUS:usize = 0x14;
+const COMMON_CONFIG_GENERATION:usize = 0x15;
+const COMMON_QUEUE_SELECT:usize = 0x16;
+const COMMON_QUEUE_SIZE:usize = 0x18;
+const COMMON_QUEUE_MSIX_VECTOR:usize = 0x1A;
+const COMMON_QUEUE_ENABLE:usize = 0x1C;
+const COMMON_QUEUE_NOTIFY_OFF:usize = 0x1E;
+const COMMON_QUEUE_DESC:usize = 0x20;
+const COMMON_QUEUE_DRIVER:usize = 0x28;
+const COMMON_QUEUE_DEVICE:usize = 0x30;
+
+///Written to an MSI-X vector register to not use an interrupt.
+pub const NO_VECTOR:u16 = 0xFFFF;
+
+///Resetting should be quick. This bounds the wait for a broken device.
+const RESET_POLLS:usize = 1_000_000;
+
+///A memory mapped register block.
+#[derive(Debug, Copy, Clone)]
+struct Region{
+	///Virtual address.
+	base:usize,
+	len:usize,
+}
+
+impl Region{
+	fn read<T:Copy>(&self, offset:usize)->T{
+		debug_assert!(offset+core::mem::size_of::<T>() <= self.len);
+		//Safety:
+		//The region is mapped device memory, and the registers are naturally aligned.
+		unsafe{core::ptr::read_volatile((self.base+offset) as *const T)}
+	}
+
+	fn write<T:Copy>(&self, offset:usize, value:T){
+		debug_assert!(offset+core::mem::size_of::<T>() <= self.len);
+		//Safety:
+		//See read.
+		unsafe{core::ptr::write_volatile((self.base+offset) as *mut T, value)}
+	}
+
+	///64 bit registers are written as two halves, low half first.
+	fn write64(&self, offset:usize, value:u64){
+		self.write::<u32>(offset, value as u32);
+		self.write::<u32>(offset+4, (value>>32) as u32);
+	}
+}
+
+///The registers of a modern virtio PCI device.
+pub struct Transport{
+	dev:Arc<PciDevice>,
+	common:Region,
+	notify:Region,
+	notify_multiplier:u32,
+	isr:Region,
+	device:Option<Region>,
+}
+
+impl Transport{
+	///Finds the register blocks of the device.
+	///Returns None for legacy only devices.
+	pub fn new(dev:&Arc<PciDevice>)->Option<Self>{
+		let mut common = None;
+		let mut notify = None;
+		let mut isr = None;
+		let mut device = None;
+		for cap in dev.capabilities.iter().filter(|c|c.id == capability::ID_VENDOR){
+			let cfg_type = dev.read8(cap.offset+3);
+			let region = match Self::region(dev, cap.offset){
+				Some(r)=>r,
+				None=>continue,
+			};
+			//The spec allows several capabilities of the same type. The first one is preferred.
+			match cfg_type{
+				CFG_TYPE_COMMON=>{common.get_or_insert(region);},
+				CFG_TYPE_NOTIFY=>{notify.get_or_insert((region, dev.read32(cap.offset+16)));},
+				CFG_TYPE_ISR=>{isr.get_or_insert(region);},
+				CFG_TYPE_DEVICE=>{device.get_or_insert(region);},
+				_=>{},
+			}
+		}
+		let (notify, notify_multiplier) = notify?;
+		dev.enable_memory_and_bus_master();
+		Some(Self{dev:dev.clone(), common:common?, notify, notify_multiplier, isr:isr?, device})
+	}
+
+	fn region(dev:&PciDevice, cap:u16)->Option<Region>{
+		let bar = dev.read8(cap+4);
+		if bar > 5{
+			return None;
+		}
+		let offset = dev.read32(cap+8) as u64;
+		let len = dev.read32(cap+12) as u64;
+		match dev.bar(bar as usize)?{
+			Bar::Memory{address, size, ..} if offset+len <= size=>Some(Region{
+				base:crate::x86_64::mem::phys_to_virt(PhysAddr::new(address+offset)).as_u64() as usize,
+				len:len as usize,
+			}),
+			_=>None,
+		}
+	}
+
+	pub fn device(&self)->&Arc<PciDevice>{
+		&self.dev
+	}
+
+	pub fn status(&self)->u8{
+		self.common.read::<u8>(COMMON_DEVICE_STATUS)
+	}
+
+	///Sets additional status bits.
+	pub fn add_status(&self, bits:u8){
+		self.common.write::<u8>(COMMON_DEVICE_STATUS, self.status() | bits);
+	}
+
+	///Resets the device and waits for the reset to finish.
+	pub fn reset(&self)->bool{
+		self.common.write::<u8>(COMMON_DEVICE_STATUS, 0);
+		for _ in 0..RESET_POLLS{
+			if self.status() == 0{
+				return true;
+			}
+			core::hint::spin_loop();
+		}
+		log::error!("Virtio device {} didn't reset.", self.dev.address);
+		false
+	}
+
+	pub fn fail(&self){
+		self.add_status(STATUS_FAILED);
+	}
+
+	fn device_features(&self)->u64{
+		self.common.write::<u32>(COMMON_DEVICE_FEATURE_SELECT, 0);
+		let low = self.common.read::<u32>(COMMON_DEVICE_FEATURE) as u64;
+		self.common.write::<u32>(COMMON_DEVICE_FEATURE_SELECT, 1);
+		let high = self.common.read::<u32>(COMMON_DEVICE_FEATURE) as u64;
+		high<<32 | low
+	}
+
+	///Accepts the features in `wanted`, that the device offers, and sets FEATURES_OK.
+	///`required` features have to be offered. VIRTIO_F_VERSION_1 always is.
+	///Returns the negotiated features, or None, if the device doesn't accept them.
+	pub fn negotiate(&self, wanted:u64, required:u64)->Option<u64>{
+		let required = required | super::F_VERSION_1;
+		let offered = self.device_features();
+		if offered & required != required{
+			log::error!("Virtio device {} lacks features {:#x}", self.dev.address, required & !offered);
+			return None;
+		}
+		let features = offered & (wanted | required);
+		self.common.write::<u32>(COMMON_DRIVER_FEATURE_SELECT, 0);
+		self.common.write::<u32>(COMMON_DRIVER_FEATURE, features as u32);
+		self.common.write::<u32>(COMMON_DRIVER_FEATURE_SELECT, 1);
+		self.common.write::<u32>(COMMON_DRIVER_FEATURE, (features>>32) as u32);
+		self.add_status(STATUS_FEATURES_OK);
+		if self.status() & STATUS_FEATURES_OK == 0{
+			log::error!("Virtio device {} rejected features {:#x}", self.dev.address, features);
+			return None;
+		}
+		Some(features)
+	}
+
+	pub fn num_queues(&self)->u16{
+		self.common.read::<u16>(COMMON_NUM_QUEUES)
+	}
+
+	///We don't use configuration change interrupts.
+	pub fn disable_config_interrupt(&self){
+		self.common.write::<u16>(COMMON_MSIX_CONFIG, NO_VECTOR);
+	}
+
+	///Sets up and enables queue `index`, with at most queue::MAX_SIZE entries.
+	///`msix_entry` is the MSI-X table entry for its interrupts. Without one, the queue is polled.
+	pub fn setup_queue(&self, index:u16, msix_entry:Option<u16>)->Option<VirtQueue>{
+		self.common.write::<u16>(COMMON_QUEUE_SELECT, index);
+		let max = self.common.read::<u16>(COMMON_QUEUE_SIZE);
+		if max == 0{
+			return None;
+		}
+		//Split queue sizes are powers of two, so the smaller one is too.
+		let size = max.min(queue::MAX_SIZE);
+		let notify_off = self.common.read::<u16>(COMMON_QUEUE_NOTIFY_OFF) as usize;
+		let notify_offset = notify_off*self.notify_multiplier as usize;
+		if notify_offset+2 > self.notify.len{
+			log::error!("Virtio device {} has its notification register for queue {} out of bounds", self.dev.address, index);
+			return None;
+		}
+		let mut polling = true;
+		if let Some(entry) = msix_entry{
+			self.common.write::<u16>(COMMON_QUEUE_MSIX_VECTOR, entry);
+			if self.common.read::<u16>(COMMON_QUEUE_MSIX_VECTOR) == entry{
+				polling = false;
+			}else{
+				log::warn!("Virtio device {} can't use MSI-X for queue {}. Polling instead.", self.dev.address, index);
+				self.common.write::<u16>(COMMON_QUEUE_MSIX_VECTOR, NO_VECTOR);
+			}
+		}
+		let queue = VirtQueue::new(index, size, self.notify.base+notify_offset, polling)?;
+		let (desc, driver, device) = queue.addresses();
+		self.common.write::<u16>(COMMON_QUEUE_SIZE, size);
+		self.common.write64(COMMON_QUEUE_DESC, desc);
+		self.common.write64(COMMON_QUEUE_DRIVER, driver);
+		self.common.write64(COMMON_QUEUE_DEVICE, device);
+		self.common.write::<u16>(COMMON_QUEUE_ENABLE, 1);
+		Some(queue)
+	}
+
+	///Reads the interrupt status. Reading it also acknowledges it.
+	pub fn read_isr(&self)->u8{
+		self.isr.read::<u8>(0)
+	}
+
+	///Changes, whenever the device changes its device specific configuration.
+	///Reads, that span several registers, have to be repeated, if it changed in between.
+	pub fn config_generation(&self)->u8{
+		self.common.read::<u8>(COMMON_CONFIG_GENERATION)
+	}
+
+	///Reads a dword of the device specific configuration.
+	///Returns None, if the device has no such field.
+	pub fn read_config32(&self, offset:usize)->Option<u32>{
+		let device = self.device?;
+		if offset+4 > device.len{
+			return None;
+		}
+		Some(device.read::<u32>(offset))
+	}
+}
@@ -0,0 +1,242 @@
+//Split virtqueues.
+//Every part of the queue sits in its own DmaBuffer, which limits the queue to MAX_SIZE entries.
+//Requests are chains of descriptors. Their descriptors go back to the free list, as soon as the device is done with them.
+//The result waits in `results` until the submitter picks it up, so many requests can be in flight at once.
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::sync::atomic::{fence, Ordering};
+use x86_64::instructions::interrupts;
+use crate::lock::{Lock, LockClass};
+use crate::sync::WaitQueue;
+use crate::x86_64::dma::DmaBuffer;
+
+///A descriptor table of this size fills exactly one page.
+pub const MAX_SIZE:u16 = 256;
+
+const DESC_SIZE:usize = 16;
+const DESC_ADDR:usize = 0;
+const DESC_LEN:usize = 8;
+const DESC_FLAGS:usize = 12;
+const DESC_NEXT:usize = 14;
+const DESC_F_NEXT:u16 = 1;
+const DESC_F_WRITE:u16 = 2;
+
+const AVAIL_IDX:usize = 2;
+const AVAIL_RING:usize = 4;
+
+const USED_IDX:usize = 2;
+const USED_RING:usize = 4;
+const USED_ELEM_SIZE:usize = 8;
+
+///A physically contiguous piece of a request.
+#[derive(Debug, Copy, Clone)]
+pub struct Buffer{
+	pub phys:u64,
+	pub len:u32,
+	///The device writes into this buffer, instead of reading it.
+	pub device_writable:bool,
+}
+
+struct State{
+	///Free descriptors are linked through their next field.
+	free_head:u16,
+	num_free:u16,
+	avail_idx:u16,
+	last_used_idx:u16,
+	///The request id of every descriptor chain in flight, by its head.
+	heads:Vec<Option<u64>>,
+	next_id:u64,
+	///The bytes written by the device, for every completed request, that wasn't picked up yet.
+	results:BTreeMap<u64, u32>,
+}
+
+pub struct VirtQueue{
+	index:u16,
+	size:u16,
+	desc:DmaBuffer,
+	avail:DmaBuffer,
+	used:DmaBuffer,
+	///Virtual address of the notification register of this queue.
+	notify:usize,
+	///Without interrupts, waiting tasks check the used ring themselves.
+	polling:bool,
+	state:Lock<State>,
+	waiters:WaitQueue,
+}
+
+static QUEUE_CLASS:LockClass = LockClass::new("virtio::queue");
+
+impl VirtQueue{
+	///Allocates the rings for a queue with `size` entries. `size` has to be a power of two and at most MAX_SIZE.
+	pub fn new(index:u16, size:u16, notify:usize, polling:bool)->Option<Self>{
+		if size == 0 || size > MAX_SIZE || !size.is_power_of_two(){
+			return None;
+		}
+		let n = size as usize;
+		let desc = DmaBuffer::new(DESC_SIZE*n)?;
+		let avail = DmaBuffer::new(6+2*n)?;
+		let used = DmaBuffer::new(6+USED_ELEM_SIZE*n)?;
+		for i in 0..size{
+			desc.write::<u16>(i as usize*DESC_SIZE+DESC_NEXT, (i+1)%size);
+		}
+		Some(Self{
+			index,
+			size,
+			desc,
+			avail,
+			used,
+			notify,
+			polling,
+			state:Lock::with_class(State{
+				free_head:0,
+				num_free:size,
+				avail_idx:0,
+				last_used_idx:0,
+				heads:alloc::vec![None;n],
+				next_id:0,
+				results:BTreeMap::new(),
+			}, &QUEUE_CLASS),
+			waiters:WaitQueue::new(),
+		})
+	}
+
+	pub fn index(&self)->u16{
+		self.index
+	}
+
+	pub fn size(&self)->u16{
+		self.size
+	}
+
+	///Physical addresses of the descriptor table, available ring and used ring.
+	pub fn addresses(&self)->(u64, u64, u64){
+		(self.desc.phys(), self.avail.phys(), self.used.phys())
+	}
+
+	///Hands a request to the device. Device readable buffers have to come before device writable ones.
+	///Waits for free descriptors, if the queue is full.
+	///Returns an id for wait.
+	pub fn submit(&self, buffers:&[Buffer])->u64{
+		assert!(!buffers.is_empty() && buffers.len() <= self.size as usize);
+		let mut id = 0;
+		self.wait(|state|{
+			if (state.num_free as usize) < buffers.len(){
+				return false;
+			}
+			id = self.push(state, buffers);
+			true
+		});
+		//The device has to see the new avail index, before we notify it.
+		fence(Ordering::SeqCst);
+		//Safety:
+		//notify points to the notification register of this queue, which is at least 16 bits wide.
+		unsafe{core::ptr::write_volatile(self.notify as *mut u16, self.index)};
+		id
+	}
+
+	fn push(&self, state:&mut State, buffers:&[Buffer])->u64{
+		let head = state.free_head;
+		let mut i = head;
+		for (n, buf) in buffers.iter().enumerate(){
+			let offset = i as usize*DESC_SIZE;
+			let mut flags = if buf.device_writable {DESC_F_WRITE} else {0};
+			if n+1 < buffers.len(){
+				flags |= DESC_F_NEXT;
+			}
+			self.desc.write::<u64>(offset+DESC_ADDR, buf.phys);
+			self.desc.write::<u32>(offset+DESC_LEN, buf.len);
+			self.desc.write::<u16>(offset+DESC_FLAGS, flags);
+			if n+1 < buffers.len(){
+				i = self.desc.read::<u16>(offset+DESC_NEXT);
+			}
+		}
+		state.free_head = self.desc.read::<u16>(i as usize*DESC_SIZE+DESC_NEXT);
+		state.num_free -= buffers.len() as u16;
+		let id = state.next_id;
+		state.next_id += 1;
+		state.heads[head as usize] = Some(id);
+		let slot = (state.avail_idx%self.size) as usize;
+		self.avail.write::<u16>(AVAIL_RING+2*slot, head);
+		//The ring entry has to be visible, before the index, that makes it available.
+		fence(Ordering::SeqCst);
+		state.avail_idx = state.avail_idx.wrapping_add(1);
+		self.avail.write::<u16>(AVAIL_IDX, state.avail_idx);
+		id
+	}
+
+	///Waits for the request to complete.
+	///Returns the amount of bytes, that the device wrote.
+	pub fn wait_for(&self, id:u64)->u32{
+		let mut len = 0;
+		self.wait(|state|match state.results.remove(&id){
+			Some(l)=>{
+				len = l;
+				true
+			},
+			None=>false,
+		});
+		len
+	}
+
+	///Blocks, until `f` returns true. `f` runs with the queue locked.
+	fn wait<F:FnMut(&mut State)->bool>(&self, mut f:F){
+		if !self.polling{
+			self.waiters.wait_until(||f(&mut self.state.lock()));
+			return;
+		}
+		loop{
+			let done = interrupts::without_interrupts(||{
+				let mut state = self.state.lock();
+				self.collect_used(&mut state);
+				f(&mut state)
+			});
+			if done{
+				return;
+			}
+			crate::sched::yield_now();
+		}
+	}
+
+	///Moves completed requests from the used ring to the results and frees their descriptors.
+	///Returns true, if there were any.
+	fn collect_used(&self, state:&mut State)->bool{
+		let used_idx = self.used.read::<u16>(USED_IDX);
+		//The used elements are only valid after reading the index.
+		fence(Ordering::Acquire);
+		let mut any = false;
+		while state.last_used_idx != used_idx{
+			let slot = (state.last_used_idx%self.size) as usize;
+			let head = self.used.read::<u32>(USED_RING+slot*USED_ELEM_SIZE) as u16;
+			let len = self.used.read::<u32>(USED_RING+slot*USED_ELEM_SIZE+4);
+			state.last_used_idx = state.last_used_idx.wrapping_add(1);
+			let id = match state.heads.get_mut(head as usize).and_then(|h|h.take()){
+				Some(id)=>id,
+				None=>{
+					log::warn!("Virtio queue {} returned descriptor {}, which wasn't in flight", self.index, head);
+					continue;
+				},
+			};
+			//Put the whole chain back on the free list.
+			let mut i = head;
+			let mut count = 1;
+			while self.desc.read::<u16>(i as usize*DESC_SIZE+DESC_FLAGS) & DESC_F_NEXT != 0{
+				i = self.desc.read::<u16>(i as usize*DESC_SIZE+DESC_NEXT);
+				count += 1;
+			}
+			self.desc.write::<u16>(i as usize*DESC_SIZE+DESC_NEXT, state.free_head);
+			state.free_head = head;
+			state.num_free += count;
+			state.results.insert(id, len);
+			any = true;
+		}
+		any
+	}
+
+	///Called from the interrupt handler of the queue.
+	pub fn handle_interrupt(&self){
+		let any = self.collect_used(&mut self.state.lock());
+		if any{
+			self.waiters.wake_all();
+		}
+	}
+}
@@ -2,6 +2,8 @@ use core::ops::{BitXor, Div, Mul};
 use core::ptr::slice_from_raw_parts;
 use kernel_efi::gop::{PixelFormat,PixelBitmask};
 
+pub mod console;
+
 ///PS is PixelSize
 pub struct FB<'a,'b,const PS:usize>{
 	pub(crate) args:kernel_efi::Args<'a,'b>,
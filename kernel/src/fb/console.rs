@@ -0,0 +1,207 @@
+//A text console on the framebuffer, for the panic handler.
+//Glyphs are rasterized from the outlines of the font, that the boot loader passed, without anti-aliasing.
+//It works without the heap and without locks, and writes over whatever is on the screen.
+use core::fmt::Write;
+use kernel_efi::ttf_parser::{Face, OutlineBuilder};
+
+///Height of a line in pixels.
+const LINE_HEIGHT:usize = 16;
+///Glyphs with more edges get cut off.
+const MAX_EDGES:usize = 256;
+///Line segments per curve.
+const CURVE_STEPS:usize = 6;
+const WHITE:u32 = 0x00FF_FFFF;
+const BLACK:u32 = 0;
+
+///The edges of a glyph outline, in pixels relative to the top left corner of its cell.
+struct Edges{
+	edges:[(f32, f32, f32, f32);MAX_EDGES],
+	len:usize,
+	scale:f32,
+	baseline:f32,
+	start:(f32, f32),
+	last:(f32, f32),
+}
+
+impl Edges{
+	fn point(&self, x:f32, y:f32)->(f32, f32){
+		(x*self.scale, self.baseline-y*self.scale)
+	}
+
+	fn push(&mut self, to:(f32, f32)){
+		let from = self.last;
+		self.last = to;
+		//Horizontal edges never cross a scanline.
+		if from.1 == to.1 || self.len == MAX_EDGES{
+			return;
+		}
+		self.edges[self.len] = (from.0, from.1, to.0, to.1);
+		self.len += 1;
+	}
+
+	///Whether the pixel center at x, y is inside of the outline, by the nonzero winding rule.
+	fn inside(&self, x:f32, y:f32)->bool{
+		let mut winding = 0;
+		for &(x0, y0, x1, y1) in &self.edges[..self.len]{
+			if (y0 <= y) == (y1 <= y){
+				continue;
+			}
+			let cross = x0+(y-y0)/(y1-y0)*(x1-x0);
+			if cross > x{
+				winding += if y1 > y0 {1} else {-1};
+			}
+		}
+		winding != 0
+	}
+}
+
+impl OutlineBuilder for Edges{
+	fn move_to(&mut self, x:f32, y:f32){
+		self.start = self.point(x, y);
+		self.last = self.start;
+	}
+
+	fn line_to(&mut self, x:f32, y:f32){
+		let to = self.point(x, y);
+		self.push(to);
+	}
+
+	fn quad_to(&mut self, x1:f32, y1:f32, x:f32, y:f32){
+		let p0 = self.last;
+		let p1 = self.point(x1, y1);
+		let p2 = self.point(x, y);
+		for i in 1..=CURVE_STEPS{
+			let t = i as f32/CURVE_STEPS as f32;
+			let u = 1.0-t;
+			self.push((u*u*p0.0+2.0*u*t*p1.0+t*t*p2.0, u*u*p0.1+2.0*u*t*p1.1+t*t*p2.1));
+		}
+	}
+
+	fn curve_to(&mut self, x1:f32, y1:f32, x2:f32, y2:f32, x:f32, y:f32){
+		let p0 = self.last;
+		let p1 = self.point(x1, y1);
+		let p2 = self.point(x2, y2);
+		let p3 = self.point(x, y);
+		for i in 1..=CURVE_STEPS{
+			let t = i as f32/CURVE_STEPS as f32;
+			let u = 1.0-t;
+			let (a, b, c, d) = (u*u*u, 3.0*u*u*t, 3.0*u*t*t, t*t*t);
+			self.push((a*p0.0+b*p1.0+c*p2.0+d*p3.0, a*p0.1+b*p1.1+c*p2.1+d*p3.1));
+		}
+	}
+
+	fn close(&mut self){
+		let start = self.start;
+		self.push(start);
+	}
+}
+
+pub struct Console{
+	fb:*mut u32,
+	width:usize,
+	height:usize,
+	///Pixels per row in memory.
+	stride:usize,
+	font:&'static Face<'static>,
+	scale:f32,
+	baseline:f32,
+	x:usize,
+	y:usize,
+}
+
+impl Console{
+	///Takes over the framebuffer, that the boot loader set up, and starts writing at the top.
+	///None, if there is no font or framebuffer, that can be used.
+	///Safety:
+	///Only meant for panics. Anything else drawing at the same time gets overwritten.
+	pub unsafe fn steal()->Option<Self>{
+		//The boot loader left the Args there, and nothing writes to them.
+		let args = &*kernel_efi::ARGS_ADDR;
+		let info = args.gop.mode.info();
+		let (width, height) = info.resolution();
+		let stride = info.stride();
+		if args.gop.fb.base.is_null() || height < LINE_HEIGHT || args.gop.fb.size < stride*height*4{
+			return None;
+		}
+		let font = &args.font;
+		let font_height = font.height() as f32;
+		if font_height <= 0.0{
+			return None;
+		}
+		let scale = LINE_HEIGHT as f32/font_height;
+		Some(Self{
+			fb:args.gop.fb.base as *mut u32,
+			width,
+			height,
+			stride,
+			font,
+			scale,
+			baseline:font.ascender() as f32*scale,
+			x:0,
+			y:0,
+		})
+	}
+
+	fn put(&mut self, x:usize, y:usize, color:u32){
+		if x < self.width && y < self.height{
+			//Safety:
+			//The pixel is inside of the framebuffer, as checked in steal.
+			unsafe{core::ptr::write_volatile(self.fb.add(y*self.stride+x), color)};
+		}
+	}
+
+	///Moves to the next line, and scrolls everything up by a line at the bottom.
+	fn newline(&mut self){
+		self.x = 0;
+		if self.y+2*LINE_HEIGHT <= self.height{
+			self.y += LINE_HEIGHT;
+			return;
+		}
+		//Safety:
+		//Both ranges are inside of the framebuffer. copy handles the overlap.
+		unsafe{core::ptr::copy(self.fb.add(LINE_HEIGHT*self.stride), self.fb, self.y*self.stride)};
+		for y in self.y..self.y+LINE_HEIGHT{
+			for x in 0..self.width{
+				self.put(x, y, BLACK);
+			}
+		}
+	}
+
+	fn draw(&mut self, c:char){
+		let font = self.font;
+		let Some(glyph) = font.glyph_index(c).or_else(||font.glyph_index('?')) else{
+			return;
+		};
+		let advance = (font.glyph_hor_advance(glyph).unwrap_or(0) as f32*self.scale+0.5) as usize;
+		if self.x+advance > self.width{
+			self.newline();
+		}
+		let mut edges = Edges{edges:[(0.0, 0.0, 0.0, 0.0);MAX_EDGES], len:0, scale:self.scale, baseline:self.baseline, start:(0.0, 0.0), last:(0.0, 0.0)};
+		//Spaces have no outline, and are only background.
+		font.outline_glyph(glyph, &mut edges);
+		for py in 0..LINE_HEIGHT{
+			for px in 0..advance{
+				let color = if edges.inside(px as f32+0.5, py as f32+0.5) {WHITE} else {BLACK};
+				self.put(self.x+px, self.y+py, color);
+			}
+		}
+		self.x += advance;
+	}
+}
+
+impl Write for Console{
+	fn write_str(&mut self, s:&str)->core::fmt::Result{
+		for c in s.chars(){
+			match c{
+				'\n'=>self.newline(),
+				'\t'=>{
+					for _ in 0..4{
+						self.draw(' ');
+					}
+				},
+				c=>self.draw(c),
+			}
+		}
+		Ok(())
+	}
+}
@@ -0,0 +1,110 @@
+//Filesystems, and the virtual filesystem on top of them.
+//Every filesystem is mounted somewhere in one tree, and everything else uses paths in that tree.
+//Paths are always absolute, since there is no working directory yet.
+pub mod fat;
+pub mod tmpfs;
+pub mod initramfs;
+mod vnode;
+//Most of the file and mount API has no callers yet. It is there for system calls, once there are processes.
+#[allow(dead_code)]
+mod mount;
+#[allow(dead_code)]
+mod path;
+#[allow(dead_code)]
+mod file;
+
+use core::fmt;
+use crate::block::BlockError;
+
+pub use vnode::{DirEntry, Directory, File, Filesystem, Metadata, Node, NodeKind, Symlink, Vnode};
+pub use mount::mount;
+pub use file::create_dir;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum FsError{
+	NotFound,
+	AlreadyExists,
+	NotADirectory,
+	IsADirectory,
+	///A directory, that should be removed, still has entries.
+	NotEmpty,
+	///The name can't be stored in this filesystem.
+	InvalidName,
+	///The filesystem or directory is full.
+	NoSpace,
+	ReadOnly,
+	///The file would get bigger, than the filesystem allows.
+	FileTooBig,
+	///The on-disk structures don't make sense.
+	Corrupted,
+	///The filesystem uses something, that we don't support, or the operation isn't supported by the filesystem.
+	Unsupported,
+	///Following symlinks didn't end.
+	SymlinkLoop,
+	///The file is in use, or something is mounted on it.
+	Busy,
+	InvalidArgument,
+	///The file descriptor isn't open, or wasn't opened for the operation.
+	BadFileDescriptor,
+	Io(BlockError),
+}
+
+impl From<BlockError> for FsError{
+	fn from(e:BlockError)->Self{
+		match e{
+			BlockError::ReadOnly=>Self::ReadOnly,
+			e=>Self::Io(e),
+		}
+	}
+}
+
+impl fmt::Display for FsError{
+	fn fmt(&self, f:&mut fmt::Formatter<'_>)->fmt::Result{
+		match self{
+			Self::NotFound=>f.write_str("not found"),
+			Self::AlreadyExists=>f.write_str("already exists"),
+			Self::NotADirectory=>f.write_str("not a directory"),
+			Self::IsADirectory=>f.write_str("is a directory"),
+			Self::NotEmpty=>f.write_str("directory not empty"),
+			Self::InvalidName=>f.write_str("invalid name"),
+			Self::NoSpace=>f.write_str("no space left"),
+			Self::ReadOnly=>f.write_str("read only filesystem"),
+			Self::FileTooBig=>f.write_str("file too big"),
+			Self::Corrupted=>f.write_str("filesystem corrupted"),
+			Self::Unsupported=>f.write_str("not supported"),
+			Self::SymlinkLoop=>f.write_str("too many levels of symbolic links"),
+			Self::Busy=>f.write_str("busy"),
+			Self::InvalidArgument=>f.write_str("invalid argument"),
+			Self::BadFileDescriptor=>f.write_str("bad file descriptor"),
+			Self::Io(e)=>write!(f, "I/O error: {}", e),
+		}
+	}
+}
+
+///Sets up the root filesystem, mounts the initial ramdisk at /initrd, and the ESP, that we booted from, at /boot.
+///Needs the block devices to be registered.
+pub fn init(initrd:Option<&'static [u8]>){
+	mount("/", tmpfs::TmpFs::new()).expect("can't mount the root filesystem");
+	if let Some(initrd) = initrd{
+		let result = initramfs::InitramFs::new(initrd).and_then(|fs|{
+			create_dir("/initrd")?;
+			mount("/initrd", fs)
+		});
+		match result{
+			Ok(())=>log::info!("Mounted the initrd ({} bytes) at /initrd", initrd.len()),
+			Err(e)=>log::error!("Can't mount the initrd: {}", e),
+		}
+	}
+	let Some(esp) = crate::block::esp() else{
+		log::warn!("No EFI system partition. Nothing is mounted at /boot.");
+		return;
+	};
+	let result = fat::FatFs::mount(esp.clone()).and_then(|fat|{
+		create_dir("/boot")?;
+		mount("/boot", fat::FatVfs::new(fat))
+	});
+	match result{
+		Ok(())=>log::info!("Mounted {} at /boot", esp.name()),
+		Err(e)=>log::error!("Can't mount {} at /boot: {}", esp.name(), e),
+	}
+}
@@ -0,0 +1,424 @@
+//FAT12, FAT16 and FAT32, with long file names.
+//All I/O goes through a block cache, and one lock serializes all operations on a filesystem.
+//Files are described by Nodes, which the caller keeps, and passes back for every operation.
+//A Node has to be updated, when its file changes, so there should be only one Node for every open file.
+mod bpb;
+mod dir;
+mod table;
+mod vnode;
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use crate::block::cache::BlockCache;
+use crate::block::BlockDevice;
+use crate::sync::Mutex;
+use super::FsError;
+use self::bpb::Bpb;
+use self::dir::{RawEntry, ATTR_DIRECTORY, ATTR_READ_ONLY};
+
+pub use self::bpb::FatType;
+pub use self::vnode::FatVfs;
+
+///The amount of sectors, that are cached for every filesystem.
+const CACHE_BLOCKS:usize = 1024;
+
+///Where the entries of a directory are.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum DirLocation{
+	///The root directory of FAT12 and FAT16, which sits between the FATs and the data clusters.
+	FixedRoot,
+	Cluster(u32),
+}
+
+///A file or directory.
+#[derive(Debug, Clone)]
+pub struct Node{
+	first_cluster:u32,
+	size:u32,
+	attributes:u8,
+	///Where the directory entry is on the disk, in bytes. None for the root directory.
+	entry:Option<u64>,
+}
+
+impl Node{
+	pub fn is_dir(&self)->bool{
+		self.attributes & ATTR_DIRECTORY != 0
+	}
+
+	///The size in bytes. Always 0 for directories.
+	pub fn size(&self)->u64{
+		self.size as u64
+	}
+
+	pub fn is_read_only(&self)->bool{
+		self.attributes & ATTR_READ_ONLY != 0
+	}
+
+	///A number, that is unique for every file on the filesystem, as long as it exists.
+	pub fn id(&self)->u64{
+		self.entry.unwrap_or(0)
+	}
+
+	pub fn is_root(&self)->bool{
+		self.entry.is_none()
+	}
+}
+
+pub struct DirEntry{
+	pub name:String,
+	pub node:Node,
+}
+
+struct State{
+	///The amount of free clusters, if we know it.
+	free_count:Option<u32>,
+	///Where the search for a free cluster starts.
+	next_free:u32,
+	///The volume has a valid FSInfo sector.
+	has_fsinfo:bool,
+	///free_count or next_free changed since FSInfo was written.
+	fsinfo_dirty:bool,
+}
+
+pub struct FatFs{
+	name:String,
+	cache:BlockCache,
+	bpb:Bpb,
+	read_only:bool,
+	state:Mutex<State>,
+}
+
+impl FatFs{
+	///Mounts the FAT filesystem on the device.
+	pub fn mount(dev:Arc<dyn BlockDevice>)->Result<Arc<Self>, FsError>{
+		let mut sector = alloc::vec![0;dev.block_size()];
+		dev.read_blocks(0, &mut sector)?;
+		let bpb = Bpb::parse(&sector)?;
+		if bpb.bytes_per_sector as usize != dev.block_size(){
+			log::warn!("{}: FAT sectors of {} bytes on a device with {} byte blocks", dev.name(), bpb.bytes_per_sector, dev.block_size());
+			return Err(FsError::Unsupported);
+		}
+		if bpb.total_sectors as u64 > dev.block_count(){
+			log::warn!("{}: the filesystem is bigger than the device", dev.name());
+			return Err(FsError::Corrupted);
+		}
+		let fs = Self{
+			name:String::from(dev.name()),
+			read_only:dev.is_read_only(),
+			cache:BlockCache::new(dev, CACHE_BLOCKS),
+			bpb,
+			state:Mutex::new(State{free_count:None, next_free:2, has_fsinfo:false, fsinfo_dirty:false}),
+		};
+		{
+			let mut state = fs.state.lock();
+			fs.read_fsinfo(&mut state)?;
+			if state.free_count.is_none(){
+				state.free_count = Some(fs.count_free()?);
+			}
+			log::info!("{}: {:?}, {} of {} clusters of {} bytes free", fs.name, bpb.fat_type,
+				state.free_count.unwrap_or(0), bpb.cluster_count, bpb.cluster_size());
+		}
+		Ok(Arc::new(fs))
+	}
+
+	pub fn fat_type(&self)->FatType{
+		self.bpb.fat_type
+	}
+
+	pub fn cluster_size(&self)->u32{
+		self.bpb.cluster_size()
+	}
+
+	pub fn is_read_only(&self)->bool{
+		self.read_only
+	}
+
+	///The amount of free bytes.
+	pub fn free_space(&self)->u64{
+		self.state.lock().free_count.unwrap_or(0) as u64*self.bpb.cluster_size() as u64
+	}
+
+	pub fn root(&self)->Node{
+		Node{first_cluster:self.bpb.root_cluster, size:0, attributes:ATTR_DIRECTORY, entry:None}
+	}
+
+	fn dir_location(&self, node:&Node)->Result<DirLocation, FsError>{
+		if !node.is_dir(){
+			return Err(FsError::NotADirectory);
+		}
+		Ok(match node.first_cluster{
+			0 if self.bpb.fat_type != bpb::FatType::Fat32=>DirLocation::FixedRoot,
+			0=>DirLocation::Cluster(self.bpb.root_cluster),
+			c=>DirLocation::Cluster(c),
+		})
+	}
+
+	fn node(entry:&RawEntry)->Node{
+		Node{
+			first_cluster:entry.first_cluster,
+			size:if entry.attributes & ATTR_DIRECTORY != 0 {0} else {entry.size},
+			attributes:entry.attributes,
+			entry:Some(entry.position()),
+		}
+	}
+
+	fn find_entry(&self, dir:&Node, name:&str)->Result<RawEntry, FsError>{
+		let slots = self.load_dir(self.dir_location(dir)?)?;
+		slots.entries().into_iter().find(|e|e.matches(name)).ok_or(FsError::NotFound)
+	}
+
+	///Reads the contents of a directory. `.` and `..` are left out.
+	pub fn read_dir(&self, dir:&Node)->Result<Vec<DirEntry>, FsError>{
+		let _state = self.state.lock();
+		let slots = self.load_dir(self.dir_location(dir)?)?;
+		Ok(slots.entries().into_iter().map(|e|DirEntry{node:Self::node(&e), name:e.name}).collect())
+	}
+
+	///Finds an entry in a directory by its long or short name, ignoring case.
+	///`.` and `..` aren't found, since the root directory doesn't have them.
+	pub fn lookup(&self, dir:&Node, name:&str)->Result<Node, FsError>{
+		let _state = self.state.lock();
+		self.find_entry(dir, name).map(|e|Self::node(&e))
+	}
+
+	///Finds a file by its path from the root directory.
+	pub fn open(&self, path:&str)->Result<Node, FsError>{
+		let mut stack = alloc::vec![self.root()];
+		for part in path.split('/'){
+			match part{
+				""|"."=>(),
+				".."=>{
+					if stack.len() > 1{
+						stack.pop();
+					}
+				},
+				name=>{
+					let next = self.lookup(stack.last().unwrap(), name)?;
+					stack.push(next);
+				},
+			}
+		}
+		Ok(stack.pop().unwrap())
+	}
+
+	///Reads from `offset` into `buf`. Returns the amount of bytes read, which is less than requested at the end of the file.
+	pub fn read(&self, node:&Node, offset:u64, buf:&mut [u8])->Result<usize, FsError>{
+		if node.is_dir(){
+			return Err(FsError::IsADirectory);
+		}
+		if offset >= node.size as u64{
+			return Ok(0);
+		}
+		let len = buf.len().min((node.size as u64-offset) as usize);
+		let _state = self.state.lock();
+		let chain = self.chain(node.first_cluster)?;
+		self.transfer_clusters(&chain, offset, len, |pos, range|self.read_bytes(pos, &mut buf[range]))?;
+		Ok(len)
+	}
+
+	///Calls `f` with the disk position and buffer range for every cluster piece of the file range.
+	fn transfer_clusters(&self, chain:&[u32], offset:u64, len:usize, mut f:impl FnMut(u64, core::ops::Range<usize>)->Result<(), FsError>)->Result<(), FsError>{
+		let cluster_size = self.bpb.cluster_size() as u64;
+		let mut done = 0;
+		while done < len{
+			let pos = offset+done as u64;
+			let cluster = *chain.get((pos/cluster_size) as usize).ok_or(FsError::Corrupted)?;
+			let within = pos%cluster_size;
+			let n = ((cluster_size-within) as usize).min(len-done);
+			f(self.bpb.cluster_start(cluster)*self.bpb.bytes_per_sector as u64+within, done..done+n)?;
+			done += n;
+		}
+		Ok(())
+	}
+
+	fn check_writable(&self, node:&Node)->Result<(), FsError>{
+		if self.read_only{
+			return Err(FsError::ReadOnly);
+		}
+		if node.is_dir(){
+			return Err(FsError::IsADirectory);
+		}
+		Ok(())
+	}
+
+	///Makes the chain long enough for `size` bytes.
+	fn extend_chain(&self, state:&mut State, node:&mut Node, chain:&mut Vec<u32>, size:u64)->Result<(), FsError>{
+		let needed = size.div_ceil(self.bpb.cluster_size() as u64) as usize;
+		if needed > chain.len(){
+			let new = self.allocate(state, needed-chain.len(), chain.last().copied())?;
+			if chain.is_empty(){
+				node.first_cluster = new[0];
+			}
+			chain.extend(new);
+		}
+		Ok(())
+	}
+
+	///Grows the file to `size` bytes, and fills the new part with zeroes.
+	fn grow(&self, state:&mut State, node:&mut Node, chain:&mut Vec<u32>, size:u64)->Result<(), FsError>{
+		self.extend_chain(state, node, chain, size)?;
+		let zeroes = alloc::vec![0;self.bpb.cluster_size() as usize];
+		let old = node.size as u64;
+		self.transfer_clusters(chain, old, (size-old) as usize, |pos, range|self.write_bytes(pos, &zeroes[..range.len()]))?;
+		node.size = size as u32;
+		Ok(())
+	}
+
+	///Writes `buf` at `offset`, growing the file, if needed. A gap between the end of the file and `offset` is filled with zeroes.
+	pub fn write(&self, node:&mut Node, offset:u64, buf:&[u8])->Result<usize, FsError>{
+		self.check_writable(node)?;
+		let end = offset.checked_add(buf.len() as u64).filter(|e|*e <= u32::MAX as u64).ok_or(FsError::FileTooBig)?;
+		let mut state = self.state.lock();
+		let mut chain = self.chain(node.first_cluster)?;
+		if offset > node.size as u64{
+			self.grow(&mut state, node, &mut chain, offset)?;
+		}
+		self.extend_chain(&mut state, node, &mut chain, end)?;
+		self.transfer_clusters(&chain, offset, buf.len(), |pos, range|self.write_bytes(pos, &buf[range]))?;
+		node.size = node.size.max(end as u32);
+		self.update_entry(node.entry.unwrap(), node.first_cluster, node.size)?;
+		self.write_fsinfo(&mut state)?;
+		Ok(buf.len())
+	}
+
+	///Cuts the file off at `size`, or grows it with zeroes.
+	pub fn set_size(&self, node:&mut Node, size:u64)->Result<(), FsError>{
+		self.check_writable(node)?;
+		if size > u32::MAX as u64{
+			return Err(FsError::FileTooBig);
+		}
+		let mut state = self.state.lock();
+		let mut chain = self.chain(node.first_cluster)?;
+		if size > node.size as u64{
+			self.grow(&mut state, node, &mut chain, size)?;
+		}else{
+			let keep = size.div_ceil(self.bpb.cluster_size() as u64) as usize;
+			if keep == 0{
+				if node.first_cluster != 0{
+					self.free_chain(&mut state, node.first_cluster)?;
+				}
+				node.first_cluster = 0;
+			}else if keep < chain.len(){
+				self.truncate_chain(&mut state, chain[keep-1])?;
+			}
+		}
+		node.size = size as u32;
+		self.update_entry(node.entry.unwrap(), node.first_cluster, node.size)?;
+		self.write_fsinfo(&mut state)
+	}
+
+	///Creates an empty file or directory.
+	pub fn create(&self, dir:&Node, name:&str, directory:bool)->Result<Node, FsError>{
+		if self.read_only{
+			return Err(FsError::ReadOnly);
+		}
+		let location = self.dir_location(dir)?;
+		let mut state = self.state.lock();
+		let entry = if directory{
+			let cluster = self.allocate(&mut state, 1, None)?[0];
+			//`..` points at cluster 0 for the root directory, even on FAT32.
+			let parent = if dir.is_root() {0} else {dir.first_cluster};
+			let entry = self.init_dir_cluster(cluster, parent)
+				.and_then(|_|self.add_entry(&mut state, location, name, ATTR_DIRECTORY, cluster));
+			if entry.is_err(){
+				self.free_chain(&mut state, cluster)?;
+			}
+			entry?
+		}else{
+			self.add_entry(&mut state, location, name, dir::ATTR_ARCHIVE, 0)?
+		};
+		self.write_fsinfo(&mut state)?;
+		Ok(Self::node(&entry))
+	}
+
+	///Deletes a file, or an empty directory.
+	pub fn remove(&self, dir:&Node, name:&str)->Result<(), FsError>{
+		if self.read_only{
+			return Err(FsError::ReadOnly);
+		}
+		let mut state = self.state.lock();
+		let entry = self.find_entry(dir, name)?;
+		let node = Self::node(&entry);
+		if node.is_dir() && !self.load_dir(self.dir_location(&node)?)?.entries().is_empty(){
+			return Err(FsError::NotEmpty);
+		}
+		self.remove_entry(&entry)?;
+		if node.first_cluster != 0{
+			self.free_chain(&mut state, node.first_cluster)?;
+		}
+		self.write_fsinfo(&mut state)
+	}
+
+	///Writes everything back to the disk.
+	pub fn sync(&self)->Result<(), FsError>{
+		if self.read_only{
+			return Ok(());
+		}
+		let mut state = self.state.lock();
+		self.write_fsinfo(&mut state)?;
+		self.cache.flush()?;
+		Ok(())
+	}
+
+	fn zero_cluster(&self, cluster:u32)->Result<(), FsError>{
+		let zeroes = alloc::vec![0;self.bpb.cluster_size() as usize];
+		self.write_bytes(self.bpb.cluster_start(cluster)*self.bpb.bytes_per_sector as u64, &zeroes)
+	}
+
+	///Reads bytes at any offset through the cache.
+	fn read_bytes(&self, offset:u64, buf:&mut [u8])->Result<(), FsError>{
+		let block_size = self.bpb.bytes_per_sector as u64;
+		let mut done = 0;
+		let mut block = alloc::vec![0;block_size as usize];
+		while done < buf.len(){
+			let pos = offset+done as u64;
+			let within = (pos%block_size) as usize;
+			let left = buf.len()-done;
+			if within == 0 && left >= block_size as usize{
+				//Whole blocks go straight into the buffer.
+				let n = left-left%block_size as usize;
+				self.cache.read_blocks(pos/block_size, &mut buf[done..done+n])?;
+				done += n;
+				continue;
+			}
+			self.cache.read_blocks(pos/block_size, &mut block)?;
+			let n = (block_size as usize-within).min(left);
+			buf[done..done+n].copy_from_slice(&block[within..within+n]);
+			done += n;
+		}
+		Ok(())
+	}
+
+	///Writes bytes at any offset through the cache. Partial blocks are read first.
+	fn write_bytes(&self, offset:u64, buf:&[u8])->Result<(), FsError>{
+		let block_size = self.bpb.bytes_per_sector as u64;
+		let mut done = 0;
+		let mut block = alloc::vec![0;block_size as usize];
+		while done < buf.len(){
+			let pos = offset+done as u64;
+			let within = (pos%block_size) as usize;
+			let left = buf.len()-done;
+			if within == 0 && left >= block_size as usize{
+				let n = left-left%block_size as usize;
+				self.cache.write_blocks(pos/block_size, &buf[done..done+n])?;
+				done += n;
+				continue;
+			}
+			self.cache.read_blocks(pos/block_size, &mut block)?;
+			let n = (block_size as usize-within).min(left);
+			block[within..within+n].copy_from_slice(&buf[done..done+n]);
+			self.cache.write_blocks(pos/block_size, &block)?;
+			done += n;
+		}
+		Ok(())
+	}
+}
+
+impl Drop for FatFs{
+	fn drop(&mut self){
+		if let Err(e) = self.sync(){
+			log::error!("{}: can't write back the filesystem: {}", self.name, e);
+		}
+	}
+}
@@ -0,0 +1,151 @@
+//The BIOS parameter block in the boot sector, which describes the layout of the filesystem.
+use crate::fs::FsError;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum FatType{
+	Fat12,
+	Fat16,
+	Fat32,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct Bpb{
+	pub fat_type:FatType,
+	pub bytes_per_sector:u32,
+	pub sectors_per_cluster:u32,
+	pub reserved_sectors:u32,
+	pub fat_count:u32,
+	///Sectors per FAT.
+	pub fat_size:u32,
+	///The amount of entries in the fixed root directory of FAT12 and FAT16.
+	pub root_entries:u32,
+	pub total_sectors:u32,
+	///The amount of data clusters. Clusters are numbered from 2 to cluster_count+1.
+	pub cluster_count:u32,
+	///The first root directory cluster on FAT32.
+	pub root_cluster:u32,
+	///The FSInfo sector on FAT32. 0, if there is none.
+	pub fsinfo_sector:u32,
+	///FAT32 can turn off mirroring, and use just one FAT.
+	pub active_fat:Option<u32>,
+}
+
+const BOOT_SIGNATURE:usize = 510;
+///Every valid FAT32 volume has fewer clusters than this.
+const MAX_FAT32_CLUSTERS:u32 = 0x0FFF_FFF5;
+
+impl Bpb{
+	pub fn parse(sector:&[u8])->Result<Self, FsError>{
+		if sector.len() < 512 || sector[BOOT_SIGNATURE] != 0x55 || sector[BOOT_SIGNATURE+1] != 0xAA{
+			return Err(FsError::Corrupted);
+		}
+		let u16_at = |i:usize|u16::from_le_bytes([sector[i], sector[i+1]]) as u32;
+		let u32_at = |i:usize|u32::from_le_bytes([sector[i], sector[i+1], sector[i+2], sector[i+3]]);
+		let bytes_per_sector = u16_at(11);
+		let sectors_per_cluster = sector[13] as u32;
+		let reserved_sectors = u16_at(14);
+		let fat_count = sector[16] as u32;
+		let root_entries = u16_at(17);
+		let total_sectors = if u16_at(19) != 0 {u16_at(19)} else {u32_at(32)};
+		let fat_size = if u16_at(22) != 0 {u16_at(22)} else {u32_at(36)};
+		if !(512..=4096).contains(&bytes_per_sector) || !bytes_per_sector.is_power_of_two()
+			|| sectors_per_cluster == 0 || !sectors_per_cluster.is_power_of_two()
+			|| reserved_sectors == 0 || fat_count == 0 || fat_size == 0{
+			return Err(FsError::Corrupted);
+		}
+		let root_sectors = (root_entries*32).div_ceil(bytes_per_sector);
+		let data_start = reserved_sectors as u64+fat_count as u64*fat_size as u64+root_sectors as u64;
+		if data_start >= total_sectors as u64{
+			return Err(FsError::Corrupted);
+		}
+		let cluster_count = (total_sectors-data_start as u32)/sectors_per_cluster;
+		//The type only depends on the amount of clusters. That is how Microsoft's driver does it, and so everybody else.
+		let fat_type = match cluster_count{
+			0..=4084=>FatType::Fat12,
+			4085..=65524=>FatType::Fat16,
+			_=>FatType::Fat32,
+		};
+		let mut bpb = Self{
+			fat_type,
+			bytes_per_sector,
+			sectors_per_cluster,
+			reserved_sectors,
+			fat_count,
+			fat_size,
+			root_entries,
+			total_sectors,
+			cluster_count,
+			root_cluster:0,
+			fsinfo_sector:0,
+			active_fat:None,
+		};
+		if fat_type == FatType::Fat32{
+			if root_entries != 0 || cluster_count >= MAX_FAT32_CLUSTERS{
+				return Err(FsError::Corrupted);
+			}
+			if u16_at(42) != 0{
+				//Only version 0.0 exists.
+				return Err(FsError::Unsupported);
+			}
+			let flags = u16_at(40);
+			if flags & 0x80 != 0{
+				bpb.active_fat = Some((flags & 0xF).min(fat_count-1));
+			}
+			bpb.root_cluster = u32_at(44);
+			if !bpb.is_valid_cluster(bpb.root_cluster){
+				return Err(FsError::Corrupted);
+			}
+			let fsinfo = u16_at(48);
+			if fsinfo != 0 && fsinfo != 0xFFFF && fsinfo < reserved_sectors{
+				bpb.fsinfo_sector = fsinfo;
+			}
+		}else if root_entries == 0{
+			return Err(FsError::Corrupted);
+		}
+		//The FAT needs an entry for every cluster.
+		let entries = fat_size as u64*bytes_per_sector as u64*2/bpb.entry_size_halves() as u64;
+		if entries < cluster_count as u64+2{
+			return Err(FsError::Corrupted);
+		}
+		Ok(bpb)
+	}
+
+	///The size of a FAT entry in half bytes.
+	fn entry_size_halves(&self)->u32{
+		match self.fat_type{
+			FatType::Fat12=>3,
+			FatType::Fat16=>4,
+			FatType::Fat32=>8,
+		}
+	}
+
+	pub fn cluster_size(&self)->u32{
+		self.bytes_per_sector*self.sectors_per_cluster
+	}
+
+	pub fn is_valid_cluster(&self, cluster:u32)->bool{
+		cluster >= 2 && cluster < self.cluster_count+2
+	}
+
+	///The first sector of the FAT with the given index.
+	pub fn fat_start(&self, index:u32)->u64{
+		self.reserved_sectors as u64+index as u64*self.fat_size as u64
+	}
+
+	///The first sector of the fixed root directory of FAT12 and FAT16.
+	pub fn root_dir_start(&self)->u64{
+		self.fat_start(self.fat_count)
+	}
+
+	pub fn root_dir_sectors(&self)->u32{
+		(self.root_entries*32).div_ceil(self.bytes_per_sector)
+	}
+
+	pub fn data_start(&self)->u64{
+		self.root_dir_start()+self.root_dir_sectors() as u64
+	}
+
+	pub fn cluster_start(&self, cluster:u32)->u64{
+		self.data_start()+(cluster-2) as u64*self.sectors_per_cluster as u64
+	}
+}
@@ -0,0 +1,400 @@
+//Directories: 32 byte entries with a 8.3 short name each, optionally preceded by long file name (LFN) entries.
+use alloc::collections::BTreeSet;
+use alloc::string::String;
+use alloc::vec::Vec;
+use crate::fs::FsError;
+use crate::time::rtc::DateTime;
+use super::{DirLocation, FatFs, State};
+
+pub const ENTRY_SIZE:usize = 32;
+pub const ATTR_READ_ONLY:u8 = 0x01;
+pub const ATTR_HIDDEN:u8 = 0x02;
+pub const ATTR_SYSTEM:u8 = 0x04;
+pub const ATTR_VOLUME_ID:u8 = 0x08;
+pub const ATTR_DIRECTORY:u8 = 0x10;
+pub const ATTR_ARCHIVE:u8 = 0x20;
+pub const ATTR_LONG_NAME:u8 = ATTR_READ_ONLY | ATTR_HIDDEN | ATTR_SYSTEM | ATTR_VOLUME_ID;
+
+///The first name byte of a deleted entry.
+const DELETED:u8 = 0xE5;
+///A first name byte of 0xE5 is stored as this, since 0xE5 means deleted.
+const KANJI_E5:u8 = 0x05;
+const LFN_LAST:u8 = 0x40;
+const LFN_CHARS:usize = 13;
+///Where the 13 UTF-16 characters of a LFN entry are.
+const LFN_CHAR_OFFSETS:[usize;LFN_CHARS] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+const MAX_NAME_LEN:usize = 255;
+///Windows NT stores the case of all-lowercase short name parts in these bits.
+const CASE_LOWER_BASE:u8 = 0x08;
+const CASE_LOWER_EXT:u8 = 0x10;
+///FAT limits directories to 65536 entries.
+const MAX_DIR_ENTRIES:usize = 65536;
+
+///A directory entry, as found on the disk.
+#[derive(Debug, Clone)]
+pub struct RawEntry{
+	pub name:String,
+	pub short_name:[u8;11],
+	pub attributes:u8,
+	pub first_cluster:u32,
+	pub size:u32,
+	///Where all slots of the entry are on the disk, in bytes. The short name entry comes last.
+	pub slots:Vec<u64>,
+}
+
+impl RawEntry{
+	///Where the short name entry is on the disk.
+	pub fn position(&self)->u64{
+		*self.slots.last().unwrap()
+	}
+
+	///Files can be found by their long and by their short name.
+	pub fn matches(&self, name:&str)->bool{
+		names_equal(&self.name, name) || names_equal(&short_name_to_string(&self.short_name, 0), name)
+	}
+}
+
+///All slots of a directory.
+pub struct Dir{
+	///Where every slot is on the disk, in bytes.
+	positions:Vec<u64>,
+	data:Vec<u8>,
+}
+
+impl Dir{
+	fn slot(&self, i:usize)->&[u8]{
+		&self.data[i*ENTRY_SIZE..(i+1)*ENTRY_SIZE]
+	}
+
+	fn len(&self)->usize{
+		self.positions.len()
+	}
+
+	///Parses all entries, except for the volume label and the `.` and `..` entries.
+	pub fn entries(&self)->Vec<RawEntry>{
+		let mut entries = Vec::new();
+		//The long name, that is being collected, with its checksum, the next expected ordinal and the slots so far.
+		let mut lfn:Option<(Vec<u16>, u8, u8, Vec<u64>)> = None;
+		for i in 0..self.len(){
+			let slot = self.slot(i);
+			match slot[0]{
+				0=>break,
+				DELETED=>{
+					lfn = None;
+					continue;
+				},
+				_=>(),
+			}
+			let attributes = slot[11];
+			if attributes & 0x3F == ATTR_LONG_NAME{
+				let ordinal = slot[0] & !LFN_LAST;
+				if slot[0] & LFN_LAST != 0{
+					lfn = (1..=20).contains(&ordinal).then(||(alloc::vec![0xFFFF;ordinal as usize*LFN_CHARS], slot[13], ordinal, Vec::new()));
+				}
+				if let Some((chars, checksum, expected, slots)) = &mut lfn{
+					if ordinal != *expected || slot[13] != *checksum{
+						lfn = None;
+						continue;
+					}
+					let start = (ordinal as usize-1)*LFN_CHARS;
+					for (n, offset) in LFN_CHAR_OFFSETS.iter().enumerate(){
+						chars[start+n] = u16::from_le_bytes([slot[*offset], slot[offset+1]]);
+					}
+					*expected -= 1;
+					slots.push(self.positions[i]);
+				}
+				continue;
+			}
+			let long_name = lfn.take();
+			if attributes & ATTR_VOLUME_ID != 0 || slot[0] == b'.'{
+				continue;
+			}
+			let mut short_name = [0;11];
+			short_name.copy_from_slice(&slot[..11]);
+			if short_name[0] == KANJI_E5{
+				short_name[0] = DELETED;
+			}
+			let mut slots = Vec::new();
+			let name = match long_name{
+				Some((chars, checksum, 0, lfn_slots)) if checksum == lfn_checksum(&short_name)=>{
+					slots = lfn_slots;
+					char::decode_utf16(chars.into_iter().take_while(|c|*c != 0 && *c != 0xFFFF))
+						.map(|c|c.unwrap_or(char::REPLACEMENT_CHARACTER))
+						.collect()
+				},
+				_=>short_name_to_string(&short_name, slot[12]),
+			};
+			slots.push(self.positions[i]);
+			let u16_at = |o:usize|u16::from_le_bytes([slot[o], slot[o+1]]) as u32;
+			entries.push(RawEntry{
+				name,
+				short_name,
+				attributes,
+				first_cluster:u16_at(20)<<16 | u16_at(26),
+				size:u32::from_le_bytes([slot[28], slot[29], slot[30], slot[31]]),
+				slots,
+			});
+		}
+		entries
+	}
+
+	///Finds `count` consecutive free slots. Returns the index of the first one.
+	fn free_run(&self, count:usize)->Option<usize>{
+		let mut run = 0;
+		for i in 0..self.len(){
+			let first = self.slot(i)[0];
+			if first == 0{
+				//Everything after the end marker is free as well.
+				return (self.len()-i+run >= count).then_some(i-run);
+			}
+			if first == DELETED{
+				run += 1;
+				if run == count{
+					return Some(i+1-count);
+				}
+			}else{
+				run = 0;
+			}
+		}
+		None
+	}
+}
+
+fn short_name_to_string(short:&[u8;11], case:u8)->String{
+	let part = |bytes:&[u8], lower:bool|->String{
+		let s = bytes.iter().map(|b|if b.is_ascii() {*b as char} else {char::REPLACEMENT_CHARACTER}).collect::<String>();
+		let s = String::from(s.trim_end_matches(' '));
+		if lower {s.to_ascii_lowercase()} else {s}
+	};
+	let base = part(&short[..8], case & CASE_LOWER_BASE != 0);
+	let ext = part(&short[8..], case & CASE_LOWER_EXT != 0);
+	if ext.is_empty() {base} else {alloc::format!("{}.{}", base, ext)}
+}
+
+pub fn lfn_checksum(short:&[u8;11])->u8{
+	short.iter().fold(0u8, |sum, b|sum.rotate_right(1).wrapping_add(*b))
+}
+
+///Names are compared like Windows does it: ignoring case.
+pub fn names_equal(a:&str, b:&str)->bool{
+	a.chars().flat_map(char::to_lowercase).eq(b.chars().flat_map(char::to_lowercase))
+}
+
+///Checks, that the name can be stored as a long file name.
+pub fn validate_name(name:&str)->Result<(), FsError>{
+	if name.is_empty() || name == "." || name == ".." || name.encode_utf16().count() > MAX_NAME_LEN
+		|| name.ends_with(['.', ' '])
+		|| name.chars().any(|c|c < ' ' || "\"*/:<>?\\|".contains(c)){
+		return Err(FsError::InvalidName);
+	}
+	Ok(())
+}
+
+fn is_short_name_char(c:char)->bool{
+	c.is_ascii_uppercase() || c.is_ascii_digit() || "$%'-_@~`!(){}^#&".contains(c)
+}
+
+///Makes up the short name for a new entry.
+///Returns the short name, the case bits, and whether a long name is needed as well.
+pub fn make_short_name(name:&str, existing:&BTreeSet<[u8;11]>)->Result<([u8;11], u8, bool), FsError>{
+	let (base, ext) = match name.rfind('.'){
+		Some(i) if i > 0=>(&name[..i], &name[i+1..]),
+		_=>(name, ""),
+	};
+	//Names, that fit into 8.3, and are all upper- or all lowercase per part, don't need a long name.
+	let fits = |part:&str, len:usize, optional:bool|part.len() <= len && (optional || !part.is_empty());
+	let single_case = |part:&str|part == part.to_ascii_uppercase() || part == part.to_ascii_lowercase();
+	let valid = |part:&str|part.chars().all(|c|is_short_name_char(c.to_ascii_uppercase()));
+	if fits(base, 8, false) && fits(ext, 3, true) && valid(base) && valid(ext) && single_case(base) && single_case(ext){
+		let mut short = [b' ';11];
+		short[..base.len()].copy_from_slice(base.to_ascii_uppercase().as_bytes());
+		short[8..8+ext.len()].copy_from_slice(ext.to_ascii_uppercase().as_bytes());
+		let mut case = 0;
+		if base.chars().any(|c|c.is_ascii_lowercase()){
+			case |= CASE_LOWER_BASE;
+		}
+		if ext.chars().any(|c|c.is_ascii_lowercase()){
+			case |= CASE_LOWER_EXT;
+		}
+		if existing.contains(&short){
+			return Err(FsError::AlreadyExists);
+		}
+		return Ok((short, case, false));
+	}
+	//Otherwise, it gets a mangled short name with a numeric tail, like LONGFI~1.TXT.
+	let convert = |part:&str, len:usize|->Vec<u8>{
+		part.chars().filter(|c|*c != ' ' && *c != '.').map(|c|{
+			let c = c.to_ascii_uppercase();
+			if is_short_name_char(c) {c as u8} else {b'_'}
+		}).take(len).collect()
+	};
+	let base = convert(base.trim_start_matches('.'), 8);
+	let ext = convert(ext, 3);
+	for n in 1..1_000_000u32{
+		let tail = alloc::format!("~{}", n);
+		let keep = base.len().min(8-tail.len());
+		let mut short = [b' ';11];
+		short[..keep].copy_from_slice(&base[..keep]);
+		short[keep..keep+tail.len()].copy_from_slice(tail.as_bytes());
+		short[8..8+ext.len()].copy_from_slice(&ext);
+		if !existing.contains(&short){
+			return Ok((short, 0, true));
+		}
+	}
+	Err(FsError::NoSpace)
+}
+
+///The LFN entries for a name, in the order they are stored.
+fn lfn_entries(name:&str, checksum:u8)->Vec<[u8;ENTRY_SIZE]>{
+	let mut chars:Vec<u16> = name.encode_utf16().collect();
+	let count = chars.len().div_ceil(LFN_CHARS);
+	//The name is terminated with a 0, if there is space, and padded with 0xFFFF.
+	if chars.len() < count*LFN_CHARS{
+		chars.push(0);
+	}
+	chars.resize(count*LFN_CHARS, 0xFFFF);
+	(1..=count).rev().map(|ordinal|{
+		let mut entry = [0;ENTRY_SIZE];
+		entry[0] = ordinal as u8 | if ordinal == count {LFN_LAST} else {0};
+		entry[11] = ATTR_LONG_NAME;
+		entry[13] = checksum;
+		let part = &chars[(ordinal-1)*LFN_CHARS..ordinal*LFN_CHARS];
+		for (c, offset) in part.iter().zip(LFN_CHAR_OFFSETS){
+			entry[offset..offset+2].copy_from_slice(&c.to_le_bytes());
+		}
+		entry
+	}).collect()
+}
+
+///The current time, in the FAT format. FAT can't store anything before 1980.
+pub fn timestamp()->(u16, u16){
+	let now = DateTime::from_unix(crate::time::wall_clock().as_secs());
+	if now.year < 1980{
+		return (0, 1<<5 | 1);
+	}
+	let time = (now.hour as u16)<<11 | (now.minute as u16)<<5 | (now.second as u16/2);
+	let date = (now.year-1980).min(127)<<9 | (now.month as u16)<<5 | now.day as u16;
+	(time, date)
+}
+
+fn short_entry(short:&[u8;11], attributes:u8, case:u8, first_cluster:u32)->[u8;ENTRY_SIZE]{
+	let mut entry = [0;ENTRY_SIZE];
+	entry[..11].copy_from_slice(short);
+	if entry[0] == DELETED{
+		entry[0] = KANJI_E5;
+	}
+	entry[11] = attributes;
+	entry[12] = case;
+	let (time, date) = timestamp();
+	//Creation, last access and last write time.
+	entry[14..16].copy_from_slice(&time.to_le_bytes());
+	entry[16..18].copy_from_slice(&date.to_le_bytes());
+	entry[18..20].copy_from_slice(&date.to_le_bytes());
+	entry[20..22].copy_from_slice(&((first_cluster>>16) as u16).to_le_bytes());
+	entry[22..24].copy_from_slice(&time.to_le_bytes());
+	entry[24..26].copy_from_slice(&date.to_le_bytes());
+	entry[26..28].copy_from_slice(&(first_cluster as u16).to_le_bytes());
+	entry
+}
+
+impl FatFs{
+	///Reads all slots of a directory.
+	pub(super) fn load_dir(&self, dir:DirLocation)->Result<Dir, FsError>{
+		let bytes_per_sector = self.bpb.bytes_per_sector as u64;
+		//Contiguous byte ranges, that make up the directory.
+		let ranges:Vec<(u64, usize)> = match dir{
+			DirLocation::FixedRoot=>alloc::vec![(self.bpb.root_dir_start()*bytes_per_sector, self.bpb.root_entries as usize*ENTRY_SIZE)],
+			DirLocation::Cluster(first)=>self.chain(first)?.into_iter()
+				.map(|c|(self.bpb.cluster_start(c)*bytes_per_sector, self.bpb.cluster_size() as usize))
+				.collect(),
+		};
+		let total = ranges.iter().map(|r|r.1).sum();
+		let mut data = alloc::vec![0;total];
+		let mut positions = Vec::with_capacity(total/ENTRY_SIZE);
+		let mut done = 0;
+		for (start, len) in ranges{
+			self.read_bytes(start, &mut data[done..done+len])?;
+			positions.extend((0..len/ENTRY_SIZE).map(|i|start+(i*ENTRY_SIZE) as u64));
+			done += len;
+		}
+		Ok(Dir{positions, data})
+	}
+
+	///Adds an entry with the given name to a directory, growing it, if needed.
+	///Returns the new entry.
+	pub(super) fn add_entry(&self, state:&mut State, dir:DirLocation, name:&str, attributes:u8, first_cluster:u32)->Result<RawEntry, FsError>{
+		validate_name(name)?;
+		let mut slots = self.load_dir(dir)?;
+		let entries = slots.entries();
+		if entries.iter().any(|e|e.matches(name)){
+			return Err(FsError::AlreadyExists);
+		}
+		let existing = entries.iter().map(|e|e.short_name).collect();
+		let (short, case, needs_lfn) = make_short_name(name, &existing)?;
+		let mut new:Vec<[u8;ENTRY_SIZE]> = if needs_lfn {lfn_entries(name, lfn_checksum(&short))} else {Vec::new()};
+		new.push(short_entry(&short, attributes, case, first_cluster));
+		let start = loop{
+			if let Some(start) = slots.free_run(new.len()){
+				break start;
+			}
+			let DirLocation::Cluster(first) = dir else{
+				//The root directory of FAT12 and FAT16 can't grow.
+				return Err(FsError::NoSpace);
+			};
+			if slots.len()+self.bpb.cluster_size() as usize/ENTRY_SIZE > MAX_DIR_ENTRIES{
+				return Err(FsError::NoSpace);
+			}
+			let last = *self.chain(first)?.last().unwrap();
+			let cluster = self.allocate(state, 1, Some(last))?[0];
+			self.zero_cluster(cluster)?;
+			slots = self.load_dir(dir)?;
+		};
+		let mut positions = Vec::with_capacity(new.len());
+		for (i, entry) in new.iter().enumerate(){
+			let position = slots.positions[start+i];
+			self.write_bytes(position, entry)?;
+			positions.push(position);
+		}
+		Ok(RawEntry{
+			name:String::from(name),
+			short_name:short,
+			attributes,
+			first_cluster,
+			size:0,
+			slots:positions,
+		})
+	}
+
+	///Marks all slots of the entry as deleted.
+	pub(super) fn remove_entry(&self, entry:&RawEntry)->Result<(), FsError>{
+		for slot in &entry.slots{
+			self.write_bytes(*slot, &[DELETED])?;
+		}
+		Ok(())
+	}
+
+	///Writes the first cluster and size of a file back to its entry, and updates the write time.
+	pub(super) fn update_entry(&self, position:u64, first_cluster:u32, size:u32)->Result<(), FsError>{
+		let mut entry = [0;ENTRY_SIZE];
+		self.read_bytes(position, &mut entry)?;
+		let (time, date) = timestamp();
+		entry[11] |= ATTR_ARCHIVE;
+		entry[18..20].copy_from_slice(&date.to_le_bytes());
+		entry[20..22].copy_from_slice(&((first_cluster>>16) as u16).to_le_bytes());
+		entry[22..24].copy_from_slice(&time.to_le_bytes());
+		entry[24..26].copy_from_slice(&date.to_le_bytes());
+		entry[26..28].copy_from_slice(&(first_cluster as u16).to_le_bytes());
+		entry[28..32].copy_from_slice(&size.to_le_bytes());
+		self.write_bytes(position, &entry)
+	}
+
+	///Fills a new directory cluster with the `.` and `..` entries.
+	pub(super) fn init_dir_cluster(&self, cluster:u32, parent:u32)->Result<(), FsError>{
+		self.zero_cluster(cluster)?;
+		let start = self.bpb.cluster_start(cluster)*self.bpb.bytes_per_sector as u64;
+		let dot = short_entry(b".          ", ATTR_DIRECTORY, 0, cluster);
+		let dotdot = short_entry(b"..         ", ATTR_DIRECTORY, 0, parent);
+		self.write_bytes(start, &dot)?;
+		self.write_bytes(start+ENTRY_SIZE as u64, &dotdot)
+	}
+}
@@ -0,0 +1,272 @@
+//The file allocation table, which links the clusters of a file into a chain, and the FSInfo sector of FAT32.
+use alloc::vec::Vec;
+use crate::fs::FsError;
+use super::bpb::FatType;
+use super::{FatFs, State};
+
+pub const FREE:u32 = 0;
+
+const FSINFO_LEAD_SIGNATURE:u32 = 0x4161_5252;
+const FSINFO_STRUCT_SIGNATURE:u32 = 0x6141_7272;
+const FSINFO_TRAIL_SIGNATURE:u32 = 0xAA55_0000;
+const FSINFO_FREE_COUNT:u64 = 488;
+const FSINFO_NEXT_FREE:u64 = 492;
+///FSInfo uses this for values, that it doesn't know.
+const FSINFO_UNKNOWN:u32 = 0xFFFF_FFFF;
+
+impl FatFs{
+	///The end of chain marker. Everything from `end_of_chain()-7` up also ends a chain.
+	fn end_of_chain(&self)->u32{
+		match self.bpb.fat_type{
+			FatType::Fat12=>0xFFF,
+			FatType::Fat16=>0xFFFF,
+			FatType::Fat32=>0x0FFF_FFFF,
+		}
+	}
+
+	///Where the entry of `cluster` starts in the FAT with the given index.
+	fn entry_offset(&self, cluster:u32, fat:u32)->u64{
+		let start = self.bpb.fat_start(fat)*self.bpb.bytes_per_sector as u64;
+		start+match self.bpb.fat_type{
+			FatType::Fat12=>cluster as u64*3/2,
+			FatType::Fat16=>cluster as u64*2,
+			FatType::Fat32=>cluster as u64*4,
+		}
+	}
+
+	fn fats(&self)->core::ops::Range<u32>{
+		match self.bpb.active_fat{
+			Some(fat)=>fat..fat+1,
+			None=>0..self.bpb.fat_count,
+		}
+	}
+
+	pub(super) fn fat_entry(&self, cluster:u32)->Result<u32, FsError>{
+		let offset = self.entry_offset(cluster, self.bpb.active_fat.unwrap_or(0));
+		Ok(match self.bpb.fat_type{
+			FatType::Fat12=>{
+				let mut b = [0;2];
+				self.read_bytes(offset, &mut b)?;
+				let v = u16::from_le_bytes(b) as u32;
+				if cluster & 1 != 0 {v>>4} else {v & 0xFFF}
+			},
+			FatType::Fat16=>{
+				let mut b = [0;2];
+				self.read_bytes(offset, &mut b)?;
+				u16::from_le_bytes(b) as u32
+			},
+			FatType::Fat32=>{
+				let mut b = [0;4];
+				self.read_bytes(offset, &mut b)?;
+				u32::from_le_bytes(b) & 0x0FFF_FFFF
+			},
+		})
+	}
+
+	///Writes the entry into every FAT, that is in use.
+	fn set_fat_entry(&self, cluster:u32, value:u32)->Result<(), FsError>{
+		for fat in self.fats(){
+			let offset = self.entry_offset(cluster, fat);
+			match self.bpb.fat_type{
+				FatType::Fat12=>{
+					let mut b = [0;2];
+					self.read_bytes(offset, &mut b)?;
+					let old = u16::from_le_bytes(b);
+					let new = if cluster & 1 != 0{
+						(old & 0x000F) | (value as u16)<<4
+					}else{
+						(old & 0xF000) | (value as u16 & 0xFFF)
+					};
+					self.write_bytes(offset, &new.to_le_bytes())?;
+				},
+				FatType::Fat16=>self.write_bytes(offset, &(value as u16).to_le_bytes())?,
+				FatType::Fat32=>{
+					//The upper 4 bits are reserved, and have to be kept.
+					let mut b = [0;4];
+					self.read_bytes(offset, &mut b)?;
+					let new = (u32::from_le_bytes(b) & 0xF000_0000) | (value & 0x0FFF_FFFF);
+					self.write_bytes(offset, &new.to_le_bytes())?;
+				},
+			}
+		}
+		Ok(())
+	}
+
+	///The cluster after `cluster` in its chain, or None at the end of the chain.
+	pub(super) fn next_cluster(&self, cluster:u32)->Result<Option<u32>, FsError>{
+		let next = self.fat_entry(cluster)?;
+		if next >= self.end_of_chain()-7{
+			return Ok(None);
+		}
+		//Free or bad clusters, and the reserved cluster numbers, can't be part of a chain.
+		if !self.bpb.is_valid_cluster(next){
+			log::warn!("{}: cluster {} links to invalid cluster {:#x}", self.name, cluster, next);
+			return Err(FsError::Corrupted);
+		}
+		Ok(Some(next))
+	}
+
+	///All clusters of the chain starting at `first`.
+	pub(super) fn chain(&self, first:u32)->Result<Vec<u32>, FsError>{
+		let mut chain = Vec::new();
+		if first == FREE{
+			return Ok(chain);
+		}
+		if !self.bpb.is_valid_cluster(first){
+			return Err(FsError::Corrupted);
+		}
+		let mut cluster = Some(first);
+		while let Some(c) = cluster{
+			//A chain can't be longer than the filesystem, without a loop.
+			if chain.len() > self.bpb.cluster_count as usize{
+				log::warn!("{}: the chain starting at cluster {} loops", self.name, first);
+				return Err(FsError::Corrupted);
+			}
+			chain.push(c);
+			cluster = self.next_cluster(c)?;
+		}
+		Ok(chain)
+	}
+
+	///Allocates `count` clusters, and links them after `prev`, if it is given.
+	///On failure, everything is given back, and `prev` is the end of its chain again.
+	pub(super) fn allocate(&self, state:&mut State, count:usize, prev:Option<u32>)->Result<Vec<u32>, FsError>{
+		let mut clusters = Vec::with_capacity(count);
+		let result = (||{
+			let mut last = prev;
+			for _ in 0..count{
+				let cluster = self.find_free(state)?;
+				self.set_fat_entry(cluster, self.end_of_chain())?;
+				clusters.push(cluster);
+				if let Some(last) = last{
+					self.set_fat_entry(last, cluster)?;
+				}
+				last = Some(cluster);
+				state.next_free = cluster+1;
+				state.free_count = state.free_count.map(|c|c.saturating_sub(1));
+			}
+			Ok(())
+		})();
+		if let Err(e) = result{
+			if let Some(prev) = prev{
+				self.set_fat_entry(prev, self.end_of_chain())?;
+			}
+			for cluster in clusters{
+				self.set_fat_entry(cluster, FREE)?;
+				state.free_count = state.free_count.map(|c|c+1);
+			}
+			return Err(e);
+		}
+		state.fsinfo_dirty = true;
+		Ok(clusters)
+	}
+
+	fn find_free(&self, state:&State)->Result<u32, FsError>{
+		if state.free_count == Some(0){
+			return Err(FsError::NoSpace);
+		}
+		let first = 2;
+		let end = self.bpb.cluster_count+2;
+		let start = if self.bpb.is_valid_cluster(state.next_free) {state.next_free} else {first};
+		for cluster in (start..end).chain(first..start){
+			if self.fat_entry(cluster)? == FREE{
+				return Ok(cluster);
+			}
+		}
+		Err(FsError::NoSpace)
+	}
+
+	///Frees every cluster of the chain starting at `first`.
+	pub(super) fn free_chain(&self, state:&mut State, first:u32)->Result<(), FsError>{
+		for cluster in self.chain(first)?{
+			self.set_fat_entry(cluster, FREE)?;
+			state.free_count = state.free_count.map(|c|c+1);
+		}
+		state.fsinfo_dirty = true;
+		Ok(())
+	}
+
+	///Makes `cluster` the end of its chain, and frees everything after it.
+	pub(super) fn truncate_chain(&self, state:&mut State, cluster:u32)->Result<(), FsError>{
+		if let Some(next) = self.next_cluster(cluster)?{
+			self.set_fat_entry(cluster, self.end_of_chain())?;
+			self.free_chain(state, next)?;
+		}
+		Ok(())
+	}
+
+	///Counts the free clusters by reading the whole FAT.
+	pub(super) fn count_free(&self)->Result<u32, FsError>{
+		let bytes_per_sector = self.bpb.bytes_per_sector as u64;
+		let start = self.bpb.fat_start(self.bpb.active_fat.unwrap_or(0))*bytes_per_sector;
+		let end = self.bpb.cluster_count+2;
+		let mut free = 0;
+		if self.bpb.fat_type == FatType::Fat12{
+			//Entries can span two sectors, and the FAT is small anyway.
+			for cluster in 2..end{
+				if self.fat_entry(cluster)? == FREE{
+					free += 1;
+				}
+			}
+			return Ok(free);
+		}
+		let entry_size = if self.bpb.fat_type == FatType::Fat16 {2} else {4};
+		let mut sector = alloc::vec![0;bytes_per_sector as usize];
+		let entries_per_sector = bytes_per_sector as u32/entry_size;
+		for s in 0..end.div_ceil(entries_per_sector){
+			self.read_bytes(start+s as u64*bytes_per_sector, &mut sector)?;
+			for (i, entry) in sector.chunks_exact(entry_size as usize).enumerate(){
+				let cluster = s*entries_per_sector+i as u32;
+				if cluster < 2 || cluster >= end{
+					continue;
+				}
+				let value = if entry_size == 2{
+					u16::from_le_bytes([entry[0], entry[1]]) as u32
+				}else{
+					u32::from_le_bytes([entry[0], entry[1], entry[2], entry[3]]) & 0x0FFF_FFFF
+				};
+				if value == FREE{
+					free += 1;
+				}
+			}
+		}
+		Ok(free)
+	}
+
+	///Reads the free cluster count and the next free hint from the FSInfo sector.
+	///The values are only hints, so anything, that doesn't look right, is ignored.
+	pub(super) fn read_fsinfo(&self, state:&mut State)->Result<(), FsError>{
+		if self.bpb.fsinfo_sector == 0{
+			return Ok(());
+		}
+		let mut sector = alloc::vec![0;self.bpb.bytes_per_sector as usize];
+		self.read_bytes(self.bpb.fsinfo_sector as u64*self.bpb.bytes_per_sector as u64, &mut sector)?;
+		let u32_at = |i:usize|u32::from_le_bytes([sector[i], sector[i+1], sector[i+2], sector[i+3]]);
+		if u32_at(0) != FSINFO_LEAD_SIGNATURE || u32_at(484) != FSINFO_STRUCT_SIGNATURE || u32_at(508) != FSINFO_TRAIL_SIGNATURE{
+			log::warn!("{}: invalid FSInfo sector", self.name);
+			return Ok(());
+		}
+		state.has_fsinfo = true;
+		let free_count = u32_at(FSINFO_FREE_COUNT as usize);
+		if free_count != FSINFO_UNKNOWN && free_count <= self.bpb.cluster_count{
+			state.free_count = Some(free_count);
+		}
+		let next_free = u32_at(FSINFO_NEXT_FREE as usize);
+		if self.bpb.is_valid_cluster(next_free){
+			state.next_free = next_free;
+		}
+		Ok(())
+	}
+
+	///Writes the free cluster count and next free hint back to the FSInfo sector, if they changed.
+	pub(super) fn write_fsinfo(&self, state:&mut State)->Result<(), FsError>{
+		if !state.has_fsinfo || !state.fsinfo_dirty{
+			return Ok(());
+		}
+		let base = self.bpb.fsinfo_sector as u64*self.bpb.bytes_per_sector as u64;
+		self.write_bytes(base+FSINFO_FREE_COUNT, &state.free_count.unwrap_or(FSINFO_UNKNOWN).to_le_bytes())?;
+		self.write_bytes(base+FSINFO_NEXT_FREE, &state.next_free.to_le_bytes())?;
+		state.fsinfo_dirty = false;
+		Ok(())
+	}
+}
@@ -0,0 +1,154 @@
+//Plugs FatFs into the VFS.
+//FAT Nodes have to be unique for every file, so the vnodes, that are in use, are kept in a table by their node id.
+use alloc::collections::BTreeMap;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use x86_64::instructions::interrupts;
+use crate::lock::{Lock, LockClass};
+use crate::sync::Mutex;
+use crate::fs::{DirEntry, Directory, File, Filesystem, FsError, Metadata, Node as VfsNode, NodeKind, Vnode};
+use super::{FatFs, FatType, Node};
+
+pub struct FatVfs{
+	fs:Arc<FatFs>,
+	this:Weak<FatVfs>,
+	vnodes:Lock<BTreeMap<u64, Weak<FatVnode>>>,
+}
+
+static VNODES_CLASS:LockClass = LockClass::new("fat::vnodes");
+
+impl FatVfs{
+	pub fn new(fs:Arc<FatFs>)->Arc<Self>{
+		Arc::new_cyclic(|this|Self{fs, this:this.clone(), vnodes:Lock::with_class(BTreeMap::new(), &VNODES_CLASS)})
+	}
+
+	///The vnode for a FAT node. If the file is already in use, that vnode is returned instead.
+	fn vnode(&self, node:Node)->VfsNode{
+		let id = node.id();
+		let is_dir = node.is_dir();
+		let vnode = interrupts::without_interrupts(||{
+			let mut vnodes = self.vnodes.lock();
+			if let Some(vnode) = vnodes.get(&id).and_then(Weak::upgrade){
+				return vnode;
+			}
+			vnodes.retain(|_, v|v.strong_count() > 0);
+			let vnode = Arc::new(FatVnode{vfs:self.this.upgrade().unwrap(), node:Mutex::new(node)});
+			vnodes.insert(id, Arc::downgrade(&vnode));
+			vnode
+		});
+		if is_dir {VfsNode::Directory(vnode)} else {VfsNode::File(vnode)}
+	}
+
+	fn is_in_use(&self, id:u64)->bool{
+		interrupts::without_interrupts(||self.vnodes.lock().get(&id).is_some_and(|v|v.strong_count() > 0))
+	}
+}
+
+impl Filesystem for FatVfs{
+	fn name(&self)->&str{
+		match self.fs.fat_type(){
+			FatType::Fat12=>"fat12",
+			FatType::Fat16=>"fat16",
+			FatType::Fat32=>"fat32",
+		}
+	}
+
+	fn root(&self)->Arc<dyn Directory>{
+		match self.vnode(self.fs.root()){
+			VfsNode::Directory(root)=>root,
+			_=>unreachable!(),
+		}
+	}
+
+	fn sync(&self)->Result<(), FsError>{
+		self.fs.sync()
+	}
+}
+
+struct FatVnode{
+	vfs:Arc<FatVfs>,
+	node:Mutex<Node>,
+}
+
+impl FatVnode{
+	fn fs(&self)->&FatFs{
+		&self.vfs.fs
+	}
+}
+
+impl Vnode for FatVnode{
+	fn metadata(&self)->Metadata{
+		let node = self.node.lock();
+		Metadata{
+			kind:if node.is_dir() {NodeKind::Directory} else {NodeKind::File},
+			size:node.size(),
+			id:node.id(),
+			read_only:node.is_read_only() || self.fs().is_read_only(),
+		}
+	}
+}
+
+impl File for FatVnode{
+	fn read_at(&self, offset:u64, buf:&mut [u8])->Result<usize, FsError>{
+		let node = self.node.lock();
+		self.fs().read(&node, offset, buf)
+	}
+
+	fn write_at(&self, offset:u64, buf:&[u8])->Result<usize, FsError>{
+		let mut node = self.node.lock();
+		self.fs().write(&mut node, offset, buf)
+	}
+
+	fn set_size(&self, size:u64)->Result<(), FsError>{
+		let mut node = self.node.lock();
+		self.fs().set_size(&mut node, size)
+	}
+}
+
+impl Directory for FatVnode{
+	fn lookup(&self, name:&str)->Result<VfsNode, FsError>{
+		let dir = self.node.lock().clone();
+		Ok(self.vfs.vnode(self.fs().lookup(&dir, name)?))
+	}
+
+	fn read_dir(&self)->Result<Vec<DirEntry>, FsError>{
+		let dir = self.node.lock().clone();
+		Ok(self.fs().read_dir(&dir)?.into_iter().map(|e|DirEntry{
+			kind:if e.node.is_dir() {NodeKind::Directory} else {NodeKind::File},
+			name:e.name,
+		}).collect())
+	}
+
+	fn create(&self, name:&str, kind:NodeKind)->Result<VfsNode, FsError>{
+		let dir = self.node.lock().clone();
+		let node = match kind{
+			NodeKind::File=>self.fs().create(&dir, name, false)?,
+			NodeKind::Directory=>self.fs().create(&dir, name, true)?,
+			NodeKind::Symlink=>return Err(FsError::Unsupported),
+		};
+		Ok(self.vfs.vnode(node))
+	}
+
+	fn remove(&self, name:&str)->Result<(), FsError>{
+		let dir = self.node.lock().clone();
+		//FAT frees the clusters right away, so open files can't be removed.
+		let node = self.fs().lookup(&dir, name)?;
+		if self.vfs.is_in_use(node.id()){
+			return Err(FsError::Busy);
+		}
+		self.fs().remove(&dir, name)
+	}
+}
+
+impl Drop for FatVnode{
+	fn drop(&mut self){
+		let id = self.node.get_mut().id();
+		interrupts::without_interrupts(||{
+			let mut vnodes = self.vfs.vnodes.lock();
+			if vnodes.get(&id).is_some_and(|v|v.strong_count() == 0){
+				vnodes.remove(&id);
+			}
+		});
+	}
+}
+
@@ -0,0 +1,246 @@
+//Open files, and the table of file descriptors.
+//There are no processes yet, so there is one table for the whole kernel.
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::ops::BitOr;
+use x86_64::instructions::interrupts;
+use crate::lock::{Lock, LockClass};
+use crate::sync::Mutex;
+use super::mount::{has_mounts_below, is_below};
+use super::path::{walk, walk_parent};
+use super::{DirEntry, FsError, Metadata, Node, NodeKind};
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct OpenFlags(u32);
+
+impl OpenFlags{
+	pub const READ:Self = Self(1<<0);
+	pub const WRITE:Self = Self(1<<1);
+	///Creates the file, if it doesn't exist.
+	pub const CREATE:Self = Self(1<<2);
+	///With CREATE, fails if the file exists.
+	pub const EXCLUSIVE:Self = Self(1<<3);
+	///Cuts the file to 0 bytes, if it is opened for writing.
+	pub const TRUNCATE:Self = Self(1<<4);
+	///Every write goes to the end of the file.
+	pub const APPEND:Self = Self(1<<5);
+
+	pub const fn contains(self, other:Self)->bool{
+		self.0 & other.0 == other.0
+	}
+}
+
+impl BitOr for OpenFlags{
+	type Output = Self;
+	fn bitor(self, rhs:Self)->Self{
+		Self(self.0 | rhs.0)
+	}
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum SeekFrom{
+	Start(u64),
+	Current(i64),
+	End(i64),
+}
+
+pub struct OpenFile{
+	///The canonical path, that the file was opened at.
+	path:String,
+	node:Node,
+	flags:OpenFlags,
+	offset:Mutex<u64>,
+}
+
+impl OpenFile{
+	pub fn open(path:&str, flags:OpenFlags)->Result<Arc<Self>, FsError>{
+		if !flags.contains(OpenFlags::READ) && !flags.contains(OpenFlags::WRITE){
+			return Err(FsError::InvalidArgument);
+		}
+		let (path, node) = match walk(path, true){
+			Ok(_) if flags.contains(OpenFlags::CREATE | OpenFlags::EXCLUSIVE)=>return Err(FsError::AlreadyExists),
+			Ok(found)=>found,
+			Err(FsError::NotFound) if flags.contains(OpenFlags::CREATE)=>{
+				let (parent, dir, name) = walk_parent(path)?;
+				let node = dir.create(&name, NodeKind::File)?;
+				(child_path(&parent, &name), node)
+			},
+			Err(e)=>return Err(e),
+		};
+		let writing = flags.contains(OpenFlags::WRITE);
+		match &node{
+			Node::Directory(_) if writing=>return Err(FsError::IsADirectory),
+			Node::File(_) if writing && node.metadata().read_only=>return Err(FsError::ReadOnly),
+			Node::File(f) if writing && flags.contains(OpenFlags::TRUNCATE)=>f.set_size(0)?,
+			_=>(),
+		}
+		Ok(Arc::new(Self{path, node, flags, offset:Mutex::new(0)}))
+	}
+
+	pub fn node(&self)->&Node{
+		&self.node
+	}
+
+	pub fn metadata(&self)->Metadata{
+		self.node.metadata()
+	}
+
+	///Reads at the current offset, and moves the offset forward.
+	pub fn read(&self, buf:&mut [u8])->Result<usize, FsError>{
+		if !self.flags.contains(OpenFlags::READ){
+			return Err(FsError::BadFileDescriptor);
+		}
+		let Node::File(file) = &self.node else{
+			return Err(FsError::IsADirectory);
+		};
+		let mut offset = self.offset.lock();
+		let n = file.read_at(*offset, buf)?;
+		*offset += n as u64;
+		Ok(n)
+	}
+
+	///Writes at the current offset, or at the end with APPEND, and moves the offset forward.
+	pub fn write(&self, buf:&[u8])->Result<usize, FsError>{
+		if !self.flags.contains(OpenFlags::WRITE){
+			return Err(FsError::BadFileDescriptor);
+		}
+		let Node::File(file) = &self.node else{
+			return Err(FsError::IsADirectory);
+		};
+		let mut offset = self.offset.lock();
+		if self.flags.contains(OpenFlags::APPEND){
+			*offset = file.metadata().size;
+		}
+		let n = file.write_at(*offset, buf)?;
+		*offset += n as u64;
+		Ok(n)
+	}
+
+	///Moves the offset. It may go past the end of the file. Returns the new offset.
+	pub fn seek(&self, pos:SeekFrom)->Result<u64, FsError>{
+		let mut offset = self.offset.lock();
+		let new = match pos{
+			SeekFrom::Start(p)=>Some(p),
+			SeekFrom::Current(d)=>offset.checked_add_signed(d),
+			SeekFrom::End(d)=>self.node.metadata().size.checked_add_signed(d),
+		};
+		*offset = new.ok_or(FsError::InvalidArgument)?;
+		Ok(*offset)
+	}
+
+	pub fn read_dir(&self)->Result<Vec<DirEntry>, FsError>{
+		self.node.as_dir()?.read_dir()
+	}
+}
+
+///An index into the file table.
+pub type Fd = usize;
+
+static FILES_CLASS:LockClass = LockClass::new("fs::files");
+static FILES:Lock<Vec<Option<Arc<OpenFile>>>> = Lock::with_class(Vec::new(), &FILES_CLASS);
+
+fn file(fd:Fd)->Result<Arc<OpenFile>, FsError>{
+	interrupts::without_interrupts(||FILES.lock().get(fd).cloned().flatten()).ok_or(FsError::BadFileDescriptor)
+}
+
+///Opens a file, and puts it into the lowest free slot of the file table.
+pub fn open(path:&str, flags:OpenFlags)->Result<Fd, FsError>{
+	let file = OpenFile::open(path, flags)?;
+	Ok(interrupts::without_interrupts(||{
+		let mut files = FILES.lock();
+		match files.iter().position(Option::is_none){
+			Some(fd)=>{
+				files[fd] = Some(file);
+				fd
+			},
+			None=>{
+				files.push(Some(file));
+				files.len()-1
+			},
+		}
+	}))
+}
+
+///Returns true, if a file in the file table was opened at `dir`, or below it.
+pub(super) fn has_open_files(dir:&str)->bool{
+	interrupts::without_interrupts(||FILES.lock().iter().flatten().any(|f|is_below(&f.path, dir)))
+}
+
+pub fn close(fd:Fd)->Result<(), FsError>{
+	//The file is dropped outside of the lock, since that can write back to the disk.
+	let file = interrupts::without_interrupts(||FILES.lock().get_mut(fd).and_then(Option::take));
+	file.map(drop).ok_or(FsError::BadFileDescriptor)
+}
+
+pub fn read(fd:Fd, buf:&mut [u8])->Result<usize, FsError>{
+	file(fd)?.read(buf)
+}
+
+pub fn write(fd:Fd, buf:&[u8])->Result<usize, FsError>{
+	file(fd)?.write(buf)
+}
+
+pub fn seek(fd:Fd, pos:SeekFrom)->Result<u64, FsError>{
+	file(fd)?.seek(pos)
+}
+
+pub fn stat(path:&str)->Result<Metadata, FsError>{
+	walk(path, true).map(|(_, node)|node.metadata())
+}
+
+pub fn read_dir(path:&str)->Result<Vec<DirEntry>, FsError>{
+	walk(path, true)?.1.as_dir()?.read_dir()
+}
+
+///Reads a whole file.
+pub fn read_file(path:&str)->Result<Vec<u8>, FsError>{
+	let file = OpenFile::open(path, OpenFlags::READ)?;
+	let mut data = alloc::vec![0;file.metadata().size as usize];
+	let mut done = 0;
+	loop{
+		if done == data.len(){
+			//The file might have grown.
+			data.resize(done+4096, 0);
+		}
+		let n = file.read(&mut data[done..])?;
+		if n == 0{
+			break;
+		}
+		done += n;
+	}
+	data.truncate(done);
+	Ok(data)
+}
+
+pub fn create_dir(path:&str)->Result<(), FsError>{
+	let (_, dir, name) = walk_parent(path)?;
+	dir.create(&name, NodeKind::Directory).map(drop)
+}
+
+///Creates a symlink at `path`, that points to `target`.
+pub fn symlink(target:&str, path:&str)->Result<(), FsError>{
+	let (_, dir, name) = walk_parent(path)?;
+	dir.symlink(&name, target).map(drop)
+}
+
+pub fn read_link(path:&str)->Result<String, FsError>{
+	match walk(path, false)?.1{
+		Node::Symlink(link)=>link.target(),
+		_=>Err(FsError::InvalidArgument),
+	}
+}
+
+///Removes a file, symlink or empty directory. Mount points can't be removed.
+pub fn remove(path:&str)->Result<(), FsError>{
+	let (parent, dir, name) = walk_parent(path)?;
+	if has_mounts_below(&child_path(&parent, &name)){
+		return Err(FsError::Busy);
+	}
+	dir.remove(&name)
+}
+
+///The canonical path of `name` in the directory at the canonical path `parent`.
+fn child_path(parent:&str, name:&str)->String{
+	if parent == "/" {alloc::format!("/{}", name)} else {alloc::format!("{}/{}", parent, name)}
+}
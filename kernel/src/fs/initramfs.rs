@@ -0,0 +1,286 @@
+//A read-only filesystem on top of the initial ramdisk, which the boot loader loads into memory.
+//The ramdisk is a cpio archive in the newc format, or a ustar archive. File data isn't copied, but used in place.
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use super::{DirEntry, Directory, File, Filesystem, FsError, Metadata, Node, NodeKind, Symlink, Vnode};
+
+const CPIO_NEWC_MAGIC:&[u8] = b"070701";
+const CPIO_NEWC_CRC_MAGIC:&[u8] = b"070702";
+const CPIO_HEADER_SIZE:usize = 110;
+const CPIO_TRAILER:&str = "TRAILER!!!";
+const USTAR_MAGIC:&[u8] = b"ustar";
+const USTAR_MAGIC_OFFSET:usize = 257;
+const TAR_BLOCK:usize = 512;
+
+const MODE_TYPE_MASK:u32 = 0o170000;
+const MODE_DIRECTORY:u32 = 0o040000;
+const MODE_FILE:u32 = 0o100000;
+const MODE_SYMLINK:u32 = 0o120000;
+
+///The tree, while the archive is read.
+enum Entry{
+	Dir(BTreeMap<String, Entry>),
+	File(&'static [u8]),
+	Symlink(String),
+}
+
+impl Entry{
+	///Adds an entry at `path`, creating the directories on the way.
+	///Directories, that already exist, keep their contents. Everything else is replaced by later entries, like tar does it.
+	fn insert(&mut self, path:&str, entry:Entry)->Result<(), FsError>{
+		let Entry::Dir(entries) = self else{
+			return Err(FsError::NotADirectory);
+		};
+		let path = path.trim_start_matches('/');
+		let (name, rest) = match path.split_once('/'){
+			Some((name, rest)) if !rest.trim_matches('/').is_empty()=>(name, Some(rest)),
+			Some((name, _))=>(name, None),
+			None=>(path, None),
+		};
+		match name{
+			""|"."=>match rest{
+				Some(rest)=>self.insert(rest, entry),
+				None=>Ok(()),
+			},
+			".."=>Err(FsError::InvalidName),
+			name=>match rest{
+				Some(rest)=>entries.entry(String::from(name)).or_insert_with(||Entry::Dir(BTreeMap::new())).insert(rest, entry),
+				None=>{
+					match (entries.get(name), &entry){
+						(Some(Entry::Dir(_)), Entry::Dir(_))=>(),
+						_=>{
+							entries.insert(String::from(name), entry);
+						},
+					}
+					Ok(())
+				},
+			},
+		}
+	}
+
+	fn find(&self, path:&str)->Option<&Entry>{
+		path.split('/').filter(|c|!c.is_empty() && *c != ".").try_fold(self, |entry, name|match entry{
+			Entry::Dir(entries)=>entries.get(name),
+			_=>None,
+		})
+	}
+
+	fn into_node(self, next_id:&mut u64)->Node{
+		*next_id += 1;
+		let id = *next_id;
+		match self{
+			Entry::Dir(entries)=>Node::Directory(Arc::new(InitDir{
+				id,
+				entries:entries.into_iter().map(|(name, e)|(name, e.into_node(next_id))).collect(),
+			})),
+			Entry::File(data)=>Node::File(Arc::new(InitFile{id, data})),
+			Entry::Symlink(target)=>Node::Symlink(Arc::new(InitSymlink{id, target})),
+		}
+	}
+}
+
+fn parse_number(field:&[u8], radix:u32)->Option<u64>{
+	let s = core::str::from_utf8(field).ok()?.trim_matches(|c:char|c == '\0' || c == ' ');
+	if s.is_empty(){
+		return Some(0);
+	}
+	u64::from_str_radix(s, radix).ok()
+}
+
+fn name(bytes:&[u8])->Result<&str, FsError>{
+	let end = bytes.iter().position(|b|*b == 0).unwrap_or(bytes.len());
+	core::str::from_utf8(&bytes[..end]).map_err(|_|FsError::InvalidName)
+}
+
+fn read_cpio(data:&'static [u8], root:&mut Entry)->Result<(), FsError>{
+	let mut offset = 0;
+	loop{
+		let header = data.get(offset..offset+CPIO_HEADER_SIZE).ok_or(FsError::Corrupted)?;
+		if &header[..6] != CPIO_NEWC_MAGIC && &header[..6] != CPIO_NEWC_CRC_MAGIC{
+			return Err(FsError::Corrupted);
+		}
+		let field = |i:usize|parse_number(&header[6+i*8..14+i*8], 16).ok_or(FsError::Corrupted);
+		let mode = field(1)? as u32;
+		let file_size = field(6)? as usize;
+		let name_size = field(11)? as usize;
+		let name_start = offset+CPIO_HEADER_SIZE;
+		let path = name(data.get(name_start..name_start+name_size).ok_or(FsError::Corrupted)?)?;
+		let data_start = (name_start+name_size).next_multiple_of(4);
+		let contents = data.get(data_start..data_start+file_size).ok_or(FsError::Corrupted)?;
+		offset = (data_start+file_size).next_multiple_of(4);
+		if path == CPIO_TRAILER{
+			return Ok(());
+		}
+		//Hard links only have their data in the last entry with the same inode. We don't track inodes, so the others end up empty.
+		let entry = match mode & MODE_TYPE_MASK{
+			MODE_DIRECTORY=>Entry::Dir(BTreeMap::new()),
+			MODE_FILE=>Entry::File(contents),
+			MODE_SYMLINK=>Entry::Symlink(String::from(core::str::from_utf8(contents).map_err(|_|FsError::InvalidName)?)),
+			_=>{
+				log::debug!("initrd: skipping special file {}", path);
+				continue;
+			},
+		};
+		root.insert(path, entry)?;
+	}
+}
+
+fn read_ustar(data:&'static [u8], root:&mut Entry)->Result<(), FsError>{
+	let mut offset = 0;
+	while let Some(header) = data.get(offset..offset+TAR_BLOCK){
+		//The archive ends with two zero blocks.
+		if header.iter().all(|b|*b == 0){
+			return Ok(());
+		}
+		if &header[USTAR_MAGIC_OFFSET..USTAR_MAGIC_OFFSET+5] != USTAR_MAGIC{
+			return Err(FsError::Corrupted);
+		}
+		let checksum = parse_number(&header[148..156], 8).ok_or(FsError::Corrupted)?;
+		//The checksum is calculated with the checksum field filled with spaces.
+		let sum:u64 = header.iter().enumerate().map(|(i, b)|if (148..156).contains(&i) {b' ' as u64} else {*b as u64}).sum();
+		if sum != checksum{
+			return Err(FsError::Corrupted);
+		}
+		let size = parse_number(&header[124..136], 8).ok_or(FsError::Corrupted)? as usize;
+		let prefix = name(&header[345..500])?;
+		let file_name = name(&header[..100])?;
+		let path = if prefix.is_empty() {String::from(file_name)} else {alloc::format!("{}/{}", prefix, file_name)};
+		let contents_start = offset+TAR_BLOCK;
+		let contents = data.get(contents_start..contents_start+size).ok_or(FsError::Corrupted)?;
+		offset = contents_start+size.next_multiple_of(TAR_BLOCK);
+		let entry = match header[156]{
+			b'0'|b'\0'|b'7'=>Entry::File(contents),
+			b'5'=>Entry::Dir(BTreeMap::new()),
+			b'2'=>Entry::Symlink(String::from(name(&header[157..257])?)),
+			//Hard links get the data of the file, that they point to.
+			b'1'=>match root.find(name(&header[157..257])?){
+				Some(Entry::File(data))=>Entry::File(data),
+				_=>{
+					log::warn!("initrd: hard link {} points to nothing", path);
+					continue;
+				},
+			},
+			t=>{
+				log::debug!("initrd: skipping {} of type {:?}", path, t as char);
+				continue;
+			},
+		};
+		root.insert(&path, entry)?;
+	}
+	Ok(())
+}
+
+pub struct InitramFs{
+	root:Arc<dyn Directory>,
+}
+
+impl InitramFs{
+	///Reads the whole archive. The data has to stay where it is forever.
+	pub fn new(data:&'static [u8])->Result<Arc<Self>, FsError>{
+		let mut root = Entry::Dir(BTreeMap::new());
+		if data.starts_with(CPIO_NEWC_MAGIC) || data.starts_with(CPIO_NEWC_CRC_MAGIC){
+			read_cpio(data, &mut root)?;
+		}else if data.get(USTAR_MAGIC_OFFSET..USTAR_MAGIC_OFFSET+5) == Some(USTAR_MAGIC){
+			read_ustar(data, &mut root)?;
+		}else{
+			log::warn!("initrd: neither a cpio nor a ustar archive");
+			return Err(FsError::Unsupported);
+		}
+		match root.into_node(&mut 0){
+			Node::Directory(root)=>Ok(Arc::new(Self{root})),
+			_=>unreachable!(),
+		}
+	}
+}
+
+impl Filesystem for InitramFs{
+	fn name(&self)->&str{
+		"initramfs"
+	}
+
+	fn root(&self)->Arc<dyn Directory>{
+		self.root.clone()
+	}
+}
+
+struct InitDir{
+	id:u64,
+	entries:BTreeMap<String, Node>,
+}
+
+impl Vnode for InitDir{
+	fn metadata(&self)->Metadata{
+		Metadata{kind:NodeKind::Directory, size:0, id:self.id, read_only:true}
+	}
+}
+
+impl Directory for InitDir{
+	fn lookup(&self, name:&str)->Result<Node, FsError>{
+		self.entries.get(name).cloned().ok_or(FsError::NotFound)
+	}
+
+	fn read_dir(&self)->Result<Vec<DirEntry>, FsError>{
+		Ok(self.entries.iter().map(|(name, node)|DirEntry{name:name.clone(), kind:node.kind()}).collect())
+	}
+
+	fn create(&self, _name:&str, _kind:NodeKind)->Result<Node, FsError>{
+		Err(FsError::ReadOnly)
+	}
+
+	fn symlink(&self, _name:&str, _target:&str)->Result<Node, FsError>{
+		Err(FsError::ReadOnly)
+	}
+
+	fn remove(&self, _name:&str)->Result<(), FsError>{
+		Err(FsError::ReadOnly)
+	}
+}
+
+struct InitFile{
+	id:u64,
+	data:&'static [u8],
+}
+
+impl Vnode for InitFile{
+	fn metadata(&self)->Metadata{
+		Metadata{kind:NodeKind::File, size:self.data.len() as u64, id:self.id, read_only:true}
+	}
+}
+
+impl File for InitFile{
+	fn read_at(&self, offset:u64, buf:&mut [u8])->Result<usize, FsError>{
+		let Some(rest) = self.data.get(offset as usize..) else{
+			return Ok(0);
+		};
+		let n = buf.len().min(rest.len());
+		buf[..n].copy_from_slice(&rest[..n]);
+		Ok(n)
+	}
+
+	fn write_at(&self, _offset:u64, _buf:&[u8])->Result<usize, FsError>{
+		Err(FsError::ReadOnly)
+	}
+
+	fn set_size(&self, _size:u64)->Result<(), FsError>{
+		Err(FsError::ReadOnly)
+	}
+}
+
+struct InitSymlink{
+	id:u64,
+	target:String,
+}
+
+impl Vnode for InitSymlink{
+	fn metadata(&self)->Metadata{
+		Metadata{kind:NodeKind::Symlink, size:self.target.len() as u64, id:self.id, read_only:true}
+	}
+}
+
+impl Symlink for InitSymlink{
+	fn target(&self)->Result<String, FsError>{
+		Ok(self.target.clone())
+	}
+}
@@ -0,0 +1,93 @@
+//The mount table.
+//Mount points are kept as canonical paths, which path resolution compares against, after every step.
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use x86_64::instructions::interrupts;
+use crate::lock::{LockClass, RWLock, RWLockMode};
+use super::file::has_open_files;
+use super::path::{canonicalize, walk};
+use super::{Directory, Filesystem, FsError, NodeKind};
+
+struct Mount{
+	///The canonical path, like /boot.
+	path:String,
+	fs:Arc<dyn Filesystem>,
+	root:Arc<dyn Directory>,
+}
+
+static MOUNTS_CLASS:LockClass = LockClass::new("fs::mounts");
+static MOUNTS:RWLock<Vec<Mount>> = RWLock::with_class(Vec::new(), RWLockMode::ReaderPreferring, &MOUNTS_CLASS);
+
+///Mounts `fs` on the directory at `path`. The root filesystem has to be mounted first, at /.
+pub fn mount(path:&str, fs:Arc<dyn Filesystem>)->Result<(), FsError>{
+	let path = if canonicalize(path) == "/"{
+		String::from("/")
+	}else{
+		let (path, node) = walk(path, true)?;
+		if node.kind() != NodeKind::Directory{
+			return Err(FsError::NotADirectory);
+		}
+		path
+	};
+	let root = fs.root();
+	interrupts::without_interrupts(||{
+		let mut mounts = MOUNTS.write_lock();
+		if mounts.iter().any(|m|m.path == path){
+			return Err(FsError::Busy);
+		}
+		log::debug!("Mounting {} at {}", fs.name(), path);
+		mounts.push(Mount{path, fs, root});
+		Ok(())
+	})
+}
+
+///Unmounts the filesystem at `path`, after writing it back.
+///Fails, if something else is mounted below it, or files on it are open.
+pub fn unmount(path:&str)->Result<(), FsError>{
+	let (path, _) = walk(path, true)?;
+	let fs = interrupts::without_interrupts(||{
+		let mounts = MOUNTS.read_lock();
+		let mount = mounts.iter().find(|m|m.path == path).ok_or(FsError::InvalidArgument)?;
+		if mounts.iter().any(|m|m.path != path && is_below(&m.path, &path)) || has_open_files(&path){
+			return Err(FsError::Busy);
+		}
+		Ok(mount.fs.clone())
+	})?;
+	fs.sync()?;
+	interrupts::without_interrupts(||MOUNTS.write_lock().retain(|m|m.path != path));
+	Ok(())
+}
+
+///Returns true, if `path` is `dir` or somewhere below it.
+pub(super) fn is_below(path:&str, dir:&str)->bool{
+	dir == "/" || path == dir || path.strip_prefix(dir).is_some_and(|rest|rest.starts_with('/'))
+}
+
+///The root directory of the filesystem mounted at the canonical `path`.
+pub(super) fn mounted_at(path:&str)->Option<Arc<dyn Directory>>{
+	interrupts::without_interrupts(||MOUNTS.read_lock().iter().rev().find(|m|m.path == path).map(|m|m.root.clone()))
+}
+
+///Returns true, if something is mounted at `path`, or below it.
+pub(super) fn has_mounts_below(path:&str)->bool{
+	interrupts::without_interrupts(||MOUNTS.read_lock().iter().any(|m|is_below(&m.path, path)))
+}
+
+///The mount points with the type of their filesystem.
+pub fn mounts()->Vec<(String, String)>{
+	interrupts::without_interrupts(||MOUNTS.read_lock().iter().map(|m|(m.path.clone(), String::from(m.fs.name()))).collect())
+}
+
+///Writes every filesystem back.
+pub fn sync_all()->Result<(), FsError>{
+	let filesystems:Vec<_> = interrupts::without_interrupts(||MOUNTS.read_lock().iter().map(|m|m.fs.clone()).collect());
+	let mut result = Ok(());
+	for fs in filesystems{
+		if let Err(e) = fs.sync(){
+			log::error!("Can't sync {}: {}", fs.name(), e);
+			result = Err(e);
+		}
+	}
+	result
+}
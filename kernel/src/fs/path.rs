@@ -0,0 +1,117 @@
+//Path resolution.
+//A path is walked one component at a time, keeping the nodes on the way on a stack, so `..` goes back to where we came from,
+//even across mount points and symlinks.
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use super::mount::mounted_at;
+use super::{Directory, FsError, Node};
+
+///Following more symlinks than this while resolving one path fails with SymlinkLoop.
+const MAX_SYMLINKS:usize = 40;
+
+fn components(path:&str)->impl Iterator<Item=&str>{
+	path.split('/').filter(|c|!c.is_empty())
+}
+
+///Makes a canonical path out of the names on the stack.
+fn join(names:&[String])->String{
+	if names.is_empty(){
+		return String::from("/");
+	}
+	names.iter().fold(String::new(), |path, name|path+"/"+name)
+}
+
+///Removes `.`, `..` and duplicate slashes, without looking at the filesystem.
+pub(super) fn canonicalize(path:&str)->String{
+	let mut names = Vec::new();
+	for c in components(path){
+		match c{
+			"."=>(),
+			".."=>{
+				names.pop();
+			},
+			name=>names.push(String::from(name)),
+		}
+	}
+	join(&names)
+}
+
+fn root()->Result<Node, FsError>{
+	mounted_at("/").map(Node::Directory).ok_or(FsError::NotFound)
+}
+
+///Resolves a path. Symlinks are followed, except for the last component, if `follow_last` is false.
+///Returns the canonical path and the node.
+pub(super) fn walk(path:&str, follow_last:bool)->Result<(String, Node), FsError>{
+	let mut names:Vec<String> = Vec::new();
+	let mut nodes = alloc::vec![root()?];
+	let mut pending:VecDeque<String> = components(path).map(String::from).collect();
+	let mut links = 0;
+	while let Some(name) = pending.pop_front(){
+		match name.as_str(){
+			"."=>continue,
+			".."=>{
+				if !names.is_empty(){
+					names.pop();
+					nodes.pop();
+				}
+				continue;
+			},
+			_=>(),
+		}
+		let dir = nodes.last().unwrap().as_dir()?.clone();
+		names.push(name);
+		let path = join(&names);
+		let node = match mounted_at(&path){
+			Some(root)=>Node::Directory(root),
+			None=>dir.lookup(names.last().unwrap())?,
+		};
+		if let Node::Symlink(link) = &node{
+			if follow_last || !pending.is_empty(){
+				links += 1;
+				if links > MAX_SYMLINKS{
+					return Err(FsError::SymlinkLoop);
+				}
+				let target = link.target()?;
+				names.pop();
+				if target.starts_with('/'){
+					names.clear();
+					nodes.truncate(1);
+				}
+				let target:Vec<&str> = components(&target).collect();
+				for c in target.into_iter().rev(){
+					pending.push_front(String::from(c));
+				}
+				continue;
+			}
+		}
+		nodes.push(node);
+	}
+	Ok((join(&names), nodes.pop().unwrap()))
+}
+
+///Resolves the directory, that contains the last component of the path, following all symlinks.
+///Returns the canonical path of the directory, the directory and the name of the last component.
+pub(super) fn walk_parent(path:&str)->Result<(String, Arc<dyn Directory>, String), FsError>{
+	let (parent, name) = match path.trim_end_matches('/').rsplit_once('/'){
+		Some((parent, name))=>(parent, name),
+		None=>("", path),
+	};
+	if name.is_empty() || name == "." || name == ".."{
+		return Err(FsError::InvalidArgument);
+	}
+	let (parent_path, dir) = walk(parent, true)?;
+	Ok((parent_path, dir.as_dir()?.clone(), String::from(name)))
+}
+
+///Finds the node at `path`, following all symlinks.
+pub fn lookup(path:&str)->Result<Node, FsError>{
+	walk(path, true).map(|(_, node)|node)
+}
+
+///Finds the node at `path`. If the last component is a symlink, the symlink itself is returned.
+pub fn lookup_no_follow(path:&str)->Result<Node, FsError>{
+	walk(path, false).map(|(_, node)|node)
+}
@@ -0,0 +1,166 @@
+//A filesystem, that only lives in memory.
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use crate::sync::Mutex;
+use super::{DirEntry, Directory, File, Filesystem, FsError, Metadata, Node, NodeKind, Symlink, Vnode};
+
+static NEXT_ID:AtomicU64 = AtomicU64::new(1);
+
+fn next_id()->u64{
+	NEXT_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+pub struct TmpFs{
+	root:Arc<TmpDir>,
+}
+
+impl TmpFs{
+	pub fn new()->Arc<Self>{
+		Arc::new(Self{root:Arc::new(TmpDir::new())})
+	}
+}
+
+impl Filesystem for TmpFs{
+	fn name(&self)->&str{
+		"tmpfs"
+	}
+
+	fn root(&self)->Arc<dyn Directory>{
+		self.root.clone()
+	}
+}
+
+struct TmpDir{
+	id:u64,
+	entries:Mutex<BTreeMap<String, Node>>,
+}
+
+impl TmpDir{
+	fn new()->Self{
+		Self{id:next_id(), entries:Mutex::new(BTreeMap::new())}
+	}
+}
+
+fn check_name(name:&str)->Result<(), FsError>{
+	if name.is_empty() || name == "." || name == ".." || name.contains(['/', '\0']){
+		return Err(FsError::InvalidName);
+	}
+	Ok(())
+}
+
+impl Vnode for TmpDir{
+	fn metadata(&self)->Metadata{
+		Metadata{kind:NodeKind::Directory, size:0, id:self.id, read_only:false}
+	}
+}
+
+impl Directory for TmpDir{
+	fn lookup(&self, name:&str)->Result<Node, FsError>{
+		self.entries.lock().get(name).cloned().ok_or(FsError::NotFound)
+	}
+
+	fn read_dir(&self)->Result<Vec<DirEntry>, FsError>{
+		Ok(self.entries.lock().iter().map(|(name, node)|DirEntry{name:name.clone(), kind:node.kind()}).collect())
+	}
+
+	fn create(&self, name:&str, kind:NodeKind)->Result<Node, FsError>{
+		let node = match kind{
+			NodeKind::File=>Node::File(Arc::new(TmpFile{id:next_id(), data:Mutex::new(Vec::new())})),
+			NodeKind::Directory=>Node::Directory(Arc::new(TmpDir::new())),
+			NodeKind::Symlink=>return Err(FsError::InvalidArgument),
+		};
+		self.insert(name, node)
+	}
+
+	fn symlink(&self, name:&str, target:&str)->Result<Node, FsError>{
+		self.insert(name, Node::Symlink(Arc::new(TmpSymlink{id:next_id(), target:String::from(target)})))
+	}
+
+	fn remove(&self, name:&str)->Result<(), FsError>{
+		let mut entries = self.entries.lock();
+		if let Node::Directory(dir) = entries.get(name).ok_or(FsError::NotFound)?{
+			if !dir.read_dir()?.is_empty(){
+				return Err(FsError::NotEmpty);
+			}
+		}
+		entries.remove(name);
+		Ok(())
+	}
+}
+
+impl TmpDir{
+	fn insert(&self, name:&str, node:Node)->Result<Node, FsError>{
+		check_name(name)?;
+		let mut entries = self.entries.lock();
+		if entries.contains_key(name){
+			return Err(FsError::AlreadyExists);
+		}
+		entries.insert(String::from(name), node.clone());
+		Ok(node)
+	}
+}
+
+struct TmpFile{
+	id:u64,
+	data:Mutex<Vec<u8>>,
+}
+
+impl Vnode for TmpFile{
+	fn metadata(&self)->Metadata{
+		Metadata{kind:NodeKind::File, size:self.data.lock().len() as u64, id:self.id, read_only:false}
+	}
+}
+
+impl File for TmpFile{
+	fn read_at(&self, offset:u64, buf:&mut [u8])->Result<usize, FsError>{
+		let data = self.data.lock();
+		let Some(rest) = data.get(offset as usize..) else{
+			return Ok(0);
+		};
+		let n = buf.len().min(rest.len());
+		buf[..n].copy_from_slice(&rest[..n]);
+		Ok(n)
+	}
+
+	fn write_at(&self, offset:u64, buf:&[u8])->Result<usize, FsError>{
+		let end = (offset as usize).checked_add(buf.len()).ok_or(FsError::FileTooBig)?;
+		let mut data = self.data.lock();
+		if end > data.len(){
+			let additional = end-data.len();
+			data.try_reserve(additional).map_err(|_|FsError::NoSpace)?;
+			data.resize(end, 0);
+		}
+		data[offset as usize..end].copy_from_slice(buf);
+		Ok(buf.len())
+	}
+
+	fn set_size(&self, size:u64)->Result<(), FsError>{
+		let mut data = self.data.lock();
+		if size as usize > data.len(){
+			let additional = size as usize-data.len();
+			data.try_reserve(additional).map_err(|_|FsError::NoSpace)?;
+		}
+		data.resize(size as usize, 0);
+		Ok(())
+	}
+}
+
+struct TmpSymlink{
+	id:u64,
+	target:String,
+}
+
+impl Vnode for TmpSymlink{
+	fn metadata(&self)->Metadata{
+		Metadata{kind:NodeKind::Symlink, size:self.target.len() as u64, id:self.id, read_only:false}
+	}
+}
+
+impl Symlink for TmpSymlink{
+	fn target(&self)->Result<String, FsError>{
+		Ok(self.target.clone())
+	}
+}
@@ -0,0 +1,103 @@
+//The interface between the VFS and the filesystems.
+//Every file, directory and symlink is a vnode. Filesystems hand them out as Node, which says, what kind it is.
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use super::FsError;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum NodeKind{
+	File,
+	Directory,
+	Symlink,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct Metadata{
+	pub kind:NodeKind,
+	///The size in bytes. Filesystems may report 0 for directories.
+	pub size:u64,
+	///Unique for every node of the filesystem, as long as it exists.
+	pub id:u64,
+	pub read_only:bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct DirEntry{
+	pub name:String,
+	pub kind:NodeKind,
+}
+
+pub trait Vnode:Send+Sync{
+	fn metadata(&self)->Metadata;
+}
+
+pub trait File:Vnode{
+	///Reads from `offset` into `buf`. Returns the amount of bytes read, which is 0 at the end of the file.
+	fn read_at(&self, offset:u64, buf:&mut [u8])->Result<usize, FsError>;
+	///Writes `buf` at `offset`, growing the file, if needed. Returns the amount of bytes written.
+	fn write_at(&self, offset:u64, buf:&[u8])->Result<usize, FsError>;
+	///Truncates the file, or grows it with zeroes.
+	fn set_size(&self, size:u64)->Result<(), FsError>;
+}
+
+pub trait Directory:Vnode{
+	///Finds an entry. `.` and `..` are handled by the VFS, and never passed here.
+	fn lookup(&self, name:&str)->Result<Node, FsError>;
+	///All entries, without `.` and `..`.
+	fn read_dir(&self)->Result<Vec<DirEntry>, FsError>;
+	///Creates an empty file or directory.
+	fn create(&self, name:&str, kind:NodeKind)->Result<Node, FsError>;
+	fn symlink(&self, _name:&str, _target:&str)->Result<Node, FsError>{
+		Err(FsError::Unsupported)
+	}
+	///Removes a file, symlink or empty directory.
+	fn remove(&self, name:&str)->Result<(), FsError>;
+}
+
+pub trait Symlink:Vnode{
+	fn target(&self)->Result<String, FsError>;
+}
+
+#[derive(Clone)]
+pub enum Node{
+	File(Arc<dyn File>),
+	Directory(Arc<dyn Directory>),
+	Symlink(Arc<dyn Symlink>),
+}
+
+impl Node{
+	pub fn metadata(&self)->Metadata{
+		match self{
+			Self::File(f)=>f.metadata(),
+			Self::Directory(d)=>d.metadata(),
+			Self::Symlink(l)=>l.metadata(),
+		}
+	}
+
+	pub fn kind(&self)->NodeKind{
+		match self{
+			Self::File(_)=>NodeKind::File,
+			Self::Directory(_)=>NodeKind::Directory,
+			Self::Symlink(_)=>NodeKind::Symlink,
+		}
+	}
+
+	pub fn as_dir(&self)->Result<&Arc<dyn Directory>, FsError>{
+		match self{
+			Self::Directory(d)=>Ok(d),
+			_=>Err(FsError::NotADirectory),
+		}
+	}
+}
+
+///A mountable filesystem.
+pub trait Filesystem:Send+Sync{
+	///The type of the filesystem, like tmpfs.
+	fn name(&self)->&str;
+	fn root(&self)->Arc<dyn Directory>;
+	///Writes everything back to the disk.
+	fn sync(&self)->Result<(), FsError>{
+		Ok(())
+	}
+}
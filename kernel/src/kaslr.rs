@@ -0,0 +1,40 @@
+//The bases, that the boot loader chose for the kernel, its heap and the direct map.
+//With KASLR they change on every boot, so addresses in the kernel are also printed unslid, which means relative to the kernel base.
+//Unslid addresses are the ones in the ELF file, so they can be looked up with addr2line or objdump.
+use core::sync::atomic::{AtomicU64, Ordering};
+use kernel_efi::Args;
+
+static KERNEL_BASE:AtomicU64 = AtomicU64::new(0);
+///Where the loaded kernel image starts and ends. Both 0, until init ran.
+static KERNEL_START:AtomicU64 = AtomicU64::new(0);
+static KERNEL_END:AtomicU64 = AtomicU64::new(0);
+
+///Takes the bases from the boot loader. Has to run before anything uses the direct map.
+pub fn init(args:&Args){
+	let bases = args.bases;
+	KERNEL_BASE.store(bases.kernel, Ordering::Relaxed);
+	KERNEL_START.store(args.elf.start, Ordering::Relaxed);
+	KERNEL_END.store(args.elf.start+(args.elf.pages*crate::x86_64::mem::PAGE_SIZE) as u64, Ordering::Relaxed);
+	crate::x86_64::mem::init(&bases);
+}
+
+///How far the kernel was moved from its link time addresses.
+pub fn kernel_base()->u64{
+	KERNEL_BASE.load(Ordering::Relaxed)
+}
+
+///The link time address of an address in the kernel image. None, if the address isn't in the kernel image, or init hasn't run yet.
+pub fn unslide(addr:u64)->Option<u64>{
+	let base = kernel_base();
+	if base == 0{
+		return None;
+	}
+	(KERNEL_START.load(Ordering::Relaxed)..KERNEL_END.load(Ordering::Relaxed)).contains(&addr).then(||addr-base)
+}
+
+///Prints the bases, so any address can be unslid by hand.
+pub fn print_bases(w:&mut dyn core::fmt::Write)->core::fmt::Result{
+	use crate::x86_64::mem;
+	writeln!(w, "Kernel base {:#x}, heap {:#x}, direct map {:#x}",
+		kernel_base(), mem::heap_base().as_u64(), mem::phys_to_virt(::x86_64::PhysAddr::new(0)).as_u64())
+}
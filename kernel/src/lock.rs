@@ -1,27 +1,77 @@
 use core::cell::UnsafeCell;
 use core::ops::{Deref, DerefMut};
-use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU8, Ordering};
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+#[cfg(feature="lockdep")]
+use core::panic::Location;
+
+///A class of locks, used by the lock dependency checker.
+///All locks, that are created with the same class, are treated as the same lock, when checking the lock order.
+///Locks without an explicit class get a class of their own, keyed by their address.
+///That only works for locks, that never move or get freed, like statics. Locks on the heap need a static class.
+///Without the `lockdep` feature, classes are ignored.
+pub struct LockClass{
+    name:&'static str,
+}
+
+impl LockClass{
+    pub const fn new(name:&'static str)->Self{
+        return Self{name};
+    }
+    pub fn name(&self)->&'static str{
+        return self.name;
+    }
+}
+
+///A FIFO ticket spinlock.
+///Every caller of [`Lock::lock`] draws a ticket and gets the lock in exactly the order the tickets were drawn.
+///This means, that no CPU can be starved, as long as every holder eventually releases the lock.
 pub struct Lock<T>{
-    locked: AtomicBool,
+    next_ticket: AtomicU32,
+    now_serving: AtomicU32,
+    #[cfg(feature="lockdep")]
+    class: Option<&'static LockClass>,
     data: UnsafeCell<T>,
 }
 
+unsafe impl<T:Send> Send for Lock<T>{}
+unsafe impl<T:Send> Sync for Lock<T>{}
+
 impl<T> Lock<T>{
     pub const fn new(data:T)->Self{
         return Self{
-            locked: AtomicBool::new(false),
+            next_ticket: AtomicU32::new(0),
+            now_serving: AtomicU32::new(0),
+            #[cfg(feature="lockdep")]
+            class: None,
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    ///Creates a lock, that belongs to the given lock class.
+    #[allow(unused_variables)]
+    pub const fn with_class(data:T, class:&'static LockClass)->Self{
+        return Self{
+            next_ticket: AtomicU32::new(0),
+            now_serving: AtomicU32::new(0),
+            #[cfg(feature="lockdep")]
+            class: Some(class),
             data: UnsafeCell::new(data),
         }
     }
 
     ///Tries to lock the data behind this lock.
-    pub fn try_lock(&self)->Option<LockGuard<T>>{
-        if self.locked.compare_exchange(
-            false,
-            true,
-            core::sync::atomic::Ordering::SeqCst,
-            core::sync::atomic::Ordering::Acquire
+    ///This will only succeed, if nobody holds or waits for the lock.
+    #[track_caller]
+    pub fn try_lock(&self)->Option<LockGuard<'_,T>>{
+        let serving = self.now_serving.load(Ordering::Relaxed);
+        if self.next_ticket.compare_exchange(
+            serving,
+            serving.wrapping_add(1),
+            Ordering::Acquire,
+            Ordering::Relaxed
         ).is_ok(){
+            #[cfg(feature="lockdep")]
+            crate::lockdep::acquire(self as *const Self as usize, self.class, 0, Location::caller(), false, true);
             return Some(LockGuard{lock: self});
         }
         return None;
@@ -29,16 +79,52 @@ impl<T> Lock<T>{
 
     ///Locks the data behind this lock.
     ///This function will block until the lock is acquired.
+    ///Waiters are served in the order they called this function.
     ///Not running the drop function of the returned guard will result in this lock being stuck as permanently locked!
-    pub fn lock(&self)->LockGuard<T>{
-        loop{
-            if let Some(guard) = self.try_lock(){
-                return guard;
-            }
+    #[track_caller]
+    pub fn lock(&self)->LockGuard<'_,T>{
+        //Check the lock order before waiting, so that a deadlock is reported, before it hangs us.
+        #[cfg(feature="lockdep")]
+        crate::lockdep::acquire(self as *const Self as usize, self.class, 0, Location::caller(), false, false);
+        return self.raw_lock();
+    }
+
+    ///Like [`Lock::lock`], but for taking another lock of the same class, while one is already held.
+    ///Every nesting level gets its own `subclass`, starting at 1 for the second lock, and the lock dependency checker
+    ///treats the levels as different classes. [`Lock::lock`] is subclass 0.
+    #[track_caller]
+    #[allow(unused_variables)]
+    pub fn lock_nested(&self, subclass:u8)->LockGuard<'_,T>{
+        #[cfg(feature="lockdep")]
+        crate::lockdep::acquire(self as *const Self as usize, self.class, subclass, Location::caller(), false, false);
+        return self.raw_lock();
+    }
+
+    fn raw_lock(&self)->LockGuard<'_,T>{
+        let ticket = self.next_ticket.fetch_add(1, Ordering::Relaxed);
+        while self.now_serving.load(Ordering::Acquire) != ticket{
+            core::hint::spin_loop();
         }
+        return LockGuard{lock: self};
+    }
+
+    ///Returns true, if the lock is currently held by someone.
+    pub fn is_locked(&self)->bool{
+        return self.next_ticket.load(Ordering::Relaxed) != self.now_serving.load(Ordering::Relaxed);
+    }
+
+    ///Returns a mutable reference to the data.
+    ///No locking is needed, since the mutable borrow guarantees, that nobody else can access the lock.
+    pub fn get_mut(&mut self)->&mut T{
+        return self.data.get_mut();
     }
+
     fn unlock(&self){
-        self.locked.store(false,core::sync::atomic::Ordering::Release);
+        #[cfg(feature="lockdep")]
+        crate::lockdep::release(self as *const Self as usize);
+        //Only the holder of the lock ever writes now_serving, so a plain load + store is fine.
+        let serving = self.now_serving.load(Ordering::Relaxed);
+        self.now_serving.store(serving.wrapping_add(1), Ordering::Release);
     }
 }
 
@@ -49,7 +135,7 @@ pub struct LockGuard<'a,T>{
 
 impl<'a, T> Drop for LockGuard<'a, T> {
     fn drop(&mut self) {
-        lock.unlock();
+        self.lock.unlock();
     }
 }
 impl<'a,T> Deref for LockGuard<'a,T>{
@@ -59,7 +145,7 @@ impl<'a,T> Deref for LockGuard<'a,T>{
     }
 }
 impl<'a,T> DerefMut for LockGuard<'a,T>{
-    fn deref_mut(&self)->&mut Self::Target{
+    fn deref_mut(&mut self)->&mut Self::Target{
         return unsafe{&mut *self.lock.data.get()};
     }
 }
@@ -70,7 +156,7 @@ pub struct ReadRWLockGuard<'a,T>{
 
 impl<'a, T> Drop for ReadRWLockGuard<'a, T> {
     fn drop(&mut self) {
-        lock.unlock();
+        self.lock.unlock_read();
     }
 }
 
@@ -88,7 +174,7 @@ pub struct WriteRWLockGuard<'a,T>{
 
 impl<'a, T> Drop for WriteRWLockGuard<'a, T> {
     fn drop(&mut self) {
-        lock.unlock();
+        self.lock.unlock_write();
     }
 }
 
@@ -99,57 +185,112 @@ impl<'a,T> Deref for WriteRWLockGuard<'a,T>{
     }
 }
 impl<'a,T> DerefMut for WriteRWLockGuard<'a,T>{
-    fn deref_mut(&self)->&mut Self::Target{
+    fn deref_mut(&mut self)->&mut Self::Target{
         return unsafe{&mut *self.lock.data.get()};
     }
 }
 
+///Decides who gets the lock, if readers and writers compete for a [`RWLock`].
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum RWLockMode{
+    ///New readers may always join active readers.
+    ///A steady stream of readers can starve writers.
+    ReaderPreferring,
+    ///New readers are blocked, as soon as a writer is waiting.
+    ///Writers can therefore not be starved, but a steady stream of writers can starve readers.
+    WriterPreferring,
+}
+
+//Layout of RWLock::state:
+// bit 63: a writer holds the lock
+// bits 32..=62: number of writers waiting for the lock
+// bits 0..=31: number of readers holding the lock
+const WRITER:u64 = 1<<63;
+const WAITING_WRITER:u64 = 1<<32;
+const WAITING_MASK:u64 = !WRITER & !(WAITING_WRITER-1);
+const READER_MASK:u64 = WAITING_WRITER-1;
 
 pub struct RWLock<T>{
-    locked: AtomicU8,
+    state: AtomicU64,
+    mode: RWLockMode,
+    #[cfg(feature="lockdep")]
+    class: Option<&'static LockClass>,
     data: UnsafeCell<T>,
 }
 
+unsafe impl<T:Send> Send for RWLock<T>{}
+unsafe impl<T:Send+Sync> Sync for RWLock<T>{}
+
 impl<T> RWLock<T>{
+    ///Creates a reader preferring RWLock.
     pub const fn new(data:T)->Self{
+        return Self::with_mode(data, RWLockMode::ReaderPreferring);
+    }
+
+    ///Creates a RWLock, that blocks new readers, once a writer is waiting.
+    pub const fn new_writer_preferring(data:T)->Self{
+        return Self::with_mode(data, RWLockMode::WriterPreferring);
+    }
+
+    pub const fn with_mode(data:T, mode:RWLockMode)->Self{
+        return Self{
+            state: AtomicU64::new(0),
+            mode,
+            #[cfg(feature="lockdep")]
+            class: None,
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    ///Creates a RWLock, that belongs to the given lock class.
+    #[allow(unused_variables)]
+    pub const fn with_class(data:T, mode:RWLockMode, class:&'static LockClass)->Self{
         return Self{
-            locked: AtomicU8::new(0),
+            state: AtomicU64::new(0),
+            mode,
+            #[cfg(feature="lockdep")]
+            class: Some(class),
             data: UnsafeCell::new(data),
         }
     }
 
+    pub fn mode(&self)->RWLockMode{
+        return self.mode;
+    }
+
     ///Tries to lock the data behind this lock.
     ///This function will return None if the lock is already locked in a mutable manner.
-    pub fn try_read_lock(&self)->Option<ReadRWLockGuard<T>>{
-        let locked = self.locked.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |x| {
-            if x == 0xFE || x == 0xFF {
-                return None;
-            }
-            return Some(x+1);
-        });
-        if locked.is_ok(){
-            return Some(ReadLockGuard{lock: self});
+    ///In [`RWLockMode::WriterPreferring`] this function will also return None, if a writer is waiting.
+    #[track_caller]
+    pub fn try_read_lock(&self)->Option<ReadRWLockGuard<'_,T>>{
+        if self.raw_try_read_lock(){
+            #[cfg(feature="lockdep")]
+            crate::lockdep::acquire(self as *const Self as usize, self.class, 0, Location::caller(), true, true);
+            return Some(ReadRWLockGuard{lock: self});
         }
         return None;
     }
 
     ///Locks the data behind this lock.
-    ///This function return None if the lock is already locked in a mutable manner.
     ///This function will block until the lock is acquired.
     ///Not running the drop function of the returned guard will result in this lock being stuck as permanently locked!
-    pub fn read_lock(&self)->ReadRWLockGuard<T>{
-        loop{
-            if let Some(guard) = self.try_read_lock(){
-                return guard;
-            }
+    #[track_caller]
+    pub fn read_lock(&self)->ReadRWLockGuard<'_,T>{
+        #[cfg(feature="lockdep")]
+        crate::lockdep::acquire(self as *const Self as usize, self.class, 0, Location::caller(), true, false);
+        while !self.raw_try_read_lock(){
+            core::hint::spin_loop();
         }
+        return ReadRWLockGuard{lock: self};
     }
 
     ///Tries to lock the data behind this lock in a mutable manner.
     ///If Read locks are active, this function will return None.
-    pub fn try_write_lock(&self)->Option<WriteRWLockGuard<T>>{
-        let locked = self.locked.compare_exchange(0,0xFF, Ordering::SeqCst,Ordering::SeqCst);
-        if locked.is_ok(){
+    #[track_caller]
+    pub fn try_write_lock(&self)->Option<WriteRWLockGuard<'_,T>>{
+        if self.raw_try_write_lock(){
+            #[cfg(feature="lockdep")]
+            crate::lockdep::acquire(self as *const Self as usize, self.class, 0, Location::caller(), false, true);
             return Some(WriteRWLockGuard{lock: self});
         }
         return None;
@@ -157,19 +298,66 @@ impl<T> RWLock<T>{
 
     ///Locks the data behind this lock in a mutable manner.
     ///This function will block until the lock is acquired.
+    ///While this function waits, new readers are blocked in [`RWLockMode::WriterPreferring`].
     ///Not running the drop function of the returned guard will result in this lock being stuck as permanently locked!
-    pub fn write_lock(&self)->WriteRWLockGuard<T>{
+    #[track_caller]
+    pub fn write_lock(&self)->WriteRWLockGuard<'_,T>{
+        #[cfg(feature="lockdep")]
+        crate::lockdep::acquire(self as *const Self as usize, self.class, 0, Location::caller(), false, false);
+        if self.raw_try_write_lock(){
+            return WriteRWLockGuard{lock: self};
+        }
+        self.state.fetch_add(WAITING_WRITER, Ordering::Relaxed);
         loop{
-            if let Some(guard) = self.try_write_lock(){
-                return guard;
+            //Take the lock and stop counting ourselves as waiting in one step.
+            let locked = self.state.fetch_update(Ordering::Acquire, Ordering::Relaxed, |x| {
+                if x & (WRITER|READER_MASK) != 0 {
+                    return None;
+                }
+                return Some((x-WAITING_WRITER)|WRITER);
+            });
+            if locked.is_ok(){
+                return WriteRWLockGuard{lock: self};
             }
+            core::hint::spin_loop();
         }
     }
-    
+
+    fn raw_try_read_lock(&self)->bool{
+        return self.state.fetch_update(Ordering::Acquire, Ordering::Relaxed, |x| {
+            if x & WRITER != 0 || x & READER_MASK == READER_MASK {
+                return None;
+            }
+            if self.mode == RWLockMode::WriterPreferring && x & WAITING_MASK != 0 {
+                return None;
+            }
+            return Some(x+1);
+        }).is_ok();
+    }
+
+    fn raw_try_write_lock(&self)->bool{
+        return self.state.fetch_update(Ordering::Acquire, Ordering::Relaxed, |x| {
+            if x & (WRITER|READER_MASK) != 0 {
+                return None;
+            }
+            return Some(x|WRITER);
+        }).is_ok();
+    }
+
+    ///Returns a mutable reference to the data.
+    ///No locking is needed, since the mutable borrow guarantees, that nobody else can access the lock.
+    pub fn get_mut(&mut self)->&mut T{
+        return self.data.get_mut();
+    }
+
     fn unlock_read(&self){
-        self.locked.fetch_sub(1, Ordering::SeqCst);
+        #[cfg(feature="lockdep")]
+        crate::lockdep::release(self as *const Self as usize);
+        self.state.fetch_sub(1, Ordering::Release);
     }
     fn unlock_write(&self){
-        self.locked.store(0, Ordering::SeqCst);
+        #[cfg(feature="lockdep")]
+        crate::lockdep::release(self as *const Self as usize);
+        self.state.fetch_and(!WRITER, Ordering::Release);
     }
-}
\ No newline at end of file
+}
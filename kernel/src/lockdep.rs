@@ -0,0 +1,309 @@
+//A small lock dependency checker.
+//Every Lock and RWLock belongs to a LockClass.
+//Whenever a lock is acquired, while other locks are held on the same cpu, the lock order is recorded as an edge in a graph between the classes.
+//A cycle in that graph means, that two code paths take the same locks in a different order, which can deadlock.
+//Additionally a class, that is taken with interrupts enabled in one place and with interrupts disabled in another, is reported.
+//
+//This module must never take a Lock itself, since it runs inside of Lock::lock.
+use core::cell::UnsafeCell;
+use core::panic::Location;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, AtomicUsize, Ordering};
+use crate::lock::LockClass;
+use crate::x86_64::cpu::{self, MAX_CPUS};
+
+///The maximum amount of lock classes, that can be tracked.
+///The dependency graph is stored as one u64 bitmap per class, so this can't be raised above 64.
+pub const MAX_CLASSES:usize = 64;
+///Subclasses of a class, for nesting locks of the same class. See Lock::lock_nested.
+///Classes and locks are at least this many bytes big, so the keys of the subclasses never hit another class or lock.
+const MAX_SUBCLASSES:usize = 8;
+///The maximum amount of locks, that a single cpu can hold at once.
+const MAX_HELD:usize = 16;
+
+struct ClassSlot{
+	key:AtomicUsize,
+	class:AtomicPtr<LockClass>,
+	irq_enabled_site:AtomicPtr<Location<'static>>,
+	irq_disabled_site:AtomicPtr<Location<'static>>,
+	irq_reported:AtomicBool,
+}
+
+#[derive(Copy, Clone)]
+struct Held{
+	class:usize,
+	key:usize,
+	site:&'static Location<'static>,
+}
+
+struct HeldStack{
+	len:usize,
+	entries:[Option<Held>;MAX_HELD],
+}
+
+struct PerCpu(UnsafeCell<HeldStack>);
+//Safety:
+//Every cpu only ever touches its own HeldStack, and only with interrupts disabled.
+unsafe impl Sync for PerCpu{}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY_SLOT:ClassSlot = ClassSlot{
+	key:AtomicUsize::new(0),
+	class:AtomicPtr::new(core::ptr::null_mut()),
+	irq_enabled_site:AtomicPtr::new(core::ptr::null_mut()),
+	irq_disabled_site:AtomicPtr::new(core::ptr::null_mut()),
+	irq_reported:AtomicBool::new(false),
+};
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY_PER_CPU:PerCpu = PerCpu(UnsafeCell::new(HeldStack{len:0,entries:[None;MAX_HELD]}));
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_DEPS:AtomicU64 = AtomicU64::new(0);
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_SITE:AtomicPtr<Location<'static>> = AtomicPtr::new(core::ptr::null_mut());
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_SITES:[AtomicPtr<Location<'static>>;MAX_CLASSES] = [NO_SITE;MAX_CLASSES];
+
+static ENABLED:AtomicBool = AtomicBool::new(true);
+static REPORTING:AtomicBool = AtomicBool::new(false);
+static REGISTERING:AtomicBool = AtomicBool::new(false);
+static CLASS_COUNT:AtomicUsize = AtomicUsize::new(0);
+static CLASSES:[ClassSlot;MAX_CLASSES] = [EMPTY_SLOT;MAX_CLASSES];
+///Bit b in DEPS[a] is set, if a lock of class b was acquired, while a lock of class a was held.
+static DEPS:[AtomicU64;MAX_CLASSES] = [NO_DEPS;MAX_CLASSES];
+///Where the lock of class b was acquired, when the edge a->b was first recorded.
+static EDGE_SITES:[[AtomicPtr<Location<'static>>;MAX_CLASSES];MAX_CLASSES] = [NO_SITES;MAX_CLASSES];
+///Where the lock of class a was acquired, when the edge a->b was first recorded.
+static EDGE_HELD_SITES:[[AtomicPtr<Location<'static>>;MAX_CLASSES];MAX_CLASSES] = [NO_SITES;MAX_CLASSES];
+static HELD:[PerCpu;MAX_CPUS] = [EMPTY_PER_CPU;MAX_CPUS];
+
+///Gets called by the locks, after they have been acquired.
+///`key` identifies the lock instance, `subclass` the nesting level within its class, `shared` is true for read locks and `try_lock` is true for try_lock style acquisitions.
+pub(crate) fn acquire(key:usize, class:Option<&'static LockClass>, subclass:u8, site:&'static Location<'static>, shared:bool, try_lock:bool){
+	if !ENABLED.load(Ordering::Relaxed){
+		return;
+	}
+	assert!((subclass as usize) < MAX_SUBCLASSES, "lockdep: subclass {} at {} is too big, there are only {}", subclass, site, MAX_SUBCLASSES);
+	let irqs = x86_64::instructions::interrupts::are_enabled();
+	x86_64::instructions::interrupts::without_interrupts(||{
+		let class_key = class.map(|c|c as *const LockClass as usize).unwrap_or(key)+subclass as usize;
+		let idx = match get_class(class_key, class){
+			Some(idx)=>idx,
+			None=>return,
+		};
+		//Safety:
+		//Interrupts are disabled, so nobody else on this cpu can touch the stack right now.
+		let held = unsafe{&mut *HELD[cpu::id()].0.get()};
+		//While we are reporting, the logger will take locks. Don't check those, to not recurse.
+		if !REPORTING.load(Ordering::Relaxed){
+			check_irq_state(idx, site, irqs);
+			//A try_lock can't deadlock, since it never waits. It can still be a dependency of later locks.
+			if !try_lock{
+				for h in held.entries[..held.len].iter().flatten(){
+					check_order(h, idx, key, site, shared);
+				}
+			}
+		}
+		if held.len == MAX_HELD{
+			report(|| log::warn!("lockdep: more than {} locks held on cpu {}. Lock at {} will not be tracked.", MAX_HELD, cpu::id(), site));
+			return;
+		}
+		held.entries[held.len] = Some(Held{class:idx,key,site});
+		held.len += 1;
+	});
+}
+
+///Gets called by the locks, when they are released.
+pub(crate) fn release(key:usize){
+	if !ENABLED.load(Ordering::Relaxed){
+		return;
+	}
+	x86_64::instructions::interrupts::without_interrupts(||{
+		//Safety:
+		//Interrupts are disabled, so nobody else on this cpu can touch the stack right now.
+		let held = unsafe{&mut *HELD[cpu::id()].0.get()};
+		//Locks don't have to be released in order, so search from the most recent lock downwards.
+		if let Some(pos) = held.entries[..held.len].iter().rposition(|h|h.map(|h|h.key)==Some(key)){
+			held.entries.copy_within(pos+1..held.len, pos);
+			held.len -= 1;
+			held.entries[held.len] = None;
+		}
+	});
+}
+
+fn get_class(key:usize, class:Option<&'static LockClass>)->Option<usize>{
+	let find = ||CLASSES[..CLASS_COUNT.load(Ordering::Acquire).min(MAX_CLASSES)]
+		.iter()
+		.position(|c|c.key.load(Ordering::Relaxed)==key);
+	if let Some(idx) = find(){
+		return Some(idx);
+	}
+	while REGISTERING.compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed).is_err(){
+		core::hint::spin_loop();
+	}
+	//Somebody else might have registered the class, while we waited.
+	let idx = find().or_else(||{
+		let idx = CLASS_COUNT.load(Ordering::Relaxed);
+		if idx >= MAX_CLASSES{
+			return None;
+		}
+		CLASSES[idx].key.store(key, Ordering::Relaxed);
+		CLASSES[idx].class.store(class.map(|c|c as *const LockClass as *mut LockClass).unwrap_or(core::ptr::null_mut()), Ordering::Relaxed);
+		CLASS_COUNT.store(idx+1, Ordering::Release);
+		Some(idx)
+	});
+	REGISTERING.store(false, Ordering::Release);
+	if idx.is_none(){
+		ENABLED.store(false, Ordering::Relaxed);
+		report(|| log::warn!("lockdep: more than {} lock classes in use. Lock dependency checking is now turned off.", MAX_CLASSES));
+	}
+	idx
+}
+
+fn check_irq_state(idx:usize, site:&'static Location<'static>, irqs:bool){
+	let slot = &CLASSES[idx];
+	let (this, other) = if irqs {
+		(&slot.irq_enabled_site, &slot.irq_disabled_site)
+	}else{
+		(&slot.irq_disabled_site, &slot.irq_enabled_site)
+	};
+	let _ = this.compare_exchange(core::ptr::null_mut(), site as *const Location as *mut Location, Ordering::Relaxed, Ordering::Relaxed);
+	let other = other.load(Ordering::Relaxed);
+	if !other.is_null() && !slot.irq_reported.swap(true, Ordering::Relaxed){
+		//Safety:
+		//We only ever store &'static Location in there.
+		let other = unsafe{&*other};
+		let (enabled, disabled) = if irqs {(site, other)} else {(other, site)};
+		report(||{
+			log::error!("lockdep: inconsistent interrupt state for lock class {}", ClassName(idx));
+			log::error!("lockdep:   acquired with interrupts enabled at {}", enabled);
+			log::error!("lockdep:   acquired with interrupts disabled at {}", disabled);
+			log::error!("lockdep: an interrupt taking this lock, while it is held with interrupts enabled, will deadlock.");
+		});
+	}
+}
+
+fn check_order(held:&Held, idx:usize, key:usize, site:&'static Location<'static>, shared:bool){
+	if held.class == idx{
+		//Recursive read locks of the same RWLock are fine. Everything else spins forever.
+		if !(shared && held.key == key){
+			report(||{
+				log::error!("lockdep: possible recursive locking of lock class {}", ClassName(idx));
+				log::error!("lockdep:   already held, acquired at {}", held.site);
+				log::error!("lockdep:   acquired again at {}", site);
+			});
+		}
+		return;
+	}
+	let bit = 1u64<<idx;
+	if DEPS[held.class].fetch_or(bit, Ordering::Relaxed) & bit != 0{
+		//We already know this edge, and it was checked when it was added.
+		return;
+	}
+	EDGE_SITES[held.class][idx].store(site as *const Location as *mut Location, Ordering::Relaxed);
+	EDGE_HELD_SITES[held.class][idx].store(held.site as *const Location as *mut Location, Ordering::Relaxed);
+	//We just added held.class -> idx. If idx can already reach held.class, we closed a cycle.
+	if let Some(path) = find_path(idx, held.class){
+		report(||{
+			log::error!("lockdep: possible circular locking dependency detected");
+			log::error!("lockdep:   {} acquired at {}", ClassName(idx), site);
+			log::error!("lockdep:   while holding {} acquired at {}", ClassName(held.class), held.site);
+			log::error!("lockdep: but the reverse order has already been seen:");
+			let mut from = idx;
+			for to in path.iter(){
+				log::error!("lockdep:   {} acquired at {}", ClassName(to), edge_site(&EDGE_SITES, from, to));
+				log::error!("lockdep:   while holding {} acquired at {}", ClassName(from), edge_site(&EDGE_HELD_SITES, from, to));
+				from = to;
+			}
+		});
+	}
+}
+
+///The path through the graph, excluding the start node.
+struct Path{
+	nodes:[usize;MAX_CLASSES],
+	len:usize,
+}
+impl Path{
+	fn iter(&self)->impl Iterator<Item=usize>+'_{
+		self.nodes[..self.len].iter().copied()
+	}
+}
+
+///Breadth first search from `from` to `to` in the dependency graph.
+fn find_path(from:usize, to:usize)->Option<Path>{
+	let mut parent = [usize::MAX;MAX_CLASSES];
+	let mut queue = [0usize;MAX_CLASSES];
+	let (mut head, mut tail) = (0, 1);
+	let mut visited = 1u64<<from;
+	queue[0] = from;
+	while head < tail{
+		let node = queue[head];
+		head += 1;
+		let mut next = DEPS[node].load(Ordering::Relaxed) & !visited;
+		while next != 0{
+			let n = next.trailing_zeros() as usize;
+			next &= next-1;
+			visited |= 1<<n;
+			parent[n] = node;
+			if n == to{
+				let mut path = Path{nodes:[0;MAX_CLASSES],len:0};
+				let mut cur = n;
+				while cur != from{
+					path.nodes[path.len] = cur;
+					path.len += 1;
+					cur = parent[cur];
+				}
+				path.nodes[..path.len].reverse();
+				return Some(path);
+			}
+			queue[tail] = n;
+			tail += 1;
+		}
+	}
+	None
+}
+
+fn edge_site(sites:&[[AtomicPtr<Location<'static>>;MAX_CLASSES];MAX_CLASSES], from:usize, to:usize)->Site{
+	let site = sites[from][to].load(Ordering::Relaxed);
+	//Safety:
+	//We only ever store &'static Location in there.
+	//We might race with the cpu adding the edge, in which case the site is still null.
+	Site(unsafe{site.as_ref()})
+}
+
+struct Site(Option<&'static Location<'static>>);
+impl core::fmt::Display for Site{
+	fn fmt(&self, f:&mut core::fmt::Formatter<'_>)->core::fmt::Result{
+		match self.0{
+			Some(l)=>core::fmt::Display::fmt(l, f),
+			None=>f.write_str("<unknown>"),
+		}
+	}
+}
+
+///Runs `f`, while making sure, that the locks taken by the logger are not checked.
+fn report(f:impl FnOnce()){
+	if REPORTING.swap(true, Ordering::Acquire){
+		return;
+	}
+	f();
+	REPORTING.store(false, Ordering::Release);
+}
+
+struct ClassName(usize);
+impl core::fmt::Display for ClassName{
+	fn fmt(&self, f:&mut core::fmt::Formatter<'_>)->core::fmt::Result{
+		let slot = &CLASSES[self.0];
+		let class = slot.class.load(Ordering::Relaxed);
+		let key = slot.key.load(Ordering::Relaxed);
+		if class.is_null(){
+			return write!(f, "lock@{:#x}", key);
+		}
+		//Safety:
+		//We only ever store &'static LockClass in there.
+		f.write_str(unsafe{&*class}.name())?;
+		match key-class as usize{
+			0=>Ok(()),
+			subclass=>write!(f, "/{}", subclass),
+		}
+	}
+}
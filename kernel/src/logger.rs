@@ -0,0 +1,63 @@
+//The log backend of the kernel.
+//Records are written to the serial port, prefixed with the time since boot.
+use core::fmt::Write;
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use crate::cmdline::{self, BoolParam, EnumParam};
+use crate::drivers::serial;
+
+struct Logger;
+
+static LOGGER:Logger = Logger;
+
+const LEVELS:[LevelFilter;6] = [LevelFilter::Off, LevelFilter::Error, LevelFilter::Warn, LevelFilter::Info, LevelFilter::Debug, LevelFilter::Trace];
+///`loglevel=` on the command line.
+static LOGLEVEL:EnumParam = EnumParam::new("loglevel", &["off", "error", "warn", "info", "debug", "trace"], 3);
+///`log_colors=off` turns off the ANSI colors of the levels.
+static COLORS:BoolParam = BoolParam::new("log_colors", true);
+
+///Installs the logger, with the level from the command line.
+///Records are dropped, until serial::init has found a serial port.
+pub fn init(){
+	cmdline::register(&LOGLEVEL);
+	cmdline::register(&COLORS);
+	if log::set_logger(&LOGGER).is_ok(){
+		log::set_max_level(LEVELS[LOGLEVEL.get()]);
+	}
+}
+
+impl Log for Logger{
+	fn enabled(&self, metadata:&Metadata)->bool{
+		metadata.level() <= log::max_level()
+	}
+
+	fn log(&self, record:&Record){
+		if !self.enabled(record.metadata()){
+			return;
+		}
+		let target = if !record.target().is_empty(){
+			record.target()
+		}else{
+			record.module_path().unwrap_or_default()
+		};
+		let time = crate::time::monotonic();
+		//The whole record is written with the port locked, so records from different cpus don't get mixed up.
+		serial::with_port(|o|{
+			write!(o, "[{:5}.{:06}] ", time.as_secs(), time.subsec_micros()).ok();
+			if COLORS.get(){
+				let color = match record.level(){
+					Level::Error=>"\x1b[31m",
+					Level::Warn=>"\x1b[33m",
+					Level::Info=>"\x1b[36m",
+					Level::Debug=>"\x1b[35m",
+					Level::Trace=>"\x1b[37m",
+				};
+				write!(o, "{}{:5}\x1b[0m", color, record.level()).ok();
+			}else{
+				write!(o, "{:5}", record.level()).ok();
+			}
+			writeln!(o, " [{}] {}", target, record.args()).ok();
+		});
+	}
+
+	fn flush(&self){}
+}
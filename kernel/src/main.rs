@@ -8,13 +8,47 @@ mod rust_lang;
 mod uefi_rs;
 mod fb;
 mod x86_64;
+mod acpi;
+mod cmdline;
+mod kaslr;
+mod symbols;
+mod backtrace;
+mod block;
+mod fs;
+mod time;
+mod drivers;
+mod logger;
 mod lock;
+mod sched;
+mod sync;
+#[cfg(feature="lockdep")]
+mod lockdep;
 
 extern crate alloc;
 
 #[no_mangle]
 fn _start() {
+	//Locks need the cpu index, and everything takes locks.
+	crate::x86_64::cpu::init();
 	let args=unsafe{core::ptr::read_volatile(kernel_efi::ARGS_ADDR)};
+	kaslr::init(&args);
+	symbols::init(&args.symbols);
+	backtrace::init(&args.symbols);
+	let rsdp=args.rsdp;
+	let boot_partition=block::BootPartition::from_args(&args.boot_partition);
+	//Safety:
+	//The boot loader loaded the initrd there, and marked its pages as used, so nothing else gets them.
+	let initrd=(args.initrd_size!=0).then(||unsafe{core::slice::from_raw_parts(
+		crate::x86_64::mem::phys_to_virt(::x86_64::PhysAddr::new(args.initrd_base)).as_ptr::<u8>(),
+		args.initrd_size as usize,
+	)});
+	cmdline::init(args.cmdline());
+	//Set up the serial port first, so everything after it can log.
+	drivers::serial::init();
+	logger::init();
+	log::info!("Command line: {}",cmdline::get());
+	//The tick rate is already needed by time::init.
+	sched::init_params();
 	let fb:fb::FB<'static,'static,4>={
 		//We are reading memory outside th scope of this binary.
 		//No assumptions should be made about the contents of ARGS_ADDR
@@ -25,7 +59,34 @@ fn _start() {
 			ph,
 		}
 	};
-	
+
+	crate::x86_64::interrupts::init();
+	crate::x86_64::apic::init();
+	acpi::init(rsdp);
+	crate::x86_64::cpu::register_madt();
+	crate::x86_64::ioapic::init();
+	drivers::serial::init_irq();
+	drivers::ps2::init();
+	time::init();
+	sched::init_cpu();
+	//Device drivers block on wait queues, so they start after the scheduler.
+	drivers::pci::init();
+	drivers::virtio::init();
+	drivers::pci::register_driver(&drivers::ahci::DRIVER);
+	drivers::pci::register_driver(&drivers::nvme::DRIVER);
+	match boot_partition{
+		Some(boot)=>{
+			block::set_boot_partition(boot);
+			match block::boot_partition(){
+				Some(esp)=>log::info!("Booted from {}", esp.name()),
+				None=>log::warn!("Can't find the boot partition {:?}", boot),
+			}
+		},
+		None=>log::warn!("The boot loader didn't tell us the boot partition"),
+	}
+	fs::init(initrd);
+	cmdline::check();
+
 	loop{
 		x86_64::instructions::hlt();
 	}
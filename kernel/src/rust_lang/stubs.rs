@@ -1,18 +1,68 @@
+use core::fmt::Write;
 use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use crate::drivers::serial::{self, SerialPort};
+use crate::fb::console::Console;
 
 // #[lang = "eh_personality"]
 // fn eh_personality() {}
 
-#[panic_handler]
-fn panic(_info: &PanicInfo) -> ! {
-    for _ in 0..u32::MAX {
-        x86_64::instructions::nop();
+const NO_CPU:usize = usize::MAX;
+///The cpu, that is printing the panic. Everyone else, that panics, stops right away.
+static PANIC_CPU:AtomicUsize = AtomicUsize::new(NO_CPU);
+///Set, when the panic handler itself panicked.
+static NESTED:AtomicBool = AtomicBool::new(false);
+
+///Writes to all sinks, that are available.
+struct Sinks{
+    serial:Option<SerialPort>,
+    fb:Option<Console>,
+}
+
+impl Write for Sinks{
+    fn write_str(&mut self, s:&str)->core::fmt::Result{
+        if let Some(port) = &mut self.serial{
+            port.write_str(s).ok();
+        }
+        if let Some(fb) = &mut self.fb{
+            fb.write_str(s).ok();
+        }
+        Ok(())
     }
-    #[cfg(not(feature = "core_intrinsics"))]
-    loop {
-        #[cfg(target_arch="x86_64")]
+}
+
+fn halt()->!{
+    loop{
+        x86_64::instructions::interrupts::disable();
         x86_64::instructions::hlt();
     }
-    #[cfg(feature="core_intrinsics")]
-    core::intrinsics::abort();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    x86_64::instructions::interrupts::disable();
+    let cpu = crate::x86_64::cpu::id();
+    if let Err(owner) = PANIC_CPU.compare_exchange(NO_CPU, cpu, Ordering::AcqRel, Ordering::Acquire){
+        //Printing the first panic panicked. Only the serial port is tried, since the framebuffer might be what broke.
+        //A third panic means even that didn't work.
+        if owner == cpu && !NESTED.swap(true, Ordering::Relaxed){
+            //Safety:
+            //We are the only one printing.
+            if let Some(mut port) = unsafe{serial::steal()} {
+                writeln!(port, "\nPanic while panicking: {}", info).ok();
+            }
+        }
+        halt();
+    }
+    //The other cpus stop in the NMI handler, so they don't print over us or keep changing the state we print.
+    crate::x86_64::interrupts::register_handler(crate::x86_64::interrupts::NMI_VECTOR, |_|halt());
+    crate::x86_64::apic::send_nmi_to_others();
+    //Safety:
+    //Whoever holds the serial port lock or draws on the screen might never finish now, so write without asking them.
+    let mut out = Sinks{serial:unsafe{serial::steal()}, fb:unsafe{Console::steal()}};
+    writeln!(out, "\nKernel panic on cpu {}: {}", cpu, info).ok();
+    crate::kaslr::print_bases(&mut out).ok();
+    writeln!(out, "Backtrace:").ok();
+    crate::backtrace::print(&mut out).ok();
+    halt();
 }
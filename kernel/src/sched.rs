@@ -0,0 +1,330 @@
+//A preemptive round-robin scheduler for kernel tasks.
+//Every cpu has its own run queue. Tasks never migrate between cpus.
+//A task switch happens on the way out of an interrupt: the interrupt stubs save the full register state as an InterruptFrame on the task's stack,
+//and resume whatever frame switch_if_needed returns.
+//Voluntary switches (yield, sleep, exit) raise YIELD_VECTOR, so they go through the same path.
+mod task;
+
+pub use task::{JoinHandle, Task, TaskState};
+
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::time::Duration;
+use x86_64::instructions::interrupts;
+use crate::cmdline::{self, IntParam};
+use crate::lock::{Lock, LockClass};
+use crate::time::hpet;
+use crate::x86_64::apic;
+use crate::x86_64::cpu::{self, MAX_CPUS};
+use crate::x86_64::interrupts::{register_handler, InterruptFrame, TIMER_VECTOR, YIELD_VECTOR};
+
+///Timer ticks per second, `sched_hz=` on the command line.
+///Has to be registered before time::init, and can't change after that.
+static HZ:IntParam = IntParam::new("sched_hz", 250, 10, 1000);
+///The initial count of the LAPIC timer, if it couldn't be calibrated, and there is no HPET comparator to tick with either.
+///The tick rate then depends on the bus clock of the machine.
+const UNCALIBRATED_TIMER_COUNT:u32 = 0x2_0000;
+
+static RUN_QUEUE_CLASS:LockClass = LockClass::new("sched::run_queue");
+static SLEEPING_CLASS:LockClass = LockClass::new("sched::sleeping");
+static RESULT_CLASS:LockClass = LockClass::new("sched::task::result");
+
+struct CpuSched{
+	online:AtomicBool,
+	run_queue:Lock<VecDeque<Arc<Task>>>,
+	sleeping:Lock<Vec<Arc<Task>>>,
+	///Only accessed by the owning cpu with interrupts disabled.
+	current:UnsafeCell<Option<Arc<Task>>>,
+	///Runs, when the run queue is empty.
+	idle:UnsafeCell<Option<Arc<Task>>>,
+	///A task, that exited on this cpu.
+	///It is only dropped on the next switch, since we are still running on its stack, while switching away from it.
+	dead:UnsafeCell<Option<Arc<Task>>>,
+	need_resched:AtomicBool,
+	ticks:AtomicU64,
+	///The HPET comparator, that drives the tick, if the LAPIC timer couldn't be calibrated.
+	///Only accessed by the owning cpu with interrupts disabled.
+	tick_timer:UnsafeCell<Option<(hpet::Timer, hpet::Mode)>>,
+}
+
+//Safety:
+//The UnsafeCells are only ever accessed by the cpu owning this CpuSched, with interrupts disabled.
+unsafe impl Sync for CpuSched{}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const CPU_SCHED:CpuSched = CpuSched{
+	online:AtomicBool::new(false),
+	run_queue:Lock::with_class(VecDeque::new(), &RUN_QUEUE_CLASS),
+	sleeping:Lock::with_class(Vec::new(), &SLEEPING_CLASS),
+	current:UnsafeCell::new(None),
+	idle:UnsafeCell::new(None),
+	dead:UnsafeCell::new(None),
+	need_resched:AtomicBool::new(false),
+	ticks:AtomicU64::new(0),
+	tick_timer:UnsafeCell::new(None),
+};
+static CPUS:[CpuSched;MAX_CPUS] = [CPU_SCHED;MAX_CPUS];
+
+///Registers the command line parameters of the scheduler.
+pub fn init_params(){
+	cmdline::register(&HZ);
+}
+
+///Timer ticks per second.
+pub fn hz()->u32{
+	HZ.get() as u32
+}
+
+fn this_cpu()->&'static CpuSched{
+	&CPUS[cpu::id()]
+}
+
+///Turns the code, that is currently running on this cpu, into a task and starts scheduling.
+///Needs to be called once on every cpu, after the IDT and LAPIC have been set up.
+///Enables interrupts.
+pub fn init_cpu(){
+	interrupts::disable();
+	let id = cpu::id();
+	let sched = &CPUS[id];
+	//Safety:
+	//Interrupts are disabled, and we are the owning cpu.
+	unsafe{
+		*sched.current.get() = Some(Task::from_current("boot", id));
+		*sched.idle.get() = Some(Task::new("idle", id, Box::new(idle)));
+	}
+	register_handler(TIMER_VECTOR, tick);
+	register_handler(YIELD_VECTOR, |_|this_cpu().need_resched.store(true, Ordering::Relaxed));
+	sched.online.store(true, Ordering::Release);
+	match crate::time::lapic_timer_count(hz()){
+		Some(count)=>apic::start_timer_periodic(TIMER_VECTOR, count),
+		None=>start_fallback_tick(sched),
+	}
+	log::info!("Scheduler running on cpu {}", id);
+	interrupts::enable();
+}
+
+fn tick_period()->Duration{
+	Duration::from_nanos(1_000_000_000/hz() as u64)
+}
+
+///Ticks with a HPET comparator, since the LAPIC timer couldn't be calibrated.
+///Without a free comparator, the LAPIC timer runs uncalibrated.
+///Has to be called with interrupts disabled.
+fn start_fallback_tick(sched:&CpuSched){
+	if let Some(timer) = hpet::Timer::new(hpet_tick){
+		let mode = if timer.supports_periodic() {hpet::Mode::Periodic} else {hpet::Mode::OneShot};
+		if timer.start(mode, tick_period()){
+			log::info!("The LAPIC timer isn't calibrated. Cpu {} ticks with a {:?} HPET comparator.", cpu::id(), mode);
+			//Safety:
+			//Interrupts are disabled, and we are the owning cpu.
+			unsafe{*sched.tick_timer.get() = Some((timer, mode))};
+			return;
+		}
+	}
+	log::warn!("The LAPIC timer isn't calibrated, and there is no HPET comparator for cpu {}. The tick rate is a guess.", cpu::id());
+	apic::start_timer_periodic(TIMER_VECTOR, UNCALIBRATED_TIMER_COUNT);
+}
+
+fn hpet_tick(frame:&mut InterruptFrame){
+	//Safety:
+	//We are in an interrupt handler, so interrupts are disabled, and we only access our own cpu.
+	if let Some((timer, hpet::Mode::OneShot)) = unsafe{&*this_cpu().tick_timer.get()}{
+		timer.start(hpet::Mode::OneShot, tick_period());
+	}
+	tick(frame);
+}
+
+fn idle(){
+	loop{
+		x86_64::instructions::interrupts::enable_and_hlt();
+	}
+}
+
+///Starts a new task on the current cpu.
+pub fn spawn<F, T>(name:&'static str, f:F)->JoinHandle<T>
+where F:FnOnce()->T+Send+'static, T:Send+'static{
+	spawn_on(cpu::id(), name, f)
+}
+
+///Starts a new task on the given cpu.
+pub fn spawn_on<F, T>(cpu:usize, name:&'static str, f:F)->JoinHandle<T>
+where F:FnOnce()->T+Send+'static, T:Send+'static{
+	assert!(CPUS[cpu].online.load(Ordering::Acquire), "cpu {} is not running the scheduler", cpu);
+	let result = Arc::new(Lock::with_class(None, &RESULT_CLASS));
+	let task_result = result.clone();
+	let task = Task::new(name, cpu, Box::new(move ||{
+		let r = f();
+		*task_result.lock() = Some(r);
+	}));
+	interrupts::without_interrupts(||CPUS[cpu].run_queue.lock().push_back(task.clone()));
+	JoinHandle{task, result}
+}
+
+///Returns the task, that is currently running.
+pub fn current()->Arc<Task>{
+	interrupts::without_interrupts(||{
+		//Safety:
+		//Interrupts are disabled, and we only access our own cpu.
+		unsafe{&*this_cpu().current.get()}.clone().expect("the scheduler is not running on this cpu")
+	})
+}
+
+///The number of timer ticks, that happened on this cpu.
+pub fn ticks()->u64{
+	this_cpu().ticks.load(Ordering::Relaxed)
+}
+
+///Gives up the cpu and lets the next task run.
+///The current task stays runnable, unless its state was changed before.
+pub fn yield_now(){
+	//Safety:
+	//0xF1 is YIELD_VECTOR. Software interrupts are delivered, even with interrupts disabled.
+	unsafe{core::arch::asm!("int 0xF1")};
+}
+
+///Puts the current task to sleep for at least `ticks` timer ticks.
+pub fn sleep_ticks(ticks:u64){
+	interrupts::without_interrupts(||{
+		let sched = this_cpu();
+		let task = current();
+		task.wake_at.store(sched.ticks.load(Ordering::Relaxed)+ticks, Ordering::Relaxed);
+		task.set_state(TaskState::Sleeping);
+		queue_timeout(sched, task);
+		yield_now();
+	});
+}
+
+///Puts the current task to sleep for at least `d`.
+pub fn sleep(d:Duration){
+	sleep_ticks(duration_to_ticks(d));
+}
+
+///Converts `d` to timer ticks, rounding up.
+pub fn duration_to_ticks(d:Duration)->u64{
+	let ticks = (d.as_nanos()*hz() as u128).div_ceil(1_000_000_000);
+	ticks.min(u64::MAX as u128) as u64
+}
+
+///Marks the current task as blocked.
+///Once it yields, it only runs again after somebody calls [`wake`] on it.
+///If it gets woken before it yields, the yield just ends its time slice.
+pub(crate) fn prepare_block(){
+	current().transition(TaskState::Running, TaskState::Blocked);
+}
+
+///Like [`prepare_block`], but the task also gets woken up after `ticks` timer ticks.
+///Returns the tick, at which that happens.
+pub(crate) fn prepare_block_timeout(ticks:u64)->u64{
+	interrupts::without_interrupts(||{
+		let sched = this_cpu();
+		let task = current();
+		let wake_at = sched.ticks.load(Ordering::Relaxed)+ticks;
+		task.wake_at.store(wake_at, Ordering::Relaxed);
+		task.transition(TaskState::Running, TaskState::Blocked);
+		queue_timeout(sched, task);
+		wake_at
+	})
+}
+
+///Takes the current task out of the sleeping list again, after a [`prepare_block_timeout`], that got woken up before the timeout.
+pub(crate) fn cancel_timeout(){
+	interrupts::without_interrupts(||{
+		let task = current();
+		let mut sleeping = this_cpu().sleeping.lock();
+		if task.timeout_queued.swap(false, Ordering::Relaxed){
+			sleeping.retain(|t|!Arc::ptr_eq(t, &task));
+		}
+	})
+}
+
+///Puts `task` into the sleeping list, unless it is still in there from an earlier timeout. Then only its wake_at changed.
+///Has to be called with interrupts disabled.
+fn queue_timeout(sched:&CpuSched, task:Arc<Task>){
+	let mut sleeping = sched.sleeping.lock();
+	if !task.timeout_queued.swap(true, Ordering::Relaxed){
+		sleeping.push(task);
+	}
+}
+
+///Makes a sleeping or blocked task runnable again.
+///Returns false, if the task wasn't sleeping or blocked.
+pub(crate) fn wake(task:&Arc<Task>)->bool{
+	if task.transition(TaskState::Blocked, TaskState::Ready) || task.transition(TaskState::Sleeping, TaskState::Ready){
+		interrupts::without_interrupts(||CPUS[task.cpu()].run_queue.lock().push_back(task.clone()));
+		return true;
+	}
+	false
+}
+
+///Ends the current task.
+pub(crate) fn exit()->!{
+	interrupts::disable();
+	let task = current();
+	task.set_state(TaskState::Dead);
+	task.exited.wake_all();
+	drop(task);
+	yield_now();
+	unreachable!("a dead task was scheduled again");
+}
+
+fn tick(_frame:&mut InterruptFrame){
+	let sched = this_cpu();
+	let now = sched.ticks.fetch_add(1, Ordering::Relaxed)+1;
+	crate::time::tick();
+	let mut woken = Vec::new();
+	sched.sleeping.lock().retain(|t|{
+		if t.wake_at.load(Ordering::Relaxed) <= now{
+			t.timeout_queued.store(false, Ordering::Relaxed);
+			woken.push(t.clone());
+			return false;
+		}
+		true
+	});
+	//Tasks, that blocked with a timeout, might have been woken up already. They are only woken up again, if they are still blocked,
+	//which waiters have to treat as a spurious wake up.
+	for t in woken{
+		wake(&t);
+	}
+	//Round robin: every tick ends the time slice of the current task.
+	sched.need_resched.store(true, Ordering::Relaxed);
+}
+
+///Gets called at the end of every interrupt.
+///Returns the frame, that should be resumed: either the interrupted one, or the one of the next task.
+pub(crate) fn switch_if_needed(frame:&mut InterruptFrame)->*mut InterruptFrame{
+	let sched = this_cpu();
+	if !sched.online.load(Ordering::Acquire) || !sched.need_resched.swap(false, Ordering::Relaxed){
+		return frame;
+	}
+	//Safety:
+	//We are in an interrupt handler, so interrupts are disabled, and we only access our own cpu.
+	let (current, idle, dead) = unsafe{(&mut *sched.current.get(), &*sched.idle.get(), &mut *sched.dead.get())};
+	//We are on another task's stack now (or the interrupt frame of the dead task is not needed anymore), so the dead task can go.
+	drop(dead.take());
+
+	let prev = current.take().expect("the scheduler is not running on this cpu");
+	prev.set_frame(frame);
+	let is_idle = idle.as_ref().map(|i|Arc::ptr_eq(i, &prev)).unwrap_or(false);
+	match prev.state(){
+		TaskState::Running=>{
+			prev.set_state(TaskState::Ready);
+			if !is_idle{
+				sched.run_queue.lock().push_back(prev);
+			}
+		},
+		TaskState::Dead=>*dead = Some(prev),
+		//Sleeping and Blocked tasks are owned by whatever will wake them up.
+		//Ready tasks were woken up, before they could switch away, and are already queued again.
+		TaskState::Sleeping|TaskState::Blocked|TaskState::Ready=>{},
+	}
+
+	let next = sched.run_queue.lock().pop_front().unwrap_or_else(||idle.clone().expect("every online cpu has an idle task"));
+	next.set_state(TaskState::Running);
+	let next_frame = next.frame();
+	*current = Some(next);
+	next_frame
+}
@@ -0,0 +1,191 @@
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, AtomicUsize, Ordering};
+use x86_64::instructions::segmentation::{CS, SS, Segment};
+use crate::lock::{Lock, LockClass};
+use crate::sync::WaitQueue;
+use crate::x86_64::interrupts::InterruptFrame;
+
+///The size of the stack of every kernel task.
+pub const STACK_SIZE:usize = 64*1024;
+///Interrupts enabled, and the always set reserved bit 1.
+const INITIAL_RFLAGS:u64 = 0x202;
+
+static NEXT_ID:AtomicU64 = AtomicU64::new(0);
+static ENTRY_CLASS:LockClass = LockClass::new("sched::task::entry");
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct TaskId(u64);
+
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum TaskState{
+	///Waiting in a run queue.
+	Ready,
+	///Currently running on its cpu.
+	Running,
+	///Waiting for its wake up time.
+	Sleeping,
+	///Waiting for something else to wake it up.
+	Blocked,
+	///Has returned from its entry function.
+	Dead,
+}
+
+impl TaskState{
+	fn from_u8(v:u8)->Self{
+		match v{
+			0=>Self::Ready,
+			1=>Self::Running,
+			2=>Self::Sleeping,
+			3=>Self::Blocked,
+			_=>Self::Dead,
+		}
+	}
+}
+
+///A kernel thread.
+pub struct Task{
+	id:TaskId,
+	name:&'static str,
+	state:AtomicU8,
+	///The cpu, whose run queue this task belongs to.
+	cpu:usize,
+	///The InterruptFrame this task will be resumed with.
+	///Only valid, while the task isn't running.
+	frame:AtomicUsize,
+	///None for tasks, that were created from an already running context.
+	#[allow(dead_code)]
+	stack:Option<Box<[u8]>>,
+	entry:Lock<Option<Box<dyn FnOnce()+Send>>>,
+	///The tick, at which a sleeping task should be woken up.
+	pub(super) wake_at:AtomicU64,
+	///Whether the task is in the sleeping list of its cpu. It is never in there twice.
+	///Only changed by the owning cpu, with the sleeping list locked.
+	pub(super) timeout_queued:AtomicBool,
+	///Tasks waiting in JoinHandle::join.
+	pub(super) exited:WaitQueue,
+}
+
+impl Task{
+	///Creates a new task, that will run `entry` on its own stack, once it is scheduled.
+	pub(super) fn new(name:&'static str, cpu:usize, entry:Box<dyn FnOnce()+Send>)->Arc<Self>{
+		let mut stack = alloc::vec![0u8;STACK_SIZE].into_boxed_slice();
+		let top = (stack.as_mut_ptr() as usize + STACK_SIZE) & !0xF;
+		let frame_addr = top - core::mem::size_of::<InterruptFrame>();
+		let task = Arc::new(Self{
+			id:TaskId(NEXT_ID.fetch_add(1, Ordering::Relaxed)),
+			name,
+			state:AtomicU8::new(TaskState::Ready as u8),
+			cpu,
+			frame:AtomicUsize::new(frame_addr),
+			stack:Some(stack),
+			entry:Lock::with_class(Some(entry), &ENTRY_CLASS),
+			wake_at:AtomicU64::new(0),
+			timeout_queued:AtomicBool::new(false),
+			exited:WaitQueue::new(),
+		});
+		let frame = InterruptFrame{
+			rdi:Arc::as_ptr(&task) as u64,
+			rip:task_entry as *const () as u64,
+			cs:CS::get_reg().0 as u64,
+			rflags:INITIAL_RFLAGS,
+			//As if task_entry had been called: 16 byte aligned before the return address was pushed.
+			rsp:frame_addr as u64 - 8,
+			ss:SS::get_reg().0 as u64,
+			..Default::default()
+		};
+		//Safety:
+		//frame_addr lies within the stack we just allocated, and is 16 byte aligned.
+		//The stack is a separate allocation, so it didn't move, when it was moved into the task.
+		unsafe{core::ptr::write(frame_addr as *mut InterruptFrame, frame)};
+		task
+	}
+
+	///Creates a task for the code, that is currently running on this cpu.
+	///Its frame gets filled in, when it is switched away from for the first time.
+	pub(super) fn from_current(name:&'static str, cpu:usize)->Arc<Self>{
+		Arc::new(Self{
+			id:TaskId(NEXT_ID.fetch_add(1, Ordering::Relaxed)),
+			name,
+			state:AtomicU8::new(TaskState::Running as u8),
+			cpu,
+			frame:AtomicUsize::new(0),
+			stack:None,
+			entry:Lock::with_class(None, &ENTRY_CLASS),
+			wake_at:AtomicU64::new(0),
+			timeout_queued:AtomicBool::new(false),
+			exited:WaitQueue::new(),
+		})
+	}
+
+	pub fn id(&self)->TaskId{
+		self.id
+	}
+	pub fn name(&self)->&'static str{
+		self.name
+	}
+	pub fn cpu(&self)->usize{
+		self.cpu
+	}
+	pub fn state(&self)->TaskState{
+		TaskState::from_u8(self.state.load(Ordering::Acquire))
+	}
+	pub(super) fn set_state(&self, state:TaskState){
+		self.state.store(state as u8, Ordering::Release);
+	}
+	///Changes the state from `from` to `to`. Returns false, if the task wasn't in state `from`.
+	pub(super) fn transition(&self, from:TaskState, to:TaskState)->bool{
+		self.state.compare_exchange(from as u8, to as u8, Ordering::AcqRel, Ordering::Acquire).is_ok()
+	}
+	pub(super) fn frame(&self)->*mut InterruptFrame{
+		self.frame.load(Ordering::Relaxed) as *mut InterruptFrame
+	}
+	pub(super) fn set_frame(&self, frame:*mut InterruptFrame){
+		self.frame.store(frame as usize, Ordering::Relaxed);
+	}
+}
+
+impl core::fmt::Debug for Task{
+	fn fmt(&self, f:&mut core::fmt::Formatter<'_>)->core::fmt::Result{
+		f.debug_struct("Task")
+			.field("id", &self.id)
+			.field("name", &self.name)
+			.field("state", &self.state())
+			.field("cpu", &self.cpu)
+			.finish()
+	}
+}
+
+///The first code every new task runs.
+extern "C" fn task_entry(task:*const Task)->!{
+	//Safety:
+	//The scheduler holds a reference to the running task, so the pointer stays valid.
+	let task = unsafe{&*task};
+	let entry = task.entry.lock().take();
+	//Tasks start with interrupts enabled, so they can be preempted.
+	if let Some(entry) = entry{
+		entry();
+	}
+	super::exit();
+}
+
+///Allows waiting for a task to finish and getting its return value.
+pub struct JoinHandle<T>{
+	pub(super) task:Arc<Task>,
+	pub(super) result:Arc<Lock<Option<T>>>,
+}
+
+impl<T> JoinHandle<T>{
+	pub fn task(&self)->&Arc<Task>{
+		&self.task
+	}
+	pub fn is_finished(&self)->bool{
+		self.task.state() == TaskState::Dead
+	}
+	///Waits for the task to finish and returns, what it returned.
+	pub fn join(self)->T{
+		self.task.exited.wait_until(||self.is_finished());
+		self.result.lock().take().expect("a finished task always leaves its result behind")
+	}
+}
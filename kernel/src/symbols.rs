@@ -0,0 +1,130 @@
+//Looks up kernel functions by address, in the symbol table that the boot loader passed along.
+//The lookup is a linear search without allocations, so it also works in the panic handler, when the heap might be broken.
+use core::fmt::{Display, Formatter};
+use core::sync::atomic::{AtomicU64, Ordering};
+use kernel_efi::Symbols;
+use crate::x86_64::mem::phys_to_ptr;
+
+//Layout of an ELF64 symbol.
+const SYM_SIZE:usize = 24;
+const ST_NAME:usize = 0;
+const ST_INFO:usize = 4;
+const ST_SHNDX:usize = 6;
+const ST_VALUE:usize = 8;
+const ST_SIZE:usize = 16;
+const STT_FUNC:u8 = 2;
+const SHN_UNDEF:u16 = 0;
+
+//Virtual addresses and sizes of the tables. The sizes are 0, until init ran, or if there are no symbols.
+static SYMTAB:AtomicU64 = AtomicU64::new(0);
+static SYMTAB_SIZE:AtomicU64 = AtomicU64::new(0);
+static STRTAB:AtomicU64 = AtomicU64::new(0);
+static STRTAB_SIZE:AtomicU64 = AtomicU64::new(0);
+
+///Takes the symbol table from the boot loader. Has to run after kaslr::init, because the tables are reached through the direct map.
+pub fn init(symbols:&Symbols){
+	if symbols.symtab_size == 0{
+		log::info!("The boot loader passed no kernel symbols, backtraces will only have addresses");
+		return;
+	}
+	SYMTAB.store(phys_to_ptr::<u8>(symbols.symtab_base) as u64, Ordering::Relaxed);
+	STRTAB.store(phys_to_ptr::<u8>(symbols.strtab_base) as u64, Ordering::Relaxed);
+	STRTAB_SIZE.store(symbols.strtab_size, Ordering::Relaxed);
+	SYMTAB_SIZE.store(symbols.symtab_size, Ordering::Release);
+	log::info!("{} kernel symbols", symbols.symtab_size as usize/SYM_SIZE);
+}
+
+fn table(base:&AtomicU64, size:&AtomicU64)->&'static [u8]{
+	let size = size.load(Ordering::Acquire) as usize;
+	if size == 0{
+		return &[];
+	}
+	//Safety:
+	//The boot loader copied the table there, and marked the pages as used, so nothing overwrites them.
+	unsafe{core::slice::from_raw_parts(base.load(Ordering::Relaxed) as *const u8, size)}
+}
+
+///A function, that contains an address.
+#[derive(Debug, Copy, Clone)]
+pub struct Symbol{
+	///The mangled name.
+	pub name:&'static str,
+	///How far the address is from the start of the function.
+	pub offset:u64,
+}
+
+impl Display for Symbol{
+	///Prints `name+0xoffset`, with a legacy mangled Rust name demangled, and its hash left out.
+	fn fmt(&self, f:&mut Formatter<'_>)->core::fmt::Result{
+		match demangle(self.name, f)?{
+			true=>(),
+			false=>f.write_str(self.name)?,
+		}
+		write!(f, "+{:#x}", self.offset)
+	}
+}
+
+///Writes a legacy mangled name (`_ZN` followed by length prefixed parts and `E`) as `a::b::c`.
+///Returns false without writing anything, if the name isn't one.
+fn demangle(name:&str, f:&mut Formatter<'_>)->Result<bool, core::fmt::Error>{
+	let Some(mut rest) = name.strip_prefix("_ZN") else{
+		return Ok(false);
+	};
+	//Check the whole name first, so nothing half demangled gets written.
+	let mut parts = 0;
+	let mut check = rest;
+	while !check.starts_with('E'){
+		let digits = check.bytes().take_while(u8::is_ascii_digit).count();
+		let Some(len) = check[..digits].parse::<usize>().ok().filter(|&l|digits+l <= check.len()) else{
+			return Ok(false);
+		};
+		check = &check[digits+len..];
+		parts += 1;
+	}
+	for i in 0..parts{
+		let digits = rest.bytes().take_while(u8::is_ascii_digit).count();
+		let len:usize = rest[..digits].parse().unwrap_or(0);
+		let part = &rest[digits..digits+len];
+		rest = &rest[digits+len..];
+		//The last part is a hash like h0123456789abcdef.
+		let hash = i+1 == parts && part.len() == 17 && part.starts_with('h') && part[1..].bytes().all(|b|b.is_ascii_hexdigit());
+		if hash{
+			break;
+		}
+		if i != 0{
+			f.write_str("::")?;
+		}
+		f.write_str(part)?;
+	}
+	Ok(true)
+}
+
+fn read_u64(entry:&[u8], at:usize)->u64{
+	u64::from_le_bytes(entry[at..at+8].try_into().unwrap())
+}
+
+///Finds the function, that contains `addr`. None, if there are no symbols, or the address isn't in a known function.
+pub fn lookup(addr:u64)->Option<Symbol>{
+	let addr = crate::kaslr::unslide(addr)?;
+	let strtab = table(&STRTAB, &STRTAB_SIZE);
+	let mut best:Option<(u64, u32)> = None;
+	for entry in table(&SYMTAB, &SYMTAB_SIZE).chunks_exact(SYM_SIZE){
+		let value = read_u64(entry, ST_VALUE);
+		let size = read_u64(entry, ST_SIZE);
+		let shndx = u16::from_le_bytes([entry[ST_SHNDX], entry[ST_SHNDX+1]]);
+		if entry[ST_INFO]&0xf != STT_FUNC || shndx == SHN_UNDEF || value > addr{
+			continue;
+		}
+		//Functions without a size are taken as reaching up to the next symbol.
+		if size != 0 && addr-value >= size{
+			continue;
+		}
+		if best.is_none_or(|(v, _)|value > v){
+			best = Some((value, u32::from_le_bytes(entry[ST_NAME..ST_NAME+4].try_into().unwrap())));
+		}
+	}
+	let (value, name) = best?;
+	let name = strtab.get(name as usize..)?;
+	let name = &name[..name.iter().position(|&b|b == 0).unwrap_or(name.len())];
+	Some(Symbol{name:core::str::from_utf8(name).unwrap_or("?"), offset:addr-value})
+}
@@ -0,0 +1,13 @@
+//Blocking synchronization primitives.
+//Unlike crate::lock, these put the waiting task to sleep, instead of spinning.
+//They can only be used from tasks, not from interrupt handlers.
+mod wait_queue;
+mod mutex;
+//Nothing uses these yet. They are kept for drivers and tasks, that need them, and can be used from their modules.
+#[allow(dead_code)]
+pub(crate) mod semaphore;
+#[allow(dead_code)]
+pub(crate) mod condvar;
+
+pub use wait_queue::WaitQueue;
+pub use mutex::{Mutex, MutexGuard};
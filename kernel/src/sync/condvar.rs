@@ -0,0 +1,55 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+use super::{MutexGuard, WaitQueue};
+
+///A condition variable, to be used together with a [`super::Mutex`].
+///Like every condition variable, waiters can wake up spuriously, so the condition has to be checked in a loop (or use wait_while).
+pub(crate) struct Condvar{
+	///Incremented on every notification, so that a waiter can tell, if it was notified since it released the mutex.
+	generation:AtomicU64,
+	waiters:WaitQueue,
+}
+
+impl Condvar{
+	pub const fn new()->Self{
+		Self{
+			generation:AtomicU64::new(0),
+			waiters:WaitQueue::new(),
+		}
+	}
+
+	///Releases the mutex, sleeps until notified and locks the mutex again.
+	pub fn wait<'a,T>(&self, guard:MutexGuard<'a,T>)->MutexGuard<'a,T>{
+		let mutex = guard.mutex();
+		//Read the generation, while still holding the mutex, so that a notification after unlocking is not missed.
+		let generation = self.generation.load(Ordering::Acquire);
+		drop(guard);
+		self.waiters.wait_until(||self.generation.load(Ordering::Acquire) != generation);
+		mutex.lock()
+	}
+
+	///Waits, until `cond` returns false.
+	pub fn wait_while<'a,T,F:FnMut(&mut T)->bool>(&self, mut guard:MutexGuard<'a,T>, mut cond:F)->MutexGuard<'a,T>{
+		while cond(&mut guard){
+			guard = self.wait(guard);
+		}
+		guard
+	}
+
+	///Wakes up one waiting task.
+	pub fn notify_one(&self){
+		self.generation.fetch_add(1, Ordering::Release);
+		self.waiters.wake_one();
+	}
+
+	///Wakes up all waiting tasks.
+	pub fn notify_all(&self){
+		self.generation.fetch_add(1, Ordering::Release);
+		self.waiters.wake_all();
+	}
+}
+
+impl Default for Condvar{
+	fn default()->Self{
+		Self::new()
+	}
+}
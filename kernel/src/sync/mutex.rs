@@ -0,0 +1,84 @@
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, Ordering};
+use super::WaitQueue;
+
+///A mutual exclusion lock, that puts waiting tasks to sleep.
+///Use this instead of crate::lock::Lock, if the lock can be held for a long time, or across sleeping.
+pub struct Mutex<T>{
+	locked:AtomicBool,
+	waiters:WaitQueue,
+	data:UnsafeCell<T>,
+}
+
+unsafe impl<T:Send> Send for Mutex<T>{}
+unsafe impl<T:Send> Sync for Mutex<T>{}
+
+impl<T> Mutex<T>{
+	pub const fn new(data:T)->Self{
+		Self{
+			locked:AtomicBool::new(false),
+			waiters:WaitQueue::new(),
+			data:UnsafeCell::new(data),
+		}
+	}
+
+	///Tries to lock the mutex without blocking.
+	pub fn try_lock(&self)->Option<MutexGuard<'_,T>>{
+		if self.locked.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed).is_ok(){
+			return Some(MutexGuard{mutex:self});
+		}
+		None
+	}
+
+	///Locks the mutex, sleeping until it is available.
+	pub fn lock(&self)->MutexGuard<'_,T>{
+		if let Some(guard) = self.try_lock(){
+			return guard;
+		}
+		self.waiters.wait_until(||self.locked.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed).is_ok());
+		MutexGuard{mutex:self}
+	}
+
+	pub fn is_locked(&self)->bool{
+		self.locked.load(Ordering::Relaxed)
+	}
+
+	///Returns a mutable reference to the data.
+	///No locking is needed, since the mutable borrow guarantees, that nobody else can access the mutex.
+	pub fn get_mut(&mut self)->&mut T{
+		self.data.get_mut()
+	}
+
+	fn unlock(&self){
+		self.locked.store(false, Ordering::Release);
+		self.waiters.wake_one();
+	}
+}
+
+pub struct MutexGuard<'a,T>{
+	mutex:&'a Mutex<T>,
+}
+
+impl<'a,T> MutexGuard<'a,T>{
+	pub(super) fn mutex(&self)->&'a Mutex<T>{
+		self.mutex
+	}
+}
+
+impl<'a,T> Drop for MutexGuard<'a,T>{
+	fn drop(&mut self){
+		self.mutex.unlock();
+	}
+}
+impl<'a,T> Deref for MutexGuard<'a,T>{
+	type Target = T;
+	fn deref(&self)->&Self::Target{
+		unsafe{&*self.mutex.data.get()}
+	}
+}
+impl<'a,T> DerefMut for MutexGuard<'a,T>{
+	fn deref_mut(&mut self)->&mut Self::Target{
+		unsafe{&mut *self.mutex.data.get()}
+	}
+}
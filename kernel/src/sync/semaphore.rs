@@ -0,0 +1,42 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+use super::WaitQueue;
+
+///A counting semaphore.
+///Tasks, that try to take a permit while none are available, are put to sleep.
+pub(crate) struct Semaphore{
+	permits:AtomicUsize,
+	waiters:WaitQueue,
+}
+
+impl Semaphore{
+	pub const fn new(permits:usize)->Self{
+		Self{
+			permits:AtomicUsize::new(permits),
+			waiters:WaitQueue::new(),
+		}
+	}
+
+	///Takes a permit, if one is available.
+	pub fn try_acquire(&self)->bool{
+		self.permits.fetch_update(Ordering::Acquire, Ordering::Relaxed, |p|p.checked_sub(1)).is_ok()
+	}
+
+	///Takes a permit, sleeping until one is available.
+	pub fn acquire(&self){
+		if self.try_acquire(){
+			return;
+		}
+		self.waiters.wait_until(||self.try_acquire());
+	}
+
+	///Returns a permit and wakes up a waiting task.
+	pub fn release(&self){
+		self.permits.fetch_add(1, Ordering::Release);
+		self.waiters.wake_one();
+	}
+
+	///The amount of currently available permits.
+	pub fn available(&self)->usize{
+		self.permits.load(Ordering::Relaxed)
+	}
+}
@@ -0,0 +1,108 @@
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use core::time::Duration;
+use x86_64::instructions::interrupts;
+use crate::lock::{Lock, LockClass};
+use crate::sched::{self, Task};
+
+static WAIT_QUEUE_CLASS:LockClass = LockClass::new("sync::wait_queue");
+
+///A list of tasks, that are blocked until something happens.
+pub struct WaitQueue{
+	waiters:Lock<VecDeque<Arc<Task>>>,
+}
+
+impl WaitQueue{
+	pub const fn new()->Self{
+		Self{
+			waiters:Lock::with_class(VecDeque::new(), &WAIT_QUEUE_CLASS),
+		}
+	}
+
+	///Blocks the current task, until `cond` returns true.
+	///`cond` is checked with the queue locked, so a waker, that makes `cond` true before calling wake_one or wake_all, can't be missed.
+	///`cond` must not block.
+	pub fn wait_until<F:FnMut()->bool>(&self, mut cond:F){
+		loop{
+			let done = interrupts::without_interrupts(||{
+				let mut waiters = self.waiters.lock();
+				if cond(){
+					return true;
+				}
+				waiters.push_back(sched::current());
+				sched::prepare_block();
+				drop(waiters);
+				sched::yield_now();
+				false
+			});
+			if done{
+				return;
+			}
+		}
+	}
+
+	///Like [`wait_until`](Self::wait_until), but gives up after `timeout`.
+	///Returns false, if `cond` was still false, when the timeout expired.
+	pub fn wait_until_timeout<F:FnMut()->bool>(&self, mut cond:F, timeout:Duration)->bool{
+		let mut ticks = sched::duration_to_ticks(timeout);
+		loop{
+			let done = interrupts::without_interrupts(||{
+				let mut waiters = self.waiters.lock();
+				if cond(){
+					return Some(true);
+				}
+				if ticks == 0{
+					return Some(false);
+				}
+				let task = sched::current();
+				waiters.push_back(task.clone());
+				let wake_at = sched::prepare_block_timeout(ticks);
+				drop(waiters);
+				sched::yield_now();
+				//We might have been woken up by the timeout, so we could still be queued.
+				//Or we were woken up before it, so the timeout is still pending.
+				sched::cancel_timeout();
+				let mut waiters = self.waiters.lock();
+				waiters.retain(|t|!Arc::ptr_eq(t, &task));
+				ticks = wake_at.saturating_sub(sched::ticks());
+				None
+			});
+			if let Some(done) = done{
+				return done;
+			}
+		}
+	}
+
+	///Wakes up the task, that has waited the longest.
+	///Returns false, if nobody was waiting.
+	pub fn wake_one(&self)->bool{
+		interrupts::without_interrupts(||{
+			let mut waiters = self.waiters.lock();
+			while let Some(task) = waiters.pop_front(){
+				if sched::wake(&task){
+					return true;
+				}
+			}
+			false
+		})
+	}
+
+	///Wakes up all waiting tasks.
+	///Returns the amount of tasks woken up.
+	pub fn wake_all(&self)->usize{
+		interrupts::without_interrupts(||{
+			let mut waiters = self.waiters.lock();
+			waiters.drain(..).filter(sched::wake).count()
+		})
+	}
+
+	pub fn is_empty(&self)->bool{
+		interrupts::without_interrupts(||self.waiters.lock().is_empty())
+	}
+}
+
+impl Default for WaitQueue{
+	fn default()->Self{
+		Self::new()
+	}
+}
@@ -0,0 +1,204 @@
+//Timekeeping.
+//The monotonic clock counts nanoseconds since time::init.
+//It uses the TSC, if it is invariant. Otherwise it falls back to the HPET main counter,
+//and without a HPET to counting scheduler ticks on the boot cpu, which is a lot coarser.
+//The wall clock is the RTC time at boot plus the monotonic clock.
+pub mod hpet;
+pub mod pit;
+pub mod rtc;
+pub mod tsc;
+
+use core::sync::atomic::{AtomicU64, AtomicU8, AtomicUsize, Ordering};
+use core::time::Duration;
+use crate::x86_64::cpu;
+
+const NANOS_PER_SEC:u64 = 1_000_000_000;
+///Spins of core::hint::spin_loop per microsecond, for busy waits without any clock.
+///A guess, that errs on the long side for current cpus.
+const SPINS_PER_US:u64 = 1000;
+///How long a Deadline check waits, while the monotonic clock doesn't run.
+const STALLED_STEP:Duration = Duration::from_micros(10);
+
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Clocksource{
+	///time::init hasn't run yet. The clock stays at 0.
+	None,
+	///Scheduler ticks on the boot cpu.
+	Ticks,
+	///The HPET main counter. Slow to read, but it has a fixed rate.
+	Hpet,
+	Tsc,
+}
+
+impl Clocksource{
+	fn from_u8(v:u8)->Self{
+		match v{
+			1=>Self::Ticks,
+			2=>Self::Hpet,
+			3=>Self::Tsc,
+			_=>Self::None,
+		}
+	}
+}
+
+static SOURCE:AtomicU8 = AtomicU8::new(Clocksource::None as u8);
+static TSC_HZ:AtomicU64 = AtomicU64::new(0);
+///The TSC value at monotonic time 0.
+static TSC_BASE:AtomicU64 = AtomicU64::new(0);
+///Nanoseconds per TSC cycle as a 32.32 fixed point number.
+static TSC_MULT:AtomicU64 = AtomicU64::new(0);
+///The rate of the LAPIC timer, as set up by apic::start_timer_periodic.
+static LAPIC_HZ:AtomicU64 = AtomicU64::new(0);
+///The HPET counter value at monotonic time 0.
+static HPET_BASE:AtomicU64 = AtomicU64::new(0);
+///Scheduler ticks on TICK_CPU.
+static TICKS:AtomicU64 = AtomicU64::new(0);
+static TICK_CPU:AtomicUsize = AtomicUsize::new(usize::MAX);
+///The wall clock time at monotonic time 0, in nanoseconds since the unix epoch.
+static BOOT_TIME:AtomicU64 = AtomicU64::new(0);
+
+///Calibrates the clocks and reads the RTC.
+///The HPET is preferred over the PIT as calibration reference, since it is much faster to read.
+///Needs to be called once on the boot cpu, after the LAPIC, IOAPIC and ACPI have been set up, and before the scheduler starts.
+pub fn init(){
+	TICK_CPU.store(cpu::id(), Ordering::Relaxed);
+	let has_hpet = hpet::init();
+	let calibration = if has_hpet {tsc::calibrate_hpet()} else {tsc::calibrate_pit()};
+	match calibration{
+		Some(c)=>{
+			log::info!("TSC runs at {} kHz, LAPIC timer at {} kHz (measured with the {})",
+				c.tsc_hz/1000, c.lapic_hz/1000, if has_hpet {"HPET"} else {"PIT"});
+			LAPIC_HZ.store(c.lapic_hz, Ordering::Relaxed);
+		},
+		None=>log::warn!("Neither HPET nor PIT work. The clocks are uncalibrated."),
+	}
+	match calibration{
+		Some(c) if c.tsc_hz != 0 && tsc::is_invariant()=>{
+			TSC_HZ.store(c.tsc_hz, Ordering::Relaxed);
+			TSC_MULT.store((NANOS_PER_SEC<<32)/c.tsc_hz, Ordering::Relaxed);
+			TSC_BASE.store(tsc::read(), Ordering::Relaxed);
+			SOURCE.store(Clocksource::Tsc as u8, Ordering::Release);
+		},
+		//A 32 bit counter would wrap around after a few minutes.
+		_ if has_hpet && hpet::counter_is_64bit()=>{
+			log::info!("No invariant TSC. Using the HPET as clocksource.");
+			HPET_BASE.store(hpet::counter(), Ordering::Relaxed);
+			SOURCE.store(Clocksource::Hpet as u8, Ordering::Release);
+		},
+		_=>{
+			log::warn!("No invariant TSC. The monotonic clock only has a resolution of {}Hz.", crate::sched::hz());
+			SOURCE.store(Clocksource::Ticks as u8, Ordering::Release);
+		},
+	}
+
+	let century = crate::acpi::fadt().map(|f|f.Century).unwrap_or(0);
+	match rtc::read(century){
+		Some(now)=>{
+			BOOT_TIME.store((now.to_unix()*NANOS_PER_SEC).saturating_sub(monotonic_ns()), Ordering::Relaxed);
+			log::info!("The time is {} UTC", now);
+		},
+		None=>log::warn!("Couldn't read the RTC. The wall clock starts at the unix epoch."),
+	}
+}
+
+pub fn clocksource()->Clocksource{
+	Clocksource::from_u8(SOURCE.load(Ordering::Acquire))
+}
+
+///The TSC frequency, or 0, if the TSC isn't used.
+pub fn tsc_hz()->u64{
+	TSC_HZ.load(Ordering::Relaxed)
+}
+
+///Nanoseconds since time::init.
+pub fn monotonic_ns()->u64{
+	match clocksource(){
+		Clocksource::Tsc=>{
+			let cycles = tsc::read().saturating_sub(TSC_BASE.load(Ordering::Relaxed));
+			((cycles as u128 * TSC_MULT.load(Ordering::Relaxed) as u128)>>32) as u64
+		},
+		Clocksource::Hpet=>hpet::ticks_to_ns(hpet::counter().saturating_sub(HPET_BASE.load(Ordering::Relaxed))),
+		Clocksource::Ticks=>TICKS.load(Ordering::Relaxed)*(NANOS_PER_SEC/crate::sched::hz() as u64),
+		Clocksource::None=>0,
+	}
+}
+
+///Time since time::init.
+pub fn monotonic()->Duration{
+	Duration::from_nanos(monotonic_ns())
+}
+
+///Time since the unix epoch.
+pub fn wall_clock()->Duration{
+	Duration::from_nanos(BOOT_TIME.load(Ordering::Relaxed)+monotonic_ns())
+}
+
+///Whether monotonic_ns moves forward right now.
+///There is no clock before time::init, and ticks are only counted with interrupts enabled.
+fn clock_runs()->bool{
+	match clocksource(){
+		Clocksource::None=>false,
+		Clocksource::Ticks=>x86_64::instructions::interrupts::are_enabled(),
+		Clocksource::Hpet|Clocksource::Tsc=>true,
+	}
+}
+
+///Spins for at least `d`.
+///While the monotonic clock doesn't run, the HPET is used, and without one a spin count, that only roughly matches `d`.
+pub fn busy_wait(d:Duration){
+	if !clock_runs(){
+		let us = d.as_micros().min(u64::MAX as u128) as u64;
+		if !hpet::busy_wait_us(us){
+			for _ in 0..us.saturating_mul(SPINS_PER_US){
+				core::hint::spin_loop();
+			}
+		}
+		return;
+	}
+	let end = monotonic_ns().saturating_add(d.as_nanos() as u64);
+	while monotonic_ns() < end{
+		core::hint::spin_loop();
+	}
+}
+
+///The end of a timeout, for polling loops.
+///While the monotonic clock doesn't run, every check waits STALLED_STEP with busy_wait and counts that as the time, that passed, so the loop still ends.
+pub struct Deadline{
+	end:u64,
+	timeout:u64,
+	stalled:u64,
+}
+
+impl Deadline{
+	pub fn after(timeout:Duration)->Self{
+		let timeout = timeout.as_nanos().min(u64::MAX as u128) as u64;
+		Self{end:monotonic_ns().saturating_add(timeout), timeout, stalled:0}
+	}
+
+	pub fn expired(&mut self)->bool{
+		if clock_runs(){
+			return monotonic_ns() >= self.end;
+		}
+		busy_wait(STALLED_STEP);
+		self.stalled = self.stalled.saturating_add(STALLED_STEP.as_nanos() as u64);
+		self.stalled >= self.timeout
+	}
+}
+
+///The initial count for the LAPIC timer, to make it fire `hz` times per second.
+///Returns None, if the LAPIC timer couldn't be calibrated.
+pub fn lapic_timer_count(hz:u32)->Option<u32>{
+	let lapic_hz = LAPIC_HZ.load(Ordering::Relaxed);
+	if lapic_hz == 0{
+		return None;
+	}
+	Some((lapic_hz/hz as u64).clamp(1, u32::MAX as u64) as u32)
+}
+
+///Gets called by the scheduler on every timer tick.
+pub(crate) fn tick(){
+	if cpu::id() == TICK_CPU.load(Ordering::Relaxed){
+		TICKS.fetch_add(1, Ordering::Relaxed);
+	}
+}
@@ -0,0 +1,260 @@
+//Driver for the High Precision Event Timer.
+//Only the first HPET block from the ACPI table is used.
+//Its comparators are delivered as FSB messages (MSI), if they support it, and through the IOAPIC otherwise.
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicU8, Ordering};
+use core::time::Duration;
+use x86_64::PhysAddr;
+use crate::x86_64::{apic, ioapic};
+use crate::x86_64::interrupts::{allocate_vector, free_vector, Handler};
+
+const REG_CAPABILITIES:usize = 0x000;
+const REG_CONFIG:usize = 0x010;
+const REG_COUNTER:usize = 0x0F0;
+const fn reg_timer_config(n:u8)->usize{0x100+0x20*n as usize}
+const fn reg_timer_comparator(n:u8)->usize{0x108+0x20*n as usize}
+const fn reg_timer_fsb_route(n:u8)->usize{0x110+0x20*n as usize}
+
+const CAP_COUNTER_64:u64 = 1<<13;
+const CONFIG_ENABLE:u64 = 1<<0;
+const CONFIG_LEGACY_ROUTE:u64 = 1<<1;
+
+const TIMER_LEVEL:u64 = 1<<1;
+const TIMER_ENABLE:u64 = 1<<2;
+const TIMER_PERIODIC:u64 = 1<<3;
+const TIMER_PERIODIC_CAP:u64 = 1<<4;
+///Lets the next comparator write set the accumulator of a periodic timer.
+const TIMER_VALUE_SET:u64 = 1<<6;
+const TIMER_ROUTE_SHIFT:u64 = 9;
+const TIMER_ROUTE_MASK:u64 = 0x1F<<TIMER_ROUTE_SHIFT;
+const TIMER_FSB_ENABLE:u64 = 1<<14;
+const TIMER_FSB_CAP:u64 = 1<<15;
+
+///The spec limits the counter period to 100ns.
+const MAX_PERIOD_FS:u64 = 100_000_000;
+const FS_PER_NS:u64 = 1_000_000;
+
+///Virtual address of the registers. 0, if there is no HPET.
+static BASE:AtomicU64 = AtomicU64::new(0);
+static PERIOD_FS:AtomicU64 = AtomicU64::new(0);
+static COUNTER_64:AtomicBool = AtomicBool::new(false);
+static COMPARATORS:AtomicU8 = AtomicU8::new(0);
+///Bit n is set, if comparator n is in use.
+static IN_USE:AtomicU32 = AtomicU32::new(0);
+
+fn read(reg:usize)->u64{
+	//Safety:
+	//The HPET registers are 64 bit wide and 8 byte aligned.
+	unsafe{core::ptr::read_volatile((BASE.load(Ordering::Relaxed) as usize + reg) as *const u64)}
+}
+
+fn write(reg:usize, value:u64){
+	//Safety:
+	//The HPET registers are 64 bit wide and 8 byte aligned.
+	unsafe{core::ptr::write_volatile((BASE.load(Ordering::Relaxed) as usize + reg) as *mut u64, value)}
+}
+
+///Finds the HPET through ACPI and starts its main counter.
+///Returns false, if there is no usable HPET.
+pub fn init()->bool{
+	let table = match crate::acpi::hpet(){
+		Some(t)=>t,
+		None=>return false,
+	};
+	let (space, address) = (table.BaseAddress.AddressSpaceId, table.BaseAddress.Address);
+	if space != 0 || address == 0{
+		log::warn!("The HPET isn't memory mapped.");
+		return false;
+	}
+	let base = crate::x86_64::mem::phys_to_virt(PhysAddr::new(address));
+	BASE.store(base.as_u64(), Ordering::Relaxed);
+	let caps = read(REG_CAPABILITIES);
+	let period = caps>>32;
+	if period == 0 || period > MAX_PERIOD_FS{
+		log::warn!("The HPET has an invalid counter period of {}fs.", period);
+		BASE.store(0, Ordering::Relaxed);
+		return false;
+	}
+	PERIOD_FS.store(period, Ordering::Relaxed);
+	COUNTER_64.store(caps & CAP_COUNTER_64 != 0, Ordering::Relaxed);
+	let comparators = (((caps>>8)&0x1F)+1) as u8;
+	COMPARATORS.store(comparators, Ordering::Relaxed);
+	for n in 0..comparators{
+		let config = read(reg_timer_config(n));
+		write(reg_timer_config(n), config & !(TIMER_ENABLE|TIMER_PERIODIC|TIMER_FSB_ENABLE));
+	}
+	//Without legacy routing, the comparators are routed as configured, and the PIT and RTC keep their IRQs.
+	let config = read(REG_CONFIG);
+	write(REG_CONFIG, (config & !CONFIG_LEGACY_ROUTE) | CONFIG_ENABLE);
+	log::info!("HPET at {:#x}: {} Hz, {} comparators, {} bit counter",
+		address, frequency(), comparators, if counter_is_64bit() {64} else {32});
+	true
+}
+
+pub fn is_available()->bool{
+	BASE.load(Ordering::Relaxed) != 0
+}
+
+///Counter ticks per second.
+pub fn frequency()->u64{
+	match PERIOD_FS.load(Ordering::Relaxed){
+		0=>0,
+		period=>1_000_000_000_000_000/period,
+	}
+}
+
+///Returns true, if the main counter is 64 bits wide.
+///A 32 bit counter wraps around after a few minutes.
+pub fn counter_is_64bit()->bool{
+	COUNTER_64.load(Ordering::Relaxed)
+}
+
+///The value of the main counter.
+pub fn counter()->u64{
+	if counter_is_64bit(){
+		read(REG_COUNTER)
+	}else{
+		read(REG_COUNTER) & 0xFFFF_FFFF
+	}
+}
+
+///Converts counter ticks to nanoseconds.
+pub fn ticks_to_ns(ticks:u64)->u64{
+	(ticks as u128 * PERIOD_FS.load(Ordering::Relaxed) as u128 / FS_PER_NS as u128) as u64
+}
+
+///Converts a duration to counter ticks, rounding up.
+pub fn duration_to_ticks(d:Duration)->u64{
+	let period = PERIOD_FS.load(Ordering::Relaxed).max(1) as u128;
+	(d.as_nanos()*FS_PER_NS as u128).div_ceil(period).min(u64::MAX as u128) as u64
+}
+
+///Spins for at least `us` microseconds.
+///Returns false, if there is no HPET.
+pub fn busy_wait_us(us:u64)->bool{
+	if !is_available(){
+		return false;
+	}
+	let ticks = duration_to_ticks(Duration::from_micros(us));
+	let start = counter();
+	//wrapping_sub with the mask handles a 32 bit counter wrapping around during the wait.
+	let mask = if counter_is_64bit() {u64::MAX} else {0xFFFF_FFFF};
+	while counter().wrapping_sub(start) & mask < ticks{
+		core::hint::spin_loop();
+	}
+	true
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Mode{
+	OneShot,
+	Periodic,
+}
+
+///How a comparator's interrupt reaches the cpu.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum Route{
+	Fsb,
+	Gsi(u32),
+}
+
+///A comparator of the HPET, that raises an interrupt, when the main counter reaches it.
+///Dropping it stops the comparator and frees its vector.
+pub struct Timer{
+	index:u8,
+	vector:u8,
+	route:Route,
+}
+
+impl Timer{
+	///Allocates a free comparator, whose interrupt runs `handler` on the current cpu.
+	///Returns None, if there is no HPET, or all comparators or vectors are in use.
+	pub fn new(handler:Handler)->Option<Self>{
+		if !is_available(){
+			return None;
+		}
+		let index = (0..COMPARATORS.load(Ordering::Relaxed)).find(|n|{
+			IN_USE.fetch_or(1<<n, Ordering::AcqRel) & (1<<n) == 0
+		})?;
+		let vector = match allocate_vector(handler){
+			Some(v)=>v,
+			None=>{
+				IN_USE.fetch_and(!(1<<index), Ordering::AcqRel);
+				return None;
+			}
+		};
+		let mut timer = Self{index, vector, route:Route::Fsb};
+		match timer.route(){
+			Some(route)=>timer.route = route,
+			None=>{
+				log::warn!("HPET comparator {} can't be routed to any free interrupt.", index);
+				//Dropping the timer frees the comparator and vector again.
+				return None;
+			}
+		}
+		Some(timer)
+	}
+
+	fn route(&self)->Option<Route>{
+		let reg = reg_timer_config(self.index);
+		let config = read(reg) & !(TIMER_ENABLE|TIMER_FSB_ENABLE|TIMER_ROUTE_MASK|TIMER_LEVEL);
+		let apic_id = apic::id();
+		if config & TIMER_FSB_CAP != 0{
+			let (address, data) = apic::msi_message(apic_id, self.vector);
+			write(reg_timer_fsb_route(self.index), address<<32 | data as u64);
+			write(reg, config | TIMER_FSB_ENABLE);
+			return Some(Route::Fsb);
+		}
+		//The upper half of the config register has a bit for every IOAPIC input the comparator can use.
+		//The ISA IRQs are only used, if nothing else is left, since their drivers might not have claimed them yet.
+		let allowed = config>>32;
+		let gsi = (16..32).chain(0..16).find(|gsi|allowed & (1<<gsi) != 0 && !ioapic::is_routed(*gsi))?;
+		if !ioapic::route(gsi, self.vector, apic_id, ioapic::Trigger::Edge, ioapic::Polarity::ActiveHigh){
+			return None;
+		}
+		write(reg, config | (gsi as u64)<<TIMER_ROUTE_SHIFT);
+		Some(Route::Gsi(gsi))
+	}
+
+	///Returns true, if the comparator can fire periodically.
+	pub fn supports_periodic(&self)->bool{
+		read(reg_timer_config(self.index)) & TIMER_PERIODIC_CAP != 0
+	}
+
+	///Makes the comparator fire once after `d`, or every `d` in periodic mode.
+	///Returns false, if the comparator doesn't support the mode.
+	pub fn start(&self, mode:Mode, d:Duration)->bool{
+		if mode == Mode::Periodic && !self.supports_periodic(){
+			return false;
+		}
+		let reg = reg_timer_config(self.index);
+		let ticks = duration_to_ticks(d).max(1);
+		let config = read(reg) & !(TIMER_ENABLE|TIMER_PERIODIC|TIMER_VALUE_SET);
+		write(reg, config);
+		match mode{
+			Mode::OneShot=>{
+				write(reg_timer_comparator(self.index), counter().wrapping_add(ticks));
+				write(reg, config | TIMER_ENABLE);
+			},
+			Mode::Periodic=>{
+				//The first comparator write after setting TIMER_VALUE_SET sets the first deadline, the second one the period.
+				write(reg, config | TIMER_PERIODIC | TIMER_VALUE_SET);
+				write(reg_timer_comparator(self.index), counter().wrapping_add(ticks));
+				write(reg_timer_comparator(self.index), ticks);
+				write(reg, config | TIMER_PERIODIC | TIMER_ENABLE);
+			},
+		}
+		true
+	}
+}
+
+impl Drop for Timer{
+	fn drop(&mut self){
+		let reg = reg_timer_config(self.index);
+		write(reg, read(reg) & !(TIMER_ENABLE|TIMER_PERIODIC|TIMER_FSB_ENABLE));
+		if let Route::Gsi(gsi) = self.route{
+			ioapic::mask(gsi);
+		}
+		free_vector(self.vector);
+		IN_USE.fetch_and(!(1<<self.index), Ordering::AcqRel);
+	}
+}
@@ -0,0 +1,64 @@
+use x86_64::instructions::port::Port;
+
+///The input clock of the 8254 PIT.
+pub const PIT_HZ:u64 = 1_193_182;
+///The longest interval channel 2 can measure with a 16 bit count.
+pub const MAX_WAIT_US:u64 = 0xFFFF * 1_000_000 / PIT_HZ;
+
+const CHANNEL2_DATA:u16 = 0x42;
+const COMMAND:u16 = 0x43;
+///Port B of the keyboard controller, which controls the gate of channel 2 and shows its output.
+const PORT_B:u16 = 0x61;
+const PORT_B_GATE2:u8 = 1<<0;
+const PORT_B_SPEAKER:u8 = 1<<1;
+const PORT_B_OUT2:u8 = 1<<5;
+///Channel 2, lobyte/hibyte access, mode 0 (interrupt on terminal count), binary.
+const CHANNEL2_ONESHOT:u8 = 0b1011_0000;
+
+///A one-shot countdown on PIT channel 2.
+///Channel 2 is used, because its output can be polled without interrupts, and nothing else uses it.
+pub struct Countdown{
+	port_b:Port<u8>,
+}
+
+impl Countdown{
+	///Starts counting down `us` microseconds (at most MAX_WAIT_US).
+	pub fn start(us:u64)->Self{
+		let count = (PIT_HZ * us.min(MAX_WAIT_US) / 1_000_000).max(1);
+		let mut port_b = Port::<u8>::new(PORT_B);
+		let mut command = Port::<u8>::new(COMMAND);
+		let mut data = Port::<u8>::new(CHANNEL2_DATA);
+		//Safety:
+		//These are the standard PIT ports. The speaker stays disconnected.
+		unsafe{
+			let b = port_b.read() & !(PORT_B_SPEAKER|PORT_B_GATE2);
+			port_b.write(b);
+			command.write(CHANNEL2_ONESHOT);
+			data.write(count as u8);
+			data.write((count>>8) as u8);
+			//A rising edge on the gate starts the count.
+			port_b.write(b|PORT_B_GATE2);
+		}
+		Self{port_b}
+	}
+
+	pub fn expired(&mut self)->bool{
+		//Safety:
+		//Reading port B has no side effects.
+		let b = unsafe{self.port_b.read()};
+		b & PORT_B_OUT2 != 0
+	}
+
+	///Waits until the countdown expires.
+	///Returns false, if it never did, which happens on machines without a PIT.
+	pub fn wait(mut self)->bool{
+		//Every port read takes around a microsecond, so this gives up after about a second.
+		for _ in 0..1_000_000{
+			if self.expired(){
+				return true;
+			}
+			core::hint::spin_loop();
+		}
+		false
+	}
+}
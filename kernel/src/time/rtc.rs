@@ -0,0 +1,162 @@
+use core::fmt;
+use x86_64::instructions::port::Port;
+
+const CMOS_INDEX:u16 = 0x70;
+const CMOS_DATA:u16 = 0x71;
+
+const REG_SECONDS:u8 = 0x00;
+const REG_MINUTES:u8 = 0x02;
+const REG_HOURS:u8 = 0x04;
+const REG_DAY:u8 = 0x07;
+const REG_MONTH:u8 = 0x08;
+const REG_YEAR:u8 = 0x09;
+const REG_STATUS_A:u8 = 0x0A;
+const REG_STATUS_B:u8 = 0x0B;
+
+///An update of the time registers is in progress. They are undefined until it is done.
+const STATUS_A_UPDATE_IN_PROGRESS:u8 = 1<<7;
+const STATUS_B_24H:u8 = 1<<1;
+const STATUS_B_BINARY:u8 = 1<<2;
+const HOUR_PM:u8 = 1<<7;
+
+///A point in time in UTC, as read from the RTC.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct DateTime{
+	pub year:u16,
+	///1-12
+	pub month:u8,
+	///1-31
+	pub day:u8,
+	pub hour:u8,
+	pub minute:u8,
+	pub second:u8,
+}
+
+impl DateTime{
+	///Seconds since 1970-01-01 00:00:00 UTC.
+	pub fn to_unix(self)->u64{
+		//Days from the civil date, counted in years starting in March, so the leap day is the last day of the year.
+		let (y, m) = if self.month <= 2 {(self.year as i64-1, self.month as i64+9)} else {(self.year as i64, self.month as i64-3)};
+		let era = y.div_euclid(400);
+		let year_of_era = y-era*400;
+		let day_of_year = (153*m+2)/5+self.day as i64-1;
+		let day_of_era = year_of_era*365+year_of_era/4-year_of_era/100+day_of_year;
+		let days = era*146097+day_of_era-719468;
+		(days*86400+self.hour as i64*3600+self.minute as i64*60+self.second as i64).max(0) as u64
+	}
+
+	///The inverse of to_unix.
+	pub fn from_unix(secs:u64)->Self{
+		let days = (secs/86400) as i64+719468;
+		let secs = secs%86400;
+		let era = days.div_euclid(146097);
+		let day_of_era = days-era*146097;
+		let year_of_era = (day_of_era-day_of_era/1460+day_of_era/36524-day_of_era/146096)/365;
+		let day_of_year = day_of_era-(365*year_of_era+year_of_era/4-year_of_era/100);
+		//The month, counted from March.
+		let m = (5*day_of_year+2)/153;
+		let day = day_of_year-(153*m+2)/5+1;
+		let month = if m < 10 {m+3} else {m-9};
+		let year = year_of_era+era*400+if month <= 2 {1} else {0};
+		Self{
+			year:year as u16,
+			month:month as u8,
+			day:day as u8,
+			hour:(secs/3600) as u8,
+			minute:(secs/60%60) as u8,
+			second:(secs%60) as u8,
+		}
+	}
+
+	fn is_valid(&self)->bool{
+		(1..=12).contains(&self.month) && (1..=31).contains(&self.day) && self.hour < 24 && self.minute < 60 && self.second < 60
+	}
+}
+
+impl fmt::Display for DateTime{
+	fn fmt(&self, f:&mut fmt::Formatter<'_>)->fmt::Result{
+		write!(f, "{:04}-{:02}-{:02} {:02}:{:02}:{:02}", self.year, self.month, self.day, self.hour, self.minute, self.second)
+	}
+}
+
+fn read_register(reg:u8)->u8{
+	let mut index = Port::<u8>::new(CMOS_INDEX);
+	let mut data = Port::<u8>::new(CMOS_DATA);
+	//Safety:
+	//These are the standard CMOS ports. Bit 7 of the index stays clear, so NMIs stay enabled.
+	unsafe{
+		index.write(reg & 0x7F);
+		data.read()
+	}
+}
+
+fn update_in_progress()->bool{
+	read_register(REG_STATUS_A) & STATUS_A_UPDATE_IN_PROGRESS != 0
+}
+
+///The raw register values, in the order year, month, day, hours, minutes, seconds, century.
+fn read_raw(century_reg:u8)->[u8;7]{
+	//An update takes at most about 2ms. Without a RTC, this just reads 0xFF, so don't wait forever.
+	for _ in 0..10_000{
+		if !update_in_progress(){
+			break;
+		}
+		core::hint::spin_loop();
+	}
+	[
+		read_register(REG_YEAR),
+		read_register(REG_MONTH),
+		read_register(REG_DAY),
+		read_register(REG_HOURS),
+		read_register(REG_MINUTES),
+		read_register(REG_SECONDS),
+		if century_reg != 0 {read_register(century_reg)} else {0},
+	]
+}
+
+fn from_bcd(v:u8)->u8{
+	(v>>4)*10+(v&0xF)
+}
+
+///Reads the current time from the RTC.
+///`century_reg` is the CMOS index of the century register from the FADT, or 0, if there is none, in which case the 21st century is assumed.
+///The RTC is expected to run in UTC.
+///Returns None, if the RTC contains garbage.
+pub fn read(century_reg:u8)->Option<DateTime>{
+	//An update could still start in the middle of reading, so read until we get the same values twice.
+	let mut raw = read_raw(century_reg);
+	for _ in 0..16{
+		let again = read_raw(century_reg);
+		if again == raw{
+			break;
+		}
+		raw = again;
+	}
+	let [year, month, day, hours, minutes, seconds, century] = raw;
+	let status_b = read_register(REG_STATUS_B);
+	let binary = status_b & STATUS_B_BINARY != 0;
+	let convert = |v:u8|if binary {v} else {from_bcd(v)};
+	let pm = hours & HOUR_PM != 0;
+	let mut hour = convert(hours & !HOUR_PM);
+	if status_b & STATUS_B_24H == 0{
+		//12 hour mode: 12 AM is midnight, 12 PM is noon.
+		hour %= 12;
+		if pm{
+			hour += 12;
+		}
+	}
+	let century = if century_reg != 0 {convert(century) as u16} else {20};
+	let time = DateTime{
+		year:century*100+convert(year) as u16,
+		month:convert(month),
+		day:convert(day),
+		hour,
+		minute:convert(minutes),
+		second:convert(seconds),
+	};
+	if !time.is_valid(){
+		log::warn!("The RTC contains an invalid time: {:?}", time);
+		return None;
+	}
+	Some(time)
+}
@@ -0,0 +1,68 @@
+use core::arch::x86_64::__cpuid;
+use crate::x86_64::apic;
+use super::{hpet, pit};
+
+///How long a single calibration run measures.
+const CALIBRATION_US:u64 = 10_000;
+const CALIBRATION_RUNS:usize = 5;
+
+///Returns true, if the TSC runs at a constant rate in all ACPI P-, C- and T-states.
+pub fn is_invariant()->bool{
+	//Leaf 0x8000_0007 is only read, if it exists.
+	if __cpuid(0x8000_0000).eax < 0x8000_0007{
+		return false;
+	}
+	__cpuid(0x8000_0007).edx>>8 & 1 == 1
+}
+
+#[inline]
+pub fn read()->u64{
+	//Safety:
+	//rdtsc is available on every x86_64 cpu.
+	unsafe{core::arch::x86_64::_rdtsc()}
+}
+
+///Frequencies measured against a reference clock.
+#[derive(Debug, Copy, Clone)]
+pub struct Calibration{
+	pub tsc_hz:u64,
+	///The rate, at which the LAPIC timer counts down, with the divider used by apic::start_timer_periodic.
+	pub lapic_hz:u64,
+}
+
+///Measures the TSC and LAPIC timer against the PIT.
+pub fn calibrate_pit()->Option<Calibration>{
+	calibrate(|us|pit::Countdown::start(us).wait(), CALIBRATION_US.min(pit::MAX_WAIT_US))
+}
+
+///Measures the TSC and LAPIC timer against the HPET.
+pub fn calibrate_hpet()->Option<Calibration>{
+	calibrate(hpet::busy_wait_us, CALIBRATION_US)
+}
+
+///Measures the TSC and LAPIC timer against `wait`, which has to busy wait for the given amount of microseconds.
+///`wait` returns false, if the reference clock doesn't work.
+///The shortest of several runs is used, since any delay (like an SMI) only ever makes a run longer.
+pub fn calibrate(mut wait:impl FnMut(u64)->bool, us:u64)->Option<Calibration>{
+	let mut best:Option<(u64,u64)> = None;
+	for _ in 0..CALIBRATION_RUNS{
+		apic::start_timer_oneshot_masked(u32::MAX);
+		let tsc_start = read();
+		let lapic_start = apic::timer_current();
+		if !wait(us){
+			apic::stop_timer();
+			return None;
+		}
+		let tsc = read()-tsc_start;
+		let lapic = (lapic_start-apic::timer_current()) as u64;
+		if best.map(|(t,_)|tsc<t).unwrap_or(true){
+			best = Some((tsc, lapic));
+		}
+	}
+	apic::stop_timer();
+	let (tsc, lapic) = best?;
+	Some(Calibration{
+		tsc_hz:tsc*1_000_000/us,
+		lapic_hz:lapic*1_000_000/us,
+	})
+}
@@ -1,2 +1,8 @@
 pub mod statics;
+pub mod cpu;
+pub mod mem;
+pub mod interrupts;
+pub mod apic;
+pub mod ioapic;
+pub mod dma;
 mod rust_lang;
\ No newline at end of file
@@ -0,0 +1,123 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::registers::model_specific::Msr;
+use x86_64::PhysAddr;
+use super::interrupts::SPURIOUS_VECTOR;
+
+const IA32_APIC_BASE:u32 = 0x1B;
+const APIC_BASE_ENABLE:u64 = 1<<11;
+const APIC_BASE_ADDR_MASK:u64 = 0x000F_FFFF_FFFF_F000;
+
+const REG_ID:usize = 0x20;
+const REG_TPR:usize = 0x80;
+const REG_EOI:usize = 0xB0;
+const REG_SVR:usize = 0xF0;
+const REG_ICR_LOW:usize = 0x300;
+const REG_ICR_HIGH:usize = 0x310;
+const REG_LVT_TIMER:usize = 0x320;
+const REG_TIMER_INITIAL:usize = 0x380;
+const REG_TIMER_CURRENT:usize = 0x390;
+const REG_TIMER_DIVIDE:usize = 0x3E0;
+
+const SVR_ENABLE:u32 = 1<<8;
+const LVT_MASKED:u32 = 1<<16;
+const LVT_TIMER_PERIODIC:u32 = 1<<17;
+///Divide the bus clock by 16 for the timer.
+const TIMER_DIVIDE_16:u32 = 0b0011;
+const ICR_DELIVERY_PENDING:u32 = 1<<12;
+const ICR_DELIVERY_NMI:u32 = 0b100<<8;
+const ICR_ALL_BUT_SELF:u32 = 0b11<<18;
+///Writes to this address range are interrupt messages to the LAPICs.
+const MSI_ADDRESS_BASE:u64 = 0xFEE0_0000;
+
+///The virtual address of the LAPIC registers. All cpus map their own LAPIC at the same address.
+static BASE:AtomicU64 = AtomicU64::new(0);
+
+///Enables the LAPIC of the current cpu.
+pub fn init(){
+	let mut msr = Msr::new(IA32_APIC_BASE);
+	//Safety:
+	//IA32_APIC_BASE exists on every cpu with a LAPIC, which every x86_64 cpu has.
+	let value = unsafe{msr.read()};
+	if value & APIC_BASE_ENABLE == 0{
+		unsafe{msr.write(value | APIC_BASE_ENABLE)};
+	}
+	let base = super::mem::phys_to_virt(PhysAddr::new(value & APIC_BASE_ADDR_MASK));
+	BASE.store(base.as_u64(), Ordering::Relaxed);
+	write(REG_TPR, 0);
+	write(REG_SVR, SVR_ENABLE | SPURIOUS_VECTOR as u32);
+	log::debug!("LAPIC {} enabled at {:#x}", id(), value & APIC_BASE_ADDR_MASK);
+}
+
+fn read(reg:usize)->u32{
+	//Safety:
+	//The LAPIC registers are 32 bit wide and 16 byte aligned.
+	unsafe{core::ptr::read_volatile((BASE.load(Ordering::Relaxed) as usize + reg) as *const u32)}
+}
+
+fn write(reg:usize, value:u32){
+	//Safety:
+	//The LAPIC registers are 32 bit wide and 16 byte aligned.
+	unsafe{core::ptr::write_volatile((BASE.load(Ordering::Relaxed) as usize + reg) as *mut u32, value)}
+}
+
+///The APIC id of the current cpu.
+pub fn id()->u32{
+	read(REG_ID)>>24
+}
+
+///Signals the end of the current interrupt.
+pub fn eoi(){
+	write(REG_EOI, 0);
+}
+
+///Makes the LAPIC timer fire `vector` every `count` timer ticks.
+///The timer runs at a 16th of the bus clock.
+pub fn start_timer_periodic(vector:u8, count:u32){
+	write(REG_TIMER_DIVIDE, TIMER_DIVIDE_16);
+	write(REG_LVT_TIMER, LVT_TIMER_PERIODIC | vector as u32);
+	write(REG_TIMER_INITIAL, count);
+}
+
+///Starts the LAPIC timer as a masked one-shot timer, that counts down from `count` once.
+///Used to measure the timer frequency, without ever raising an interrupt.
+pub fn start_timer_oneshot_masked(count:u32){
+	write(REG_TIMER_DIVIDE, TIMER_DIVIDE_16);
+	write(REG_LVT_TIMER, LVT_MASKED);
+	write(REG_TIMER_INITIAL, count);
+}
+
+///Stops the LAPIC timer.
+pub fn stop_timer(){
+	write(REG_LVT_TIMER, LVT_MASKED);
+	write(REG_TIMER_INITIAL, 0);
+}
+
+///Returns the current count of the LAPIC timer.
+pub fn timer_current()->u32{
+	read(REG_TIMER_CURRENT)
+}
+
+///The address and data of a message signaled interrupt, that delivers `vector` to the cpu with the given APIC id.
+///Edge triggered, fixed delivery mode, physical destination.
+pub fn msi_message(apic_id:u32, vector:u8)->(u64, u32){
+	(MSI_ADDRESS_BASE | ((apic_id as u64 & 0xFF)<<12), vector as u32)
+}
+
+///Sends an NMI to every other cpu. Does nothing, if the LAPIC isn't set up yet, since then no other cpu runs either.
+///Doesn't wait for the delivery, since the other cpus might not respond anymore.
+pub fn send_nmi_to_others(){
+	if BASE.load(Ordering::Relaxed) == 0{
+		return;
+	}
+	write(REG_ICR_HIGH, 0);
+	write(REG_ICR_LOW, ICR_ALL_BUT_SELF | ICR_DELIVERY_NMI);
+}
+
+///Sends a fixed interrupt with `vector` to the cpu with the given APIC id.
+pub fn send_ipi(apic_id:u32, vector:u8){
+	write(REG_ICR_HIGH, apic_id<<24);
+	write(REG_ICR_LOW, vector as u32);
+	while read(REG_ICR_LOW) & ICR_DELIVERY_PENDING != 0{
+		core::hint::spin_loop();
+	}
+}
@@ -0,0 +1,107 @@
+use core::arch::x86_64::__cpuid;
+use core::sync::atomic::{AtomicU32, AtomicU8, AtomicUsize, Ordering};
+use x86_64::registers::model_specific::GsBase;
+use x86_64::VirtAddr;
+use crate::acpi::MadtEntry;
+
+///The highest amount of cpus, that per-cpu data structures are sized for.
+pub const MAX_CPUS:usize = 64;
+///xAPIC ids are 8 bits wide.
+const MAX_APIC_ID:u32 = 0xFF;
+const NO_INDEX:u8 = u8::MAX;
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_CPU:AtomicU8 = AtomicU8::new(NO_INDEX);
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_APIC_ID:AtomicU32 = AtomicU32::new(0);
+///The index of every APIC id, that was registered.
+static INDICES:[AtomicU8;MAX_APIC_ID as usize+1] = [NO_CPU;MAX_APIC_ID as usize+1];
+///The APIC id of every index.
+static APIC_IDS:[AtomicU32;MAX_CPUS] = [NO_APIC_ID;MAX_CPUS];
+static COUNT:AtomicUsize = AtomicUsize::new(0);
+///Every cpu points GS at its own entry, so id() is a single load.
+static SELF_INDEX:[usize;MAX_CPUS] = {
+	let mut indices = [0;MAX_CPUS];
+	let mut i = 0;
+	while i < MAX_CPUS{
+		indices[i] = i;
+		i += 1;
+	}
+	indices
+};
+
+///Gives the current cpu its index, and makes [`id`] return it.
+///Has to be the first thing every cpu does, since locks already use id.
+pub fn init(){
+	let apic_id = __cpuid(1).ebx >> 24;
+	let index = register(apic_id);
+	GsBase::write(VirtAddr::from_ptr(&SELF_INDEX[index]));
+}
+
+///Returns the index of the cpu with the given APIC id, and gives it the next free one, if it has none yet.
+///Indices are dense, starting at 0, so they can be used for per-cpu data structures.
+///Panics, if the APIC id can't be used with them.
+pub fn register(apic_id:u32)->usize{
+	assert!(apic_id <= MAX_APIC_ID, "APIC id {} is not an xAPIC id", apic_id);
+	try_register(apic_id).unwrap_or_else(||panic!("APIC id {}: more than {} cpus", apic_id, MAX_CPUS))
+}
+
+///Gives every usable cpu in the MADT an index, so per-cpu data can be set up for cpus, that aren't running yet.
+///Cpus, that don't fit, are left out, and can't be brought up.
+pub fn register_madt(){
+	for entry in crate::acpi::madt_entries(){
+		//Bit 0 means enabled, bit 1 means it can be enabled later.
+		if let MadtEntry::LocalApic{apic_id, flags, ..} = entry{
+			if flags & 0b11 != 0 && try_register(apic_id as u32).is_none(){
+				log::warn!("Cpu with APIC id {} is left out, only {} cpus are supported", apic_id, MAX_CPUS);
+			}
+		}
+	}
+}
+
+fn try_register(apic_id:u32)->Option<usize>{
+	let slot = INDICES.get(apic_id as usize)?;
+	loop{
+		let index = slot.load(Ordering::Acquire);
+		if index != NO_INDEX{
+			return Some(index as usize);
+		}
+		//Reserve the index first, so two cpus never get the same one.
+		let index = COUNT.fetch_add(1, Ordering::Relaxed);
+		if index >= MAX_CPUS{
+			COUNT.store(MAX_CPUS, Ordering::Relaxed);
+			return None;
+		}
+		APIC_IDS[index].store(apic_id, Ordering::Relaxed);
+		if slot.compare_exchange(NO_INDEX, index as u8, Ordering::AcqRel, Ordering::Acquire).is_ok(){
+			return Some(index);
+		}
+		//Somebody else registered the same APIC id at the same time. Their index wins, ours stays unused.
+	}
+}
+
+///Returns the index of the cpu we are currently running on.
+///It is assigned by [`init`], so it is stable for the lifetime of the cpu.
+pub fn id()->usize{
+	let index:usize;
+	//Safety:
+	//init pointed GS at this cpu's entry of SELF_INDEX.
+	unsafe{core::arch::asm!("mov {}, gs:[0]", out(reg) index, options(nostack, preserves_flags, readonly))};
+	index
+}
+
+///The index of the cpu with the given APIC id, if it was registered.
+pub fn index_of(apic_id:u32)->Option<usize>{
+	let index = INDICES.get(apic_id as usize)?.load(Ordering::Acquire);
+	(index != NO_INDEX).then_some(index as usize)
+}
+
+///The APIC id of the cpu with the given index.
+pub fn apic_id(index:usize)->u32{
+	APIC_IDS[index].load(Ordering::Relaxed)
+}
+
+///The amount of cpus, that have an index. Every index below it is valid.
+pub fn count()->usize{
+	COUNT.load(Ordering::Relaxed).min(MAX_CPUS)
+}
@@ -0,0 +1,96 @@
+//Memory, that devices access directly.
+use alloc::alloc::{alloc_zeroed, dealloc, Layout};
+use x86_64::VirtAddr;
+use super::mem::{virt_to_phys, PAGE_SIZE};
+
+///A zeroed, physically contiguous buffer of at most one page.
+///It is aligned to its size (rounded up to a power of two), so it never crosses a page boundary.
+pub struct DmaBuffer{
+	ptr:*mut u8,
+	layout:Layout,
+	phys:u64,
+}
+
+//Safety:
+//DmaBuffer owns its memory, just like a Box.
+unsafe impl Send for DmaBuffer{}
+unsafe impl Sync for DmaBuffer{}
+
+impl DmaBuffer{
+	///Returns None, if `size` is 0 or bigger than a page, or if there is no memory left.
+	pub fn new(size:usize)->Option<Self>{
+		if size == 0 || size > PAGE_SIZE{
+			return None;
+		}
+		let layout = Layout::from_size_align(size, size.next_power_of_two()).ok()?;
+		//Safety:
+		//The layout has a non zero size.
+		let ptr = unsafe{alloc_zeroed(layout)};
+		if ptr.is_null(){
+			return None;
+		}
+		let phys = match virt_to_phys(VirtAddr::new(ptr as u64)){
+			Some(p)=>p.as_u64(),
+			None=>{
+				//Safety:
+				//We just allocated it with this layout.
+				unsafe{dealloc(ptr, layout)};
+				return None;
+			}
+		};
+		Some(Self{ptr, layout, phys})
+	}
+
+	pub fn phys(&self)->u64{
+		self.phys
+	}
+
+	pub fn as_ptr(&self)->*mut u8{
+		self.ptr
+	}
+
+	pub fn len(&self)->usize{
+		self.layout.size()
+	}
+
+	pub fn is_empty(&self)->bool{
+		false
+	}
+
+	///The caller has to make sure, that the device doesn't write to the buffer at the same time.
+	pub fn as_slice(&self)->&[u8]{
+		//Safety:
+		//ptr points to len initialized bytes, that we own.
+		unsafe{core::slice::from_raw_parts(self.ptr, self.len())}
+	}
+
+	pub fn as_mut_slice(&mut self)->&mut [u8]{
+		//Safety:
+		//See as_slice.
+		unsafe{core::slice::from_raw_parts_mut(self.ptr, self.len())}
+	}
+
+	///Reads a value at `offset` with a volatile read, since the device might have changed it.
+	pub fn read<T:Copy>(&self, offset:usize)->T{
+		assert!(offset+core::mem::size_of::<T>() <= self.len());
+		//Safety:
+		//The value is inside the buffer. T is only used with plain integers and repr(C) structs of them.
+		unsafe{core::ptr::read_volatile(self.ptr.add(offset) as *const T)}
+	}
+
+	///Writes a value at `offset` with a volatile write, so the device sees it.
+	pub fn write<T:Copy>(&self, offset:usize, value:T){
+		assert!(offset+core::mem::size_of::<T>() <= self.len());
+		//Safety:
+		//See read.
+		unsafe{core::ptr::write_volatile(self.ptr.add(offset) as *mut T, value)}
+	}
+}
+
+impl Drop for DmaBuffer{
+	fn drop(&mut self){
+		//Safety:
+		//ptr was allocated with layout in new.
+		unsafe{dealloc(self.ptr, self.layout)}
+	}
+}
@@ -0,0 +1,306 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+use x86_64::instructions::port::Port;
+use x86_64::instructions::segmentation::{CS, Segment};
+use x86_64::structures::DescriptorTablePointer;
+use x86_64::VirtAddr;
+
+///Vectors 0x20..0x30 are where the legacy PIC is remapped to. They only ever see spurious interrupts.
+const PIC_BASE:u8 = 0x20;
+///Dynamically allocated vectors for device interrupts.
+pub const DYNAMIC_VECTORS:core::ops::RangeInclusive<u8> = 0x30..=0xEF;
+///The LAPIC timer, which drives the scheduler.
+pub const TIMER_VECTOR:u8 = 0xF0;
+///Software interrupt, used by tasks to give up the cpu.
+pub const YIELD_VECTOR:u8 = 0xF1;
+///Spurious interrupts from the LAPIC.
+pub const SPURIOUS_VECTOR:u8 = 0xFF;
+///Non maskable interrupts. The panic handler uses them to stop the other cpus.
+pub const NMI_VECTOR:u8 = 2;
+
+///The state of the interrupted code, as saved by the interrupt stubs.
+///The general purpose registers are pushed by us, the rest by the cpu.
+#[repr(C)]
+#[derive(Debug, Clone, Default)]
+pub struct InterruptFrame{
+	pub r15:u64,
+	pub r14:u64,
+	pub r13:u64,
+	pub r12:u64,
+	pub r11:u64,
+	pub r10:u64,
+	pub r9:u64,
+	pub r8:u64,
+	pub rbp:u64,
+	pub rdi:u64,
+	pub rsi:u64,
+	pub rdx:u64,
+	pub rcx:u64,
+	pub rbx:u64,
+	pub rax:u64,
+	pub vector:u64,
+	///0, if the exception does not push an error code.
+	pub error_code:u64,
+	pub rip:u64,
+	pub cs:u64,
+	pub rflags:u64,
+	pub rsp:u64,
+	pub ss:u64,
+}
+
+pub type Handler = fn(&mut InterruptFrame);
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct IdtEntry{
+	offset_low:u16,
+	selector:u16,
+	ist:u8,
+	type_attr:u8,
+	offset_mid:u16,
+	offset_high:u32,
+	reserved:u32,
+}
+
+impl IdtEntry{
+	const MISSING:Self = Self{offset_low:0,selector:0,ist:0,type_attr:0,offset_mid:0,offset_high:0,reserved:0};
+	///A present ring 0 interrupt gate.
+	fn new(addr:u64, selector:u16)->Self{
+		Self{
+			offset_low:addr as u16,
+			selector,
+			ist:0,
+			type_attr:0x8E,
+			offset_mid:(addr>>16) as u16,
+			offset_high:(addr>>32) as u32,
+			reserved:0,
+		}
+	}
+}
+
+static mut IDT:[IdtEntry;256] = [IdtEntry::MISSING;256];
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_HANDLER:AtomicUsize = AtomicUsize::new(0);
+static HANDLERS:[AtomicUsize;256] = [NO_HANDLER;256];
+
+extern "C"{
+	static isr_stub_table:[u64;256];
+}
+
+//Every vector gets its own stub, which pushes a dummy error code (if the cpu doesn't push one) and the vector number.
+//All stubs then save the general purpose registers and call interrupt_dispatch with a pointer to the InterruptFrame.
+//interrupt_dispatch returns the frame to resume, which allows the scheduler to switch stacks.
+core::arch::global_asm!(
+	".altmacro",
+	".macro isr_stub vec",
+	"isr_stub_\\vec:",
+	".if (\\vec == 8) || ((\\vec >= 10) && (\\vec <= 14)) || (\\vec == 17) || (\\vec == 21) || (\\vec == 29) || (\\vec == 30)",
+	".else",
+	"push 0",
+	".endif",
+	"push \\vec",
+	"jmp isr_common",
+	".endm",
+	".macro isr_entry vec",
+	".quad isr_stub_\\vec",
+	".endm",
+	"",
+	".pushsection .text",
+	"isr_common:",
+	"push rax",
+	"push rbx",
+	"push rcx",
+	"push rdx",
+	"push rsi",
+	"push rdi",
+	"push rbp",
+	"push r8",
+	"push r9",
+	"push r10",
+	"push r11",
+	"push r12",
+	"push r13",
+	"push r14",
+	"push r15",
+	"mov rdi, rsp",
+	"cld",
+	"call {dispatch}",
+	"mov rsp, rax",
+	"pop r15",
+	"pop r14",
+	"pop r13",
+	"pop r12",
+	"pop r11",
+	"pop r10",
+	"pop r9",
+	"pop r8",
+	"pop rbp",
+	"pop rdi",
+	"pop rsi",
+	"pop rdx",
+	"pop rcx",
+	"pop rbx",
+	"pop rax",
+	//vector and error code
+	"add rsp, 16",
+	"iretq",
+	".set i, 0",
+	".rept 256",
+	"isr_stub %i",
+	".set i, i+1",
+	".endr",
+	".popsection",
+	"",
+	".pushsection .rodata",
+	".balign 8",
+	".global isr_stub_table",
+	"isr_stub_table:",
+	".set i, 0",
+	".rept 256",
+	"isr_entry %i",
+	".set i, i+1",
+	".endr",
+	".popsection",
+	".noaltmacro",
+	dispatch = sym interrupt_dispatch,
+);
+
+///Sets up the IDT for the current cpu and moves the legacy PIC out of the way.
+///Interrupts stay disabled. They get enabled by the scheduler.
+pub fn init(){
+	disable_pic();
+	let selector = CS::get_reg().0;
+	//Safety:
+	//The IDT is only ever written here, before it is loaded.
+	//Every cpu runs this with the same values.
+	unsafe{
+		let idt = &mut *core::ptr::addr_of_mut!(IDT);
+		for (entry, stub) in idt.iter_mut().zip(isr_stub_table.iter()){
+			*entry = IdtEntry::new(*stub, selector);
+		}
+		x86_64::instructions::tables::lidt(&DescriptorTablePointer{
+			limit:(core::mem::size_of::<[IdtEntry;256]>()-1) as u16,
+			base:VirtAddr::new(idt.as_ptr() as u64),
+		});
+	}
+	register_handler(SPURIOUS_VECTOR, |_|{});
+}
+
+///Remaps the 8259 PIC to PIC_BASE and masks all of its interrupts.
+///Without remapping, spurious PIC interrupts would show up as cpu exceptions.
+fn disable_pic(){
+	let mut master_cmd = Port::<u8>::new(0x20);
+	let mut master_data = Port::<u8>::new(0x21);
+	let mut slave_cmd = Port::<u8>::new(0xA0);
+	let mut slave_data = Port::<u8>::new(0xA1);
+	//Safety:
+	//These are the standard PIC ports. The init sequence is ICW1 to ICW4.
+	unsafe{
+		master_cmd.write(0x11);
+		slave_cmd.write(0x11);
+		master_data.write(PIC_BASE);
+		slave_data.write(PIC_BASE+8);
+		master_data.write(4);
+		slave_data.write(2);
+		master_data.write(1);
+		slave_data.write(1);
+		master_data.write(0xFF);
+		slave_data.write(0xFF);
+	}
+}
+
+///Installs the handler for the given vector, replacing any previous one.
+pub fn register_handler(vector:u8, handler:Handler){
+	HANDLERS[vector as usize].store(handler as usize, Ordering::Release);
+}
+
+///Removes the handler for the given vector.
+pub fn unregister_handler(vector:u8){
+	HANDLERS[vector as usize].store(0, Ordering::Release);
+}
+
+///Finds a free vector in DYNAMIC_VECTORS and installs the handler for it.
+pub fn allocate_vector(handler:Handler)->Option<u8>{
+	DYNAMIC_VECTORS.into_iter().find(|v|{
+		HANDLERS[*v as usize].compare_exchange(0, handler as usize, Ordering::AcqRel, Ordering::Relaxed).is_ok()
+	})
+}
+
+///Finds `handlers.len()` consecutive free vectors, with the first one aligned to the next power of two of that count, and installs the handlers for them.
+///Multi-message MSI needs such a block, since the device puts the message number into the low bits of the vector.
+///Returns the first vector of the block.
+pub fn allocate_vector_block(handlers:&[Handler])->Option<u8>{
+	let count = handlers.len();
+	if count == 0{
+		return None;
+	}
+	let align = count.next_power_of_two();
+	let start = *DYNAMIC_VECTORS.start() as usize;
+	let end = *DYNAMIC_VECTORS.end() as usize;
+	let mut base = start.next_multiple_of(align);
+	while base+count-1 <= end{
+		let claimed = handlers.iter().enumerate().take_while(|(i, handler)|{
+			HANDLERS[base+i].compare_exchange(0, **handler as usize, Ordering::AcqRel, Ordering::Relaxed).is_ok()
+		}).count();
+		if claimed == count{
+			return Some(base as u8);
+		}
+		//Somebody else got one of the vectors. Give back the ones we got, and try the next block.
+		for handler in &HANDLERS[base..base+claimed]{
+			handler.store(0, Ordering::Release);
+		}
+		base += align;
+	}
+	None
+}
+
+///Frees a vector, that was returned by allocate_vector or allocate_vector_block.
+pub fn free_vector(vector:u8){
+	debug_assert!(DYNAMIC_VECTORS.contains(&vector));
+	unregister_handler(vector);
+}
+
+extern "C" fn interrupt_dispatch(frame:*mut InterruptFrame)->*mut InterruptFrame{
+	//Safety:
+	//The stub passes a pointer to the frame it just pushed onto the stack.
+	let frame = unsafe{&mut *frame};
+	let vector = frame.vector as u8;
+	let handler = HANDLERS[vector as usize].load(Ordering::Acquire);
+	if handler != 0{
+		//Safety:
+		//We only ever store Handler in there.
+		let handler:Handler = unsafe{core::mem::transmute(handler)};
+		handler(frame);
+	}else if vector < 32{
+		exception(frame);
+	}else if (PIC_BASE..PIC_BASE+16).contains(&vector){
+		//A spurious interrupt from the masked PIC. The PIC doesn't expect an EOI for those.
+		return frame;
+	}else{
+		log::warn!("Unhandled interrupt on vector {:#x}", vector);
+	}
+	//Software interrupts and spurious interrupts don't go through the LAPIC and must not be acknowledged.
+	if vector >= 32 && vector != YIELD_VECTOR && vector != SPURIOUS_VECTOR{
+		super::apic::eoi();
+	}
+	crate::sched::switch_if_needed(frame)
+}
+
+const EXCEPTIONS:[&str;32] = [
+	"Divide Error", "Debug", "Non Maskable Interrupt", "Breakpoint",
+	"Overflow", "Bound Range Exceeded", "Invalid Opcode", "Device Not Available",
+	"Double Fault", "Coprocessor Segment Overrun", "Invalid TSS", "Segment Not Present",
+	"Stack-Segment Fault", "General Protection Fault", "Page Fault", "Reserved",
+	"x87 Floating-Point Exception", "Alignment Check", "Machine Check", "SIMD Floating-Point Exception",
+	"Virtualization Exception", "Control Protection Exception", "Reserved", "Reserved",
+	"Reserved", "Reserved", "Reserved", "Reserved",
+	"Hypervisor Injection Exception", "VMM Communication Exception", "Security Exception", "Reserved",
+];
+
+fn exception(frame:&mut InterruptFrame){
+	let name = EXCEPTIONS[frame.vector as usize];
+	if frame.vector == 14{
+		let addr = x86_64::registers::control::Cr2::read();
+		panic!("{} accessing {:#x} at {:#x} (error code {:#x})\n{:#x?}", name, addr.as_u64(), frame.rip, frame.error_code, frame);
+	}
+	panic!("{} at {:#x} (error code {:#x})\n{:#x?}", name, frame.rip, frame.error_code, frame);
+}
@@ -0,0 +1,175 @@
+//Routing of external interrupts through the IOAPICs described in the MADT.
+//Every input of an IOAPIC has a global system interrupt (GSI) number.
+//The ISA IRQs are mapped to the first GSIs, unless the MADT has an override for them.
+use alloc::vec::Vec;
+use x86_64::instructions::interrupts;
+use x86_64::PhysAddr;
+use crate::acpi::{self, MadtEntry};
+use crate::lock::{Lock, LockClass};
+
+const REG_SELECT:usize = 0x00;
+const REG_WINDOW:usize = 0x10;
+
+const REG_VERSION:u32 = 0x01;
+const REG_REDIRECTION:u32 = 0x10;
+
+const ENTRY_POLARITY_LOW:u64 = 1<<13;
+const ENTRY_TRIGGER_LEVEL:u64 = 1<<15;
+const ENTRY_MASKED:u64 = 1<<16;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Trigger{
+	Edge,
+	Level,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Polarity{
+	ActiveHigh,
+	ActiveLow,
+}
+
+struct IoApic{
+	///Virtual address of the registers.
+	base:usize,
+	gsi_base:u32,
+	inputs:u32,
+}
+
+impl IoApic{
+	fn read(&self, reg:u32)->u32{
+		//Safety:
+		//base points to the registers of this IOAPIC, and the lock around IOAPICS keeps select and window access together.
+		unsafe{
+			core::ptr::write_volatile((self.base+REG_SELECT) as *mut u32, reg);
+			core::ptr::read_volatile((self.base+REG_WINDOW) as *const u32)
+		}
+	}
+
+	fn write(&self, reg:u32, value:u32){
+		//Safety:
+		//See read.
+		unsafe{
+			core::ptr::write_volatile((self.base+REG_SELECT) as *mut u32, reg);
+			core::ptr::write_volatile((self.base+REG_WINDOW) as *mut u32, value);
+		}
+	}
+
+	fn read_entry(&self, input:u32)->u64{
+		let reg = REG_REDIRECTION+input*2;
+		self.read(reg) as u64 | (self.read(reg+1) as u64)<<32
+	}
+
+	fn write_entry(&self, input:u32, entry:u64){
+		let reg = REG_REDIRECTION+input*2;
+		//Mask the entry while it is changed, so no interrupt gets delivered with a half written entry.
+		self.write(reg, ENTRY_MASKED as u32);
+		self.write(reg+1, (entry>>32) as u32);
+		self.write(reg, entry as u32);
+	}
+
+	fn handles(&self, gsi:u32)->bool{
+		(self.gsi_base..self.gsi_base+self.inputs).contains(&gsi)
+	}
+}
+
+#[derive(Debug, Copy, Clone)]
+struct Override{
+	irq:u8,
+	gsi:u32,
+	trigger:Trigger,
+	polarity:Polarity,
+}
+
+struct State{
+	ioapics:Vec<IoApic>,
+	overrides:Vec<Override>,
+}
+
+static IOAPIC_CLASS:LockClass = LockClass::new("ioapic");
+static STATE:Lock<State> = Lock::with_class(State{ioapics:Vec::new(), overrides:Vec::new()}, &IOAPIC_CLASS);
+
+///Finds the IOAPICs in the MADT and masks all of their inputs.
+///Needs acpi::init to have run.
+pub fn init(){
+	let mut ioapics = Vec::new();
+	let mut overrides = Vec::new();
+	for entry in acpi::madt_entries(){
+		match entry{
+			MadtEntry::IoApic{id, address, gsi_base}=>{
+				let base = super::mem::phys_to_virt(PhysAddr::new(address as u64)).as_u64() as usize;
+				let mut ioapic = IoApic{base, gsi_base, inputs:0};
+				ioapic.inputs = ((ioapic.read(REG_VERSION)>>16)&0xFF)+1;
+				for input in 0..ioapic.inputs{
+					ioapic.write_entry(input, ENTRY_MASKED);
+				}
+				log::debug!("IOAPIC {} at {:#x} handles GSI {}..{}", id, address, gsi_base, gsi_base+ioapic.inputs);
+				ioapics.push(ioapic);
+			},
+			//Only bus 0 (ISA) is defined.
+			MadtEntry::InterruptOverride{bus:0, source, gsi, flags}=>{
+				overrides.push(Override{
+					irq:source,
+					gsi,
+					polarity:if flags&0b11 == 0b11 {Polarity::ActiveLow} else {Polarity::ActiveHigh},
+					trigger:if (flags>>2)&0b11 == 0b11 {Trigger::Level} else {Trigger::Edge},
+				});
+			},
+			_=>{},
+		}
+	}
+	if ioapics.is_empty(){
+		log::warn!("The MADT doesn't list any IOAPICs. External interrupts won't work.");
+	}
+	interrupts::without_interrupts(||{
+		let mut state = STATE.lock();
+		state.ioapics = ioapics;
+		state.overrides = overrides;
+	});
+}
+
+///Returns the GSI, trigger mode and polarity of an ISA IRQ.
+pub fn isa_irq(irq:u8)->(u32, Trigger, Polarity){
+	interrupts::without_interrupts(||{
+		STATE.lock().overrides.iter().find(|o|o.irq == irq)
+			.map(|o|(o.gsi, o.trigger, o.polarity))
+			.unwrap_or((irq as u32, Trigger::Edge, Polarity::ActiveHigh))
+	})
+}
+
+///Delivers the GSI as `vector` to the cpu with the given APIC id.
+///Returns false, if no IOAPIC handles the GSI.
+pub fn route(gsi:u32, vector:u8, apic_id:u32, trigger:Trigger, polarity:Polarity)->bool{
+	let mut entry = vector as u64 | (apic_id as u64)<<56;
+	if trigger == Trigger::Level{
+		entry |= ENTRY_TRIGGER_LEVEL;
+	}
+	if polarity == Polarity::ActiveLow{
+		entry |= ENTRY_POLARITY_LOW;
+	}
+	with_ioapic(gsi, |ioapic, input|ioapic.write_entry(input, entry)).is_some()
+}
+
+///Routes an ISA IRQ, honouring the overrides from the MADT.
+pub fn route_isa_irq(irq:u8, vector:u8, apic_id:u32)->bool{
+	let (gsi, trigger, polarity) = isa_irq(irq);
+	route(gsi, vector, apic_id, trigger, polarity)
+}
+
+///Stops delivering the GSI.
+pub fn mask(gsi:u32){
+	with_ioapic(gsi, |ioapic, input|ioapic.write_entry(input, ENTRY_MASKED));
+}
+
+///Returns true, if the GSI exists and is currently delivered to a cpu.
+pub fn is_routed(gsi:u32)->bool{
+	with_ioapic(gsi, |ioapic, input|ioapic.read_entry(input) & ENTRY_MASKED == 0).unwrap_or(false)
+}
+
+fn with_ioapic<R>(gsi:u32, f:impl FnOnce(&IoApic, u32)->R)->Option<R>{
+	interrupts::without_interrupts(||{
+		let state = STATE.lock();
+		let ioapic = state.ioapics.iter().find(|i|i.handles(gsi))?;
+		Some(f(ioapic, gsi-ioapic.gsi_base))
+	})
+}
@@ -0,0 +1,63 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::{OffsetPageTable, PageTable, Translate};
+use x86_64::{PhysAddr, VirtAddr};
+
+pub const PAGE_SIZE:usize = 4096;
+
+///The virtual address, at which physical address 0 is mapped.
+///init sets it to the randomized direct map base, that the boot loader chose. With KASLR off, that is 0,
+///and the identity map of the boot loader is used.
+static PHYS_OFFSET:AtomicU64 = AtomicU64::new(0);
+///Where the virtual address range for the kernel heap starts.
+static HEAP_BASE:AtomicU64 = AtomicU64::new(kernel_efi::HEAP_ADDR);
+
+///Takes over the direct map and heap base, that the boot loader chose.
+pub fn init(bases:&kernel_efi::Bases){
+	PHYS_OFFSET.store(bases.direct_map, Ordering::Relaxed);
+	HEAP_BASE.store(bases.heap, Ordering::Relaxed);
+}
+
+pub fn heap_base()->VirtAddr{
+	VirtAddr::new(HEAP_BASE.load(Ordering::Relaxed))
+}
+
+///Returns the virtual address, through which the kernel can access the given physical address.
+pub fn phys_to_virt(addr:PhysAddr)->VirtAddr{
+	VirtAddr::new(addr.as_u64() + PHYS_OFFSET.load(Ordering::Relaxed))
+}
+
+///Returns a pointer, through which the kernel can access the given physical address.
+pub fn phys_to_ptr<T>(addr:u64)->*mut T{
+	phys_to_virt(PhysAddr::new(addr)).as_mut_ptr()
+}
+
+
+///Looks up the physical address, that the given virtual address is mapped to, in the current page tables.
+///Only 4 level paging is supported.
+pub fn virt_to_phys(addr:VirtAddr)->Option<PhysAddr>{
+	let (frame, _) = Cr3::read();
+	let offset = VirtAddr::new(PHYS_OFFSET.load(Ordering::Relaxed));
+	//Safety:
+	//The page tables are reachable through the physical memory mapping, and we only read them.
+	let table = unsafe{OffsetPageTable::new(&mut *phys_to_ptr::<PageTable>(frame.start_address().as_u64()), offset)};
+	table.translate_addr(addr)
+}
+
+///Splits a buffer into physically contiguous pieces, as (physical address, length) pairs.
+///Returns None, if part of the buffer isn't mapped.
+pub fn phys_segments(ptr:*const u8, len:usize)->Option<alloc::vec::Vec<(u64, usize)>>{
+	let mut segments:alloc::vec::Vec<(u64, usize)> = alloc::vec::Vec::new();
+	let mut addr = ptr as usize;
+	let end = addr+len;
+	while addr < end{
+		let chunk = (PAGE_SIZE-addr%PAGE_SIZE).min(end-addr);
+		let phys = virt_to_phys(VirtAddr::new(addr as u64))?.as_u64();
+		match segments.last_mut(){
+			Some((start, l)) if *start+*l as u64 == phys=>*l += chunk,
+			_=>segments.push((phys, chunk)),
+		}
+		addr += chunk;
+	}
+	Some(segments)
+}
\ No newline at end of file
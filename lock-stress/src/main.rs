@@ -0,0 +1,145 @@
+//Host side stress test for the kernel spinlocks.
+//kernel/src/lock.rs only depends on core, so it is compiled here as is, in the kernel's style of explicit returns.
+#[allow(dead_code, clippy::needless_return)]
+#[path = "../../kernel/src/lock.rs"]
+mod lock;
+
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Barrier};
+use std::time::{Duration, Instant};
+use lock::{Lock, RWLock, RWLockMode};
+
+const RUN_TIME:Duration = Duration::from_secs(2);
+const WRITES:u64 = 200;
+const WRITER_DEADLINE:Duration = Duration::from_secs(3);
+
+fn main() -> Result<(),String>{
+	let cpus = std::thread::available_parallelism().map(|x|x.get()).unwrap_or(1);
+	//Fairness only shows, if every thread spins on its own cpu.
+	//With fewer cpus the host scheduler decides who gets to draw a ticket, not the lock.
+	let threads = cpus.clamp(2,16);
+	println!("Running with {} threads on {} cpus", threads, cpus);
+	ticket_lock_fairness(threads, cpus >= threads)?;
+	rwlock_writer_preference(threads)?;
+	println!("All lock stress tests passed.");
+	Ok(())
+}
+
+///Every thread hammers the same lock for RUN_TIME.
+///With a FIFO lock every thread should get roughly the same amount of acquisitions.
+fn ticket_lock_fairness(threads:usize, check_fairness:bool) -> Result<(),String>{
+	let lock = Arc::new(Lock::new(0u64));
+	let stop = Arc::new(AtomicBool::new(false));
+	let barrier = Arc::new(Barrier::new(threads+1));
+	let handles:Vec<_> = (0..threads).map(|_|{
+		let lock = lock.clone();
+		let stop = stop.clone();
+		let barrier = barrier.clone();
+		std::thread::spawn(move ||{
+			let mut acquired = 0u64;
+			barrier.wait();
+			while !stop.load(Ordering::Relaxed){
+				let mut guard = lock.lock();
+				//do a non-atomic read-modify-write, so that lost updates show up as a wrong total.
+				let v = *guard;
+				std::hint::black_box(&v);
+				*guard = v + 1;
+				drop(guard);
+				acquired += 1;
+			}
+			acquired
+		})
+	}).collect();
+	barrier.wait();
+	std::thread::sleep(RUN_TIME);
+	stop.store(true, Ordering::Relaxed);
+	let counts:Vec<u64> = handles.into_iter().map(|h|h.join().unwrap()).collect();
+
+	let total:u64 = counts.iter().sum();
+	let min = *counts.iter().min().unwrap();
+	let max = *counts.iter().max().unwrap();
+	let ratio = min as f64 / max as f64;
+	println!("Lock: acquisitions per thread {:?}, min/max = {:.3}", counts, ratio);
+	if *lock.lock() != total{
+		return Err(format!("Lock lost updates: counter is {}, but {} acquisitions happened", *lock.lock(), total));
+	}
+	if !check_fairness{
+		println!("Lock: not enough cpus to judge fairness, only checked mutual exclusion.");
+	}else if ratio < 0.5{
+		return Err(format!("Lock is unfair: min/max acquisition ratio {:.3} is below 0.5", ratio));
+	}
+	Ok(())
+}
+
+///Readers keep the lock permanently read-locked between them.
+///A writer preferring lock must still let a writer through in bounded time.
+fn rwlock_writer_preference(threads:usize) -> Result<(),String>{
+	let readers = (threads-1).max(2);
+	for mode in [RWLockMode::ReaderPreferring, RWLockMode::WriterPreferring]{
+		let lock = Arc::new(RWLock::with_mode(0u64, mode));
+		let stop = Arc::new(AtomicBool::new(false));
+		let torn = Arc::new(AtomicU64::new(0));
+		let barrier = Arc::new(Barrier::new(readers+1));
+		let handles:Vec<_> = (0..readers).map(|_|{
+			let lock = lock.clone();
+			let stop = stop.clone();
+			let torn = torn.clone();
+			let barrier = barrier.clone();
+			std::thread::spawn(move ||{
+				barrier.wait();
+				while !stop.load(Ordering::Relaxed){
+					let guard = lock.read_lock();
+					//The writer only ever leaves even values behind.
+					if *guard % 2 != 0{
+						torn.fetch_add(1, Ordering::Relaxed);
+					}
+					//hold the lock for a bit, so that reader critical sections overlap.
+					for _ in 0..200{
+						std::hint::spin_loop();
+					}
+					drop(guard);
+				}
+			})
+		}).collect();
+		barrier.wait();
+
+		let start = Instant::now();
+		let mut writes = 0;
+		let mut worst = Duration::ZERO;
+		while writes < WRITES && start.elapsed() < WRITER_DEADLINE{
+			let wait = Instant::now();
+			//try first, so that the reader preferring lock can't block us past the deadline.
+			let guard = loop{
+				if let Some(g) = lock.try_write_lock(){
+					break Some(g);
+				}
+				if mode == RWLockMode::WriterPreferring{
+					break Some(lock.write_lock());
+				}
+				if start.elapsed() >= WRITER_DEADLINE{
+					break None;
+				}
+				std::hint::spin_loop();
+			};
+			let Some(mut guard) = guard else { break };
+			worst = worst.max(wait.elapsed());
+			*guard += 1;
+			std::hint::black_box(&*guard);
+			*guard += 1;
+			drop(guard);
+			writes += 1;
+		}
+		stop.store(true, Ordering::Relaxed);
+		for h in handles{
+			h.join().unwrap();
+		}
+		println!("RWLock {:?}: writer got {}/{} write locks in {:?}, worst wait {:?}", mode, writes, WRITES, start.elapsed(), worst);
+		if torn.load(Ordering::Relaxed) != 0{
+			return Err(format!("RWLock {:?}: readers observed a write in progress", mode));
+		}
+		if mode == RWLockMode::WriterPreferring && writes < WRITES{
+			return Err(format!("RWLock {:?}: writer was starved ({}/{} writes)", mode, writes, WRITES));
+		}
+	}
+	Ok(())
+}
@@ -1,4 +1,9 @@
 pub mod tables;
 pub mod mem;
 pub mod fs;
-pub mod gop;
\ No newline at end of file
+pub mod gop;
+pub mod boot_device;
+pub mod config;
+pub mod load_options;
+pub mod menu;
+pub mod kaslr;
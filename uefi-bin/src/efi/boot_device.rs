@@ -0,0 +1,51 @@
+//Finds out, which partition we were loaded from, so the kernel can find its ESP again.
+use uefi::Handle;
+use uefi::proto::device_path::{DevicePath, DeviceSubType, DeviceType};
+use uefi::proto::loaded_image::LoadedImage;
+use uefi::table::boot::{OpenProtocolAttributes, OpenProtocolParams};
+use kernel_efi::BootPartition;
+
+///Reads the hard drive node from the device path of the device, that the image was loaded from.
+///Returns BootPartition::UNKNOWN, if there is none, like when booting over the network.
+pub fn boot_partition(handle:Handle)->BootPartition{
+	let st = unsafe{uefi_services::system_table().as_ref()};
+	let bs = st.boot_services();
+	let params = |h|OpenProtocolParams{handle:h, agent:handle, controller:None};
+	//SAFETY:
+	// Both protocols are only read, and closed again before we return.
+	let device = match unsafe{bs.open_protocol::<LoadedImage>(params(handle), OpenProtocolAttributes::GetProtocol)}{
+		Ok(image)=>image.device(),
+		Err(e)=>{
+			log::warn!("Can't open the loaded image protocol: {:?}", e.status());
+			return BootPartition::UNKNOWN;
+		}
+	};
+	let path = match unsafe{bs.open_protocol::<DevicePath>(params(device), OpenProtocolAttributes::GetProtocol)}{
+		Ok(path)=>path,
+		Err(e)=>{
+			log::warn!("The boot device has no device path: {:?}", e.status());
+			return BootPartition::UNKNOWN;
+		}
+	};
+	for node in path.node_iter(){
+		if node.device_type()!=DeviceType::MEDIA || node.sub_type()!=DeviceSubType::MEDIA_HARD_DRIVE{
+			continue;
+		}
+		//partition number u32, start u64, size u64, signature [u8;16], partition format u8, signature type u8
+		let data = node.data();
+		if data.len()<38{
+			break;
+		}
+		let mut signature = [0;16];
+		signature.copy_from_slice(&data[20..36]);
+		let boot = BootPartition{
+			signature_type:data[37],
+			signature,
+			partition_number:u32::from_le_bytes([data[0],data[1],data[2],data[3]]),
+		};
+		log::info!("Booted from partition {} (signature type {})", boot.partition_number, boot.signature_type);
+		return boot;
+	}
+	log::warn!("The boot device isn't a hard drive partition.");
+	BootPartition::UNKNOWN
+}
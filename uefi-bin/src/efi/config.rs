@@ -0,0 +1,369 @@
+//The boot configuration in boot.cfg on the ESP.
+//Every line is `key = value`. Values may be quoted like in TOML, `#` starts a comment, and empty lines are ignored.
+//Keys, that are missing, keep their defaults. Lines with errors are reported and skipped.
+//`[[entry]]` starts a boot entry for the menu. An entry starts out with the kernel, initrd and cmdline, that were set above it.
+//`timeout` (seconds) and `default` (a name, or a position starting at 0) control the menu.
+//`kaslr` turns the randomized kernel base on or off, and `kaslr_window = "start-end"` sets the range of addresses for the kernel.
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::{Display, Formatter};
+use log::LevelFilter;
+use kernel_efi::{CMDLINE_MAX, Window};
+
+pub const CONFIG_NAME:&str="boot.cfg";
+
+///Seconds, that the menu waits, if boot.cfg has entries, but no timeout.
+const DEFAULT_TIMEOUT:u32=5;
+///The lowest canonical address with bit 47 set.
+const HIGHER_HALF:u64=0xFFFF_8000_0000_0000;
+
+///A kernel, that can be booted.
+#[derive(Debug, Clone)]
+pub struct BootEntry{
+	///The name in the menu.
+	pub name:String,
+	///Path of the kernel ELF file.
+	pub kernel:String,
+	///Path of the initial ramdisk. None disables it (`initrd = ""`).
+	pub initrd:Option<String>,
+	///The command line for the kernel.
+	pub cmdline:String,
+}
+
+impl Default for BootEntry{
+	fn default()->Self{
+		Self{
+			name:String::new(),
+			kernel:String::from(super::fs::KERNEL_NAME),
+			initrd:Some(String::from(super::fs::INITRD_NAME)),
+			cmdline:String::new(),
+		}
+	}
+}
+
+#[derive(Debug, Clone)]
+pub struct BootConfig{
+	///Path of the font file for the kernel console.
+	pub font:String,
+	///The face to use, if the font is a collection.
+	pub font_index:u32,
+	///Preferred resolution as (width, height). None picks the best mode.
+	pub resolution:Option<(usize,usize)>,
+	///Log level of the boot loader.
+	pub log_level:LevelFilter,
+	///Seconds, until the menu boots the default entry. 0 boots it right away.
+	pub timeout:u32,
+	///Index of the default entry.
+	pub default:usize,
+	///Randomize the bases of the kernel, its heap and the direct map.
+	pub kaslr:bool,
+	///The addresses, that the kernel base is picked from.
+	pub kaslr_window:Window,
+	///There is always at least one.
+	pub entries:Vec<BootEntry>,
+}
+
+impl Default for BootConfig{
+	fn default()->Self{
+		Self{
+			font:String::from(super::fs::FONT_NAME),
+			font_index:0,
+			resolution:None,
+			log_level:LevelFilter::Info,
+			timeout:0,
+			default:0,
+			kaslr:true,
+			kaslr_window:kernel_efi::KERNEL_WINDOW,
+			entries:alloc::vec![BootEntry::finish(BootEntry::default())],
+		}
+	}
+}
+
+#[derive(Debug)]
+pub struct ConfigError{
+	pub line:usize,
+	pub kind:ErrorKind,
+}
+
+#[derive(Debug)]
+pub enum ErrorKind{
+	MissingEquals,
+	UnknownKey(String),
+	UnterminatedString,
+	TrailingCharacters,
+	InvalidNumber(String),
+	InvalidResolution(String),
+	InvalidLogLevel(String),
+	InvalidBool(String),
+	InvalidWindow(String),
+	EmptyPath(&'static str),
+	UnknownSection(String),
+	OnlyInEntry(String),
+	NotInEntry(String),
+	UnknownEntry(String),
+}
+
+impl Display for ConfigError{
+	fn fmt(&self, f:&mut Formatter<'_>)->core::fmt::Result{
+		write!(f,"{}:{}: ",CONFIG_NAME,self.line)?;
+		match &self.kind{
+			ErrorKind::MissingEquals=>write!(f,"expected `key = value`"),
+			ErrorKind::UnknownKey(k)=>write!(f,"unknown key `{}`",k),
+			ErrorKind::UnterminatedString=>write!(f,"missing closing quote"),
+			ErrorKind::TrailingCharacters=>write!(f,"unexpected characters after the closing quote"),
+			ErrorKind::InvalidNumber(v)=>write!(f,"`{}` is not a number",v),
+			ErrorKind::InvalidResolution(v)=>write!(f,"`{}` is not a resolution like 1024x768",v),
+			ErrorKind::InvalidLogLevel(v)=>write!(f,"`{}` is not one of off, error, warn, info, debug, trace",v),
+			ErrorKind::InvalidBool(v)=>write!(f,"`{}` is not one of on, off, true, false, yes, no",v),
+			ErrorKind::InvalidWindow(v)=>write!(f,"`{}` is not a higher half address range outside of the heap and direct map, like 0xffffffff80000000-0xfffffffff0000000",v),
+			ErrorKind::EmptyPath(k)=>write!(f,"`{}` needs a path",k),
+			ErrorKind::UnknownSection(s)=>write!(f,"unknown section `{}`, only [[entry]] is supported",s),
+			ErrorKind::OnlyInEntry(k)=>write!(f,"`{}` can only be set in an [[entry]]",k),
+			ErrorKind::NotInEntry(k)=>write!(f,"`{}` can't be set in an [[entry]], put it above the first one",k),
+			ErrorKind::UnknownEntry(e)=>write!(f,"there is no entry `{}` to use as default",e),
+		}
+	}
+}
+
+///Removes a comment, that isn't inside of a string.
+fn strip_comment(line:&str)->&str{
+	let mut quoted=false;
+	let mut chars=line.char_indices();
+	while let Some((i,c))=chars.next(){
+		match c{
+			'"'=>quoted = !quoted,
+			'\\' if quoted=>{
+				chars.next();
+			},
+			'#' if !quoted=>return &line[..i],
+			_=>(),
+		}
+	}
+	line
+}
+
+///Takes the quotes off a value. Inside of quotes `\"` and `\\` are escapes.
+fn unquote(value:&str)->Result<String,ErrorKind>{
+	let Some(rest)=value.strip_prefix('"') else{
+		return Ok(String::from(value));
+	};
+	let mut out=String::new();
+	let mut chars=rest.chars();
+	while let Some(c)=chars.next(){
+		match c{
+			'"'=>return if chars.as_str().trim().is_empty() {Ok(out)} else {Err(ErrorKind::TrailingCharacters)},
+			'\\'=>match chars.next(){
+				Some(c)=>out.push(c),
+				None=>break,
+			},
+			c=>out.push(c),
+		}
+	}
+	Err(ErrorKind::UnterminatedString)
+}
+
+///UEFI paths use backslashes.
+fn path(key:&'static str, value:&str)->Result<String,ErrorKind>{
+	if value.is_empty(){
+		return Err(ErrorKind::EmptyPath(key));
+	}
+	Ok(value.replace('/',"\\"))
+}
+
+fn number(value:&str)->Result<u32,ErrorKind>{
+	value.parse().map_err(|_|ErrorKind::InvalidNumber(String::from(value)))
+}
+
+fn resolution(value:&str)->Result<Option<(usize,usize)>,ErrorKind>{
+	if value.is_empty() || value=="auto"{
+		return Ok(None);
+	}
+	value.split_once(['x','X'])
+		.and_then(|(w,h)|Some((w.trim().parse().ok()?,h.trim().parse().ok()?)))
+		.filter(|&(w,h)|w>0 && h>0)
+		.map(Some)
+		.ok_or_else(||ErrorKind::InvalidResolution(String::from(value)))
+}
+
+fn log_level(value:&str)->Result<LevelFilter,ErrorKind>{
+	value.parse().map_err(|_|ErrorKind::InvalidLogLevel(String::from(value)))
+}
+
+fn boolean(value:&str)->Result<bool,ErrorKind>{
+	match value{
+		"on"|"true"|"yes"|"1"=>Ok(true),
+		"off"|"false"|"no"|"0"=>Ok(false),
+		_=>Err(ErrorKind::InvalidBool(String::from(value))),
+	}
+}
+
+///An address, either hexadecimal with 0x, or decimal.
+fn address(value:&str)->Option<u64>{
+	let value=value.trim();
+	match value.strip_prefix("0x").or_else(||value.strip_prefix("0X")){
+		Some(hex)=>u64::from_str_radix(&hex.replace('_',""),16).ok(),
+		None=>value.parse().ok(),
+	}
+}
+
+///A window like `start-end`. The alignment stays the one of the default window.
+///It has to be in the higher half, and can't overlap the heap and direct map windows.
+fn window(value:&str)->Result<Window,ErrorKind>{
+	let overlaps=|w:&Window,other:&Window|w.start<other.end && other.start<w.end;
+	value.split_once('-')
+		.and_then(|(start,end)|Some(Window{start:address(start)?,end:address(end)?,align:kernel_efi::KERNEL_WINDOW.align}))
+		.filter(|w|w.start<w.end && w.start>=HIGHER_HALF)
+		.filter(|w|!overlaps(w,&kernel_efi::HEAP_WINDOW) && !overlaps(w,&kernel_efi::DIRECT_MAP_WINDOW))
+		.ok_or_else(||ErrorKind::InvalidWindow(String::from(value)))
+}
+
+impl BootEntry{
+	fn finish(mut self)->Self{
+		if self.name.is_empty(){
+			self.name=self.kernel.clone();
+		}
+		self
+	}
+
+	fn set(&mut self, key:&str, value:&str)->Result<bool,ErrorKind>{
+		match key{
+			"kernel"=>self.kernel=path("kernel",value)?,
+			"initrd"=>self.initrd=if value.is_empty() {None} else {Some(path("initrd",value)?)},
+			"cmdline"=>self.cmdline=String::from(value),
+			_=>return Ok(false),
+		}
+		Ok(true)
+	}
+
+	///The command line, as it is passed to the kernel. Too long command lines are cut off at a character boundary.
+	pub fn cmdline_bytes(&self)->([u8;CMDLINE_MAX],usize){
+		let mut len=self.cmdline.len();
+		if len>CMDLINE_MAX{
+			log::warn!("The kernel command line is longer than {} bytes, and gets cut off.",CMDLINE_MAX);
+			len=CMDLINE_MAX;
+			while !self.cmdline.is_char_boundary(len){
+				len-=1;
+			}
+		}
+		let mut bytes=[0;CMDLINE_MAX];
+		bytes[..len].copy_from_slice(&self.cmdline.as_bytes()[..len]);
+		(bytes,len)
+	}
+}
+
+///Keys, that only exist outside of entries.
+const GLOBAL_KEYS:[&str;8]=["font","font_index","resolution","log_level","timeout","default","kaslr","kaslr_window"];
+
+///The state, while a config is parsed.
+struct Parser{
+	config:BootConfig,
+	///kernel, initrd and cmdline above the first entry.
+	global:BootEntry,
+	entries:Vec<BootEntry>,
+	timeout:Option<u32>,
+	///The value and line of `default`.
+	default:Option<(String,usize)>,
+	///The line, that is parsed right now.
+	line:usize,
+}
+
+impl Parser{
+	fn parse_line(&mut self, line:&str)->Result<(),ErrorKind>{
+		if let Some(section)=line.strip_prefix('['){
+			return match section{
+				"[entry]]"|"entry]"=>{
+					self.entries.push(BootEntry{name:String::new(),..self.global.clone()});
+					Ok(())
+				},
+				_=>Err(ErrorKind::UnknownSection(String::from(line))),
+			};
+		}
+		let (key,value)=line.split_once('=').ok_or(ErrorKind::MissingEquals)?;
+		let key=key.trim();
+		let value=unquote(value.trim())?;
+		let value=value.as_str();
+		if let Some(entry)=self.entries.last_mut(){
+			return match key{
+				"name"=>{
+					entry.name=String::from(value);
+					Ok(())
+				},
+				_=>match entry.set(key,value)?{
+					true=>Ok(()),
+					false if GLOBAL_KEYS.contains(&key)=>Err(ErrorKind::NotInEntry(String::from(key))),
+					false=>Err(ErrorKind::UnknownKey(String::from(key))),
+				},
+			};
+		}
+		if self.global.set(key,value)?{
+			return Ok(());
+		}
+		let config=&mut self.config;
+		match key{
+			"font"=>config.font=path("font",value)?,
+			"font_index"=>config.font_index=number(value)?,
+			"resolution"=>config.resolution=resolution(value)?,
+			"log_level"=>config.log_level=log_level(value)?,
+			"timeout"=>self.timeout=Some(number(value)?),
+			"default"=>self.default=Some((String::from(value),self.line)),
+			"kaslr"=>config.kaslr=boolean(value)?,
+			"kaslr_window"=>config.kaslr_window=window(value)?,
+			"name"=>return Err(ErrorKind::OnlyInEntry(String::from(key))),
+			key=>return Err(ErrorKind::UnknownKey(String::from(key))),
+		}
+		Ok(())
+	}
+}
+
+impl BootConfig{
+	///Parses a config. Lines with errors are skipped, and their errors passed to `error`.
+	pub fn parse(text:&str, mut error:impl FnMut(ConfigError))->Self{
+		let mut parser=Parser{config:Self::default(),global:BootEntry::default(),entries:Vec::new(),timeout:None,default:None,line:0};
+		for (i,line) in text.lines().enumerate(){
+			let line=strip_comment(line).trim();
+			if line.is_empty(){
+				continue;
+			}
+			parser.line=i+1;
+			if let Err(kind)=parser.parse_line(line){
+				error(ConfigError{line:i+1,kind});
+			}
+		}
+		let Parser{mut config,global,entries,timeout,default,..}=parser;
+		config.timeout=timeout.unwrap_or(if entries.is_empty() {0} else {DEFAULT_TIMEOUT});
+		config.entries=if entries.is_empty() {alloc::vec![global]} else {entries};
+		config.entries=config.entries.into_iter().map(BootEntry::finish).collect();
+		//The default is an entry name, or the position of the entry, starting at 0.
+		if let Some((name,line))=default{
+			let found=config.entries.iter().position(|e|e.name==name)
+				.or_else(||name.parse().ok().filter(|&i|i<config.entries.len()));
+			match found{
+				Some(i)=>config.default=i,
+				None=>error(ConfigError{line,kind:ErrorKind::UnknownEntry(name)}),
+			}
+		}
+		config
+	}
+
+	///Loads boot.cfg from the ESP. Without one, the defaults are used.
+	pub fn load()->Self{
+		let file=match super::fs::load_file(CONFIG_NAME){
+			Ok(file)=>file,
+			Err(e)=>{
+				log::info!("No {} ({:?}), using the defaults.",CONFIG_NAME,e.status());
+				return Self::default();
+			}
+		};
+		let config=match core::str::from_utf8(file){
+			Ok(text)=>Self::parse(text,|e|log::error!("{}",e)),
+			Err(e)=>{
+				log::error!("{} isn't valid UTF-8 (at byte {}), using the defaults.",CONFIG_NAME,e.valid_up_to());
+				Self::default()
+			}
+		};
+		if let Err(e)=super::fs::free_file(file){
+			log::warn!("Can't free the memory of {}: {:?}",CONFIG_NAME,e.status());
+		}
+		config
+	}
+}
@@ -9,8 +9,8 @@ use uefi::Identify;
 pub mod elf;
 
 pub const KERNEL_NAME:&str="kernel";
-#[allow(non_upper_case_globals)]
-const KiB4:usize=0xFFF;
+pub const FONT_NAME:&str="font.ttf";
+const PAGE_SIZE:usize=4096;
 
 pub fn get_file(name:&str) ->uefi::Result<RegularFile> {
 	let st = unsafe { uefi_services::system_table().as_ref() };
@@ -42,31 +42,62 @@ pub fn get_file(name:&str) ->uefi::Result<RegularFile> {
 	}
 	Err(Status::NO_MEDIA.into())
 }
+///The amount of pages, that load_file allocates for a file of `size` bytes.
+fn file_pages(size:usize)->usize{
+	size.div_ceil(PAGE_SIZE).max(1)
+}
+
 //The static lifetime is fine. We
 //alloc mem, but we NEVER dealloc it here.
-//the caller has to deallocate, if applicable
+//the caller has to deallocate with free_file, if applicable
 pub fn load_file(name:&str)->uefi::Result<&'static [u8]>{
 	let mut file = get_file(name)?;
 	//get file size
 	let info=file.get_boxed_info::<FileInfo>()?;
-	let file_size=info.file_size();
-	//alloc the amount of pages needed. (maybe a little more, if the data is EXACTLY a multiple of 4KiB big)
-	let pages=file_size>>12 + 1;
-	let mem=unsafe{uefi_services::system_table().as_ref()}.boot_services().allocate_pages(AllocateType::AnyPages,MemoryType::LOADER_DATA,pages as usize)?;
+	let file_size=info.file_size() as usize;
+	let pages=file_pages(file_size);
+	let mem=unsafe{uefi_services::system_table().as_ref()}.boot_services().allocate_pages(AllocateType::AnyPages,MemoryType::LOADER_DATA,pages)?;
 	//init contents
-	unsafe{core::ptr::write_bytes(mem as *mut u8,0,(pages as usize)*KiB4)};
+	unsafe{core::ptr::write_bytes(mem as *mut u8,0,pages*PAGE_SIZE)};
 	//now read as many times, as we need to.
 	let mut size=0;
-	log::debug!("Prepared everything for reading Kernel ELF File");
+	log::debug!("Prepared everything for reading {}",name);
 	while size<file_size{//we have not yet read enough bytes. try to read more.
 		//we need to adjust the buffer everytime we read, since we read everytime at a different file offset.
-		let buf=unsafe{core::slice::from_raw_parts_mut((mem+size) as *mut u8,(pages*KiB4 as u64-size) as usize)};
+		let buf=unsafe{core::slice::from_raw_parts_mut((mem as usize+size) as *mut u8,file_size-size)};
 		//actually read
 		let size_n =file.read(buf).map_err(|x|Error::new(x.status(),()))?;
+		if size_n==0{
+			//The file got shorter, than FileInfo said.
+			return Err(Status::END_OF_FILE.into());
+		}
 		//update size, so we eventually terminate
-		size+=size_n as u64;
+		size+=size_n;
 	}
 	//Construct the memory view, of the fully loaded file
-	let mem_buf = unsafe{core::slice::from_raw_parts(mem as *mut u8,pages as usize*KiB4)};
+	let mem_buf = unsafe{core::slice::from_raw_parts(mem as *mut u8,file_size)};
 	Ok(mem_buf)
-}
\ No newline at end of file
+}
+
+///Gives the memory of a file from load_file back to the firmware.
+pub fn free_file(file:&'static [u8])->uefi::Result{
+	let st = unsafe{uefi_services::system_table().as_ref()};
+	st.boot_services().free_pages(file.as_ptr() as u64,file_pages(file.len()))
+}
+
+///The name of the initial ramdisk on the ESP.
+pub const INITRD_NAME:&str="initrd";
+
+///Loads the initial ramdisk, if there is one.
+pub fn load_initrd(name:&str)->Option<&'static [u8]>{
+	match load_file(name){
+		Ok(initrd)=>{
+			log::info!("Loaded the initrd {} ({} bytes)",name,initrd.len());
+			Some(initrd)
+		},
+		Err(e)=>{
+			log::info!("No initrd {}: {:?}",name,e.status());
+			None
+		}
+	}
+}
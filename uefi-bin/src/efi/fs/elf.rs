@@ -1,79 +1,328 @@
+use alloc::vec::Vec;
+use core::fmt::{Display, Formatter};
 use elf_rs::{Elf, ElfFile, ProgramHeaderFlags};
+use uefi::Status;
 use uefi::table::boot::{AllocateType, MemoryType};
 use x86_64::{align_down, align_up};
 use x86_64::structures::paging::page_table::PageTableEntry;
 use x86_64::structures::paging::PageTableFlags;
 use x64::paging::PageWalker;
 use x64::paging::traits::Level4;
-use kernel_efi::MapElfRet;
-use uefi::Result;
-
-///maps a elf file to memory. Returns a base address, and the offset to the entry_point.
-///Page table
-pub fn map_elf(file:&[u8],addr:*mut u8)->Result<MapElfRet>{
-	let elf=Elf::from_bytes(file).map_err(|_|uefi::Status::COMPROMISED_DATA)?;
-	let mut i=0;
-	let mut size=0u64;
-	//this gets the totally needed size
-	loop{
-		if let Some(ph)=elf.program_header_nth(i){
-			if ph.ph_type()==elf_rs::ProgramType::LOAD{
-				let align=ph.align();
-				let base_addr=x86_64::align_down(ph.vaddr(),align);//This should be the base addr
-				size+=x86_64::align_up(base_addr,align);//This should be the whole memory region, that needs to be mapped.
-			}
-		}else{
-			break;
+use kernel_efi::{MapElfRet, Symbols};
+
+mod reloc;
+
+const PAGE_SIZE:u64=4096;
+///The most, that get_phy_pg can map, which is one entry of the level 4 table.
+const MAX_IMAGE_SIZE:u64=512<<30;
+
+//Offsets and values in the ELF header.
+const EHDR_SIZE:usize=64;
+const ELF_MAGIC:&[u8;4]=b"\x7fELF";
+const EI_CLASS:usize=4;
+const ELFCLASS64:u8=2;
+const EI_DATA:usize=5;
+const ELFDATA2LSB:u8=1;
+const E_TYPE:usize=16;
+const ET_EXEC:u16=2;
+const ET_DYN:u16=3;
+const E_MACHINE:usize=18;
+const EM_X86_64:u16=62;
+const E_PHOFF:usize=32;
+const E_PHENTSIZE:usize=54;
+const E_PHNUM:usize=56;
+const PHDR_SIZE:u16=56;
+const PT_GNU_EH_FRAME:u32=0x6474_E550;
+///The size of an entry in the symbol table.
+const SYM_SIZE:u64=24;
+
+#[derive(Debug)]
+pub enum ElfError{
+	///The file has no ELF magic, or elf_rs couldn't make sense of it.
+	Malformed,
+	NotElf64,
+	NotLittleEndian,
+	WrongMachine(u16),
+	WrongType(u16),
+	///The program header table isn't inside of the file, or has entries of the wrong size.
+	BadProgramHeaders,
+	NoLoadSegments,
+	///The segment with this index has data outside of the file.
+	SegmentOutsideFile(usize),
+	FileSizeAboveMemSize(usize),
+	BadAlignment(usize),
+	///The end address of the segment doesn't fit in 64 bits.
+	SegmentTooLarge(usize),
+	///The segment shares pages with the one before it.
+	SegmentsOverlap(usize),
+	ImageTooLarge(u64),
+	///The entry point isn't inside of an executable segment.
+	EntryOutsideCode(u64),
+	OutOfMemory(Status),
+	MapFailed,
+	///The kernel needs shared libraries.
+	NotStatic,
+	BadDynamic(&'static str),
+	UnsupportedRelocation(u32),
+	UndefinedSymbol(u32),
+	///A relocation or the dynamic section points outside of the image.
+	OutsideImage(u64),
+}
+
+impl ElfError{
+	pub fn status(&self)->Status{
+		match self{
+			ElfError::NotElf64|ElfError::NotLittleEndian|ElfError::WrongMachine(_)|ElfError::WrongType(_)=>Status::UNSUPPORTED,
+			ElfError::OutOfMemory(s)=>*s,
+			ElfError::MapFailed=>Status::OUT_OF_RESOURCES,
+			ElfError::NotStatic|ElfError::UnsupportedRelocation(_)=>Status::UNSUPPORTED,
+			_=>Status::LOAD_ERROR,
 		}
-		i+=1;
 	}
-	let page_num=(size>>12) as usize;
-	let mem=unsafe{uefi_services::system_table().as_ref()}.boot_services().allocate_pages(AllocateType::AnyPages,MemoryType::LOADER_DATA,page_num)?;
-	let mem=mem as *mut u8;
-	{//copy data
-		let file = file as *const [u8] as *const u8;
-		i=0;
-		loop{
-			if let Some(ph)=elf.program_header_nth(i){
-				if ph.ph_type()==elf_rs::ProgramType::LOAD{
-					let membase = unsafe{mem.add(ph.vaddr() as usize)};
-					unsafe{core::ptr::copy(file.add(ph.offset() as usize),membase,ph.filesz() as usize)}
-					let diff=ph.memsz()-ph.filesz();
-					if diff>0{
-						unsafe {core::ptr::write_bytes(mem.add(ph.filesz() as usize),0,diff as usize)};
-					}
-				}
-			}else{
-				break;
-			}
-			i+=1;
+}
+
+impl Display for ElfError{
+	fn fmt(&self, f:&mut Formatter<'_>)->core::fmt::Result{
+		match self{
+			ElfError::Malformed=>write!(f,"not an ELF file"),
+			ElfError::NotElf64=>write!(f,"not a 64 bit ELF file"),
+			ElfError::NotLittleEndian=>write!(f,"not a little endian ELF file"),
+			ElfError::WrongMachine(m)=>write!(f,"built for machine {}, not x86_64",m),
+			ElfError::WrongType(t)=>write!(f,"ELF type {} is neither an executable, nor position independent",t),
+			ElfError::BadProgramHeaders=>write!(f,"the program header table is broken"),
+			ElfError::NoLoadSegments=>write!(f,"there is nothing to load"),
+			ElfError::SegmentOutsideFile(i)=>write!(f,"segment {} ends after the end of the file",i),
+			ElfError::FileSizeAboveMemSize(i)=>write!(f,"segment {} has more bytes in the file, than in memory",i),
+			ElfError::BadAlignment(i)=>write!(f,"segment {} has an invalid alignment",i),
+			ElfError::SegmentTooLarge(i)=>write!(f,"segment {} reaches past the end of the address space",i),
+			ElfError::SegmentsOverlap(i)=>write!(f,"segment {} overlaps with the one before it",i),
+			ElfError::ImageTooLarge(size)=>write!(f,"the image needs {:#x} bytes, but at most {:#x} are possible",size,MAX_IMAGE_SIZE),
+			ElfError::EntryOutsideCode(e)=>write!(f,"the entry point {:#x} isn't in an executable segment",e),
+			ElfError::OutOfMemory(s)=>write!(f,"can't allocate memory for the image: {:?}",s),
+			ElfError::MapFailed=>write!(f,"can't map the image"),
+			ElfError::NotStatic=>write!(f,"it needs shared libraries"),
+			ElfError::BadDynamic(why)=>write!(f,"the dynamic section is broken: {}",why),
+			ElfError::UnsupportedRelocation(kind)=>write!(f,"relocation type {} isn't supported",kind),
+			ElfError::UndefinedSymbol(i)=>write!(f,"a relocation needs the undefined symbol {}",i),
+			ElfError::OutsideImage(vaddr)=>write!(f,"{:#x} is outside of the image",vaddr),
 		}
 	}
-	
-	set_pt_attr(&elf,mem,addr);
-	let entry_point=elf.entry_point() as usize + addr as usize;
-	Ok(MapElfRet{base:mem,pages:page_num,entry_point})
-}
-
-#[inline]
-fn set_pt_attr(elf:&Elf,mem:*mut u8,addr:*mut u8){
-	let mut i=0;
-	loop{
-		if let Some(ph)=elf.program_header_nth(i){
-			if ph.ph_type()==elf_rs::ProgramType::LOAD{
-				let align=ph.align();
-				let membase = align_down(mem as u64 + ph.vaddr(),align);
-				let memmax = align_up(mem as u64 + ph.vaddr() + ph.memsz(),align);
-				let addrbase = align_down(addr as u64 +ph.vaddr(),align);
-				let addrmax = align_up(addr as u64 + ph.vaddr() + ph.memsz(),align);
-				
-				get_phy_pg(membase,memmax,addrbase,addrmax,align,ph.flags()).unwrap();
-			}
-		}else{
-			break;
+}
+
+///A LOAD segment, that was checked against the file.
+struct Segment{
+	index:usize,
+	offset:u64,
+	vaddr:u64,
+	filesz:u64,
+	memsz:u64,
+	flags:ProgramHeaderFlags,
+}
+
+impl Segment{
+	fn end(&self)->u64{
+		self.vaddr+self.memsz
+	}
+}
+
+fn read_u16(file:&[u8], at:usize)->u16{
+	u16::from_le_bytes([file[at],file[at+1]])
+}
+
+///Checks the ELF header, and that the program header table is inside of the file.
+///Returns the type.
+fn check_header(file:&[u8])->Result<u16,ElfError>{
+	if file.len()<EHDR_SIZE || !file.starts_with(ELF_MAGIC){
+		return Err(ElfError::Malformed);
+	}
+	if file[EI_CLASS]!=ELFCLASS64{
+		return Err(ElfError::NotElf64);
+	}
+	if file[EI_DATA]!=ELFDATA2LSB{
+		return Err(ElfError::NotLittleEndian);
+	}
+	match read_u16(file,E_MACHINE){
+		EM_X86_64=>(),
+		m=>return Err(ElfError::WrongMachine(m)),
+	}
+	let elf_type=match read_u16(file,E_TYPE){
+		t@(ET_EXEC|ET_DYN)=>t,
+		t=>return Err(ElfError::WrongType(t)),
+	};
+	let phoff=u64::from_le_bytes(file[E_PHOFF..E_PHOFF+8].try_into().unwrap());
+	let phnum=read_u16(file,E_PHNUM) as u64;
+	if phnum!=0 && read_u16(file,E_PHENTSIZE)!=PHDR_SIZE{
+		return Err(ElfError::BadProgramHeaders);
+	}
+	match phoff.checked_add(phnum*PHDR_SIZE as u64){
+		Some(end) if end<=file.len() as u64=>Ok(elf_type),
+		_=>Err(ElfError::BadProgramHeaders),
+	}
+}
+
+///Collects the LOAD segments, that take up memory, sorted by address, and checks them.
+fn load_segments(elf:&Elf, file_len:u64)->Result<Vec<Segment>,ElfError>{
+	let mut segments=Vec::new();
+	for (index,ph) in (0..).map_while(|i|elf.program_header_nth(i)).enumerate(){
+		if ph.ph_type()!=elf_rs::ProgramType::LOAD || ph.memsz()==0{
+			continue;
+		}
+		let s=Segment{index,offset:ph.offset(),vaddr:ph.vaddr(),filesz:ph.filesz(),memsz:ph.memsz(),flags:ph.flags()};
+		if s.filesz>s.memsz{
+			return Err(ElfError::FileSizeAboveMemSize(index));
+		}
+		if s.offset.checked_add(s.filesz).is_none_or(|end|end>file_len){
+			return Err(ElfError::SegmentOutsideFile(index));
+		}
+		let align=ph.align();
+		if align>1 && (!align.is_power_of_two() || s.vaddr%align!=s.offset%align){
+			return Err(ElfError::BadAlignment(index));
 		}
-		i+=1;
+		if s.vaddr.checked_add(s.memsz).and_then(|end|end.checked_add(PAGE_SIZE)).is_none(){
+			return Err(ElfError::SegmentTooLarge(index));
+		}
+		segments.push(s);
+	}
+	segments.sort_unstable_by_key(|s|s.vaddr);
+	//Pages get the flags of their segment, so segments can't share pages.
+	for pair in segments.windows(2){
+		if align_up(pair[0].end(),PAGE_SIZE)>align_down(pair[1].vaddr,PAGE_SIZE){
+			return Err(ElfError::SegmentsOverlap(pair[1].index));
+		}
+	}
+	Ok(segments)
+}
+
+///The page aligned span of the image, from the lowest to the highest address.
+fn span(segments:&[Segment])->Result<(u64,u64),ElfError>{
+	let (Some(first),Some(last_end))=(segments.first(),segments.iter().map(Segment::end).max()) else{
+		return Err(ElfError::NoLoadSegments);
+	};
+	Ok((align_down(first.vaddr,PAGE_SIZE),align_up(last_end,PAGE_SIZE)))
+}
+
+///How many bytes from its base a position independent kernel takes up, so a base can be picked for it.
+///None for other kernels, which have to be loaded at the addresses they were linked for.
+pub fn relocatable_size(file:&[u8])->Result<Option<u64>,ElfError>{
+	if check_header(file)?!=ET_DYN{
+		return Ok(None);
+	}
+	let elf=Elf::from_bytes(file).map_err(|_|ElfError::Malformed)?;
+	let (_,max)=span(&load_segments(&elf,file.len() as u64)?)?;
+	Ok(Some(max))
+}
+
+///Maps an ELF file to memory at `addr`. The virtual addresses of the segments are taken as offsets from `addr`.
+///A position independent kernel (a static PIE) is also relocated for `addr`. Other kernels are not, so `addr` has to be the one they were built for.
+///Returns the physical base address and size of the image, its virtual start, and the entry point.
+pub fn map_elf(file:&[u8],addr:*mut u8)->Result<MapElfRet,ElfError>{
+	let base=addr as u64;
+	let is_pie=check_header(file)?==ET_DYN;
+	let elf=Elf::from_bytes(file).map_err(|_|ElfError::Malformed)?;
+	let segments=load_segments(&elf,file.len() as u64)?;
+	let (min,max)=span(&segments)?;
+	let size=max-min;
+	if size>MAX_IMAGE_SIZE || base.checked_add(max).is_none(){
+		return Err(ElfError::ImageTooLarge(size));
+	}
+	let entry=elf.entry_point();
+	if !segments.iter().any(|s|s.flags.contains(ProgramHeaderFlags::EXECUTE) && (s.vaddr..s.end()).contains(&entry)){
+		return Err(ElfError::EntryOutsideCode(entry));
+	}
+	let page_num=(size/PAGE_SIZE) as usize;
+	let mem=unsafe{uefi_services::system_table().as_ref()}.boot_services()
+		.allocate_pages(AllocateType::AnyPages,MemoryType::LOADER_DATA,page_num)
+		.map_err(|e|ElfError::OutOfMemory(e.status()))?;
+	let mem=mem as *mut u8;
+	//Zeroing everything covers the BSS at the end of the segments, and the gaps between them.
+	//SAFETY:
+	// We just allocated page_num pages at mem.
+	unsafe{core::ptr::write_bytes(mem,0,size as usize)};
+	for s in &segments{
+		//SAFETY:
+		// load_segments checked, that the data is inside of the file, and min..max contains all segments.
+		unsafe{core::ptr::copy_nonoverlapping(file.as_ptr().add(s.offset as usize),mem.add((s.vaddr-min) as usize),s.filesz as usize)};
+	}
+	//The relocations are applied, while the image is still where we loaded it.
+	let dynamic=(0..).map_while(|i|elf.program_header_nth(i)).find(|ph|ph.ph_type()==elf_rs::ProgramType::DYNAMIC);
+	if let (true,Some(dynamic))=(is_pie,dynamic){
+		//SAFETY:
+		// The image is size bytes at mem, and nothing else refers to it yet.
+		let data=unsafe{core::slice::from_raw_parts_mut(mem,size as usize)};
+		let count=reloc::relocate(&mut reloc::Image{data,min,base},dynamic.vaddr(),dynamic.memsz())?;
+		log::info!("Applied {} relocations for the base {:#x}",count,base);
 	}
+	set_pt_attr(&segments,mem,min,base)?;
+	Ok(MapElfRet{base:mem,pages:page_num,entry_point:(base+entry) as usize,start:base+min})
+}
+
+///Moves the pages of the segments from where they were loaded (`mem` is at address `min`) to `base`, and sets their flags.
+fn set_pt_attr(segments:&[Segment],mem:*mut u8,min:u64,base:u64)->Result<(),ElfError>{
+	for s in segments{
+		let start=align_down(s.vaddr,PAGE_SIZE);
+		let end=align_up(s.end(),PAGE_SIZE);
+		let membase=mem as u64+(start-min);
+		let addrbase=base+start;
+		get_phy_pg(membase,membase+(end-start),addrbase,addrbase+(end-start),PAGE_SIZE,s.flags).ok_or(ElfError::MapFailed)?;
+	}
+	Ok(())
+}
+
+///Finds the symbol table and the string table, that it uses. None, if the kernel was stripped, or the tables are broken.
+fn symbol_tables(file:&[u8])->Option<(&[u8],&[u8])>{
+	let elf=Elf::from_bytes(file).ok()?;
+	let symtab=elf.section_header_iter().find(|sh|sh.sh_type()==elf_rs::SectionType::SHT_SYMTAB)?;
+	if symtab.entsize()!=SYM_SIZE{
+		log::warn!("The symbol table has entries of {} bytes instead of {}.",symtab.entsize(),SYM_SIZE);
+		return None;
+	}
+	let strtab=elf.section_header_nth(symtab.link() as usize).filter(|sh|sh.sh_type()==elf_rs::SectionType::SHT_STRTAB);
+	let (Some(symbols),Some(strings))=(symtab.content(),strtab.and_then(|sh|sh.content())) else{
+		log::warn!("The symbol table or its string table isn't inside of the file.");
+		return None;
+	};
+	Some((symbols,strings))
+}
+
+///The address and size of .eh_frame_hdr. It is in a LOAD segment, so it gets loaded with the rest of the kernel.
+fn eh_frame_hdr(file:&[u8])->Option<(u64,u64)>{
+	let elf=Elf::from_bytes(file).ok()?;
+	let ph=(0..).map_while(|i|elf.program_header_nth(i)).find(|ph|ph.ph_type()==elf_rs::ProgramType::OsSpecific(PT_GNU_EH_FRAME))?;
+	Some((ph.vaddr(),ph.memsz()))
+}
+
+///Copies a table to pages of its own, because the kernel file gets freed.
+fn keep(table:&[u8])->Result<u64,ElfError>{
+	let pages=(table.len() as u64).div_ceil(PAGE_SIZE) as usize;
+	let mem=unsafe{uefi_services::system_table().as_ref()}.boot_services()
+		.allocate_pages(AllocateType::AnyPages,MemoryType::LOADER_DATA,pages)
+		.map_err(|e|ElfError::OutOfMemory(e.status()))?;
+	//SAFETY:
+	// We just allocated enough pages at mem.
+	unsafe{core::ptr::copy_nonoverlapping(table.as_ptr(),mem as *mut u8,table.len())};
+	Ok(mem)
+}
+
+///Keeps a copy of the symbol table of the kernel, so it can print function names in backtraces.
+///Also finds .eh_frame_hdr, so the kernel can unwind its stack without frame pointers.
+pub fn keep_symbols(file:&[u8])->Result<Symbols,ElfError>{
+	let mut symbols=Symbols::NONE;
+	if let Some((eh_frame_hdr,size))=eh_frame_hdr(file){
+		symbols.eh_frame_hdr=eh_frame_hdr;
+		symbols.eh_frame_hdr_size=size;
+	}
+	let Some((symtab,strtab))=symbol_tables(file) else{
+		log::info!("The kernel has no symbol table, so backtraces will only have addresses.");
+		return Ok(symbols);
+	};
+	log::info!("Keeping {} symbols of the kernel",symtab.len() as u64/SYM_SIZE);
+	Ok(Symbols{
+		symtab_base:keep(symtab)?,
+		symtab_size:symtab.len() as u64,
+		strtab_base:keep(strtab)?,
+		strtab_size:strtab.len() as u64,
+		..symbols
+	})
 }
 
 pub fn get_pte<F, O>(pw: &mut PageWalker<Level4>, addr:u64, f:F) -> core::result::Result<O, u8>
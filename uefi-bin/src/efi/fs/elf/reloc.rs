@@ -0,0 +1,182 @@
+//Applies the dynamic relocations of a position independent kernel, so it can run at any base address.
+//Only the relocations, that a static PIE can have, are supported. Anything else fails the load.
+use super::{ElfError, SYM_SIZE};
+
+//Tags in the dynamic section.
+const DT_NULL:u64=0;
+const DT_NEEDED:u64=1;
+const DT_PLTRELSZ:u64=2;
+const DT_SYMTAB:u64=6;
+const DT_RELA:u64=7;
+const DT_RELASZ:u64=8;
+const DT_RELAENT:u64=9;
+const DT_SYMENT:u64=11;
+const DT_REL:u64=17;
+const DT_PLTREL:u64=20;
+const DT_JMPREL:u64=23;
+const DT_RELRSZ:u64=35;
+const DT_RELR:u64=36;
+const DT_RELRENT:u64=37;
+
+const DYN_SIZE:u64=16;
+const RELA_SIZE:u64=24;
+
+const R_X86_64_NONE:u32=0;
+const R_X86_64_64:u32=1;
+const R_X86_64_GLOB_DAT:u32=6;
+const R_X86_64_JUMP_SLOT:u32=7;
+const R_X86_64_RELATIVE:u32=8;
+
+const SHN_UNDEF:u16=0;
+const STB_WEAK:u8=2;
+
+///The loaded image. `data` starts at the virtual address `min`, and the image runs at `base`+vaddr.
+pub(super) struct Image<'a>{
+	pub data:&'a mut [u8],
+	pub min:u64,
+	pub base:u64,
+}
+
+impl Image<'_>{
+	///The offset in data for `len` bytes at `vaddr`.
+	fn offset(&self, vaddr:u64, len:u64)->Result<usize,ElfError>{
+		vaddr.checked_sub(self.min)
+			.filter(|o|o.checked_add(len).is_some_and(|end|end<=self.data.len() as u64))
+			.map(|o|o as usize)
+			.ok_or(ElfError::OutsideImage(vaddr))
+	}
+
+	fn read_u64(&self, vaddr:u64)->Result<u64,ElfError>{
+		let o=self.offset(vaddr,8)?;
+		Ok(u64::from_le_bytes(self.data[o..o+8].try_into().unwrap()))
+	}
+
+	fn write_u64(&mut self, vaddr:u64, value:u64)->Result<(),ElfError>{
+		let o=self.offset(vaddr,8)?;
+		self.data[o..o+8].copy_from_slice(&value.to_le_bytes());
+		Ok(())
+	}
+}
+
+///What the dynamic section says about relocations.
+#[derive(Default)]
+struct Dynamic{
+	rela:Option<(u64,u64)>,
+	jmprel:Option<(u64,u64)>,
+	relr:Option<(u64,u64)>,
+	symtab:Option<u64>,
+}
+
+fn read_dynamic(image:&Image, vaddr:u64, size:u64)->Result<Dynamic,ElfError>{
+	image.offset(vaddr,size)?;
+	let mut d=Dynamic::default();
+	let (mut rela,mut relasz,mut jmprel,mut pltrelsz,mut relr,mut relrsz)=(None,0,None,0,None,0);
+	for i in 0..size/DYN_SIZE{
+		let tag=image.read_u64(vaddr+i*DYN_SIZE)?;
+		let value=image.read_u64(vaddr+i*DYN_SIZE+8)?;
+		match tag{
+			DT_NULL=>break,
+			DT_NEEDED=>return Err(ElfError::NotStatic),
+			DT_REL=>return Err(ElfError::BadDynamic("REL relocations aren't used on x86_64")),
+			DT_PLTREL if value!=DT_RELA=>return Err(ElfError::BadDynamic("the PLT relocations aren't RELA")),
+			DT_RELAENT if value!=RELA_SIZE=>return Err(ElfError::BadDynamic("RELA entries have the wrong size")),
+			DT_SYMENT if value!=SYM_SIZE=>return Err(ElfError::BadDynamic("symbols have the wrong size")),
+			DT_RELRENT if value!=8=>return Err(ElfError::BadDynamic("RELR entries have the wrong size")),
+			DT_RELA=>rela=Some(value),
+			DT_RELASZ=>relasz=value,
+			DT_JMPREL=>jmprel=Some(value),
+			DT_PLTRELSZ=>pltrelsz=value,
+			DT_RELR=>relr=Some(value),
+			DT_RELRSZ=>relrsz=value,
+			DT_SYMTAB=>d.symtab=Some(value),
+			_=>(),
+		}
+	}
+	d.rela=rela.map(|r|(r,relasz));
+	d.jmprel=jmprel.map(|r|(r,pltrelsz));
+	d.relr=relr.map(|r|(r,relrsz));
+	Ok(d)
+}
+
+///The value of symbol `index`. Undefined weak symbols are 0.
+fn symbol(image:&Image, symtab:Option<u64>, index:u32)->Result<u64,ElfError>{
+	let symtab=symtab.ok_or(ElfError::BadDynamic("relocations need symbols, but there is no symbol table"))?;
+	let at=symtab.checked_add(index as u64*SYM_SIZE).ok_or(ElfError::OutsideImage(symtab))?;
+	let o=image.offset(at,SYM_SIZE)?;
+	let sym=&image.data[o..o+SYM_SIZE as usize];
+	let info=sym[4];
+	let shndx=u16::from_le_bytes([sym[6],sym[7]]);
+	let value=u64::from_le_bytes(sym[8..16].try_into().unwrap());
+	match shndx{
+		SHN_UNDEF if info>>4==STB_WEAK=>Ok(0),
+		SHN_UNDEF=>Err(ElfError::UndefinedSymbol(index)),
+		_=>Ok(image.base.wrapping_add(value)),
+	}
+}
+
+fn apply_rela(image:&mut Image, symtab:Option<u64>, (table,size):(u64,u64))->Result<usize,ElfError>{
+	if !size.is_multiple_of(RELA_SIZE){
+		return Err(ElfError::BadDynamic("the size of a RELA table isn't a multiple of its entries"));
+	}
+	image.offset(table,size)?;
+	for i in 0..size/RELA_SIZE{
+		let entry=table+i*RELA_SIZE;
+		let offset=image.read_u64(entry)?;
+		let info=image.read_u64(entry+8)?;
+		let addend=image.read_u64(entry+16)?;
+		let (kind,sym)=(info as u32,(info>>32) as u32);
+		let value=match kind{
+			R_X86_64_NONE=>continue,
+			R_X86_64_RELATIVE=>image.base.wrapping_add(addend),
+			R_X86_64_64=>symbol(image,symtab,sym)?.wrapping_add(addend),
+			R_X86_64_GLOB_DAT|R_X86_64_JUMP_SLOT=>symbol(image,symtab,sym)?,
+			kind=>return Err(ElfError::UnsupportedRelocation(kind)),
+		};
+		image.write_u64(offset,value)?;
+	}
+	Ok((size/RELA_SIZE) as usize)
+}
+
+///RELR is a packed list of relative relocations without addends: an address, followed by bitmaps of the next 63 words.
+fn apply_relr(image:&mut Image, (table,size):(u64,u64))->Result<usize,ElfError>{
+	image.offset(table,size)?;
+	let mut count=0;
+	let mut next=0;
+	for i in 0..size/8{
+		let entry=image.read_u64(table+i*8)?;
+		if entry&1==0{
+			let value=image.read_u64(entry)?;
+			image.write_u64(entry,value.wrapping_add(image.base))?;
+			count+=1;
+			next=entry+8;
+			continue;
+		}
+		let words=entry>>1;
+		for bit in 0..63{
+			if words&(1<<bit)!=0{
+				let at=next+bit*8;
+				let value=image.read_u64(at)?;
+				image.write_u64(at,value.wrapping_add(image.base))?;
+				count+=1;
+			}
+		}
+		next+=63*8;
+	}
+	Ok(count)
+}
+
+///Applies all relocations, that the dynamic section at `vaddr` lists. Returns how many there were.
+pub(super) fn relocate(image:&mut Image, vaddr:u64, size:u64)->Result<usize,ElfError>{
+	let d=read_dynamic(image,vaddr,size)?;
+	let mut count=0;
+	if let Some(relr)=d.relr{
+		count+=apply_relr(image,relr)?;
+	}
+	if let Some(rela)=d.rela{
+		count+=apply_rela(image,d.symtab,rela)?;
+	}
+	if let Some(jmprel)=d.jmprel{
+		count+=apply_rela(image,d.symtab,jmprel)?;
+	}
+	Ok(count)
+}
@@ -4,7 +4,8 @@ use uefi::Result;
 use uefi::table::boot::{OpenProtocolAttributes, OpenProtocolParams, SearchType};
 use uefi::Identify;
 
-pub fn get_best_gop_fb(handle:Handle)->Result<kernel_efi::GOP>{
+///Sets the mode with the `preferred` resolution, if there is one, or else the best mode.
+pub fn get_best_gop_fb(handle:Handle, preferred:Option<(usize,usize)>)->Result<kernel_efi::GOP>{
 	//if uefi_services::system_table().as_ptr().is_null(){panic!();}
 	//SAFETY:
 	// The SystemTable is passed to Main fn.
@@ -43,31 +44,38 @@ pub fn get_best_gop_fb(handle:Handle)->Result<kernel_efi::GOP>{
 	// The protocol was opened in exclusive mode. UEFI should satisfy exclusive control.
 	let mut gop = &mut *gop_p;
 	{
-		let i = gop.modes();
-		let i = i.filter(|m|{let f = m.info().pixel_format(); f==PixelFormat::Bgr||f==PixelFormat::Rgb});
+		let usable = |m:&Mode|{let f = m.info().pixel_format(); f==PixelFormat::Bgr||f==PixelFormat::Rgb};
 		let mut mi:Option<Mode>=None;
-		for m in i{
-			if let Some(mis)=&mi{
-				let (rxn,ryn) = m.info().resolution();
-				let (rx,ry) = mis.info().resolution();
+		if let Some(res)=preferred{
+			mi=gop.modes().filter(usable).find(|m|m.info().resolution()==res);
+			if mi.is_none(){
+				log::warn!("There is no mode with a resolution of {}x{}, picking the best one instead.",res.0,res.1);
+			}
+		}
+		if mi.is_none(){
+			for m in gop.modes().filter(usable){
+				if let Some(mis)=&mi{
+					let (rxn,ryn) = m.info().resolution();
+					let (rx,ry) = mis.info().resolution();
 				
-				let same_or_better_res = rxn>=rx && ryn>=ry;
-				let better_pf = (m.info().pixel_format() as u32) < (mis.info().pixel_format() as u32);
-				let same_or_better_pf = (m.info().pixel_format() as u32) <= (mis.info().pixel_format() as u32);
-				//There is a better resolution available
-				if (ry!=ryn || rx!=rxn) && rxn>=rx && ryn>=ry {
-					mi=Some(m);
-					//There is an easier pixel format to work in
-				}else if better_pf && same_or_better_res {
-					mi=Some(m);
-					//we can waste less space.
-				}else if m.info().stride() < mis.info().stride() && same_or_better_pf && same_or_better_res {
+					let same_or_better_res = rxn>=rx && ryn>=ry;
+					let better_pf = (m.info().pixel_format() as u32) < (mis.info().pixel_format() as u32);
+					let same_or_better_pf = (m.info().pixel_format() as u32) <= (mis.info().pixel_format() as u32);
+					//There is a better resolution available
+					if (ry!=ryn || rx!=rxn) && rxn>=rx && ryn>=ry {
+						mi=Some(m);
+						//There is an easier pixel format to work in
+					}else if better_pf && same_or_better_res {
+						mi=Some(m);
+						//we can waste less space.
+					}else if m.info().stride() < mis.info().stride() && same_or_better_pf && same_or_better_res {
+						mi=Some(m);
+					}else{
+						continue;
+					}
+				} else {
 					mi=Some(m);
-				}else{
-					continue;
 				}
-			} else {
-				mi=Some(m);
 			}
 		}
 		if let Some(m)=mi{
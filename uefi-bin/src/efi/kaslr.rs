@@ -0,0 +1,137 @@
+//Kernel address space layout randomization. The boot loader picks random bases for the kernel, its heap and the direct map.
+//The randomness comes from EFI_RNG_PROTOCOL. Without it, RDSEED or RDRAND are used, and as a last resort the TSC.
+use core::fmt::{Display, Formatter};
+use uefi::proto::rng::Rng;
+use x86_64::{align_down, align_up};
+use kernel_efi::{Bases, Window};
+
+///How often RDSEED and RDRAND are tried, before giving up. Both can fail, if the hardware is out of entropy.
+const RETRIES:usize=16;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Source{
+	Firmware,
+	RdSeed,
+	RdRand,
+	Tsc,
+}
+
+impl Display for Source{
+	fn fmt(&self, f:&mut Formatter<'_>)->core::fmt::Result{
+		match self{
+			Source::Firmware=>write!(f,"EFI_RNG_PROTOCOL"),
+			Source::RdSeed=>write!(f,"RDSEED"),
+			Source::RdRand=>write!(f,"RDRAND"),
+			Source::Tsc=>write!(f,"the TSC"),
+		}
+	}
+}
+
+fn firmware()->Option<u64>{
+	let bs=unsafe{uefi_services::system_table().as_ref()}.boot_services();
+	let handle=bs.get_handle_for_protocol::<Rng>().ok()?;
+	let mut rng=match bs.open_protocol_exclusive::<Rng>(handle){
+		Ok(rng)=>rng,
+		Err(e)=>{
+			log::debug!("Can't open the RNG protocol: {:?}",e.status());
+			return None;
+		}
+	};
+	let mut buf=[0;8];
+	//None lets the firmware pick its default algorithm.
+	if let Err(e)=rng.get_rng(None,&mut buf){
+		log::debug!("The RNG protocol failed: {:?}",e.status());
+		return None;
+	}
+	Some(u64::from_le_bytes(buf))
+}
+
+fn rdseed()->Option<u64>{
+	//SAFETY:
+	// cpuid is always available in long mode.
+	let max_leaf=unsafe{core::arch::x86_64::__cpuid(0)}.eax;
+	//CPUID.(EAX=7,ECX=0):EBX bit 18
+	if max_leaf<7 || unsafe{core::arch::x86_64::__cpuid_count(7,0)}.ebx&(1<<18)==0{
+		return None;
+	}
+	#[target_feature(enable="rdseed")]
+	unsafe fn step()->Option<u64>{
+		let mut value=0;
+		(core::arch::x86_64::_rdseed64_step(&mut value)==1).then_some(value)
+	}
+	//SAFETY:
+	// We checked above, that the cpu has RDSEED.
+	(0..RETRIES).find_map(|_|unsafe{step()})
+}
+
+fn rdrand()->Option<u64>{
+	let rdrand=x86_64::instructions::random::RdRand::new()?;
+	(0..RETRIES).find_map(|_|rdrand.get_u64())
+}
+
+///The TSC is predictable, but better than nothing. splitmix64 spreads its low bits over the whole number.
+fn tsc()->u64{
+	//SAFETY:
+	// rdtsc has no side effects.
+	let mut z=unsafe{core::arch::x86_64::_rdtsc()}.wrapping_add(0x9E37_79B9_7F4A_7C15);
+	z=(z^(z>>30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+	z=(z^(z>>27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+	z^(z>>31)
+}
+
+///A random number from the best source, that is available.
+pub fn random()->(u64,Source){
+	if let Some(r)=firmware(){
+		return (r,Source::Firmware);
+	}
+	if let Some(r)=rdseed(){
+		return (r,Source::RdSeed);
+	}
+	if let Some(r)=rdrand(){
+		return (r,Source::RdRand);
+	}
+	(tsc(),Source::Tsc)
+}
+
+///Picks a random base in `window` for `size` bytes. None, if they don't fit.
+pub fn choose(window:&Window, size:u64, what:&str)->Option<u64>{
+	let first=align_up(window.start,window.align);
+	let last=align_down(window.end.checked_sub(size)?,window.align);
+	if last<first{
+		return None;
+	}
+	let slots=(last-first)/window.align+1;
+	let (r,source)=random();
+	let base=first+r%slots*window.align;
+	log::info!("KASLR: the {} is at {:#x} ({} possible bases, random number from {})",what,base,slots,source);
+	if source==Source::Tsc{
+		log::warn!("There is no hardware random number generator, so the {} base is easy to guess.",what);
+	}
+	Some(base)
+}
+
+///Picks the bases for the kernel, its heap and the direct map. Without KASLR, or if something doesn't fit in its window, the fixed bases are used.
+///`kernel_size` is None for a kernel, that isn't position independent. It can't be moved, so it always gets KERNEL_ADDR.
+pub fn bases(enabled:bool, kernel_window:&Window, kernel_size:Option<u64>, heap_size:u64, phys_end:u64)->Bases{
+	let pick=|window:&Window,size:u64,what:&str,fixed:u64|{
+		if !enabled{
+			return fixed;
+		}
+		choose(window,size,what).unwrap_or_else(||{
+			log::warn!("The {} doesn't fit in {:#x}..{:#x}, so it isn't randomized.",what,window.start,window.end);
+			fixed
+		})
+	};
+	Bases{
+		kernel:match kernel_size{
+			Some(size)=>pick(kernel_window,size,"kernel",kernel_efi::KERNEL_ADDR as u64),
+			None=>{
+				log::warn!("The kernel isn't position independent, so it is loaded at {:#x} without KASLR.",kernel_efi::KERNEL_ADDR as u64);
+				kernel_efi::KERNEL_ADDR as u64
+			},
+		},
+		heap:pick(&kernel_efi::HEAP_WINDOW,heap_size,"heap",kernel_efi::HEAP_ADDR),
+		//0 keeps using the identity map of UEFI.
+		direct_map:pick(&kernel_efi::DIRECT_MAP_WINDOW,phys_end,"direct map",0),
+	}
+}
@@ -0,0 +1,42 @@
+//The load options of our image, which are used as kernel command line.
+//They are set by boot entries, or by the UEFI shell, which puts the path of the image in front.
+use alloc::string::{String, ToString};
+use uefi::Handle;
+use uefi::proto::loaded_image::LoadedImage;
+use uefi::table::boot::{OpenProtocolAttributes, OpenProtocolParams};
+
+///Returns the load options as command line, or None, if there aren't any.
+pub fn cmdline(handle:Handle)->Option<String>{
+	let st = unsafe{uefi_services::system_table().as_ref()};
+	//SAFETY:
+	// The protocol is only read, and closed again before we return.
+	let image = match unsafe{st.boot_services().open_protocol::<LoadedImage>(
+		OpenProtocolParams{handle, agent:handle, controller:None},
+		OpenProtocolAttributes::GetProtocol,
+	)}{
+		Ok(image)=>image,
+		Err(e)=>{
+			log::warn!("Can't open the loaded image protocol: {:?}", e.status());
+			return None;
+		}
+	};
+	let options = match image.load_options_as_cstr16(){
+		Ok(options)=>options.to_string(),
+		Err(e)=>{
+			log::debug!("No usable load options: {:?}", e);
+			return None;
+		}
+	};
+	//Drop the path of the image, that the shell passes as first argument.
+	let options = options.trim();
+	let options = match options.split_once(' '){
+		Some((first, rest)) if first.to_ascii_lowercase().ends_with(".efi")=>rest.trim(),
+		None if options.to_ascii_lowercase().ends_with(".efi")=>"",
+		_=>options,
+	};
+	if options.is_empty(){
+		return None;
+	}
+	log::info!("Using the load options as kernel command line: {}", options);
+	Some(String::from(options))
+}
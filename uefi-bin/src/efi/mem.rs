@@ -38,4 +38,129 @@ pub fn get_mem<F,R>(f:Option<impl FnOnce(&dyn Iterator<Item=MemoryDescriptor>)->
 
 fn get_type(x:&MemoryDescriptor)->bool{
 	x.ty==MemoryType::CONVENTIONAL
-}
\ No newline at end of file
+}
+///The end of the highest range in the memory map, so everything the firmware knows about is below it.
+pub fn phys_end()->Option<u64>{
+	let st=unsafe{uefi_services::system_table().as_ref()};
+	let boot=st.boot_services();
+	let mem_size=boot.memory_map_size();
+	let size=mem_size.map_size+mem_size.entry_size*8;
+	let s=boot.allocate_pool(MemoryType::LOADER_DATA,size).ok()?;
+	//SAFETY:
+	// We just allocated size bytes at s.
+	let buf=unsafe{
+		boot.set_mem(s,size,0);
+		core::slice::from_raw_parts_mut(s,size)
+	};
+	let end=boot.memory_map(buf).ok().and_then(|mm|mm.entries().map(|d|d.phys_start+d.page_count*4096).max());
+	boot.free_pool(s).ok()?;
+	end
+}
+
+///MMIO below 4 GiB, like the LAPIC, the IOAPIC and PCI BARs, is usually not in the memory map, so the direct map covers at least this much.
+pub const LOW_MMIO_END:u64=1<<32;
+const SIZE_4K:u64=4096;
+const SIZE_2M:u64=2<<20;
+const SIZE_1G:u64=1<<30;
+
+///Memory types, that are RAM. Everything else might be MMIO.
+const RAM_TYPES:[MemoryType;10]=[
+	MemoryType::CONVENTIONAL,
+	MemoryType::LOADER_CODE,
+	MemoryType::LOADER_DATA,
+	MemoryType::BOOT_SERVICES_CODE,
+	MemoryType::BOOT_SERVICES_DATA,
+	MemoryType::RUNTIME_SERVICES_CODE,
+	MemoryType::RUNTIME_SERVICES_DATA,
+	MemoryType::ACPI_RECLAIM,
+	MemoryType::ACPI_NON_VOLATILE,
+	MemoryType::PERSISTENT_MEMORY,
+];
+
+///The RAM in the memory map, as sorted [start, end) ranges, with neighbouring ranges merged.
+fn ram_ranges()->Option<alloc::vec::Vec<(u64,u64)>>{
+	let st=unsafe{uefi_services::system_table().as_ref()};
+	let boot=st.boot_services();
+	let mem_size=boot.memory_map_size();
+	let size=mem_size.map_size+mem_size.entry_size*8;
+	let s=boot.allocate_pool(MemoryType::LOADER_DATA,size).ok()?;
+	//SAFETY:
+	// We just allocated size bytes at s.
+	let buf=unsafe{
+		boot.set_mem(s,size,0);
+		core::slice::from_raw_parts_mut(s,size)
+	};
+	let mut ranges:alloc::vec::Vec<(u64,u64)>=match boot.memory_map(buf){
+		Ok(mm)=>mm.entries().filter(|d|RAM_TYPES.contains(&d.ty)).map(|d|(d.phys_start,d.phys_start+d.page_count*SIZE_4K)).collect(),
+		Err(_)=>{
+			boot.free_pool(s).ok()?;
+			return None;
+		}
+	};
+	boot.free_pool(s).ok()?;
+	ranges.sort_unstable();
+	let mut merged:alloc::vec::Vec<(u64,u64)>=alloc::vec::Vec::with_capacity(ranges.len());
+	for (start,end) in ranges{
+		match merged.last_mut(){
+			Some((_,last_end)) if *last_end>=start=>*last_end=(*last_end).max(end),
+			_=>merged.push((start,end)),
+		}
+	}
+	Some(merged)
+}
+
+fn has_1g_pages()->bool{
+	//SAFETY:
+	// cpuid is always available in long mode, and leaf 0x8000_0001 is only read, if it exists.
+	unsafe{
+		core::arch::x86_64::__cpuid(0x8000_0000).eax>=0x8000_0001 && core::arch::x86_64::__cpuid(0x8000_0001).edx&(1<<26)!=0
+	}
+}
+
+///Maps physical memory from 0 up to `end` at `base`, for the direct map of the kernel.
+///RAM is mapped write-back, everything else uncached, since it might be MMIO.
+///The biggest pages are used, that fit into a run of either, so only their edges need 4 KiB pages.
+pub fn map_phys(base:u64, end:u64)->Option<()>{
+	use x86_64::structures::paging::PageTableFlags;
+	let ram=ram_ranges()?;
+	let sizes:&[u64]=if has_1g_pages() {&[SIZE_1G,SIZE_2M,SIZE_4K]} else {&[SIZE_2M,SIZE_4K]};
+	let ram_flags=PageTableFlags::PRESENT|PageTableFlags::WRITABLE|PageTableFlags::NO_EXECUTE;
+	let mmio_flags=ram_flags|PageTableFlags::NO_CACHE|PageTableFlags::WRITE_THROUGH;
+	let end=x86_64::align_up(end,SIZE_4K);
+	let mut addr=0;
+	while addr<end{
+		//The run from addr on, that is either all RAM or all not RAM.
+		let (is_ram,run_end)=match ram.iter().find(|(_,e)|*e>addr){
+			Some(&(s,e)) if s<=addr=>(true,e),
+			Some(&(s,_))=>(false,s),
+			None=>(false,end),
+		};
+		let run_end=run_end.min(end);
+		//Everything is 4 KiB aligned, so the smallest size always fits.
+		let size=*sizes.iter().find(|s|addr%**s==0 && addr+**s<=run_end).unwrap_or(&SIZE_4K);
+		map_page(base+addr,addr,size,if is_ram {ram_flags} else {mmio_flags})?;
+		addr+=size;
+	}
+	Some(())
+}
+
+///Maps a single page of `size` bytes. 2 MiB and 1 GiB pages are set directly in the page directory and the PDPT.
+fn map_page(virt:u64, phys:u64, size:u64, flags:x86_64::structures::paging::PageTableFlags)->Option<()>{
+	use x86_64::PhysAddr;
+	use x86_64::structures::paging::PageTableFlags;
+	let mut pw5;
+	let mut pw=match x64::paging::get_page_walker()?{
+		Ok(w)=>{
+			pw5=w;
+			pw5.create_pt(virt).ok()?
+		},
+		Err(w)=>w,
+	};
+	let addr=PhysAddr::new(phys);
+	match size{
+		SIZE_1G=>pw.create_pt(virt).ok()?.entry(virt).set_addr(addr,flags|PageTableFlags::HUGE_PAGE),
+		SIZE_2M=>pw.create_pt(virt).ok()?.create_pt(virt).ok()?.entry(virt).set_addr(addr,flags|PageTableFlags::HUGE_PAGE),
+		_=>super::fs::elf::get_pte(&mut pw,virt,|x|x.set_addr(addr,flags)).ok()?,
+	}
+	Some(())
+}
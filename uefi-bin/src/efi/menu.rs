@@ -0,0 +1,140 @@
+//The boot menu on the UEFI text console.
+//Only plain ASCII and cursor positioning are used, so it also works on a serial terminal, like OVMF with -serial stdio.
+use core::fmt::Write;
+use alloc::string::String;
+use alloc::vec::Vec;
+use uefi::proto::console::text::{Key, ScanCode};
+use kernel_efi::CMDLINE_MAX;
+use super::config::{BootConfig, BootEntry};
+
+///How often the keyboard is checked, in microseconds.
+const POLL_INTERVAL:usize=50_000;
+const POLLS_PER_SECOND:usize=1_000_000/POLL_INTERVAL;
+///The row of the first entry.
+const FIRST_ENTRY_ROW:usize=2;
+
+const ENTER:char='\r';
+const BACKSPACE:char='\u{8}';
+
+fn st()->&'static mut uefi::table::SystemTable<uefi::table::Boot>{
+	//SAFETY:
+	// uefi_services keeps the SystemTable, that was passed to main. The references are never held across calls.
+	unsafe{uefi_services::system_table().as_mut()}
+}
+
+///Returns the next key press, if there is one.
+fn read_key()->Option<Key>{
+	match st().stdin().read_key(){
+		Ok(key)=>key,
+		Err(e)=>{
+			log::warn!("Can't read from the console: {:?}",e.status());
+			None
+		}
+	}
+}
+
+///Waits for the next key press.
+fn wait_key()->Key{
+	loop{
+		if let Some(key)=read_key(){
+			return key;
+		}
+		st().boot_services().stall(POLL_INTERVAL);
+	}
+}
+
+fn draw(entries:&[BootEntry], selected:usize){
+	let out=st().stdout();
+	out.clear().ok();
+	write!(out,"Boot menu\n\n").ok();
+	for (i,e) in entries.iter().enumerate(){
+		writeln!(out,"{} {}",if i==selected {'>'} else {' '},e.name).ok();
+	}
+	write!(out,"\nUp/Down: select, Enter: boot, e: edit the command line\n").ok();
+}
+
+///Shows the countdown below the help line.
+fn draw_countdown(entries:&[BootEntry], default:usize, seconds:usize){
+	let out=st().stdout();
+	out.set_cursor_position(0,FIRST_ENTRY_ROW+entries.len()+2).ok();
+	write!(out,"Booting {} in {}s. ",entries[default].name,seconds).ok();
+}
+
+///Lets the user edit a command line. Escape keeps the old one.
+fn edit(cmdline:&mut String){
+	write!(st().stdout(),"\nEnter: accept, Esc: cancel\n> {}",cmdline).ok();
+	let mut line=cmdline.clone();
+	loop{
+		match wait_key(){
+			Key::Special(ScanCode::ESCAPE)=>return,
+			Key::Printable(c)=>match char::from(c){
+				ENTER=>{
+					*cmdline=line;
+					return;
+				},
+				BACKSPACE=>{
+					if line.pop().is_some(){
+						write!(st().stdout(),"{0} {0}",BACKSPACE).ok();
+					}
+				},
+				c if !c.is_control() && line.len()+c.len_utf8()<=CMDLINE_MAX=>{
+					line.push(c);
+					write!(st().stdout(),"{}",c).ok();
+				},
+				_=>(),
+			},
+			Key::Special(_)=>(),
+		}
+	}
+}
+
+///Shows the boot menu, if there is a timeout, and returns the entry to boot.
+///Any key stops the countdown.
+pub fn choose(config:&BootConfig)->BootEntry{
+	let mut entries:Vec<BootEntry>=config.entries.clone();
+	if config.timeout==0{
+		return entries.swap_remove(config.default);
+	}
+	//The firmware resets the machine, if a boot application runs for more than 5 minutes.
+	if let Err(e)=st().boot_services().set_watchdog_timer(0,0x10000,None){
+		log::warn!("Can't disable the watchdog timer: {:?}",e.status());
+	}
+	st().stdin().reset(false).ok();
+	let mut selected=config.default;
+	let mut polls=Some(config.timeout as usize*POLLS_PER_SECOND);
+	draw(&entries,selected);
+	loop{
+		let key=match polls{
+			Some(0)=>break,
+			Some(p)=>{
+				if p%POLLS_PER_SECOND==0{
+					draw_countdown(&entries,config.default,p/POLLS_PER_SECOND);
+				}
+				match read_key(){
+					Some(key)=>{
+						polls=None;
+						key
+					},
+					None=>{
+						st().boot_services().stall(POLL_INTERVAL);
+						polls=Some(p-1);
+						continue;
+					}
+				}
+			},
+			None=>wait_key(),
+		};
+		match key{
+			Key::Special(ScanCode::UP)=>selected=(selected+entries.len()-1)%entries.len(),
+			Key::Special(ScanCode::DOWN)=>selected=(selected+1)%entries.len(),
+			Key::Printable(c) if char::from(c)==ENTER=>break,
+			Key::Printable(c) if char::from(c)=='e'=>edit(&mut entries[selected].cmdline),
+			_=>continue,
+		}
+		draw(&entries,selected);
+	}
+	st().stdout().clear().ok();
+	let entry=entries.swap_remove(selected);
+	log::info!("Booting {}",entry.name);
+	entry
+}
@@ -1,5 +1,6 @@
 #![allow(non_snake_case)]
 use core::ffi::c_void;
+use uefi::table::cfg::{ACPI2_GUID, ACPI_GUID};
 
 const RSDP_SIGNATURE_MAGIC:&[u8]="RSD PTR ".as_bytes();
 #[repr(packed)]
@@ -83,4 +84,22 @@ impl RSDP2{
 		// }
 		
 	}
+}
+
+///Finds the RSDP in the UEFI configuration table.
+///The ACPI 2.0 RSDP is preferred, since it points to the XSDT.
+pub fn find_rsdp()->Option<*const c_void>{
+	let st = unsafe{uefi_services::system_table().as_ref()};
+	let tables = st.config_table();
+	let entry = tables.iter().find(|e|e.guid==ACPI2_GUID)
+		.or_else(||tables.iter().find(|e|e.guid==ACPI_GUID))?;
+	//Safety:
+	//The firmware guarantees, that the address of an ACPI config table entry points to a RSDP.
+	match unsafe{RSDP::from_ptr(entry.address)}{
+		Ok(_)=>Some(entry.address),
+		Err(_)=>{
+			log::warn!("The RSDP from the UEFI configuration table has an invalid signature.");
+			None
+		}
+	}
 }
\ No newline at end of file
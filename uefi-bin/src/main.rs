@@ -34,6 +34,15 @@ fn entry(handle: Handle, system_table: SystemTable<Boot>) -> uefi::Status{
 fn main(handle: Handle, mut system_table: SystemTable<Boot>) -> uefi::Result {
     uefi_services::init(&mut system_table).unwrap();
     let system_table=unsafe{uefi_services::system_table().as_mut()};
+    let mut config=efi::config::BootConfig::load();
+    log::set_max_level(config.log_level);
+    //Load options from a boot entry or the shell take precedence over boot.cfg.
+    if let Some(cmdline)=efi::load_options::cmdline(handle){
+        for entry in &mut config.entries{
+            entry.cmdline=cmdline.clone();
+        }
+    }
+    let entry=efi::menu::choose(&config);
     //This logger impl will be taken over by uefi-services
     #[cfg(any())]
     if false{
@@ -108,27 +117,65 @@ fn main(handle: Handle, mut system_table: SystemTable<Boot>) -> uefi::Result {
         }
     }
     {
-        let map_file={
-            let elf_kernel_file=efi::fs::load_file(efi::fs::KERNEL_NAME)?;
-            let map_file=efi::fs::elf::map_elf(elf_kernel_file,kernel_efi::KERNEL_ADDR)?;
-            system_table.boot_services().free_pages(elf_kernel_file as *const [u8] as *const u8 as u64,(elf_kernel_file.len()>>12)+1)?;
-            map_file
+        let (map_file,bases,symbols)={
+            let elf_kernel_file=efi::fs::load_file(&entry.kernel).map_err(|e|{
+                log::error!("Can't load the kernel {}: {:?}",entry.kernel,e.status());
+                e
+            })?;
+            let kernel_size=efi::fs::elf::relocatable_size(elf_kernel_file).map_err(|e|{
+                log::error!("Can't load the kernel {}: {}",entry.kernel,e);
+                e.status()
+            })?;
+            let phys_end=efi::mem::phys_end().ok_or(Status::OUT_OF_RESOURCES)?.max(efi::mem::LOW_MMIO_END);
+            let bases=efi::kaslr::bases(config.kaslr,&config.kaslr_window,kernel_size,heap_size*4096,phys_end);
+            if bases.direct_map!=0{
+                efi::mem::map_phys(bases.direct_map,phys_end).ok_or_else(||{
+                    log::error!("Can't map physical memory at {:#x}",bases.direct_map);
+                    Status::OUT_OF_RESOURCES
+                })?;
+            }
+            let map_file=efi::fs::elf::map_elf(elf_kernel_file,bases.kernel as *mut u8).map_err(|e|{
+                log::error!("Can't load the kernel {}: {}",entry.kernel,e);
+                e.status()
+            })?;
+            let symbols=efi::fs::elf::keep_symbols(elf_kernel_file).map_err(|e|{
+                log::error!("Can't keep the symbols of the kernel {}: {}",entry.kernel,e);
+                e.status()
+            })?;
+            efi::fs::free_file(elf_kernel_file)?;
+            (map_file,bases,symbols)
         };
         set_bits(base_prt as *mut u64,map_file.base/4096,map_file.pages+3);
+        //The kernel must not hand out the pages of the symbol table either.
+        reserve(base_prt as *mut u64,symbols.symtab_base,symbols.symtab_size);
+        reserve(base_prt as *mut u64,symbols.strtab_base,symbols.strtab_size);
+        let initrd=entry.initrd.as_deref().and_then(efi::fs::load_initrd);
+        //The kernel must not hand out the pages of the initrd.
+        if let Some(initrd)=initrd{
+            reserve(base_prt as *mut u64,initrd.as_ptr() as u64,initrd.len() as u64);
+        }
         let ff ={
-            const FONT_FILE:&str = "font.ttf";
-            let f = efi::fs::load_file(FONT_FILE)?;
-            if let Some(nf)=kernel_efi::ttf_parser::fonts_in_collection(f){
-                if nf>1{
-                    kernel_efi::ttf_parser::Face::from_slice(f,0).unwrap()
-                }else {
-                    panic!();
+            let f = efi::fs::load_file(&config.font).map_err(|e|{
+                log::error!("Can't load the font {}: {:?}",config.font,e.status());
+                e
+            })?;
+            //A plain font file has a single face.
+            let faces=kernel_efi::ttf_parser::fonts_in_collection(f).unwrap_or(1);
+            if config.font_index>=faces{
+                log::error!("The font {} has {} face(s), so there is no face {}.",config.font,faces,config.font_index);
+                efi::fs::free_file(f)?;
+                return Err(Status::INVALID_PARAMETER.into());
+            }
+            match kernel_efi::ttf_parser::Face::from_slice(f,config.font_index){
+                Ok(face)=>face,
+                Err(e)=>{
+                    log::error!("Can't parse face {} of the font {}: {:?}",config.font_index,config.font,e);
+                    efi::fs::free_file(f)?;
+                    return Err(Status::LOAD_ERROR.into());
                 }
-            }else{
-                system_table.boot_services().free_pages(f as *const [u8] as *const u8 as u64,(f.len()>>12)+1).unwrap();
-                panic!();
             }
         };
+        let (cmdline,cmdline_len)=entry.cmdline_bytes();
         let mut pte = [core::ptr::null_mut();3];
         //Mark kernel_efi::ARGS_ADDR as r,w,nx.
         {
@@ -181,11 +228,19 @@ fn main(handle: Handle, mut system_table: SystemTable<Boot>) -> uefi::Result {
                  Args {
                      elf: map_file,
                      font: ff,
-                     gop: efi::gop::get_best_gop_fb(handle)?,
+                     gop: efi::gop::get_best_gop_fb(handle,config.resolution)?,
                      heap_size,
                      page_tracker_base: base_prt as *mut (),
                      page_tracker_page_size: prt_pages as usize,
                      page_table_entry: pte,
+                     rsdp: efi::tables::rsdp::find_rsdp().map(|p|p as u64).unwrap_or(0),
+                     boot_partition: efi::boot_device::boot_partition(handle),
+                     initrd_base: initrd.map(|i|i.as_ptr() as u64).unwrap_or(0),
+                     initrd_size: initrd.map(|i|i.len() as u64).unwrap_or(0),
+                     cmdline,
+                     cmdline_len,
+                     bases,
+                     symbols,
                  }
             );
         }
@@ -204,33 +259,30 @@ fn main(handle: Handle, mut system_table: SystemTable<Boot>) -> uefi::Result {
     Ok(())
 }
 
+///Marks every page, that `size` bytes at the physical address `base` touch, as used in the page tracker.
+fn reserve(base_ptr:*mut u64, base:u64, size:u64){
+    if size==0{
+        return;
+    }
+    let first=base/4096;
+    let end=(base+size).div_ceil(4096);
+    set_bits(base_ptr,first as usize,(end-first) as usize);
+}
+
+///Marks `size` pages, starting at page `offset`, as used in the page tracker at `base_ptr`. Page n is bit n%64 of word n/64.
+///Bits, that are already set, stay set, so reservations can share a word.
 fn set_bits(base_ptr:*mut u64, offset:usize, size:usize){
-    {
-        let mut prt_offset = offset; //map_file.base as u64 / 4096;
-        let mut size = size;//map_file.pages;
-        if prt_offset%64 != 0 {
-            let offset = prt_offset % 64;
-            let bits_available = 64 - offset;
-            let bits_to_set =
-                //mask all bits before the first bit to set
-                (!(u64::MAX<<bits_available))&
-                    //mask all bits after the potentially last bit to set
-                    (!(u64::MAX<< if size > bits_available as usize {0} else {size}));
-            //this should set size or bits_available bits to 1 (whichever is less), starting at offset
-            prt_offset/=64;
-            unsafe {
-                core::ptr::write_volatile((base_ptr as *mut u64).wrapping_offset(prt_offset as isize), bits_to_set);
-            }
-            prt_offset+=1;
-            size-=bits_to_set.count_ones() as usize;
-        };
-        while size >= 64 {
-            unsafe{core::ptr::write_volatile((base_ptr as *mut u64).wrapping_offset(prt_offset as isize), u64::MAX)}
-            prt_offset+=1;
-            size-=64;
-        }
+    let end = offset+size;
+    let mut page = offset;
+    while page < end {
+        let bit = page % 64;
+        let bits = (64 - bit).min(end - page);
+        //bits is 1..=64, so the shift stays below 64.
+        let mask = (u64::MAX >> (64 - bits)) << bit;
+        let word = base_ptr.wrapping_add(page / 64);
         unsafe {
-            core::ptr::write_volatile((base_ptr as *mut u64).wrapping_offset(prt_offset as isize), !(u64::MAX << 64-size));
+            core::ptr::write_volatile(word, core::ptr::read_volatile(word) | mask);
         }
+        page += bits;
     }
-}
\ No newline at end of file
+}
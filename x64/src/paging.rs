@@ -69,6 +69,12 @@ impl<'a,L:LevelTable> PageWalker<'a,L>{
 			).map_err(|(l,_)|l)
 	}
 	
+	///The entry for `addr` in this table, whether it is used or not.
+	///For mapping a huge page at this level, instead of a table below it.
+	pub fn entry(&mut self,addr:u64)->&mut PageTableEntry{
+		self.addr.index_mut(((addr>>12>>(L::get_level().get_level()-1)*9)&511) as usize)
+	}
+	
 	///## Safety:
 	/// L1 needs to be equal to L::Down
 	fn index(&mut self, index: usize) -> Option<PageWalker<'a,L::Down>> {